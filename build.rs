@@ -1,31 +1,222 @@
 use std::path::Path;
 
+use kernel_shared::integrity::{
+    IntegrityRecord, ReadOnlySegments, Sha256, INTEGRITY_MAGIC, INTEGRITY_SYMBOL,
+};
+
 const UEFI_IMAGE_NAME: &str = "uefi.img";
 const BIOS_IMAGE_NAME: &str = "bios.img";
+const INITRAMFS_NAME: &str = "initramfs.cpio";
+const SEALED_KERNEL_NAME: &str = "kernel-sealed";
+// A directory of built userspace binaries, packed into the initramfs under bin/. Relative to the
+// repository root, and left out if it doesn't exist.
+const USERLAND_VARIABLE: &str = "OXIDIZED_USERLAND";
+const USERLAND_DEFAULT: &str = "userland";
+const DIRECTORY_MODE: u32 = 0o040755;
+const FILE_MODE: u32 = 0o100755;
 
 fn main() {
     // set by cargo, build scripts should use this directory for output files
     let kernel_os_string = std::env::var_os("CARGO_BIN_FILE_KERNEL_kernel").unwrap();
     let kernel_str = kernel_os_string.to_str().unwrap();
-    let ramdisk = Path::new("build.rs").canonicalize().unwrap();
 
     let out_dir_os_string = std::env::var_os("OUT_DIR").unwrap();
     let out_dir_str = out_dir_os_string.to_str().unwrap();
     let out_dir = Path::new(out_dir_str);
     // set by cargo's artifact dependency feature, see
     // https://doc.rust-lang.org/nightly/cargo/reference/unstable.html#artifact-dependencies
-    let kernel = Path::new(kernel_str);
+    let artifact = Path::new(kernel_str);
+    // Everything boots the sealed copy, with its integrity record filled in.
+    let kernel = out_dir.join(SEALED_KERNEL_NAME);
+    seal_kernel(artifact, &kernel);
+    // Build scripts aren't told which target an artifact was built for, the kernel's ELF header
+    // says.
+    let kernel_arch = kernel_arch(artifact);
+
+    let userland = std::env::var(USERLAND_VARIABLE).unwrap_or(USERLAND_DEFAULT.to_string());
+    println!("cargo:rerun-if-env-changed={}", USERLAND_VARIABLE);
+    println!("cargo:rerun-if-changed={}", userland);
+    println!("cargo:rerun-if-changed=build.rs");
+    let initramfs_path = out_dir.join(INITRAMFS_NAME);
+    pack_initramfs(Path::new(&userland), &initramfs_path);
 
     // create an UEFI disk image (optional)
     let uefi_path = out_dir.join(UEFI_IMAGE_NAME);
     let bios_path = out_dir.join(BIOS_IMAGE_NAME);
-    let binding = kernel.to_path_buf();
-    let mut disk_image_builder = bootloader::DiskImageBuilder::new(binding);
-    disk_image_builder.set_ramdisk(ramdisk);
-    disk_image_builder.create_uefi_image(&uefi_path).unwrap();
-    //disk_image_builder.create_bios_image(&bios_path).unwrap();
+    // The bootloader only builds x86_64 images, other architectures boot the kernel directly.
+    if kernel_arch == "x86_64" {
+        let mut disk_image_builder = bootloader::DiskImageBuilder::new(kernel.clone());
+        disk_image_builder.set_ramdisk(initramfs_path.clone());
+        disk_image_builder.create_uefi_image(&uefi_path).unwrap();
+        //disk_image_builder.create_bios_image(&bios_path).unwrap();
+    }
 
-    // pass the disk image paths as env variables to the `main.rs`
+    // pass the architecture, kernel, initramfs and image paths as env variables to the `main.rs`
+    println!("cargo:rustc-env=KERNEL_ARCH={}", kernel_arch);
+    println!("cargo:rustc-env=KERNEL_PATH={}", kernel.display());
+    println!(
+        "cargo:rustc-env=INITRAMFS_PATH={}",
+        initramfs_path.display()
+    );
     println!("cargo:rustc-env=UEFI_PATH={}", uefi_path.display());
     println!("cargo:rustc-env=BIOS_PATH={}", bios_path.display());
 }
+
+/// Writes a copy of the kernel with its integrity record holding the digest of its read-only
+/// segments, see kernel_shared::integrity. The record is in writable data, so writing it doesn't
+/// change the digest.
+fn seal_kernel(kernel: &Path, output: &Path) {
+    let mut image = std::fs::read(kernel).expect("Unable to read the kernel");
+    let mut sha = Sha256::new();
+    let mut length = 0;
+    for segment in ReadOnlySegments::new(&image).expect("The kernel is not an ELF64 image") {
+        let start = segment.offset as usize;
+        sha.update(&image[start..start + segment.file_size as usize]);
+        length += segment.file_size;
+    }
+    let record = IntegrityRecord {
+        sealed: 1,
+        length,
+        digest: sha.finish(),
+        ..IntegrityRecord::unsealed()
+    };
+    match symbol_file_offset(&image, INTEGRITY_SYMBOL) {
+        Some(offset) if read_field(&image, offset, 8) == INTEGRITY_MAGIC => {
+            let bytes = record.to_bytes();
+            image[offset..offset + bytes.len()].copy_from_slice(&bytes);
+        }
+        _ => println!(
+            "cargo:warning=The kernel has no {} record, it is left unsealed",
+            INTEGRITY_SYMBOL
+        ),
+    }
+    std::fs::write(output, image).expect("Unable to write the sealed kernel");
+}
+
+// Where a symbol's data is in the file, from the symbol table and the section it's in.
+fn symbol_file_offset(image: &[u8], name: &str) -> Option<usize> {
+    const SECTION_HEADER_LENGTH: usize = 64;
+    const SYMBOL_LENGTH: usize = 24;
+    const SYMBOL_TABLE: u64 = 2;
+    let section_table = read_field(image, 0x28, 8) as usize;
+    let section_count = read_field(image, 0x3c, 2) as usize;
+    let section = |index: usize| section_table + index * SECTION_HEADER_LENGTH;
+
+    let symbol_table = (0..section_count)
+        .map(section)
+        .find(|header| read_field(image, header + 4, 4) == SYMBOL_TABLE)?;
+    let strings = section(read_field(image, symbol_table + 0x28, 4) as usize);
+    let strings_start = read_field(image, strings + 0x18, 8) as usize;
+    let symbols_start = read_field(image, symbol_table + 0x18, 8) as usize;
+    let symbols_length = read_field(image, symbol_table + 0x20, 8) as usize;
+
+    for symbol in (symbols_start..symbols_start + symbols_length).step_by(SYMBOL_LENGTH) {
+        let name_start = strings_start + read_field(image, symbol, 4) as usize;
+        let name_length = image[name_start..].iter().position(|b| *b == 0)?;
+        if &image[name_start..name_start + name_length] != name.as_bytes() {
+            continue;
+        }
+        let containing = section(read_field(image, symbol + 6, 2) as usize);
+        let address = read_field(image, symbol + 8, 8);
+        let section_address = read_field(image, containing + 0x10, 8);
+        let section_offset = read_field(image, containing + 0x18, 8);
+        return Some((section_offset + address - section_address) as usize);
+    }
+    None
+}
+
+/// The architecture the kernel was built for, from the machine field of its ELF header. Named the
+/// way the runner's KERNEL_ARCH expects.
+fn kernel_arch(kernel: &Path) -> &'static str {
+    const MACHINE_OFFSET: usize = 0x12;
+    const EM_X86_64: u64 = 0x3e;
+    const EM_AARCH64: u64 = 0xb7;
+    const EM_RISCV: u64 = 0xf3;
+    let image = std::fs::read(kernel).expect("Unable to read the kernel");
+    match read_field(&image, MACHINE_OFFSET, 2) {
+        EM_X86_64 => "x86_64",
+        EM_AARCH64 => "aarch64",
+        EM_RISCV => "riscv64",
+        machine => panic!(
+            "The kernel is built for an unsupported machine {:#x}",
+            machine
+        ),
+    }
+}
+
+// A little endian field of the kernel ELF.
+fn read_field(image: &[u8], offset: usize, length: usize) -> u64 {
+    image[offset..offset + length]
+        .iter()
+        .rev()
+        .fold(0, |value, byte| (value << 8) | *byte as u64)
+}
+
+/// Packs every file under userland into a cpio archive, under bin/. An empty archive if there's
+/// no userland yet.
+fn pack_initramfs(userland: &Path, output: &Path) {
+    let mut archive = Vec::new();
+    let mut inode = 0;
+    if userland.is_dir() {
+        add_directory(&mut archive, &mut inode, userland, "bin");
+    }
+    write_cpio_entry(&mut archive, 0, "TRAILER!!!", 0, &[]);
+    std::fs::write(output, archive).expect("Unable to write the initramfs");
+}
+
+fn add_directory(archive: &mut Vec<u8>, inode: &mut u32, directory: &Path, name: &str) {
+    *inode += 1;
+    write_cpio_entry(archive, *inode, name, DIRECTORY_MODE, &[]);
+    let mut entries: Vec<_> = std::fs::read_dir(directory)
+        .expect("Unable to read the userland directory")
+        .filter_map(|e| e.ok())
+        .collect();
+    // Sorted, so the same files always make the same archive.
+    entries.sort_by_key(|e| e.file_name());
+    for entry in entries {
+        let path = entry.path();
+        let entry_name = format!("{}/{}", name, entry.file_name().to_string_lossy());
+        if path.is_dir() {
+            add_directory(archive, inode, &path, &entry_name);
+        } else {
+            let data = std::fs::read(&path).expect("Unable to read a userland binary");
+            *inode += 1;
+            write_cpio_entry(archive, *inode, &entry_name, FILE_MODE, &data);
+        }
+    }
+}
+
+// The cpio "newc" format, the one Linux initramfs uses: a header of hex fields, the name, then
+// the data, each padded to 4 bytes. Times and owners are zero, so builds are reproducible.
+fn write_cpio_entry(archive: &mut Vec<u8>, inode: u32, name: &str, mode: u32, data: &[u8]) {
+    let fields = [
+        inode,
+        mode,
+        0, // uid
+        0, // gid
+        1, // links
+        0, // mtime
+        data.len() as u32,
+        0, // device major
+        0, // device minor
+        0, // rdev major
+        0, // rdev minor
+        name.len() as u32 + 1,
+        0, // checksum, unused by newc
+    ];
+    archive.extend_from_slice(b"070701");
+    for field in fields {
+        archive.extend_from_slice(format!("{:08x}", field).as_bytes());
+    }
+    archive.extend_from_slice(name.as_bytes());
+    archive.push(0);
+    pad_to_four(archive);
+    archive.extend_from_slice(data);
+    pad_to_four(archive);
+}
+
+fn pad_to_four(archive: &mut Vec<u8>) {
+    while archive.len() % 4 != 0 {
+        archive.push(0);
+    }
+}
@@ -0,0 +1,25 @@
+/// Called with the context it was created with, when a timer expires.
+pub type TimerCallback = fn(context: usize);
+
+/// Identifies a timer created by a clock, to cancel it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TimerId(pub u64);
+
+/// Capability of devices that keep time, see Device::clock().
+pub trait Clock: Sync + Send {
+    /// Nanoseconds since an arbitrary point at boot, never goes backwards.
+    fn monotonic_nanoseconds(&self) -> u64;
+
+    /// Nanoseconds since the Unix epoch, None if the time of day isn't known.
+    fn wall_time_nanoseconds(&self) -> Option<u64>;
+
+    /// Smallest difference between two readings, in nanoseconds.
+    fn resolution_nanoseconds(&self) -> u64;
+
+    /// Calls callback once, no sooner than delay nanoseconds from now. Timers are run from the
+    /// kernel's idle loop, so they may run late, and must not block.
+    fn create_timer(&self, delay: u64, callback: TimerCallback, context: usize) -> TimerId;
+
+    /// Returns false if the timer already ran, or never existed.
+    fn cancel_timer(&self, timer: TimerId) -> bool;
+}
@@ -4,18 +4,25 @@
 #![feature(error_in_core)]
 extern crate alloc;
 
+pub mod clock;
+#[cfg(feature = "kernel")]
+pub mod naming;
+#[cfg(feature = "kernel")]
+pub mod readiness;
+pub mod sensor;
+pub mod smbus;
+#[cfg(feature = "user")]
+pub mod user;
 pub mod well_known;
 
-use core::{
-    cell::OnceCell, error::Error, fmt::Display, intrinsics::type_name,
-};
+use core::{error::Error, fmt::Display, intrinsics::type_name};
 
-use alloc::{
-    boxed::Box,
-    collections::BTreeMap,
-    string::{String, ToString},
-    vec::Vec,
-};
+use alloc::string::{String, ToString};
+#[cfg(feature = "kernel")]
+use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
+#[cfg(feature = "kernel")]
+use kernel_shared::init_cell::InitCell;
+#[cfg(feature = "kernel")]
 use spin::{RwLock, RwLockReadGuard, RwLockWriteGuard};
 use uuid::Uuid;
 
@@ -36,26 +43,15 @@ impl Device for DeviceTreeDevice{
         true
     }
 
-    fn function(&self, id: usize, args: &[usize]) -> Result<&[u8], DeviceError> {
-        match id {
-            0 => {
-                todo!()
-            },
-            1 => {
-                todo!()
-            }
-            _ => Err(DeviceError::new(DeviceErrorCode::NotImplemented))
-        }
-    }
-
     fn uuid(&self) -> Uuid {
-        *well_known::DEVICE_TREE
+        well_known::DEVICE_TREE
     }
 }
 
 #[cfg(feature = "kernel")]
 pub struct DeviceTree {
-    map: BTreeMap<u128, Box<dyn Device>>,
+    map: BTreeMap<u128, Arc<dyn Device>>,
+    names: naming::DeviceNames,
 }
 
 #[cfg(feature = "kernel")]
@@ -63,6 +59,7 @@ impl DeviceTree {
     fn new() -> Self {
         let mut ret = Self {
             map: BTreeMap::new(),
+            names: naming::DeviceNames::new(),
         };
         ret.register(DeviceTreeDevice{});
         ret
@@ -74,10 +71,28 @@ impl DeviceTree {
             current = current.wrapping_add(1);
         }
 
-        self.map.insert(current, Box::new(device));
+        self.names
+            .assign(current, device.class(), &device.uuid(), device.location());
+        self.map.insert(current, Arc::new(device));
         current
     }
 
+    pub fn names(&self) -> &naming::DeviceNames {
+        &self.names
+    }
+
+    pub fn names_mut(&mut self) -> &mut naming::DeviceNames {
+        &mut self.names
+    }
+
+    /// Finds a device by its name, an alias, or its id in hex.
+    pub fn resolve(&self, name: &str) -> Option<Arc<dyn Device>> {
+        match self.names.resolve(name) {
+            Some(id) => self.get(&id),
+            None => self.get(&u128::from_str_radix(name, 16).ok()?),
+        }
+    }
+
     pub fn get_device_path(&self, device: &(impl Device + ?Sized)) -> String {
         let mut ret = String::new();
         ret.insert_str(0, device.name().as_str());
@@ -86,8 +101,7 @@ impl DeviceTree {
             None => None,
         };
 
-        while next.is_some() {
-            let current = next.unwrap();
+        while let Some(current) = next {
             next = match current.parent_id() {
                 Some(p) => match self.get(&p) {
                     Some(s) => Some(s),
@@ -103,22 +117,21 @@ impl DeviceTree {
         ret
     }
 
-    pub fn unregister(&mut self, id: u128) -> Option<Box<dyn Device>> {
-        self.map.remove(&id)
+    /// Removes a device from the tree. Anyone who looked it up before may still be using it,
+    /// see Unregistered.
+    pub fn unregister(&mut self, id: u128) -> Option<Unregistered> {
+        readiness::forget(id);
+        self.names.remove(id);
+        self.map.remove(&id).map(|device| Unregistered { device })
     }
 
-    pub fn get(&self, id: &u128) -> Option<&dyn Device> {
-        match self.map.get(id) {
-            Some(v) => Some(v.as_ref()),
-            None => None,
-        }
+    pub fn get(&self, id: &u128) -> Option<Arc<dyn Device>> {
+        self.map.get(id).cloned()
     }
 
-    pub fn get_mut(&mut self, id: &u128) -> Option<&mut dyn Device> {
-        match self.map.get_mut(id) {
-            Some(v) => Some(v.as_mut()),
-            None => None,
-        }
+    /// The device, if nobody else holds a reference to it.
+    pub fn get_mut(&mut self, id: &u128) -> Option<&mut (dyn Device + 'static)> {
+        Arc::get_mut(self.map.get_mut(id)?)
     }
 
     pub fn keys(&self) -> Vec<u128> {
@@ -129,35 +142,95 @@ impl DeviceTree {
         v
     }
 
-    pub fn all(&self) -> Vec<&dyn Device> {
-        let mut ret = Vec::new();
-        for kv in self.map.iter() {
-            ret.push(kv.1.as_ref());
+    pub fn all(&self) -> Vec<Arc<dyn Device>> {
+        self.map.values().cloned().collect()
+    }
+}
+
+/// A device that's been taken out of the tree, but may still be in use by whoever looked it up
+/// before. It's only gone once they've all let go.
+#[cfg(feature = "kernel")]
+pub struct Unregistered {
+    device: Arc<dyn Device>,
+}
+
+#[cfg(feature = "kernel")]
+impl Unregistered {
+    /// How many references are still held, besides this one.
+    pub fn references(&self) -> usize {
+        Arc::strong_count(&self.device) - 1
+    }
+
+    pub fn in_use(&self) -> bool {
+        self.references() > 0
+    }
+
+    /// Waits for every other reference to be dropped, and returns the device, or gives it back
+    /// if expired() says to give up first. Don't hold the device tree's lock while waiting.
+    pub fn wait(self, expired: impl Fn() -> bool) -> Result<Arc<dyn Device>, Self> {
+        loop {
+            if !self.in_use() {
+                return Ok(self.device);
+            }
+            if expired() {
+                return Err(self);
+            }
+            core::hint::spin_loop();
         }
-        ret
     }
 }
 
+/// Takes the device tree's write lock. Never with interrupts disabled, which includes interrupt
+/// context: a reader the handler interrupted can't release its lock until it returns, and
+/// spinning with interrupts off holds up every IPI sent to this CPU.
 #[cfg(feature = "kernel")]
 pub fn get_mut_device_tree() -> RwLockWriteGuard<'static, DeviceTree> {
-    unsafe {
-        DEVICE_TREE
-            .get_or_init(|| RwLock::new(DeviceTree::new()))
-            .write()
-    }
+    debug_assert!(
+        interrupts_enabled(),
+        "device tree write lock taken with interrupts disabled"
+    );
+    DEVICE_TREE
+        .get_or_init(|| RwLock::new(DeviceTree::new()))
+        .write()
 }
 
 #[cfg(feature = "kernel")]
 pub fn get_device_tree() -> RwLockReadGuard<'static, DeviceTree> {
-    unsafe {
-        DEVICE_TREE
-            .get_or_init(|| RwLock::new(DeviceTree::new()))
-            .read()
+    DEVICE_TREE
+        .get_or_init(|| RwLock::new(DeviceTree::new()))
+        .read()
+}
+
+/// The device tree, or None while it's being changed. For interrupt handlers, which would
+/// spin forever on a write lock held by the code they interrupted.
+#[cfg(feature = "kernel")]
+pub fn try_get_device_tree() -> Option<RwLockReadGuard<'static, DeviceTree>> {
+    DEVICE_TREE
+        .get_or_init(|| RwLock::new(DeviceTree::new()))
+        .try_read()
+}
+
+/// Tells the device tree how to know whether interrupts are enabled, which it can't tell on its
+/// own. Until set, it assumes they are: the boot CPU registers its devices before it first
+/// enables them, when nothing can interrupt it, so the kernel sets this once boot is done.
+#[cfg(feature = "kernel")]
+pub fn set_interrupts_enabled_check(check: fn() -> bool) {
+    INTERRUPTS_ENABLED_CHECK.call_once(|| check);
+}
+
+#[cfg(feature = "kernel")]
+fn interrupts_enabled() -> bool {
+    match INTERRUPTS_ENABLED_CHECK.get() {
+        Some(check) => check(),
+        None => true,
     }
 }
 
 #[cfg(feature = "kernel")]
-static mut DEVICE_TREE: OnceCell<RwLock<DeviceTree>> = OnceCell::new();
+static INTERRUPTS_ENABLED_CHECK: spin::Once<fn() -> bool> = spin::Once::new();
+
+#[cfg(feature = "kernel")]
+static DEVICE_TREE: InitCell<RwLock<DeviceTree>> = InitCell::new();
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum DeviceErrorCode {
@@ -175,6 +248,10 @@ impl DeviceError {
     pub fn new(error_code: DeviceErrorCode) -> Self {
         DeviceError { error_code }
     }
+
+    pub fn code(&self) -> DeviceErrorCode {
+        self.error_code
+    }
 }
 
 impl Display for DeviceError {
@@ -209,6 +286,33 @@ pub trait Device: Sync + Send {
     }
     fn ready(&self) -> bool;
 
+    /// The kind of device, used to give it a stable name like disk0 or ttyS1. Devices without a
+    /// class are only known by their id and aliases. Well known devices have the one registered
+    /// for their uuid.
+    fn class(&self) -> Option<&'static str> {
+        well_known::class_for_uuid(&self.uuid())
+    }
+
+    /// Where the device is attached, such as pci-0000:00:1f.2, for its by-path alias.
+    fn location(&self) -> Option<String> {
+        None
+    }
+
+    /// The device's clock interface, if it keeps time.
+    fn clock(&self) -> Option<&dyn clock::Clock> {
+        None
+    }
+
+    /// The device's bus interface, if it's an SMBus host controller.
+    fn smbus(&self) -> Option<&dyn smbus::Smbus> {
+        None
+    }
+
+    /// The device's sensor interface, if it monitors the hardware.
+    fn sensor(&self) -> Option<&dyn sensor::Sensor> {
+        None
+    }
+
     #[allow(unused_variables)]
     fn function(&self, id: usize, args: &[usize]) -> Result<&[u8], DeviceError> {
         Err(DeviceError::new(DeviceErrorCode::NotImplemented))
@@ -0,0 +1,80 @@
+use alloc::{
+    collections::BTreeMap,
+    format,
+    string::{String, ToString},
+};
+
+/// Stable names for devices, and the aliases they can also be found by.
+///
+/// A device with a class gets the class followed by the next free index for it, in registration
+/// order: the first two disks are disk0 and disk1. Every device can be found by its UUID under
+/// uuid/, and by its location under by-path/ when it has one.
+pub struct DeviceNames {
+    names: BTreeMap<u128, String>,
+    next_index: BTreeMap<&'static str, usize>,
+    aliases: BTreeMap<String, u128>,
+}
+
+impl DeviceNames {
+    pub(crate) const fn new() -> Self {
+        Self {
+            names: BTreeMap::new(),
+            next_index: BTreeMap::new(),
+            aliases: BTreeMap::new(),
+        }
+    }
+
+    pub(crate) fn assign(
+        &mut self,
+        id: u128,
+        class: Option<&'static str>,
+        uuid: &uuid::Uuid,
+        location: Option<String>,
+    ) {
+        if let Some(class) = class {
+            let index = self.next_index.entry(class).or_insert(0);
+            self.names.insert(id, format!("{}{}", class, index));
+            *index += 1;
+        }
+        // The first device registered with a UUID keeps the alias.
+        self.aliases
+            .entry(format!("uuid/{}", uuid.as_hyphenated()))
+            .or_insert(id);
+        if let Some(location) = location {
+            self.aliases.insert(format!("by-path/{}", location), id);
+        }
+    }
+
+    pub(crate) fn remove(&mut self, id: u128) {
+        self.names.remove(&id);
+        self.aliases.retain(|_, target| *target != id);
+    }
+
+    pub fn name(&self, id: u128) -> Option<&str> {
+        self.names.get(&id).map(|name| name.as_str())
+    }
+
+    /// Adds another name for a device, returns false if the alias is already taken.
+    pub fn add_alias(&mut self, alias: &str, id: u128) -> bool {
+        if self.resolve(alias).is_some() {
+            return false;
+        }
+        self.aliases.insert(alias.to_string(), id);
+        true
+    }
+
+    pub fn aliases(&self, id: u128) -> impl Iterator<Item = &str> {
+        self.aliases
+            .iter()
+            .filter(move |(_, target)| **target == id)
+            .map(|(alias, _)| alias.as_str())
+    }
+
+    /// Finds a device by its name or an alias.
+    pub fn resolve(&self, name: &str) -> Option<u128> {
+        if let Some((id, _)) = self.names.iter().find(|(_, n)| n.as_str() == name) {
+            return Some(*id);
+        }
+        self.aliases.get(name).copied()
+    }
+}
@@ -0,0 +1,59 @@
+use alloc::{collections::BTreeMap, vec::Vec};
+use spin::RwLock;
+
+use crate::try_get_device_tree;
+
+/// Called with a device id, and whether it is now ready, each time a device's readiness changes.
+pub type ReadinessListener = fn(device_id: u128, ready: bool);
+
+// Readiness signalled by drivers. Devices that never signal report Device::ready() instead.
+static READINESS: RwLock<BTreeMap<u128, bool>> = RwLock::new(BTreeMap::new());
+static LISTENERS: RwLock<Vec<ReadinessListener>> = RwLock::new(Vec::new());
+
+/// Records that a device has become ready, or stopped being ready, and tells the listeners.
+pub fn signal_ready(device_id: u128, ready: bool) {
+    let previous = READINESS.write().insert(device_id, ready);
+    if previous == Some(ready) {
+        return;
+    }
+    // Copied out, so a listener can subscribe or signal without deadlocking.
+    let listeners = LISTENERS.read().clone();
+    for listener in listeners {
+        listener(device_id, ready);
+    }
+}
+
+/// Forgets a device's signalled readiness, call when it is unregistered.
+pub fn forget(device_id: u128) {
+    READINESS.write().remove(&device_id);
+}
+
+/// Safe from interrupt handlers: a device is reported not ready while the device tree is being
+/// changed, rather than waiting for it.
+pub fn is_ready(device_id: u128) -> bool {
+    if let Some(ready) = READINESS.read().get(&device_id) {
+        return *ready;
+    }
+    match try_get_device_tree().and_then(|device_tree| device_tree.get(&device_id)) {
+        Some(device) => device.ready(),
+        None => false,
+    }
+}
+
+/// Waits for a device to be ready, returns false if expired() says to give up first. This takes
+/// the device tree's read lock, don't hold its write lock while waiting.
+pub fn await_ready(device_id: u128, expired: impl Fn() -> bool) -> bool {
+    loop {
+        if is_ready(device_id) {
+            return true;
+        }
+        if expired() {
+            return false;
+        }
+        core::hint::spin_loop();
+    }
+}
+
+pub fn subscribe(listener: ReadinessListener) {
+    LISTENERS.write().push(listener);
+}
@@ -0,0 +1,31 @@
+/// What a sensor measures, which decides the unit of its readings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SensorKind {
+    /// Millidegrees Celsius.
+    Temperature,
+    /// Millivolts.
+    Voltage,
+    /// Milliamps.
+    Current,
+    /// Revolutions per minute.
+    Fan,
+}
+
+impl SensorKind {
+    pub fn unit(&self) -> &'static str {
+        match self {
+            SensorKind::Temperature => "m°C",
+            SensorKind::Voltage => "mV",
+            SensorKind::Current => "mA",
+            SensorKind::Fan => "RPM",
+        }
+    }
+}
+
+/// Capability of hardware monitoring devices, see Device::sensor().
+pub trait Sensor: Sync + Send {
+    fn kind(&self) -> SensorKind;
+
+    /// The current reading, in the unit of the sensor's kind.
+    fn read(&self) -> Result<i64, crate::DeviceError>;
+}
@@ -0,0 +1,87 @@
+#[cfg(feature = "kernel")]
+use alloc::{format, string::String};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SmbusError {
+    /// Nothing acknowledged the address.
+    NoDevice,
+    /// Another transaction, or another bus master, has the bus.
+    Busy,
+    Timeout,
+    /// Arbitration was lost, or the controller failed the transaction.
+    BusError,
+}
+
+/// Capability of SMBus host controllers, see Device::smbus(). Addresses are 7 bit.
+pub trait Smbus: Sync + Send {
+    /// Addresses a device without transferring data, to find out if it's there.
+    fn quick(&self, address: u8) -> Result<(), SmbusError>;
+
+    fn read_byte_data(&self, address: u8, command: u8) -> Result<u8, SmbusError>;
+
+    fn write_byte_data(&self, address: u8, command: u8, value: u8) -> Result<(), SmbusError>;
+
+    /// Reads two bytes, the first one received is the low byte.
+    fn read_word_data(&self, address: u8, command: u8) -> Result<u16, SmbusError>;
+}
+
+/// A device on an SMBus, registered in the device tree as a child of its controller, and
+/// reaching the bus through it.
+#[cfg(feature = "kernel")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SmbusClient {
+    controller: u128,
+    address: u8,
+}
+
+#[cfg(feature = "kernel")]
+impl SmbusClient {
+    pub fn new(controller: u128, address: u8) -> Self {
+        Self {
+            controller,
+            address,
+        }
+    }
+
+    /// The controller's device id, the client's parent.
+    pub fn controller(&self) -> u128 {
+        self.controller
+    }
+
+    pub fn address(&self) -> u8 {
+        self.address
+    }
+
+    /// The controller's location, followed by the address, like pci-0000:00:01.3-smbus-48.
+    pub fn location(&self) -> String {
+        let controller = crate::get_device_tree()
+            .get(&self.controller)
+            .and_then(|c| c.location())
+            .unwrap_or_else(|| format!("{:032x}", self.controller));
+        format!("{}-smbus-{:02x}", controller, self.address)
+    }
+
+    /// Runs f with the controller's bus.
+    pub fn with_bus<T>(
+        &self,
+        f: impl FnOnce(&dyn Smbus, u8) -> Result<T, SmbusError>,
+    ) -> Result<T, SmbusError> {
+        let controller = crate::get_device_tree()
+            .get(&self.controller)
+            .ok_or(SmbusError::NoDevice)?;
+        let bus = controller.smbus().ok_or(SmbusError::NoDevice)?;
+        f(bus, self.address)
+    }
+
+    pub fn read_byte_data(&self, command: u8) -> Result<u8, SmbusError> {
+        self.with_bus(|bus, address| bus.read_byte_data(address, command))
+    }
+
+    pub fn write_byte_data(&self, command: u8, value: u8) -> Result<(), SmbusError> {
+        self.with_bus(|bus, address| bus.write_byte_data(address, command, value))
+    }
+
+    pub fn read_word_data(&self, command: u8) -> Result<u16, SmbusError> {
+        self.with_bus(|bus, address| bus.read_word_data(address, command))
+    }
+}
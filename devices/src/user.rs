@@ -0,0 +1,152 @@
+//! Devices, from userspace. Lists the kernel's device tree and calls device functions through
+//! system calls, behind the same Device trait drivers implement in the kernel, so code using it
+//! doesn't care which side of the boundary it's on.
+
+use alloc::{string::String, vec, vec::Vec};
+use kernel_shared::{
+    constants::SyscallNumber,
+    device::{
+        DeviceCallRequest, DeviceDescription, DeviceListRequest, DEVICE_CALL_NATIVE_ERROR,
+        DEVICE_CALL_NOT_IMPLEMENTED, DEVICE_CALL_OK,
+    },
+    syscall::syscall,
+};
+use uuid::Uuid;
+
+use crate::{well_known::WELL_KNOWN_DEVICES, Device, DeviceError, DeviceErrorCode};
+
+// Most results are small, larger ones are fetched again with a buffer that fits.
+const INITIAL_RESULT_CAPACITY: usize = 256;
+
+/// A device in the kernel's device tree, as it was when listed.
+#[derive(Debug, Clone)]
+pub struct RemoteDevice {
+    id: u128,
+    uuid: Uuid,
+    parent_id: Option<u128>,
+    ready: bool,
+    name: String,
+    class: Option<&'static str>,
+}
+
+fn text(field: &[u8]) -> &str {
+    let length = field.iter().position(|b| *b == 0).unwrap_or(field.len());
+    core::str::from_utf8(&field[..length]).unwrap_or("")
+}
+
+impl RemoteDevice {
+    fn new(description: &DeviceDescription) -> Self {
+        let parent_id = u128::from_le_bytes(description.parent_id);
+        let class = text(&description.class);
+        Self {
+            id: u128::from_le_bytes(description.id),
+            uuid: Uuid::from_u128(u128::from_le_bytes(description.uuid)),
+            parent_id: match parent_id {
+                0 => None,
+                id => Some(id),
+            },
+            ready: description.ready != 0,
+            name: String::from(text(&description.name)),
+            // Matched against the well known devices' classes, so class() can hand back a static
+            // string like the kernel's devices do. Others come back as None.
+            class: WELL_KNOWN_DEVICES
+                .iter()
+                .filter_map(|(_, _, known)| *known)
+                .find(|known| *known == class),
+        }
+    }
+
+    /// The device's id in the tree, which differs from its uuid when several share one.
+    pub fn id(&self) -> u128 {
+        self.id
+    }
+
+    /// Calls a device function, and returns its result. Device::function can't hand back a
+    /// buffer it doesn't own, so this is the way to call functions from userspace.
+    pub fn call(&self, function: usize, args: &[usize]) -> Result<Vec<u8>, DeviceError> {
+        let mut result = vec![0; INITIAL_RESULT_CAPACITY];
+        loop {
+            let mut request = DeviceCallRequest {
+                device_id: self.id.to_le_bytes(),
+                function: function as u64,
+                arguments: args.as_ptr(),
+                argument_count: args.len() as u64,
+                result: result.as_mut_ptr(),
+                capacity: result.len() as u64,
+                length: 0,
+                status: DEVICE_CALL_OK,
+            };
+            syscall(
+                SyscallNumber::CallDevice,
+                &mut request as *mut DeviceCallRequest as *const u8,
+            );
+            let code = match request.status {
+                DEVICE_CALL_OK => {
+                    let length = request.length as usize;
+                    if length <= result.len() {
+                        result.truncate(length);
+                        return Ok(result);
+                    }
+                    // Called again with room for all of it.
+                    result.resize(length, 0);
+                    continue;
+                }
+                DEVICE_CALL_NOT_IMPLEMENTED => DeviceErrorCode::NotImplemented,
+                status if status & DEVICE_CALL_NATIVE_ERROR != 0 => {
+                    DeviceErrorCode::DeviceNativeError(status & !DEVICE_CALL_NATIVE_ERROR)
+                }
+                _ => DeviceErrorCode::Malfunction,
+            };
+            return Err(DeviceError::new(code));
+        }
+    }
+}
+
+impl Device for RemoteDevice {
+    fn uuid(&self) -> Uuid {
+        self.uuid
+    }
+
+    fn parent_id(&self) -> Option<u128> {
+        self.parent_id
+    }
+
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn ready(&self) -> bool {
+        self.ready
+    }
+
+    fn class(&self) -> Option<&'static str> {
+        self.class
+    }
+}
+
+/// Lists every device in the kernel's device tree.
+pub fn devices() -> Vec<RemoteDevice> {
+    let mut entries = Vec::new();
+    loop {
+        let mut request = DeviceListRequest {
+            entries: entries.as_mut_ptr(),
+            capacity: entries.len() as u64,
+            count: 0,
+        };
+        syscall(
+            SyscallNumber::ListDevices,
+            &mut request as *mut DeviceListRequest as *const u8,
+        );
+        let count = request.count as usize;
+        // Devices may have been registered since the buffer was sized.
+        if count <= entries.len() {
+            return entries[..count].iter().map(RemoteDevice::new).collect();
+        }
+        entries.resize(count, DeviceDescription::default());
+    }
+}
+
+/// Finds a device by its id in the tree.
+pub fn device(id: u128) -> Option<RemoteDevice> {
+    devices().into_iter().find(|device| device.id == id)
+}
@@ -1,48 +1,79 @@
-use lazy_static::lazy_static;
 use uuid::Uuid;
-// e8398181-1506-4a68-b239-20c24970080a
-// df14874d-211d-40b5-af49-42c6fdc4b003
-// 7d9e622e-0d6c-481f-938f-5c342e6da46f
-// c84d9a17-c924-42ad-99f8-9b8f59833fda
-// 95770a8d-9908-4ef0-9f68-e81d79627591
-// d18aca33-eb38-4cf9-b781-5a9b3fff7baa
-// 559d122e-4a37-4939-9307-dc7fa59cd6f2
-// eb5fa3af-4619-4ed3-b948-1faa86499cc8
-// 701963bb-7737-4e95-893b-dfe4813b6258
-// 049bb518-6482-466b-8e0e-00d46d39a5a0
-// 3c5d3549-eb7a-48b4-bc6c-46a749f401ff
-// 95680e19-3933-4ad2-ba7b-fa4b8e9109e8
-// bcc85e8d-7b49-4f02-b1e4-7ca19e84b3c4
-// 80bfaea3-540b-4c96-b6a3-91db69054fe9
-// 0d0b2466-2405-4c9e-8d5c-9f9f9ba84642
-// 8713f9e6-bf71-47a5-9090-baad58b1008b
-// 4abeec9d-11bb-4046-b41c-b9166f71b1f3
-// aaddf835-c0d4-4c6a-ad07-80e95cc46e11
-// ed5c39af-9fb9-405b-83e4-5c5399b654da
-// 9ecb5bef-5258-4cfe-aaa0-01e852818eea
-// f80ce1ac-9df3-4366-92d8-3d669c374640
-// 1ba7d016-1552-4d3d-9683-5e083e249d58
-// 208c442e-867d-4a24-9aa9-d5108b41ec15
-// 5e74548c-e385-417c-a76e-4e7c22e31262
-// 866e0ed0-d1ec-4e0e-a3a5-a2fd78b4d722
-// b214f3f7-3465-4efb-96d8-6bb1c4de5c7d
-// 41a2792f-0414-4e15-aa4d-63a409315644
-// aa4988af-659b-458e-9804-698b81ab99cb
-// 98b38a66-0e07-4a77-949b-c1979a2ee808
 
-// f80ce1ac-4619-4ed3-b948-1faa86499cc8
-macro_rules! device_uuid {
-    ($ident: ident, $val: tt) => {
-        lazy_static! {
-            pub static ref $ident: Uuid = Uuid::parse_str($val).unwrap();
-        }
+/// Declares the well known devices as (NAME, uuid, class) triples: a constant for each, and a
+/// table of them all. Two devices sharing a uuid fails the build.
+macro_rules! well_known_devices {
+    ($(($ident: ident, $uuid: literal, $class: expr)),* $(,)?) => {
+        $(pub const $ident: Uuid = Uuid::from_u128($uuid);)*
+
+        /// Every well known device's uuid, name, and class.
+        pub const WELL_KNOWN_DEVICES: &[(Uuid, &str, Option<&str>)] =
+            &[$(($ident, stringify!($ident), $class)),*];
+
+        const _: () = assert!(
+            all_different(&[$($uuid),*]),
+            "two well known devices share a uuid"
+        );
     };
 }
 
-const _KERNEL_ASSIGNED_PREFIX: &str = "f80ce1ac";
+const fn all_different(uuids: &[u128]) -> bool {
+    let mut i = 0;
+    while i < uuids.len() {
+        let mut j = i + 1;
+        while j < uuids.len() {
+            if uuids[i] == uuids[j] {
+                return false;
+            }
+            j += 1;
+        }
+        i += 1;
+    }
+    true
+}
 
-device_uuid!(FRAMEBUFFER, "f80ce1ac-890f-4a92-8844-fb447d01992c");
-device_uuid!(SERIAL, "f80ce1ac-7bde-4b7a-9398-ea31faff52c1");
-device_uuid!(IPL, "f80ce1ac-5759-458f-bbd1-71112e971117");
-device_uuid!(CPU, "f80ce1ac-d1ec-4e0e-a3a5-a2fd78b4d722");
-device_uuid!(DEVICE_TREE, "f80ce1ac-0000-4000-8000-000000000000");
+well_known_devices![
+    (
+        FRAMEBUFFER,
+        0xf80ce1ac_890f_4a92_8844_fb447d01992c,
+        Some("fb")
+    ),
+    (SERIAL, 0xf80ce1ac_7bde_4b7a_9398_ea31faff52c1, Some("ttyS")),
+    (IPL, 0xf80ce1ac_5759_458f_bbd1_71112e971117, None),
+    (
+        KEYBOARD,
+        0xf80ce1ac_4b3e_4f0a_9d5c_6e2a8c1f7b30,
+        Some("kbd")
+    ),
+    (
+        SPEAKER,
+        0xf80ce1ac_2d71_4c8e_b5a9_93f06e4d1c52,
+        Some("speaker")
+    ),
+    (CLOCK, 0xf80ce1ac_9c0e_4d27_8b61_0a3f5e7d2c94, Some("clock")),
+    (MEMORY, 0xf80ce1ac_6a14_4f3b_9e07_c2d85b19a3e6, Some("mem")),
+    (CPU, 0xf80ce1ac_d1ec_4e0e_a3a5_a2fd78b4d722, None),
+    (SMBUS, 0xf80ce1ac_3e58_4a1d_b7c2_51d09f6e8a47, Some("smbus")),
+    (
+        TEMPERATURE_SENSOR,
+        0xf80ce1ac_b41f_47e6_8d93_2a7c05e1f968,
+        Some("sensor")
+    ),
+    (DEVICE_TREE, 0xf80ce1ac_0000_4000_8000_000000000000, None),
+];
+
+/// The well known device's name, like SERIAL, for diagnostics.
+pub fn name_for_uuid(uuid: &Uuid) -> Option<&'static str> {
+    WELL_KNOWN_DEVICES
+        .iter()
+        .find(|(known, _, _)| known == uuid)
+        .map(|(_, name, _)| *name)
+}
+
+/// The well known device's class, like ttyS, the one its Device::class() reports.
+pub fn class_for_uuid(uuid: &Uuid) -> Option<&'static str> {
+    WELL_KNOWN_DEVICES
+        .iter()
+        .find(|(known, _, _)| known == uuid)
+        .and_then(|(_, _, class)| *class)
+}
@@ -113,6 +113,7 @@ fn create_metadata_constants() {
         ("CARGO_CFG_TARGET_ARCH", "BUILD_ARCH"),
         ("TARGET", "BUILD_TARGET"),
         ("PROFILE", "PROFILE"),
+        ("OXIDIZED_CMDLINE", "CMDLINE"),
     ]);
     let out_dir = env::var_os("OUT_DIR").unwrap();
     let dest_path = Path::new(&out_dir).join("metadata_constants.rs");
@@ -136,6 +137,7 @@ fn create_metadata_constants() {
     fs::write(&dest_path, constants).unwrap();
 
     println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-env-changed=OXIDIZED_CMDLINE");
 }
 
 fn env_val(name: &str) -> OsString {
@@ -1,6 +1,7 @@
-use core::{cell::OnceCell, panic, ptr::NonNull};
+use core::ptr::NonNull;
 
-use acpi::{AcpiHandler, AcpiTables};
+use acpi::{sdt::Signature, AcpiError, AcpiHandler, AcpiTables};
+use kernel_shared::init_cell::InitCell;
 use x86_64::PhysAddr;
 
 use crate::{debug, memory::KERNEL_MEMORY_MANAGER, warn};
@@ -27,48 +28,66 @@ impl AcpiHandler for AcpiHandlerImpl {
 }
 
 static ACPI_HANDLER: AcpiHandlerImpl = AcpiHandlerImpl {};
-pub(crate) static mut ACPI_TABLES: OnceCell<AcpiTables<AcpiHandlerImpl>> = OnceCell::new();
+pub(crate) static ACPI_TABLES: InitCell<AcpiTables<AcpiHandlerImpl>> = InitCell::new();
 
-unsafe fn load_acpi(rsdp_addr: Option<u64>) -> AcpiTables<AcpiHandlerImpl> {
+unsafe fn load_acpi(rsdp_addr: Option<u64>) -> Result<AcpiTables<AcpiHandlerImpl>, AcpiError> {
     match rsdp_addr {
         Some(addr) => acpi::AcpiTables::from_rsdp(ACPI_HANDLER, addr as usize),
         None => acpi::AcpiTables::search_for_rsdp_bios(ACPI_HANDLER),
     }
-    .expect("Unable to find ACPI tables!")
 }
 
-pub(crate) fn get_acpi_tables() -> &'static mut AcpiTables<AcpiHandlerImpl> {
-    unsafe {
-        match ACPI_TABLES.get_mut() {
-            Some(res) => res,
-            None => panic!("Attempted to get ACPI tables before initialization"),
+/// The firmware's tables, None if it has none we could read.
+pub(crate) fn acpi_tables() -> Option<&'static AcpiTables<AcpiHandlerImpl>> {
+    ACPI_TABLES.get()
+}
+
+/// A table the firmware provided, as raw bytes, header included. For tables the acpi crate
+/// doesn't parse itself.
+pub(crate) fn find_table(signature: Signature) -> Option<&'static [u8]> {
+    let sdt = acpi_tables()?.sdts.get(&signature)?;
+    let memory_manager = KERNEL_MEMORY_MANAGER.lock();
+    let address = memory_manager.translate(PhysAddr::new(sdt.physical_address as u64));
+    Some(unsafe { core::slice::from_raw_parts(address.as_ptr(), sdt.length as usize) })
+}
+
+/// Fails without usable tables. Tables without a usable MADT still load, for the other tables in
+/// them, and the kernel runs on the boot CPU alone.
+pub(crate) fn init(rsdp_addr: Option<u64>) -> Result<(), &'static str> {
+    if ACPI_TABLES.is_initialized() {
+        warn!("Attempted to re-initialize ACPI tables. Ignoring.");
+        return Ok(());
+    }
+    let acpi_tables = match unsafe { load_acpi(rsdp_addr) } {
+        Ok(acpi_tables) => acpi_tables,
+        Err(err) => {
+            warn!("Unable to load ACPI tables: {:?}", err);
+            return Err("no usable ACPI tables");
         }
+    };
+    if ACPI_TABLES.set(acpi_tables).is_err() {
+        warn!("ACPI tables were initialized by another CPU while parsing. Ignoring.");
+        return Ok(());
     }
-}
+    let acpi_tables = ACPI_TABLES.get_initialized();
 
-pub(crate) fn init(rsdp_addr: Option<u64>) {
-    unsafe {
-        if ACPI_TABLES.get().is_none() {
-            if ACPI_TABLES.set(load_acpi(rsdp_addr)).is_err() {
-                panic!("Failed to set ACPI tables after parsing, this should never happen!");
+    debug!("Loaded ACPI Tables, Revison: {}", acpi_tables.revision);
+    match acpi_tables.platform_info() {
+        Ok(platform_info) => match platform_info.processor_info {
+            Some(cpu_info) => {
+                debug!("Processor info:");
+                debug!("-- {:?}", cpu_info.boot_processor);
             }
-        } else {
-            warn!("Attempted to re-initialize ACPI tables. Ignoring.");
-            return;
+            None => {
+                warn!("ACPI has no processor configuration, only the boot CPU will be used");
+            }
+        },
+        Err(err) => {
+            warn!(
+                "Unable to read the MADT ({:?}), only the boot CPU will be used",
+                err
+            );
         }
-        let acpi_tables = ACPI_TABLES.get_mut().unwrap();
-
-        debug!("Loaded ACPI Tables, Revison: {}", acpi_tables.revision);
-        let platform_info = acpi_tables
-            .platform_info()
-            .expect("Unable to retrieve platform info from ACPI!");
-
-        //debug!("Interrupt model: {:?}", platform_info.interrupt_model);
-        let cpu_info = platform_info
-            .processor_info
-            .expect("Unable to read processor configuration!");
-
-        debug!("Processor info:");
-        debug!("-- {:?}", cpu_info.boot_processor);
-    };
+    }
+    Ok(())
 }
@@ -1,22 +1,33 @@
-use core::{panic};
+use core::{
+    panic,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
 
 use acpi::InterruptModel::*;
 
-use x86::{
-    msr::{
-        rdmsr, wrmsr, IA32_APIC_BASE, IA32_X2APIC_APICID, IA32_X2APIC_DIV_CONF, IA32_X2APIC_EOI,
-        IA32_X2APIC_ICR, IA32_X2APIC_INIT_COUNT, IA32_X2APIC_LVT_ERROR, IA32_X2APIC_LVT_TIMER,
-        IA32_X2APIC_PPR, IA32_X2APIC_SIVR, IA32_X2APIC_TPR, IA32_X2APIC_VERSION,
-    },
-};
-use x86_64::{
-    structures::paging::{PageTableFlags, PhysFrame},
-    PhysAddr,
+use x86::msr::{
+    rdmsr, wrmsr, IA32_APIC_BASE, IA32_TSC_DEADLINE, IA32_X2APIC_APICID, IA32_X2APIC_DIV_CONF,
+    IA32_X2APIC_EOI, IA32_X2APIC_ESR, IA32_X2APIC_ICR, IA32_X2APIC_INIT_COUNT,
+    IA32_X2APIC_LVT_ERROR, IA32_X2APIC_LVT_TIMER, IA32_X2APIC_PPR, IA32_X2APIC_SIVR,
+    IA32_X2APIC_TPR, IA32_X2APIC_VERSION,
 };
+use x86_64::{structures::paging::PageTableFlags, PhysAddr};
 
-use crate::{debug, memory::KERNEL_MEMORY_MANAGER};
+use crate::{
+    cmdline, debug,
+    memory::{allocator::PAGE_SIZE, virtual_area},
+    time::Deadline,
+    warn,
+};
 
-use super::{acpi::get_acpi_tables, cpuid::cpuid};
+use super::{
+    acpi::acpi_tables,
+    cpu,
+    cpuid::cpuid,
+    gdt::MAX_CPU_COUNT,
+    idt::{latency, vectors::APIC_TIMER_VECTOR},
+    tsc::{nanoseconds_to_tsc_ticks, read_tsc},
+};
 
 const APIC_REGISTER_ADDRESS_MASK: usize = 0x0FF0;
 
@@ -34,6 +45,68 @@ const APIC_REGISTER_OFFSET_ERROR_STATUS: usize = 0x280;
 const APIC_REGISTER_IPI_LOW: usize = 0x300;
 const APIC_REGISTER_IPI_HIGH: usize = 0x310;
 const APIC_REGISTER_OFFSET_LOCAL_VECTOR_TABLE_ERROR: usize = 0x370;
+const IPI_DELIVERY_TIMEOUT_US: u64 = 1000;
+
+// Local vector table timer modes
+const LVT_TIMER_PERIODIC: u64 = 0x20000;
+const LVT_TIMER_TSC_DEADLINE: u64 = 0x40000;
+/// How often the timer ticks. Exact with the TSC deadline timer, and roughly the rate of the
+/// periodic timer under QEMU.
+pub const TIMER_TICK_NANOSECONDS: u64 = 1_000_000;
+const NO_DEADLINE: u64 = 0;
+
+// IA32_APIC_BASE MSR bits
+const APIC_BASE_BOOTSTRAP_PROCESSOR: u64 = 1 << 8;
+const APIC_BASE_X2APIC_ENABLE: u64 = 1 << 10;
+const APIC_BASE_GLOBAL_ENABLE: u64 = 1 << 11;
+const APIC_BASE_ADDRESS_SHIFT: u64 = 12;
+// Used when CPUID can't tell us the physical address width, the architectural maximum.
+const DEFAULT_PHYSICAL_ADDRESS_BITS: u8 = 52;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ApicBase(u64);
+
+impl ApicBase {
+    pub fn read() -> Self {
+        Self(unsafe { rdmsr(IA32_APIC_BASE) })
+    }
+
+    pub fn write(&self) {
+        unsafe { wrmsr(IA32_APIC_BASE, self.0) }
+    }
+
+    fn address_mask() -> u64 {
+        let physical_address_bits = cpuid()
+            .and_then(|c| c.get_processor_capacity_feature_info())
+            .map_or(DEFAULT_PHYSICAL_ADDRESS_BITS, |f| f.physical_address_bits())
+            .min(DEFAULT_PHYSICAL_ADDRESS_BITS);
+        ((1u64 << physical_address_bits) - 1) & !((1u64 << APIC_BASE_ADDRESS_SHIFT) - 1)
+    }
+
+    pub fn address(&self) -> u64 {
+        self.0 & Self::address_mask()
+    }
+
+    pub fn is_bootstrap_processor(&self) -> bool {
+        self.0 & APIC_BASE_BOOTSTRAP_PROCESSOR != 0
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.0 & APIC_BASE_GLOBAL_ENABLE != 0
+    }
+
+    pub fn is_x2apic_enabled(&self) -> bool {
+        self.is_enabled() && self.0 & APIC_BASE_X2APIC_ENABLE != 0
+    }
+
+    pub fn enabled(&self, x2: bool) -> Self {
+        let value = self.0 | APIC_BASE_GLOBAL_ENABLE;
+        match x2 {
+            true => Self(value | APIC_BASE_X2APIC_ENABLE),
+            false => Self(value),
+        }
+    }
+}
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub struct AdvancedProgrammableInterruptController {
@@ -186,8 +259,8 @@ impl AdvancedProgrammableInterruptController {
     #[inline]
     pub fn get_error_status(&self) -> u64 {
         if self.x2 {
-            self.write_apic_msr(IA32_X2APIC_LVT_ERROR, 0);
-            self.read_apic_msr(IA32_X2APIC_LVT_ERROR)
+            self.write_apic_msr(IA32_X2APIC_ESR, 0);
+            self.read_apic_msr(IA32_X2APIC_ESR)
         } else {
             self.write_register(APIC_REGISTER_OFFSET_ERROR_STATUS, 0);
             self.read_register(APIC_REGISTER_OFFSET_ERROR_STATUS) as u64
@@ -197,7 +270,7 @@ impl AdvancedProgrammableInterruptController {
     #[inline]
     pub fn set_error_status(&self, value: u64) {
         if self.x2 {
-            self.write_apic_msr(IA32_X2APIC_LVT_ERROR, value)
+            self.write_apic_msr(IA32_X2APIC_ESR, value)
         } else {
             self.write_register(APIC_REGISTER_OFFSET_ERROR_STATUS, value as u32)
         }
@@ -215,23 +288,32 @@ impl AdvancedProgrammableInterruptController {
         }
     }
 
+    /// Waits for the delivery status bit to clear, returns false if it did not within the timeout.
     #[inline]
-    pub fn wait_for_ipi_delivery(&self) {
+    pub fn wait_for_ipi_delivery(&self) -> bool {
         if self.x2 {
-            return;
+            return true;
         }
         const PENDING: u64 = 1 << 12;
+        if self.get_icr() & PENDING != PENDING {
+            return true;
+        }
+        let deadline = Deadline::after_us(IPI_DELIVERY_TIMEOUT_US);
         while self.get_icr() & PENDING == PENDING {
+            if deadline.is_expired() {
+                return false;
+            }
             core::hint::spin_loop();
         }
+        true
     }
 
     #[inline]
-    pub fn send_ipi_init(&self, cpu_id: usize) {
+    pub fn send_ipi_init(&self, cpu_id: usize) -> bool {
         self.clear_apic_errors();
         // Assert INIT
         let icr_value: u64 = 0x4500 | self.get_icr_cpu_value(cpu_id);
-        self.set_icr(icr_value);
+        self.set_icr(icr_value)
     }
 
     fn get_icr_cpu_value(&self, cpu_id: usize) -> u64 {
@@ -243,35 +325,46 @@ impl AdvancedProgrammableInterruptController {
     }
 
     #[inline]
-    pub fn send_ipi_start(&self, cpu_id: usize, segment: u8) {
+    pub fn send_ipi_start(&self, cpu_id: usize, segment: u8) -> bool {
         self.clear_apic_errors();
         // SIPI
         let icr_value = self.get_icr_cpu_value(cpu_id) | 0x4600 | (segment as u64);
-        self.set_icr(icr_value);
+        self.set_icr(icr_value)
+    }
+
+    /// Sends a fixed IPI, interrupting the CPU on vector.
+    #[inline]
+    pub fn send_ipi_fixed(&self, cpu_id: usize, vector: u8) -> bool {
+        self.clear_apic_errors();
+        let icr_value = self.get_icr_cpu_value(cpu_id) | 0x4000 | (vector as u64);
+        self.set_icr(icr_value)
     }
 
     pub fn clear_apic_errors(&self) {
         if self.x2 {
-            self.write_apic_msr(IA32_X2APIC_LVT_ERROR, 0);
+            self.write_apic_msr(IA32_X2APIC_ESR, 0);
         } else {
             self.write_register(APIC_REGISTER_OFFSET_ERROR_STATUS, 0);
         }
     }
 
+    /// Sends an IPI, returns false if a previous IPI, or this one, was not delivered in time.
     #[inline]
-    pub fn set_icr(&self, value: u64) {
-        self.wait_for_ipi_delivery();
+    pub fn set_icr(&self, value: u64) -> bool {
+        if !self.wait_for_ipi_delivery() {
+            return false;
+        }
         if self.x2 {
-            self.write_apic_msr(IA32_X2APIC_ICR, value)
+            self.write_apic_msr(IA32_X2APIC_ICR, value);
+            true
         } else {
             let ipi_high = (value >> 32) & u32::MAX as u64;
             let ipi_high = ipi_high as u32;
-            self.wait_for_ipi_delivery();
             self.write_register(APIC_REGISTER_IPI_HIGH, ipi_high);
             let ipi_low = value as u32;
 
             self.write_register(APIC_REGISTER_IPI_LOW, ipi_low);
-            self.wait_for_ipi_delivery();
+            self.wait_for_ipi_delivery()
         }
     }
 
@@ -290,18 +383,42 @@ pub(crate) static mut LOCAL_APIC: AdvancedProgrammableInterruptController =
         x2: false,
     };
 
-pub fn init() {
-    let acpi = get_acpi_tables();
-    let platform_info = match acpi.platform_info() {
-        Ok(v) => v,
-        _ => panic!("Unable to retrieve MADT from ACPI?"),
-    };
-    let apic_info = match platform_info.interrupt_model {
-        Apic(a) => a,
-        _ => panic!("APIC is required by this kernel."),
+/// Fails if the CPU has no local APIC, or it can't be mapped. Without the MADT the APIC is still
+/// used, at the address IA32_APIC_BASE has, but the other CPUs aren't known.
+pub fn init() -> Result<(), &'static str> {
+    if !has_local_apic() {
+        return Err("the CPU has no local APIC");
+    }
+    let madt_address = match acpi_tables().and_then(|tables| tables.platform_info().ok()) {
+        Some(platform_info) => match platform_info.interrupt_model {
+            Apic(apic_info) => Some(apic_info.local_apic_address),
+            _ => None,
+        },
+        None => None,
     };
-    super::pic_init();
-    let addr = apic_info.local_apic_address;
+    if madt_address.is_none() {
+        warn!("No APIC configuration in the MADT, using the local APIC at the address the CPU reports");
+    }
+    super::pic::disable();
+
+    let apic_base = ApicBase::read();
+    debug!(
+        "IA32_APIC_BASE: {:#016x} (enabled: {}, x2APIC: {}, BSP: {})",
+        apic_base.0,
+        apic_base.is_enabled(),
+        apic_base.is_x2apic_enabled(),
+        apic_base.is_bootstrap_processor()
+    );
+    // The MSR is authoritative, firmware may have relocated the APIC without updating the MADT.
+    let addr = apic_base.address();
+    if let Some(madt_address) = madt_address {
+        if addr != madt_address {
+            warn!(
+                "Local APIC base {:#x} does not match the MADT address {:#x}, using the MSR value",
+                addr, madt_address
+            );
+        }
+    }
 
     let x2_apic = cpuid().map_or(false, |r| {
         r.get_feature_info()
@@ -311,38 +428,150 @@ pub fn init() {
         unsafe {
             LOCAL_APIC.x2 = true;
         }
+        if !apic_base.is_x2apic_enabled() {
+            debug!("Firmware left x2APIC mode disabled, it will be enabled");
+        }
         debug!("System has x2 apic support, using that instead of legacy APIC");
     } else {
-        debug!("Local APIC address: {:p}", addr as usize as *const ());
-        KERNEL_MEMORY_MANAGER.lock().identity_map(
-            PhysFrame::containing_address(PhysAddr::new_truncate(addr)),
-            PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE | PageTableFlags::PRESENT,
-        );
-        let apic_ptr: *mut u8 = addr as *mut u8;
-        unsafe {
-            LOCAL_APIC.address = apic_ptr;
-        }
+        map_local_apic(addr)?;
     }
 
+    // Latency measurement needs the deadline, even with the periodic tick asked for.
+    let deadline_mode =
+        supports_tsc_deadline() && (latency::enabled() || cmdline::get("nohz") != Some("off"));
+    TSC_DEADLINE_MODE.store(deadline_mode, Ordering::Relaxed);
+
     unsafe {
         init_ap();
     }
+    Ok(())
 }
 
-pub(crate) unsafe fn init_ap() {
-    if LOCAL_APIC.x2 {
-        LOCAL_APIC.write_apic_msr(
-            IA32_APIC_BASE,
-            LOCAL_APIC.read_apic_msr(IA32_APIC_BASE) | 1 << 10,
-        );
+fn has_local_apic() -> bool {
+    cpuid()
+        .and_then(|c| c.get_feature_info())
+        .map_or(false, |f| f.has_apic())
+}
+
+fn map_local_apic(addr: u64) -> Result<(), &'static str> {
+    debug!("Local APIC address: {:p}", addr as usize as *const ());
+    // In the MMIO window, like any other device's registers.
+    let apic_ptr: *mut u8 = virtual_area::ioremap(
+        PhysAddr::new_truncate(addr),
+        PAGE_SIZE,
+        PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+    )
+    .ok_or("unable to map the local APIC")?
+    .as_mut_ptr();
+    unsafe {
+        LOCAL_APIC.address = apic_ptr;
+    }
+    Ok(())
+}
+
+/// Enables the local APIC of the current CPU in the IA32_APIC_BASE MSR.
+unsafe fn enable_local_apic(x2: bool) {
+    let apic_base = ApicBase::read();
+    let enabled = apic_base.enabled(x2);
+    if enabled != apic_base {
+        enabled.write();
     }
+}
+
+/// Acknowledges the interrupt on vector, on the PICs if they're delivering interrupts in place of
+/// the local APIC.
+pub(crate) fn end_of_interrupt(vector: u8) {
+    if super::pic::in_use() {
+        super::pic::end_of_interrupt(vector);
+    } else {
+        unsafe { LOCAL_APIC.end_of_interrupt() };
+    }
+}
+
+pub(crate) unsafe fn init_ap() {
+    enable_local_apic(LOCAL_APIC.x2);
     let mut sivr = LOCAL_APIC.get_spurious_interrupt_vector();
     sivr = sivr | 0x1FF;
     LOCAL_APIC.set_spurious_interrupt_vector(sivr);
-    debug!("Starting timer on IRQ0 (Vector 32)");
-    // 0x20000 - Enable periodic timer, 32 == interrupt vector (IRQ0, Vector 32)
-    LOCAL_APIC.set_local_vector_table_timer(32 | 0x20000);
-    LOCAL_APIC.set_timer_divisor(0x03);
-    LOCAL_APIC.set_timer_initial_count(0xFF00);
+    if tsc_deadline_mode() {
+        debug!(
+            "Starting TSC deadline timer on vector {}",
+            APIC_TIMER_VECTOR
+        );
+        LOCAL_APIC.set_local_vector_table_timer(APIC_TIMER_VECTOR as u64 | LVT_TIMER_TSC_DEADLINE);
+        arm_timer_deadline(read_tsc() + nanoseconds_to_tsc_ticks(TIMER_TICK_NANOSECONDS));
+    } else {
+        debug!("Starting timer on IRQ0 (Vector 32)");
+        // 32 == interrupt vector (IRQ0, Vector 32)
+        LOCAL_APIC.set_local_vector_table_timer(APIC_TIMER_VECTOR as u64 | LVT_TIMER_PERIODIC);
+        LOCAL_APIC.set_timer_divisor(0x03);
+        LOCAL_APIC.set_timer_initial_count(0xFF00);
+    }
     debug!("APIC setup complete.");
 }
+
+static TSC_DEADLINE_MODE: AtomicBool = AtomicBool::new(false);
+// The TSC value each CPU's timer was last armed to fire at.
+static TIMER_DEADLINES: [AtomicU64; MAX_CPU_COUNT] =
+    [const { AtomicU64::new(NO_DEADLINE) }; MAX_CPU_COUNT];
+// CPUs whose timer only fires when they asked for it, not every tick.
+static TICK_STOPPED: [AtomicBool; MAX_CPU_COUNT] =
+    [const { AtomicBool::new(false) }; MAX_CPU_COUNT];
+
+/// True if the timer runs off TSC deadlines, which lets an idle CPU stop its tick.
+pub(crate) fn tsc_deadline_mode() -> bool {
+    TSC_DEADLINE_MODE.load(Ordering::Relaxed)
+}
+
+fn supports_tsc_deadline() -> bool {
+    cpuid()
+        .and_then(|c| c.get_feature_info())
+        .map_or(false, |f| f.has_tsc_deadline())
+}
+
+/// Fires the current CPU's timer once the TSC reaches deadline. The timer must be in TSC deadline mode.
+fn arm_timer_deadline(deadline: u64) {
+    TIMER_DEADLINES[cpu::current()].store(deadline, Ordering::Relaxed);
+    unsafe {
+        // The LVT write must be ordered before the deadline, or the deadline can be ignored.
+        core::arch::x86_64::_mm_mfence();
+        wrmsr(IA32_TSC_DEADLINE, deadline);
+    }
+}
+
+fn disarm_timer_deadline() {
+    TIMER_DEADLINES[cpu::current()].store(NO_DEADLINE, Ordering::Relaxed);
+    unsafe { wrmsr(IA32_TSC_DEADLINE, NO_DEADLINE) };
+}
+
+/// Stops the current CPU's tick, its timer next fires at the deadline, or never without one.
+/// Interrupts must be disabled until the CPU halts, so the tick can't restart itself first.
+pub(crate) fn stop_tick(deadline: Option<u64>) {
+    TICK_STOPPED[cpu::current()].store(true, Ordering::Relaxed);
+    match deadline {
+        Some(deadline) => arm_timer_deadline(deadline.max(1)),
+        None => disarm_timer_deadline(),
+    }
+}
+
+pub(crate) fn restart_tick() {
+    if TICK_STOPPED[cpu::current()].swap(false, Ordering::Relaxed) {
+        arm_timer_deadline(read_tsc() + nanoseconds_to_tsc_ticks(TIMER_TICK_NANOSECONDS));
+    }
+}
+
+/// Called from the timer interrupt at now, returns the deadline that fired and arms the next
+/// tick, unless the tick is stopped. None if the timer is periodic.
+pub(crate) fn rearm_timer_deadline(now: u64) -> Option<u64> {
+    let deadline = TIMER_DEADLINES[cpu::current()].load(Ordering::Relaxed);
+    if deadline == NO_DEADLINE {
+        return None;
+    }
+    if TICK_STOPPED[cpu::current()].load(Ordering::Relaxed) {
+        return Some(deadline);
+    }
+    let period = nanoseconds_to_tsc_ticks(TIMER_TICK_NANOSECONDS);
+    // Skip ticks we were too late for, rather than firing them back to back.
+    arm_timer_deadline((deadline + period).max(now + period / 2));
+    Some(deadline)
+}
@@ -1,7 +1,13 @@
-use core::{alloc::Layout, arch::asm, cell::OnceCell};
+use core::{
+    alloc::Layout,
+    arch::{
+        asm,
+        x86_64::{__cpuid, __cpuid_count},
+    },
+    sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering},
+};
 
-use alloc::{format, string::String};
-use bitvec::array::BitArray;
+use alloc::{format, string::String, vec::Vec};
 use bitvec::prelude::*;
 
 use iced_x86::{Decoder, DecoderOptions, Formatter, Instruction, Mnemonic, NasmFormatter};
@@ -9,27 +15,48 @@ use spin::Mutex;
 use x86::msr::{rdmsr, IA32_EFER};
 use x86_64::{
     instructions::interrupts,
-    registers::{control::{Cr0, Cr4, Cr4Flags, Cr0Flags}, model_specific::{EferFlags, Efer}},
-    structures::paging::{PageTableFlags, PhysFrame},
+    registers::{
+        control::{Cr0, Cr0Flags, Cr4, Cr4Flags},
+        model_specific::{Efer, EferFlags},
+    },
+    structures::{
+        idt::InterruptStackFrame,
+        paging::{PageTableFlags, PhysFrame},
+    },
     PhysAddr,
 };
 
-use kernel_shared::memory::memcpy;
+use kernel_shared::{init_cell::InitCell, memory::memcpy};
 
 use crate::kernel_cpu_main;
 use crate::{
-    arch::arch_x86_64::{apic, gdt, idt},
-    memory::allocator::kmalloc,
+    arch::arch_x86_64::{
+        apic,
+        gdt::{self, MAX_CPU_COUNT},
+        idt, pat,
+    },
+    memory::allocator::{kfree, kmalloc},
 };
 use crate::{
-    debug,
+    debug, error,
     memory::{
         allocator::{KERNEL_FRAME_ALLOCATOR, PAGE_SIZE},
+        stack::{poison_stack, register_stack, unregister_stack},
         KERNEL_MEMORY_MANAGER,
     },
+    time::{busy_delay_ms, busy_delay_us, Deadline},
+    warn,
+};
+
+use super::{
+    acpi::acpi_tables,
+    apic::{end_of_interrupt, LOCAL_APIC},
+    idt::{request_interrupt, vectors::VectorClass, InterruptContext},
 };
 
-use super::{acpi::ACPI_TABLES, apic::LOCAL_APIC};
+pub mod registry;
+
+use registry::{current_cpu_index, register_cpu};
 
 pub(crate) const CPU_STACK_PAGES: usize = 256;
 
@@ -44,14 +71,131 @@ static mut BSP_CR4: u64 = 0;
 
 /*
 trampoline:
-    .page_table: dq 0 ; -2
-    .stack_end: dq 0 ; -1
-    .code: dq 0 ; 0
+    .stage: dq 0 ; -4
+    .page_table: dq 0 ; -3
+    .stack_end: dq 0 ; -2
+    .code: dq 0 ; -1
+    .base: dq 0 ; 0
 */
+const TRAMPOLINE_STAGE_OFFSET: isize = -4;
 const PAGE_TABLE_OFFSET: isize = -3;
 const STACK_END_OFFSET: isize = -2;
 const ENTRY_ADDRESS_OFFSET: isize = -1;
 
+// Timings from the Intel MultiProcessor specification's INIT-SIPI-SIPI sequence.
+const INIT_ASSERT_DELAY_MS: u64 = 10;
+const STARTUP_IPI_DELAY_US: u64 = 200;
+const AP_BOOT_TIMEOUT_MS: u64 = 100;
+const AP_BOOT_ATTEMPTS: usize = 2;
+const NO_VECTOR: u8 = 0;
+const STOP_TIMEOUT_MS: u64 = 100;
+
+// Boot stages, 1-3 are written by the trampoline, the rest by ap_entry.
+const AP_STAGE_NOT_STARTED: u8 = 0;
+const AP_STAGE_REAL_MODE: u8 = 1;
+const AP_STAGE_ENTERING_LONG_MODE: u8 = 2;
+const AP_STAGE_LONG_MODE: u8 = 3;
+const AP_STAGE_RUST_ENTRY: u8 = 4;
+const AP_STAGE_CONTROL_REGISTERS: u8 = 5;
+const AP_STAGE_GDT: u8 = 6;
+const AP_STAGE_IDT: u8 = 7;
+const AP_STAGE_APIC: u8 = 8;
+const AP_STAGE_ONLINE: u8 = 9;
+
+static AP_BOOT_STAGES: [AtomicU8; MAX_CPU_COUNT] =
+    [const { AtomicU8::new(AP_STAGE_NOT_STARTED) }; MAX_CPU_COUNT];
+// Sent to a CPU to stop it before it's parked.
+static STOP_VECTOR: AtomicU8 = AtomicU8::new(NO_VECTOR);
+// Set by each CPU once it has stopped, with interrupts disabled, for cpu_down to wait on.
+static STOPPED: [AtomicBool; MAX_CPU_COUNT] = [const { AtomicBool::new(false) }; MAX_CPU_COUNT];
+
+fn ap_boot_stage_name(stage: u8) -> &'static str {
+    match stage {
+        AP_STAGE_NOT_STARTED => "never reached the trampoline",
+        AP_STAGE_REAL_MODE => "trampoline, real mode",
+        AP_STAGE_ENTERING_LONG_MODE => "trampoline, entering long mode",
+        AP_STAGE_LONG_MODE => "trampoline, long mode",
+        AP_STAGE_RUST_ENTRY => "ap_entry",
+        AP_STAGE_CONTROL_REGISTERS => "control registers set",
+        AP_STAGE_GDT => "GDT loaded",
+        AP_STAGE_IDT => "IDT loaded",
+        AP_STAGE_APIC => "APIC initialized",
+        AP_STAGE_ONLINE => "online",
+        _ => "unknown",
+    }
+}
+
+fn set_ap_boot_stage(stage: u8) {
+    AP_BOOT_STAGES[current_cpu_index()].store(stage, Ordering::Release);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApState {
+    Offline,
+    Starting,
+    Online,
+    // Held in wait-for-SIPI by an INIT, the stack is kept for the next bring-up.
+    Parked,
+}
+
+/// Bring-up state owned by a single AP.
+#[derive(Debug, Clone, Copy)]
+pub struct ApBootRecord {
+    stack: usize,
+    stack_length: usize,
+    state: ApState,
+}
+
+impl ApBootRecord {
+    const EMPTY: Self = Self {
+        stack: 0,
+        stack_length: 0,
+        state: ApState::Offline,
+    };
+
+    pub fn stack(&self) -> *mut u8 {
+        self.stack as *mut u8
+    }
+
+    pub fn stack_length(&self) -> usize {
+        self.stack_length
+    }
+
+    pub fn state(&self) -> ApState {
+        self.state
+    }
+}
+
+static AP_BOOT_RECORDS: Mutex<[ApBootRecord; MAX_CPU_COUNT]> =
+    Mutex::new([ApBootRecord::EMPTY; MAX_CPU_COUNT]);
+
+pub fn ap_boot_record(cpu: usize) -> ApBootRecord {
+    AP_BOOT_RECORDS.lock()[cpu]
+}
+
+// The trampoline parameters are shared by every AP. The CPU that owns them releases them once it
+// is running on its own stack, and no other AP may be started until it does.
+const TRAMPOLINE_FREE: usize = usize::MAX;
+static TRAMPOLINE_OWNER: AtomicUsize = AtomicUsize::new(TRAMPOLINE_FREE);
+
+fn claim_trampoline(cpu: usize) {
+    while TRAMPOLINE_OWNER
+        .compare_exchange(TRAMPOLINE_FREE, cpu, Ordering::AcqRel, Ordering::Acquire)
+        .is_err()
+    {
+        core::hint::spin_loop();
+    }
+}
+
+fn release_trampoline(cpu: usize) {
+    let _ = TRAMPOLINE_OWNER.compare_exchange(
+        cpu,
+        TRAMPOLINE_FREE,
+        Ordering::AcqRel,
+        Ordering::Acquire,
+    );
+}
+
 #[repr(C)]
 pub struct InterProcessorInterruptPayload {
     payload: *mut u64,
@@ -150,6 +294,22 @@ impl InterProcessorInterruptPayload {
         }
     }
 
+    fn get_value(&self, index: isize) -> u64 {
+        unsafe {
+            let end = (self.payload as *mut u8).offset(BOOTSTRAP_CODE.len() as isize) as *mut u64;
+            let target = end.offset(-1).offset(index);
+            target.read_volatile()
+        }
+    }
+
+    /// The furthest boot stage the AP currently being started has reported.
+    pub fn boot_stage(&self, cpu: usize) -> u8 {
+        match AP_BOOT_STAGES[cpu].load(Ordering::Acquire) {
+            AP_STAGE_NOT_STARTED => self.get_value(TRAMPOLINE_STAGE_OFFSET) as u8,
+            stage => stage,
+        }
+    }
+
     pub fn set_page_table(&self, page_table: u64) {
         self.set_value(PAGE_TABLE_OFFSET, page_table);
     }
@@ -162,77 +322,171 @@ impl InterProcessorInterruptPayload {
         (self.as_ptr() as usize >> 12) as u16 & 0x00FFu16
     }
 
-    pub fn is_ready(&self, cpu_id: usize) -> bool {
+    pub fn is_ready(&self, cpu: usize) -> bool {
         let mutex = get_online_cpu_status_bits();
         let status_bits = mutex.lock();
-        let cpu_id = cpu_id;
-        let result = match status_bits.get(cpu_id).as_deref() {
+        let result = match status_bits.get(cpu).as_deref() {
             Some(v) => *v,
             None => false,
         };
         result
     }
 
-    pub fn boot(&self, cpu_id: usize) {
+    /// Starts an AP with INIT-SIPI-SIPI, returns false if it did not come online.
+    pub fn boot(&self, cpu: usize, apic_id: usize) -> bool {
         let segment = self.get_code_segment() as u8;
-        unsafe {
-            //self.dump_assembly();
-            LOCAL_APIC.send_ipi_init(cpu_id);
-            debug!("IPI INIT  -> ID: {}", cpu_id);
-            LOCAL_APIC.send_ipi_start(cpu_id, segment);
-            debug!("IPI START -> ID: {} CS: {}", cpu_id, segment);
-            while !self.is_ready(cpu_id) {
+        for attempt in 1..=AP_BOOT_ATTEMPTS {
+            self.set_value(TRAMPOLINE_STAGE_OFFSET, AP_STAGE_NOT_STARTED as u64);
+            AP_BOOT_STAGES[cpu].store(AP_STAGE_NOT_STARTED, Ordering::Release);
+            unsafe {
+                //self.dump_assembly();
+                if !LOCAL_APIC.send_ipi_init(apic_id) {
+                    warn!("INIT IPI to CPU {} was not delivered", cpu);
+                }
+                debug!("IPI INIT  -> ID: {}", apic_id);
+                busy_delay_ms(INIT_ASSERT_DELAY_MS);
+                // The second SIPI is only needed if the first one was missed.
+                for _ in 0..2 {
+                    if !LOCAL_APIC.send_ipi_start(apic_id, segment) {
+                        warn!("STARTUP IPI to CPU {} was not delivered", cpu);
+                    }
+                    debug!("IPI START -> ID: {} CS: {}", apic_id, segment);
+                    busy_delay_us(STARTUP_IPI_DELAY_US);
+                    if self.boot_stage(cpu) != AP_STAGE_NOT_STARTED {
+                        break;
+                    }
+                }
+            }
+
+            let deadline = Deadline::after_ms(AP_BOOT_TIMEOUT_MS);
+            while !self.is_ready(cpu) && !deadline.is_expired() {
                 core::hint::spin_loop();
             }
 
-            debug!("CPU {} Signaled ready", cpu_id);
+            if self.is_ready(cpu) {
+                debug!("CPU {} Signaled ready", cpu);
+                return true;
+            }
+
+            warn!(
+                "CPU {} did not come online (attempt {} of {}), last stage: {}",
+                cpu,
+                attempt,
+                AP_BOOT_ATTEMPTS,
+                ap_boot_stage_name(self.boot_stage(cpu))
+            );
         }
+
+        // The AP may still be reading the trampoline parameters, INIT holds it in wait-for-SIPI so
+        // they are safe to hand to the next AP.
+        park_cpu(cpu);
+        false
     }
 }
 
+const X2APIC_ID_UNKNOWN: u8 = 0;
+const X2APIC_ID_SUPPORTED: u8 = 1;
+const X2APIC_ID_UNSUPPORTED: u8 = 2;
+static X2APIC_ID_LEAF: AtomicU8 = AtomicU8::new(X2APIC_ID_UNKNOWN);
+
 pub extern "C" fn cpu_apic_id() -> usize {
     unsafe {
-        let mut id: usize;
-        asm!(
-            "push rbx",
-            "mov rax, 1",
-            "cpuid",
-            "mov rax, rbx",
-            "shr rax, 24",
-            "and rax, 0xff",
-            "pop rbx",
-            out("rax") id,
-            out("rcx") _,
-            out("rdx") _
-        );
-
-        id
+        // CPUID leaf 0xB has the full 32 bit x2APIC ID, leaf 1 only has the low 8 bits.
+        let leaf = match X2APIC_ID_LEAF.load(Ordering::Relaxed) {
+            X2APIC_ID_UNKNOWN => {
+                let supported = __cpuid(0).eax >= 0xB && __cpuid_count(0xB, 0).ebx != 0;
+                let leaf = match supported {
+                    true => X2APIC_ID_SUPPORTED,
+                    false => X2APIC_ID_UNSUPPORTED,
+                };
+                X2APIC_ID_LEAF.store(leaf, Ordering::Relaxed);
+                leaf
+            }
+            leaf => leaf,
+        };
+        match leaf {
+            X2APIC_ID_SUPPORTED => __cpuid_count(0xB, 0).edx as usize,
+            _ => ((__cpuid(1).ebx >> 24) & 0xFF) as usize,
+        }
     }
 }
 
 pub fn start_additional_cpus() {
+    // Without the MADT there's no telling which CPUs there are, or how to reach them.
+    let processor_info = match acpi_tables()
+        .and_then(|tables| tables.platform_info().ok())
+        .and_then(|platform_info| platform_info.processor_info)
+    {
+        Some(processor_info) => processor_info,
+        None => {
+            warn!("No processor configuration in the MADT, running on the boot CPU only");
+            return;
+        }
+    };
+    match request_interrupt(VectorClass::InterProcessor, stop_interrupt_handler, None) {
+        Some(vector) => STOP_VECTOR.store(vector, Ordering::Release),
+        None => {
+            warn!("No inter-processor vector free, CPUs can't be taken down");
+        }
+    }
     let frame = unsafe {
         KERNEL_FRAME_ALLOCATOR
             .force_allocate(PhysFrame::containing_address(PhysAddr::new(0)))
             .expect("Unable to allocate conventional memory for IPI bootstrap trampoline!")
     };
     let frame_start_pointer = frame.start_address().as_u64() as usize as *mut u8;
-    KERNEL_MEMORY_MANAGER
-        .lock()
-        .identity_map(frame, PageTableFlags::WRITABLE | PageTableFlags::PRESENT);
+    // Exempt from the mapping policy, the APs run the trampoline and write their progress to it.
+    unsafe {
+        KERNEL_MEMORY_MANAGER
+            .lock()
+            .identity_map_unchecked(frame, PageTableFlags::WRITABLE | PageTableFlags::PRESENT);
+    }
     let ipi_payload = InterProcessorInterruptPayload::new(frame_start_pointer);
     ipi_payload.load(BOOTSTRAP_CODE);
 
     get_online_cpu_status_bits()
-        .get_mut()
-        .set(cpu_apic_id() as usize, true);
+        .lock()
+        .set(current_cpu_index(), true);
 
     unsafe {
-        let platform_info = ACPI_TABLES.get().unwrap().platform_info().unwrap();
-        let processor_info = platform_info.processor_info.unwrap();
-
+        // Number every CPU up front, in MADT order, so logical IDs don't depend on which APs
+        // happen to start.
+        let mut cpus = Vec::new();
         for app_cpu in processor_info.application_processors.iter() {
-            start_cpu(app_cpu.local_apic_id as usize, &ipi_payload);
+            let apic_id = app_cpu.local_apic_id as usize;
+            match register_cpu(apic_id) {
+                Some(cpu) => cpus.push((cpu, apic_id)),
+                None => {
+                    warn!(
+                        "Ignoring CPU with APIC ID {}, only {} CPUs are supported",
+                        apic_id, MAX_CPU_COUNT
+                    );
+                }
+            }
+        }
+
+        let mut failed_cpus = Vec::new();
+        for (cpu, apic_id) in cpus.iter() {
+            if !start_cpu(*cpu, *apic_id, &ipi_payload) {
+                failed_cpus.push((*cpu, *apic_id, ipi_payload.boot_stage(*cpu)));
+            }
+        }
+
+        if !failed_cpus.is_empty() {
+            error!(
+                "{} of {} application processors failed to start:",
+                failed_cpus.len(),
+                processor_info.application_processors.len()
+            );
+            for (cpu, apic_id, stage) in failed_cpus.iter() {
+                error!(
+                    "  CPU {} (APIC ID {}): last stage {} ({})",
+                    cpu,
+                    apic_id,
+                    stage,
+                    ap_boot_stage_name(*stage)
+                );
+            }
         }
     }
 
@@ -242,12 +496,102 @@ pub fn start_additional_cpus() {
     //unsafe { KERNEL_FRAME_ALLOCATOR.free(frame.start_address()) };
 }
 
-fn start_cpu(cpu_id: usize, ipi_payload: &InterProcessorInterruptPayload) {
-    if cpu_id == cpu_apic_id() as usize {
+fn start_cpu(cpu: usize, apic_id: usize, ipi_payload: &InterProcessorInterruptPayload) -> bool {
+    if apic_id == cpu_apic_id() {
         panic!("Attempted to start CPU that is currently executing code");
     }
-    setup_trampoline(&ipi_payload);
-    ipi_payload.boot(cpu_id);
+    claim_trampoline(cpu);
+    setup_trampoline(&ipi_payload, cpu);
+    let started = ipi_payload.boot(cpu, apic_id);
+    if started {
+        // The AP signals ready after it has released the trampoline, this just keeps the
+        // ordering explicit.
+        while TRAMPOLINE_OWNER.load(Ordering::Acquire) == cpu {
+            core::hint::spin_loop();
+        }
+    }
+    started
+}
+
+fn park_cpu(cpu: usize) {
+    let apic_id = registry::apic_id(cpu).expect("Attempted to park an unknown CPU");
+    if unsafe { !LOCAL_APIC.send_ipi_init(apic_id) } {
+        warn!("INIT IPI to CPU {} was not delivered while parking it", cpu);
+    }
+    get_online_cpu_status_bits().lock().set(cpu, false);
+    get_booting_cpu_status_bits().lock().set(cpu, false);
+    AP_BOOT_RECORDS.lock()[cpu].state = ApState::Parked;
+    release_trampoline(cpu);
+}
+
+fn stop_interrupt_handler(
+    _frame: InterruptStackFrame,
+    vector: u8,
+    _error_code: Option<u64>,
+    _context: InterruptContext,
+) {
+    end_of_interrupt(vector);
+    interrupts::disable();
+    STOPPED[current_cpu_index()].store(true, Ordering::Release);
+    // Held here until the INIT IPI parks it. Interrupts stay off, an NMI just halts again.
+    loop {
+        x86_64::instructions::hlt();
+    }
+}
+
+// Sends the CPU the stop IPI, and waits for it to say it has stopped.
+fn stop_cpu(cpu: usize) -> bool {
+    let vector = STOP_VECTOR.load(Ordering::Acquire);
+    let apic_id = match registry::apic_id(cpu) {
+        Some(apic_id) if vector != NO_VECTOR => apic_id,
+        _ => return false,
+    };
+    STOPPED[cpu].store(false, Ordering::Release);
+    if unsafe { !LOCAL_APIC.send_ipi_fixed(apic_id, vector) } {
+        return false;
+    }
+    let deadline = Deadline::after_ms(STOP_TIMEOUT_MS);
+    while !STOPPED[cpu].load(Ordering::Acquire) {
+        if deadline.is_expired() {
+            return false;
+        }
+        core::hint::spin_loop();
+    }
+    true
+}
+
+/// Takes an AP offline: it's sent an IPI, and once it has stopped with interrupts disabled, it
+/// is marked offline and parked with an INIT IPI. Its stack is kept, and reused the next time
+/// the CPU is started. Returns false, leaving the CPU online, if it didn't stop in time.
+///
+/// The caller must make sure the CPU is not holding any locks, it stops wherever the IPI lands.
+pub fn cpu_down(cpu: usize) -> bool {
+    if cpu == current_cpu_index() {
+        panic!("Attempted to take down the CPU that is currently executing code");
+    }
+    if !stop_cpu(cpu) {
+        warn!("CPU {} didn't stop, leaving it online", cpu);
+        return false;
+    }
+    park_cpu(cpu);
+    debug!("CPU {} parked", cpu);
+    true
+}
+
+/// Frees the stack of a parked or never started AP.
+pub fn free_ap_stack(cpu: usize) -> bool {
+    let mut records = AP_BOOT_RECORDS.lock();
+    let record = &mut records[cpu];
+    if record.stack == 0 || matches!(record.state, ApState::Starting | ApState::Online) {
+        return false;
+    }
+    unregister_stack(record.stack());
+    kfree(
+        record.stack(),
+        Layout::from_size_align(record.stack_length, 16).unwrap(),
+    );
+    *record = ApBootRecord::EMPTY;
+    true
 }
 
 pub fn create_ap_stack(size: usize) -> *mut u8 {
@@ -270,52 +614,67 @@ pub fn setup_trampoline_common_parameters(ipi_code: &InterProcessorInterruptPayl
     }
 }
 
-static mut CPU_ONLINE_STATUS_BITS: OnceCell<Mutex<BitArray>> = OnceCell::new();
-static mut CPU_BOOTING_STATUS_BITS: OnceCell<Mutex<BitArray>> = OnceCell::new();
+/// One bit per logical CPU index.
+pub type CpuStatusBits = BitArr!(for MAX_CPU_COUNT);
 
-pub fn get_online_cpu_status_bits() -> &'static mut Mutex<BitArray> {
-    unsafe {
-        CPU_ONLINE_STATUS_BITS.get_or_init(|| Mutex::new(bitarr!(512)));
-        CPU_ONLINE_STATUS_BITS.get_mut().unwrap()
-    }
+static CPU_ONLINE_STATUS_BITS: InitCell<Mutex<CpuStatusBits>> = InitCell::new();
+static CPU_BOOTING_STATUS_BITS: InitCell<Mutex<CpuStatusBits>> = InitCell::new();
+
+pub fn get_online_cpu_status_bits() -> &'static Mutex<CpuStatusBits> {
+    CPU_ONLINE_STATUS_BITS.get_or_init(|| Mutex::new(bitarr![0; MAX_CPU_COUNT]))
 }
 
-pub fn get_booting_cpu_status_bits() -> &'static mut Mutex<BitArray> {
-    unsafe {
-        CPU_BOOTING_STATUS_BITS.get_or_init(|| Mutex::new(bitarr!(512)));
-        CPU_BOOTING_STATUS_BITS.get_mut().unwrap()
-    }
+pub fn get_booting_cpu_status_bits() -> &'static Mutex<CpuStatusBits> {
+    CPU_BOOTING_STATUS_BITS.get_or_init(|| Mutex::new(bitarr![0; MAX_CPU_COUNT]))
 }
 
-pub fn setup_trampoline(ipi_payload: &InterProcessorInterruptPayload) {
-    let stack_length = CPU_STACK_PAGES * PAGE_SIZE;
-    let stack = create_ap_stack(stack_length);
-    ipi_payload.set_stack(stack, stack_length);
+pub fn setup_trampoline(ipi_payload: &InterProcessorInterruptPayload, cpu: usize) {
+    let mut records = AP_BOOT_RECORDS.lock();
+    let record = &mut records[cpu];
+    if record.stack == 0 {
+        let stack_length = CPU_STACK_PAGES * PAGE_SIZE;
+        let stack = create_ap_stack(stack_length);
+        unsafe { register_stack("KERNEL", 0, cpu, stack, stack_length) };
+        record.stack = stack as usize;
+        record.stack_length = stack_length;
+    } else {
+        // Reusing the stack of a parked CPU, nothing is running on it.
+        unsafe { poison_stack(record.stack(), record.stack_length) };
+    }
+    record.state = ApState::Starting;
+    ipi_payload.set_stack(record.stack(), record.stack_length);
     setup_trampoline_common_parameters(&ipi_payload);
 }
 
 fn mark_cpu_online() {
-    let mutex = get_online_cpu_status_bits();
-    let status_bits = mutex.get_mut();
-    let local_apic_id = cpu_apic_id();
-    status_bits.set(local_apic_id.into(), true);
+    get_online_cpu_status_bits()
+        .lock()
+        .set(current_cpu_index(), true);
 }
 
 fn mark_cpu_booting() {
-    let mutex = get_booting_cpu_status_bits();
-    let status_bits = mutex.get_mut();
-    let local_apic_id = cpu_apic_id();
-    status_bits.set(local_apic_id.into(), true);
+    get_booting_cpu_status_bits()
+        .lock()
+        .set(current_cpu_index(), true);
 }
 
 pub unsafe extern "C" fn ap_entry() -> ! {
     // Make sure interrupts are disabled.
     interrupts::disable();
+    registry::load_cpu_index();
+    set_ap_boot_stage(AP_STAGE_RUST_ENTRY);
+    // We're on our own stack now, the next AP can have the trampoline.
+    release_trampoline(current_cpu_index());
     mark_cpu_booting();
     set_control_regs();
+    pat::init();
+    set_ap_boot_stage(AP_STAGE_CONTROL_REGISTERS);
     gdt::init();
+    set_ap_boot_stage(AP_STAGE_GDT);
     idt::init();
+    set_ap_boot_stage(AP_STAGE_IDT);
     apic::init_ap();
+    set_ap_boot_stage(AP_STAGE_APIC);
     ap_main();
 }
 
@@ -330,11 +689,14 @@ unsafe fn set_control_regs() {
 }
 
 pub fn ap_main() -> ! {
+    set_ap_boot_stage(AP_STAGE_ONLINE);
+    AP_BOOT_RECORDS.lock()[current_cpu_index()].state = ApState::Online;
     mark_cpu_online();
     interrupts::enable();
     kernel_cpu_main();
 }
 
+/// Logical index of the current CPU, use cpu_apic_id when talking to the APIC.
 pub fn current() -> usize {
-    cpu_apic_id()
+    current_cpu_index()
 }
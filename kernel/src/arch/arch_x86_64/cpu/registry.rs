@@ -0,0 +1,108 @@
+use core::{
+    arch::asm,
+    sync::atomic::{AtomicU32, AtomicUsize, Ordering},
+};
+
+use spin::Mutex;
+use x86_64::{registers::model_specific::GsBase, VirtAddr};
+
+use crate::{arch::arch_x86_64::gdt::MAX_CPU_COUNT, raw_println};
+
+use super::cpu_apic_id;
+
+const NO_APIC_ID: u32 = u32::MAX;
+
+// Logical CPU index -> APIC ID. Entries are only ever added, so lookups don't need the lock.
+static APIC_IDS: [AtomicU32; MAX_CPU_COUNT] = [const { AtomicU32::new(NO_APIC_ID) }; MAX_CPU_COUNT];
+static CPU_COUNT: AtomicUsize = AtomicUsize::new(0);
+static REGISTRATION_LOCK: Mutex<()> = Mutex::new(());
+// Each CPU's GS base points at its own entry, so it reads its index from gs:0 rather than
+// looking its APIC ID up, which takes a CPUID.
+static CPU_INDEXES: [usize; MAX_CPU_COUNT] = cpu_indexes();
+
+const fn cpu_indexes() -> [usize; MAX_CPU_COUNT] {
+    let mut indexes = [0; MAX_CPU_COUNT];
+    let mut index = 0;
+    while index < MAX_CPU_COUNT {
+        indexes[index] = index;
+        index += 1;
+    }
+    indexes
+}
+
+/// Assigns the next logical CPU index to an APIC ID, or returns the one it already has.
+///
+/// APIC IDs can be sparse, and larger than the number of CPUs, so every per-CPU table is indexed
+/// by the logical index instead. The boot CPU is always 0, the rest are numbered in the order
+/// they are discovered.
+pub fn register_cpu(apic_id: usize) -> Option<usize> {
+    let _guard = REGISTRATION_LOCK.lock();
+    if let Some(index) = logical_cpu_index(apic_id) {
+        return Some(index);
+    }
+    let index = CPU_COUNT.load(Ordering::Acquire);
+    if index >= MAX_CPU_COUNT {
+        return None;
+    }
+    APIC_IDS[index].store(apic_id as u32, Ordering::Release);
+    CPU_COUNT.store(index + 1, Ordering::Release);
+    Some(index)
+}
+
+pub fn register_boot_cpu() {
+    let index = register_cpu(cpu_apic_id());
+    assert_eq!(index, Some(0), "The boot CPU must be registered first");
+    load_cpu_index();
+}
+
+pub fn logical_cpu_index(apic_id: usize) -> Option<usize> {
+    let count = CPU_COUNT.load(Ordering::Acquire);
+    APIC_IDS[0..count]
+        .iter()
+        .position(|id| id.load(Ordering::Acquire) as usize == apic_id)
+}
+
+pub fn apic_id(cpu: usize) -> Option<usize> {
+    match APIC_IDS.get(cpu)?.load(Ordering::Acquire) {
+        NO_APIC_ID => None,
+        id => Some(id as usize),
+    }
+}
+
+/// Number of CPUs that have been discovered, whether or not they are online.
+pub fn cpu_count() -> usize {
+    CPU_COUNT.load(Ordering::Acquire)
+}
+
+/// Points this CPU's GS base at its logical index. Every CPU calls it as soon as it's running
+/// Rust, current_cpu_index reads garbage until it has.
+pub fn load_cpu_index() {
+    let apic_id = cpu_apic_id();
+    match logical_cpu_index(apic_id) {
+        Some(index) => GsBase::write(VirtAddr::from_ptr(&CPU_INDEXES[index])),
+        None => {
+            // Not a panic, logging (and so the panic handler) needs the CPU index.
+            raw_println!(
+                "CPU with APIC ID {} was never registered, halting it",
+                apic_id
+            );
+            loop {
+                x86_64::instructions::interrupts::disable();
+                x86_64::instructions::hlt();
+            }
+        }
+    }
+}
+
+/// Logical index of the CPU we're running on.
+pub fn current_cpu_index() -> usize {
+    let index: usize;
+    unsafe {
+        asm!(
+            "mov {}, gs:[0]",
+            out(reg) index,
+            options(nostack, readonly, preserves_flags)
+        )
+    };
+    index
+}
@@ -1,10 +1,9 @@
 use alloc::vec::Vec;
 
-
 use lazy_static::lazy_static;
 use spin::Mutex;
 use x86_64::instructions::tables::load_tss;
-use x86_64::registers::segmentation::{Segment};
+use x86_64::registers::segmentation::Segment;
 use x86_64::structures::gdt::{
     Descriptor, DescriptorFlags, GlobalDescriptorTable, SegmentSelector,
 };
@@ -12,16 +11,32 @@ use x86_64::structures::gdt::{
 use x86_64::structures::tss::TaskStateSegment;
 use x86_64::VirtAddr;
 
-use crate::memory::allocator::PAGE_SIZE;
+use crate::memory::{allocator::PAGE_SIZE, stack::register_stack};
 
-use super::cpu::cpu_apic_id;
+use super::cpu::registry::current_cpu_index;
 
 pub const INTERRUPT_STACK_SIZE_PAGES: usize = 4;
 pub const INTERRUPT_STACK_SIZE: usize = PAGE_SIZE * INTERRUPT_STACK_SIZE_PAGES;
+/// Maximum number of logical CPUs, per-CPU tables are indexed by logical CPU index.
 pub const MAX_CPU_COUNT: usize = 256;
 
 pub fn init() {
-    load_gdt(cpu_apic_id());
+    let cpu = current_cpu_index();
+    track_tss_stacks(cpu);
+    load_gdt(cpu);
+}
+
+fn track_tss_stacks(cpu: usize) {
+    unsafe {
+        // The first 7 stacks are the interrupt stack table, the rest are privilege stacks.
+        for (index, stack) in TSS_STACKS[cpu].iter_mut().enumerate() {
+            let (name, index) = match index < 7 {
+                true => ("IST", index),
+                false => ("PST", index - 7),
+            };
+            register_stack(name, index, cpu, stack.as_mut_ptr(), INTERRUPT_STACK_SIZE);
+        }
+    }
 }
 
 pub fn load_gdt(cpu: usize) {
@@ -146,8 +161,8 @@ lazy_static! {
             let mut tss = TaskStateSegment::new();
             let stacks = get_tss_stacks_for_cpu(i as u16);
             for x in 0..stacks.len() {
-                let stack_address = 
-                (VirtAddr::from_ptr(&stacks[x]) + (INTERRUPT_STACK_SIZE - 256)).align_down(16 as u64);
+                let stack_address = (VirtAddr::from_ptr(&stacks[x]) + (INTERRUPT_STACK_SIZE - 256))
+                    .align_down(16 as u64);
                 if x < 7 {
                     tss.interrupt_stack_table[x] = stack_address;
                 }
@@ -162,10 +177,10 @@ lazy_static! {
     };
 }
 lazy_static! {
-    pub(crate) static ref SMP_TSS_POINTERS: Mutex<[GdtPointer; 512]> =
-        Mutex::new([GdtPointer::null(); 512]);
+    pub(crate) static ref SMP_TSS_POINTERS: Mutex<[GdtPointer; MAX_CPU_COUNT]> =
+        Mutex::new([GdtPointer::null(); MAX_CPU_COUNT]);
 }
 lazy_static! {
-    pub(crate) static ref SMP_GDT_POINTERS: Mutex<[GdtPointer; 512]> =
-        Mutex::new([GdtPointer::null(); 512]);
+    pub(crate) static ref SMP_GDT_POINTERS: Mutex<[GdtPointer; MAX_CPU_COUNT]> =
+        Mutex::new([GdtPointer::null(); MAX_CPU_COUNT]);
 }
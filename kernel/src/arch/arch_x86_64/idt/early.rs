@@ -0,0 +1,77 @@
+use core::mem::MaybeUninit;
+
+use x86_64::{
+    instructions::{hlt, interrupts},
+    registers::control::Cr2,
+    set_general_handler,
+    structures::idt::{InterruptDescriptorTable, InterruptStackFrame},
+};
+
+use crate::raw_println;
+
+// Built at runtime, InterruptDescriptorTable::new isn't const. Nothing here may touch the heap
+// or a lazy_static, this is installed before either is ready.
+static mut EARLY_IDT: MaybeUninit<InterruptDescriptorTable> = MaybeUninit::uninit();
+
+const EXCEPTION_NAMES: [&str; 32] = [
+    "DIVIDE ERROR",
+    "DEBUG",
+    "NMI",
+    "BREAKPOINT",
+    "OVERFLOW",
+    "BOUND RANGE EXCEEDED",
+    "INVALID OPCODE",
+    "DEVICE NOT AVAILABLE",
+    "DOUBLE FAULT",
+    "COPROCESSOR SEGMENT OVERRUN",
+    "INVALID TSS",
+    "SEGMENT NOT PRESENT",
+    "STACK SEGMENT FAULT",
+    "GENERAL PROTECTION FAULT",
+    "PAGE FAULT",
+    "RESERVED",
+    "X87 FLOATING POINT",
+    "ALIGNMENT CHECK",
+    "MACHINE CHECK",
+    "SIMD FLOATING POINT",
+    "VIRTUALIZATION",
+    "CONTROL PROTECTION",
+    "RESERVED",
+    "RESERVED",
+    "RESERVED",
+    "RESERVED",
+    "RESERVED",
+    "RESERVED",
+    "HYPERVISOR INJECTION",
+    "VMM COMMUNICATION EXCEPTION",
+    "SECURITY EXCEPTION",
+    "RESERVED",
+];
+
+/// Installs an IDT that reports exceptions over serial and halts. Used until idt::init loads
+/// the real one.
+pub fn init() {
+    unsafe {
+        let idt = EARLY_IDT.write(InterruptDescriptorTable::new());
+        set_general_handler!(idt, early_exception_handler, 0..=31);
+        EARLY_IDT.assume_init_ref().load();
+    }
+}
+
+fn early_exception_handler(stack_frame: InterruptStackFrame, index: u8, error_code: Option<u64>) {
+    raw_println!(
+        "EARLY EXCEPTION {:#04x} ({}) at {:#016x}, error code: {:?}",
+        index,
+        EXCEPTION_NAMES[index as usize],
+        stack_frame.instruction_pointer.as_u64(),
+        error_code
+    );
+    if index == 14 {
+        raw_println!("Faulting address: {:?}", Cr2::read());
+    }
+    raw_println!("{:#?}", stack_frame);
+    interrupts::disable();
+    loop {
+        hlt();
+    }
+}
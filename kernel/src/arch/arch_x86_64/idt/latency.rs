@@ -0,0 +1,87 @@
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use crate::cmdline;
+
+// Bucket n counts latencies from 2^n up to 2^(n+1) nanoseconds, the last also counts anything longer.
+const BUCKETS: usize = 32;
+const VECTORS: usize = 256;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Worst case and 99th percentile interrupt latency for a vector.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LatencySummary {
+    pub samples: u64,
+    pub worst_nanoseconds: u64,
+    /// Rounded up to a power of two, the histogram doesn't keep anything finer.
+    pub p99_nanoseconds: u64,
+}
+
+struct Histogram {
+    buckets: [AtomicU64; BUCKETS],
+    samples: AtomicU64,
+    worst: AtomicU64,
+}
+
+impl Histogram {
+    const fn new() -> Self {
+        Self {
+            buckets: [const { AtomicU64::new(0) }; BUCKETS],
+            samples: AtomicU64::new(0),
+            worst: AtomicU64::new(0),
+        }
+    }
+
+    fn record(&self, nanoseconds: u64) {
+        let bucket = (u64::BITS - nanoseconds.leading_zeros()).saturating_sub(1) as usize;
+        self.buckets[bucket.min(BUCKETS - 1)].fetch_add(1, Ordering::Relaxed);
+        self.samples.fetch_add(1, Ordering::Relaxed);
+        self.worst.fetch_max(nanoseconds, Ordering::Relaxed);
+    }
+
+    fn summary(&self) -> Option<LatencySummary> {
+        let samples = self.samples.load(Ordering::Relaxed);
+        if samples == 0 {
+            return None;
+        }
+        let worst_nanoseconds = self.worst.load(Ordering::Relaxed);
+        // The smallest bucket bound that at least 99% of the samples fall under.
+        let target = samples - samples / 100;
+        let mut seen = 0;
+        let mut p99_nanoseconds = worst_nanoseconds;
+        for (bucket, count) in self.buckets.iter().enumerate() {
+            seen += count.load(Ordering::Relaxed);
+            if seen >= target {
+                p99_nanoseconds = (1u64 << (bucket + 1)).min(worst_nanoseconds);
+                break;
+            }
+        }
+        Some(LatencySummary {
+            samples,
+            worst_nanoseconds,
+            p99_nanoseconds,
+        })
+    }
+}
+
+static HISTOGRAMS: [Histogram; VECTORS] = [const { Histogram::new() }; VECTORS];
+
+/// Turns measurement on if the `irqlatency` command line flag is set. Must run before the
+/// interrupt sources that are measured are set up.
+pub fn init() {
+    ENABLED.store(cmdline::flag("irqlatency"), Ordering::Relaxed);
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Records the time between a hardware event and its handler starting.
+pub fn record(vector: u8, nanoseconds: u64) {
+    HISTOGRAMS[vector as usize].record(nanoseconds);
+}
+
+/// None until the vector has a measurement.
+pub fn summary(vector: u8) -> Option<LatencySummary> {
+    HISTOGRAMS[vector as usize].summary()
+}
@@ -0,0 +1,101 @@
+//! The entry for `int 0x80`, the legacy system call. It saves the caller's registers before any
+//! compiled code runs, so the call number and argument are read from where the caller left them.
+
+use core::arch::asm;
+
+use crate::{
+    arch::arch_x86_64::syscall::{SyscallParameters, SYSCALL_TABLES},
+    debug,
+};
+
+/// The caller's registers, as _legacy_syscall pushes them, followed by the interrupt frame.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct SavedRegisters {
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rbp: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+// The 5 word interrupt frame and 15 pushes keep the stack 16 byte aligned for the call.
+#[naked]
+pub unsafe extern "C" fn _legacy_syscall() {
+    asm!(
+        "
+	push	r15
+	push	r14
+	push	r13
+	push	r12
+	push	r11
+	push	r10
+	push	r9
+	push	r8
+	push	rbp
+	push	rdi
+	push	rsi
+	push	rdx
+	push	rcx
+	push	rbx
+	push	rax
+
+	mov		rdi, rsp
+	call	legacy_syscall
+
+	pop	rax
+	pop	rbx
+	pop	rcx
+	pop	rdx
+	pop	rsi
+	pop	rdi
+	pop	rbp
+	pop	r8
+	pop	r9
+	pop	r10
+	pop	r11
+	pop	r12
+	pop	r13
+	pop	r14
+	pop	r15
+	iretq
+	",
+        options(noreturn)
+    );
+}
+
+/// rax holds the call number, rdi its argument.
+#[no_mangle]
+unsafe extern "C" fn legacy_syscall(registers: *mut SavedRegisters) {
+    let registers = &*registers;
+    debug!(
+        "Legacy syscall via interrupt ISR: {:#02x}, from RIP: {:#016x}",
+        registers.rax, registers.rip
+    );
+    // TODO: Load personality ID from the calling process.
+    let table = match SYSCALL_TABLES.read().get_personality(usize::MAX) {
+        Some(table) => table,
+        None => return,
+    };
+    let parameters = SyscallParameters::new(registers.rax as usize, registers.rdi as usize);
+    if let Ok(callback) = table.try_get_syscall(&parameters) {
+        callback(&parameters);
+    } else {
+        // TODO: Reap process.
+    }
+}
@@ -1,7 +1,7 @@
 use core::{
-    arch::asm,
+    any::Any,
     panic,
-    ptr::{read_volatile, write_volatile},
+    sync::atomic::{AtomicUsize, Ordering},
 };
 
 use lazy_static::*;
@@ -9,21 +9,38 @@ use spin::{self, Mutex};
 
 use x86_64::{
     set_general_handler,
-    structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode}, VirtAddr,
+    structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode},
+    VirtAddr,
 };
 
 use crate::{
     arch::arch_x86_64::{
         cpu,
-        gdt::{DOUBLE_FAULT_IST_INDEX},
-        syscall::{SyscallParameters, SYSCALL_TABLES},
+        gdt::{DOUBLE_FAULT_IST_INDEX, MAX_CPU_COUNT},
     },
-    debug, println, warn,
+    debug,
+    memory::virtual_area,
+    println,
+    thread::preempt,
+    warn,
 };
 
-use super::{apic::LOCAL_APIC, gdt::INTERRUPT_STACK_SIZE};
+use super::{
+    apic::{end_of_interrupt, rearm_timer_deadline, LOCAL_APIC},
+    gdt::INTERRUPT_STACK_SIZE,
+    tsc::{read_tsc, tsc_ticks_to_nanoseconds},
+};
 
 pub mod contextswitch;
+pub mod early;
+pub mod latency;
+pub mod legacysyscall;
+pub mod vectors;
+
+use vectors::{
+    VectorClass, APIC_SPURIOUS_VECTOR, APIC_TIMER_VECTOR, CONTEXT_SWITCH_VECTOR,
+    LEGACY_SYSCALL_VECTOR, VECTOR_ALLOCATOR,
+};
 
 static boot_cpu_gs_base: [u8; INTERRUPT_STACK_SIZE] = [0; INTERRUPT_STACK_SIZE];
 
@@ -61,6 +78,11 @@ impl InterruptHandlers {
         error_code: PageFaultErrorCode,
     ) {
         let virtual_address = x86_64::registers::control::Cr2::read();
+        let write_protect =
+            PageFaultErrorCode::CAUSED_BY_WRITE | PageFaultErrorCode::PROTECTION_VIOLATION;
+        if error_code.contains(write_protect) && virtual_area::handle_write_fault(virtual_address) {
+            return;
+        }
         panic!(
             "Page fault in early memory manager, stack frame IP: {:#016x}, error code: {:?}\n{:?}\n\nOffending virtual address: {:?}",
             stack_frame.instruction_pointer.as_u64(),
@@ -173,40 +195,58 @@ lazy_static! {
         add_handler!(idt, vmm_communication_exception);
         add_handler!(idt, x87_floating_point);
 
-        // Allocate all general handlers to our generic handler.
+        // Every other vector goes to our generic handler, so handlers can be added at runtime
+        // without touching the IDT.
         unsafe {
-            idt[0xFE].set_handler_addr(VirtAddr::from_ptr(contextswitch::_context_switch as *const u8));
+            idt[CONTEXT_SWITCH_VECTOR as usize].set_handler_addr(VirtAddr::from_ptr(contextswitch::_context_switch as *const u8));
         }
-        set_general_handler!(&mut idt, general_interrupt_handler, 0x20);
+        set_general_handler!(&mut idt, general_interrupt_handler, 0x20..=0xFD);
         set_general_handler!(&mut idt, general_interrupt_handler, 0xFF);
-        set_general_handler!(&mut idt, general_interrupt_handler, 0x80);
-        set_interrupt_handler(0x20, Some(apic_timer_interrupt_handler));
-        set_interrupt_handler(0x80, Some(legacy_syscall_interrupt_handler));
-        set_interrupt_handler(0xFF, Some(apic_spurious_interrupt_handler));
+        set_interrupt_handler(APIC_TIMER_VECTOR, Some(apic_timer_interrupt_handler), Some(&TICKS));
+        // Its own entry, which saves the caller's registers for the call number and argument.
+        unsafe {
+            idt[LEGACY_SYSCALL_VECTOR as usize].set_handler_addr(VirtAddr::from_ptr(legacysyscall::_legacy_syscall as *const u8));
+        }
+        set_interrupt_handler(APIC_SPURIOUS_VECTOR, Some(apic_spurious_interrupt_handler), None);
         idt
     };
 }
 
 pub fn init() {
+    latency::init();
+    // A CPU parked from its stop IPI handler never returned from it.
+    INTERRUPT_DEPTH[cpu::current()].store(0, Ordering::Relaxed);
     IDT.load();
 }
 
 fn apic_timer_interrupt_handler(
     _frame: InterruptStackFrame,
-    _vector: u8,
+    vector: u8,
     _error_code: Option<u64>,
+    context: InterruptContext,
 ) {
-    unsafe {
-        let ticks = read_volatile(&TICKS);
-        write_volatile(&mut TICKS, ticks + 1);
-        LOCAL_APIC.end_of_interrupt();
+    let now = read_tsc();
+    if let Some(deadline) = rearm_timer_deadline(now) {
+        if latency::enabled() {
+            latency::record(
+                vector,
+                tsc_ticks_to_nanoseconds(now.saturating_sub(deadline)),
+            );
+        }
     }
+    if let Some(ticks) = context.and_then(|c| c.downcast_ref::<AtomicUsize>()) {
+        ticks.fetch_add(1, Ordering::Relaxed);
+    }
+    preempt::scheduler_tick();
+    // The PICs deliver IRQ0 here too, when there's no usable local APIC.
+    end_of_interrupt(vector);
 }
 
 fn apic_spurious_interrupt_handler(
     _frame: InterruptStackFrame,
     _vector: u8,
     _error_code: Option<u64>,
+    _context: InterruptContext,
 ) {
     debug!("Spurious interrupt!!");
     unsafe {
@@ -214,68 +254,105 @@ fn apic_spurious_interrupt_handler(
     }
 }
 
-static mut TICKS: usize = 0;
+static TICKS: AtomicUsize = AtomicUsize::new(0);
 
 pub fn get_timer_ticks_hardware() -> usize {
-    unsafe { read_volatile(&TICKS) }
+    TICKS.load(Ordering::Relaxed)
 }
 
-type SoftwareInterruptHandler = fn(InterruptStackFrame, u8, Option<u64>);
+/// Data registered alongside a handler, and passed back to it on every dispatch. Handlers
+/// downcast it to whatever they registered.
+pub type InterruptContext = Option<&'static (dyn Any + Send + Sync)>;
 
-fn legacy_syscall_interrupt_handler(
-    stack_frame: InterruptStackFrame,
-    index: u8,
-    _error_code: Option<u64>,
-) {
-    unsafe {
-        let rax: usize;
-        asm!(
-            "nop",
-            out("rax") rax, options(pure, nomem)
-        );
-        debug!(
-            "Legacy syscall via interrupt ISR: {:#02x}, from RIP: {:#016x}",
-            index, stack_frame.instruction_pointer
-        );
-        // TODO: Load personality ID from context data.
-        let table = SYSCALL_TABLES.read().get_personality(usize::MAX).unwrap();
-        let parameters = SyscallParameters::new(rax);
-
-        let callback = table.try_get_syscall(&parameters);
-        if let Ok(cb) = callback {
-            cb(&parameters);
-        } else {
-            // TODO: Reap process.
-        }
-    }
+pub type SoftwareInterruptHandler = fn(InterruptStackFrame, u8, Option<u64>, InterruptContext);
+
+#[derive(Clone, Copy)]
+struct RegisteredHandler {
+    handler: SoftwareInterruptHandler,
+    context: InterruptContext,
 }
+
 lazy_static! {
-    static ref SOFTWARE_HANDLERS: Mutex<[Option<SoftwareInterruptHandler>; 224]> =
-        Mutex::new([None; 224]);
+    static ref SOFTWARE_HANDLERS: Mutex<[Option<RegisteredHandler>; 224]> = Mutex::new([None; 224]);
 }
 
 pub fn clear_interrupt_handler(interrupt: u8) {
-    set_interrupt_handler(interrupt, None);
+    set_interrupt_handler(interrupt, None, None);
 }
-pub fn set_interrupt_handler(interrupt: u8, handler: Option<SoftwareInterruptHandler>) {
+pub fn set_interrupt_handler(
+    interrupt: u8,
+    handler: Option<SoftwareInterruptHandler>,
+    context: InterruptContext,
+) {
     if interrupt < 32 {
         panic!("Hardware exception interrupt {:#02x} cannot be configured with a software interrupt handler", interrupt);
     }
 
     let index = interrupt - 32;
     let mut handlers = SOFTWARE_HANDLERS.lock();
-    handlers[index as usize] = handler;
+    handlers[index as usize] = handler.map(|handler| RegisteredHandler { handler, context });
+}
+
+/// Allocates a vector of the given class, and installs handler on it.
+pub fn request_interrupt(
+    class: VectorClass,
+    handler: SoftwareInterruptHandler,
+    context: InterruptContext,
+) -> Option<u8> {
+    let vector = VECTOR_ALLOCATOR.lock().allocate(class)?;
+    set_interrupt_handler(vector, Some(handler), context);
+    Some(vector)
+}
+
+/// Installs handler on a specific vector, returns false if the vector is already in use.
+pub fn request_interrupt_vector(
+    vector: u8,
+    handler: SoftwareInterruptHandler,
+    context: InterruptContext,
+) -> bool {
+    if VectorClass::of(vector).is_fixed() || !VECTOR_ALLOCATOR.lock().reserve(vector) {
+        return false;
+    }
+    set_interrupt_handler(vector, Some(handler), context);
+    true
+}
+
+/// Removes the handler from a vector returned by request_interrupt, and frees the vector.
+/// Exception and reserved vectors are refused, they were never handed out.
+pub fn release_interrupt(vector: u8) {
+    if VectorClass::of(vector).is_fixed() {
+        warn!("Refusing to release fixed vector {:#04x}", vector);
+        return;
+    }
+    clear_interrupt_handler(vector);
+    VECTOR_ALLOCATOR.lock().free(vector);
+}
+
+static INTERRUPT_DEPTH: [AtomicUsize; MAX_CPU_COUNT] =
+    [const { AtomicUsize::new(0) }; MAX_CPU_COUNT];
+
+/// Number of software interrupt handlers currently executing on this CPU.
+pub fn interrupt_depth() -> usize {
+    INTERRUPT_DEPTH[cpu::current()].load(Ordering::Relaxed)
 }
 
 fn general_interrupt_handler(stack_frame: InterruptStackFrame, index: u8, error_code: Option<u64>) {
-    let handlers = SOFTWARE_HANDLERS.lock();
-    let handler = handlers[(index - 32) as usize];
-    if handler.is_some() {
+    let depth = &INTERRUPT_DEPTH[cpu::current()];
+    depth.fetch_add(1, Ordering::Relaxed);
+    dispatch_interrupt(stack_frame, index, error_code);
+    depth.fetch_sub(1, Ordering::Relaxed);
+    preempt::interrupt_exit();
+}
+
+fn dispatch_interrupt(stack_frame: InterruptStackFrame, index: u8, error_code: Option<u64>) {
+    // Don't hold the lock while the handler runs, it may need to change handlers itself.
+    let handler = SOFTWARE_HANDLERS.lock()[(index - 32) as usize];
+    if let Some(registered) = handler {
         // debug!(
         //     "DISPATCH: {:#02x} from {:#016x}",
         //     index, stack_frame.instruction_pointer
         // );
-        handler.unwrap()(stack_frame, index, error_code);
+        (registered.handler)(stack_frame, index, error_code, registered.context);
     } else {
         warn!(
             "Unable to dispatch {:#02x} from {:#016x}, no handler is defined.",
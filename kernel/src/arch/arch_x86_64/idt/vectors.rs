@@ -0,0 +1,119 @@
+use spin::Mutex;
+
+use crate::arch::arch_x86_64::{PIC_1_OFFSET, PIC_2_OFFSET};
+
+pub const APIC_TIMER_VECTOR: u8 = 0x20;
+pub const LEGACY_SYSCALL_VECTOR: u8 = 0x80;
+pub const CONTEXT_SWITCH_VECTOR: u8 = 0xFE;
+pub const APIC_SPURIOUS_VECTOR: u8 = 0xFF;
+
+/// The kind of interrupt a vector is being allocated for. Each kind has its own range, higher
+/// vectors have a higher priority on the local APIC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VectorClass {
+    /// CPU exceptions, never allocated.
+    Exception,
+    /// ISA IRQs, as remapped from the legacy PICs.
+    LegacyIrq,
+    /// Message signaled interrupts, and any other device interrupts.
+    Device,
+    /// Inter-processor interrupts.
+    InterProcessor,
+    /// The context switch and spurious interrupt vectors, fixed by the kernel and never
+    /// allocated or freed.
+    Reserved,
+}
+
+impl VectorClass {
+    pub const fn range(&self) -> (u8, u8) {
+        match self {
+            VectorClass::Exception => (0x00, 0x1F),
+            VectorClass::LegacyIrq => (PIC_1_OFFSET, PIC_2_OFFSET + 7),
+            VectorClass::Device => (0x30, 0xDF),
+            VectorClass::InterProcessor => (0xE0, 0xFD),
+            VectorClass::Reserved => (CONTEXT_SWITCH_VECTOR, APIC_SPURIOUS_VECTOR),
+        }
+    }
+
+    /// True for the classes the allocator never hands out or takes back.
+    pub fn is_fixed(&self) -> bool {
+        matches!(self, VectorClass::Exception | VectorClass::Reserved)
+    }
+
+    pub fn of(vector: u8) -> Self {
+        [
+            VectorClass::Exception,
+            VectorClass::LegacyIrq,
+            VectorClass::Device,
+            VectorClass::InterProcessor,
+            VectorClass::Reserved,
+        ]
+        .into_iter()
+        .find(|c| {
+            let (start, end) = c.range();
+            vector >= start && vector <= end
+        })
+        // The ranges cover every vector.
+        .unwrap_or(VectorClass::Reserved)
+    }
+}
+
+/// Tracks which IDT vectors are in use.
+pub struct VectorAllocator {
+    used: [u64; 4],
+}
+
+impl VectorAllocator {
+    const fn new() -> Self {
+        Self { used: [0; 4] }
+    }
+
+    fn is_used(&self, vector: u8) -> bool {
+        self.used[vector as usize / 64] & (1 << (vector % 64)) != 0
+    }
+
+    fn set_used(&mut self, vector: u8, used: bool) {
+        let bit = 1 << (vector % 64);
+        match used {
+            true => self.used[vector as usize / 64] |= bit,
+            false => self.used[vector as usize / 64] &= !bit,
+        }
+    }
+
+    /// Claims a specific vector, returns false if it is already in use.
+    pub fn reserve(&mut self, vector: u8) -> bool {
+        if self.is_used(vector) {
+            return false;
+        }
+        self.set_used(vector, true);
+        true
+    }
+
+    /// Claims the lowest free vector in the range for the class.
+    pub fn allocate(&mut self, class: VectorClass) -> Option<u8> {
+        if class.is_fixed() {
+            return None;
+        }
+        let (start, end) = class.range();
+        let vector = (start..=end).find(|v| !self.is_used(*v))?;
+        self.set_used(vector, true);
+        Some(vector)
+    }
+
+    pub fn free(&mut self, vector: u8) {
+        if VectorClass::of(vector).is_fixed() {
+            panic!("Attempted to free fixed vector {:#02x}", vector);
+        }
+        self.set_used(vector, false);
+    }
+}
+
+pub(super) static VECTOR_ALLOCATOR: Mutex<VectorAllocator> = Mutex::new({
+    let mut allocator = VectorAllocator::new();
+    // Exceptions, and the vectors the kernel sets up itself.
+    allocator.used[0] = u32::MAX as u64;
+    allocator.used[(APIC_TIMER_VECTOR / 64) as usize] |= 1 << (APIC_TIMER_VECTOR % 64);
+    allocator.used[(LEGACY_SYSCALL_VECTOR / 64) as usize] |= 1 << (LEGACY_SYSCALL_VECTOR % 64);
+    allocator.used[3] |= 1 << (CONTEXT_SWITCH_VECTOR % 64) | 1 << (APIC_SPURIOUS_VECTOR % 64);
+    allocator
+});
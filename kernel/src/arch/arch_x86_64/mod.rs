@@ -1,21 +1,35 @@
 use alloc::string::{String, ToString};
 
-use bootloader_api::BootInfo;
 use lazy_static::lazy_static;
-use pic8259::ChainedPics;
 use x86::cpuid::CpuId;
-use x86_64::instructions::interrupts;
+use x86_64::instructions::{interrupts, port::Port, random::RdRand};
 
-use crate::{arch::arch_x86_64::cpu::start_additional_cpus, debug};
+use crate::{
+    arch::arch_x86_64::cpu::start_additional_cpus,
+    initcall::{self, InitCall, InitLevel},
+    state::{self, BootData},
+    time::{self, ClockSource},
+    warn,
+};
 
-use self::cpu::cpu_apic_id;
+use self::cpu::registry::{current_cpu_index, register_boot_cpu};
 
 pub(crate) mod acpi;
 pub(crate) mod apic;
 pub(crate) mod cpu;
 pub(crate) mod gdt;
 pub(crate) mod idt;
+pub(crate) mod msi;
+pub(crate) mod pat;
+pub(crate) mod pci;
+pub(crate) mod pic;
+pub(crate) mod pit;
+pub(crate) mod ps2;
+pub(crate) mod rtc;
 pub(crate) mod syscall;
+pub(crate) mod tlb;
+pub(crate) mod tsc;
+pub(crate) mod vmx;
 pub mod cpuid;
 
 pub const PIC_1_OFFSET: u8 = 32;
@@ -23,32 +37,65 @@ pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 
 pub fn init_common() {}
 
-pub fn init_hardware(boot_info: &BootInfo) {
-    debug!("Initializing GDT");
-    gdt::init();
-    debug!("Initializing IDT");
-    idt::init();
-    debug!("Initializing ACPI");
-    acpi::init(boot_info.rsdp_addr.into_option());
-    debug!("Initializing APIC");
-    apic::init();
-    start_additional_cpus();
-
-    debug!("Initializing syscalls");
-    syscall::init();
-}
-
-fn pic_init() {
-    unsafe {
-        debug!(
-            "Remapping PIC1 and 2 interrupts offsets to {} and {}",
-            PIC_1_OFFSET, PIC_2_OFFSET
+/// Runs before anything else, including the heap.
+pub fn early_init_hardware() {
+    crate::serial::init_raw();
+    idt::early::init();
+    register_boot_cpu();
+}
+
+/// The boot CPU's and the platform's init calls, see initcall.rs.
+pub(crate) static INITCALLS: [InitCall; 9] = [
+    InitCall::new("TSC calibration", InitLevel::Early, &[], register_tsc),
+    InitCall::new("PAT", InitLevel::Early, &[], pat::init),
+    InitCall::new("GDT", InitLevel::Early, &[], gdt::init).required(),
+    InitCall::new("IDT", InitLevel::Early, &["GDT"], idt::init).required(),
+    InitCall::fallible("ACPI", InitLevel::Arch, &[], init_acpi),
+    InitCall::fallible(
+        "APIC",
+        InitLevel::Arch,
+        &["IDT", "TSC calibration"],
+        init_apic,
+    ),
+    InitCall::fallible(
+        "TLB shootdown",
+        InitLevel::Arch,
+        &["IDT", "APIC"],
+        tlb::init,
+    ),
+    InitCall::new(
+        "SMP bring-up",
+        InitLevel::Arch,
+        &["ACPI", "APIC", "PAT", "TLB shootdown"],
+        start_additional_cpus,
+    ),
+    InitCall::new("Syscalls", InitLevel::Arch, &["IDT"], syscall::init).required(),
+];
+
+pub fn init_hardware() {
+    initcall::run_level(InitLevel::Early);
+    initcall::run_level(InitLevel::Arch);
+}
+
+fn register_tsc() {
+    time::register_clock_source(&tsc::TSC_CLOCK_SOURCE);
+    state::register(&tsc::TSC_CLOCK_SOURCE);
+}
+
+fn init_acpi() -> Result<(), &'static str> {
+    acpi::init(state::get::<BootData>().rsdp_address)
+}
+
+// Falls back to the legacy PICs, so there's still a tick, if the local APIC can't be used.
+fn init_apic() -> Result<(), &'static str> {
+    apic::init().map_err(|reason| {
+        warn!(
+            "No usable local APIC ({}), falling back to the legacy PICs, on the boot CPU only",
+            reason
         );
-        let mut pics = ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET);
-        pics.initialize(); // initialize the pics, and immediately disable them.
-        pics.disable();
-        debug!("8529 PICs have been disabled successfully.");
-    }
+        pic::start_timer();
+        reason
+    })
 }
 
 pub fn breakpoint_hardware() {
@@ -76,10 +123,93 @@ pub fn enable_interrupts_hardware() {
     interrupts::enable();
 }
 
+pub fn interrupts_enabled_hardware() -> bool {
+    interrupts::are_enabled()
+}
+
 pub fn wait_for_interrupt_hardware() {
     interrupts::enable_and_hlt();
 }
 
+/// Halts with the tick stopped until an interrupt, or the monotonic clock reaching deadline.
+/// Falls back to halting until the next tick when the timer can't be stopped.
+pub fn idle_until_hardware(deadline_nanoseconds: Option<u64>) {
+    if !apic::tsc_deadline_mode() {
+        wait_for_interrupt_hardware();
+        return;
+    }
+    interrupts::disable();
+    apic::stop_tick(deadline_nanoseconds.map(monotonic_to_tsc_deadline));
+    interrupts::enable_and_hlt();
+    apic::restart_tick();
+}
+
+// When the monotonic clock runs off the TSC its nanoseconds convert straight to a deadline,
+// otherwise the time left is counted from the TSC now.
+fn monotonic_to_tsc_deadline(deadline_nanoseconds: u64) -> u64 {
+    if time::clock_source().name() == tsc::TSC_CLOCK_SOURCE.name() {
+        return tsc::nanoseconds_to_tsc_ticks(deadline_nanoseconds);
+    }
+    let remaining = deadline_nanoseconds.saturating_sub(time::monotonic_nanoseconds());
+    tsc::read_tsc().saturating_add(tsc::nanoseconds_to_tsc_ticks(remaining))
+}
+
+pub fn probe_input_hardware() -> bool {
+    ps2::probe()
+}
+
+pub fn read_input_byte_hardware() -> Option<ps2::Ps2Byte> {
+    ps2::read_byte()
+}
+
+pub fn enable_mouse_hardware() -> bool {
+    ps2::enable_mouse()
+}
+
+pub fn read_wall_clock_hardware() -> u64 {
+    rtc::read_unix_time()
+}
+
+pub fn speaker_start_hardware(frequency: u32) {
+    pit::speaker_start(frequency)
+}
+
+pub fn speaker_stop_hardware() {
+    pit::speaker_stop()
+}
+
+pub fn pci_read_config_hardware(bus: u8, device: u8, function: u8, offset: u16) -> u32 {
+    pci::read_config(bus, device, function, offset)
+}
+
+pub fn pci_write_config_hardware(bus: u8, device: u8, function: u8, offset: u16, value: u32) {
+    pci::write_config(bus, device, function, offset, value)
+}
+
+pub fn dma_remapping_table_hardware() -> Option<&'static [u8]> {
+    acpi::find_table(::acpi::sdt::Signature::DMAR)
+}
+
+pub fn io_read_u8_hardware(port: u16) -> u8 {
+    unsafe { Port::<u8>::new(port).read() }
+}
+
+pub fn io_write_u8_hardware(port: u16, value: u8) {
+    unsafe { Port::<u8>::new(port).write(value) }
+}
+
 pub fn current_cpu() -> usize {
-    cpu_apic_id()
+    current_cpu_index()
+}
+
+pub fn read_timestamp_counter() -> u64 {
+    tsc::read_tsc()
+}
+
+pub fn timestamp_counter_frequency() -> Option<u64> {
+    Some(tsc::TSC_CLOCK_SOURCE.frequency())
+}
+
+pub fn read_hardware_random() -> Option<u64> {
+    RdRand::new()?.get_u64()
 }
@@ -0,0 +1,55 @@
+use alloc::boxed::Box;
+use x86_64::structures::idt::InterruptStackFrame;
+
+use super::{
+    apic::LOCAL_APIC,
+    cpu::cpu_apic_id,
+    idt::{release_interrupt, request_interrupt, vectors::VectorClass, InterruptContext},
+    pic,
+};
+
+const MSI_ADDRESS_BASE: u64 = 0xFEE0_0000;
+const MSI_DESTINATION_SHIFT: u64 = 12;
+
+/// What a device writes, and where, to raise a message signaled interrupt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MsiMessage {
+    pub address: u64,
+    pub data: u32,
+    pub vector: u8,
+}
+
+fn msi_interrupt_handler(
+    _frame: InterruptStackFrame,
+    vector: u8,
+    _error_code: Option<u64>,
+    context: InterruptContext,
+) {
+    if let Some(handler) = context.and_then(|c| c.downcast_ref::<fn(u8)>()) {
+        handler(vector);
+    }
+    unsafe {
+        LOCAL_APIC.end_of_interrupt();
+    }
+}
+
+/// Allocates a device vector for handler, delivered to the current CPU, fixed and edge
+/// triggered. Returns the message a device should be programmed with to raise it, or None if
+/// vectors ran out, or there's no local APIC to deliver it.
+pub fn request_msi(handler: fn(u8)) -> Option<MsiMessage> {
+    if pic::in_use() {
+        return None;
+    }
+    // Registrations live as long as the kernel, so the handler is its own context.
+    let context: &'static fn(u8) = Box::leak(Box::new(handler));
+    let vector = request_interrupt(VectorClass::Device, msi_interrupt_handler, Some(context))?;
+    Some(MsiMessage {
+        address: MSI_ADDRESS_BASE | (cpu_apic_id() as u64 & 0xFF) << MSI_DESTINATION_SHIFT,
+        data: vector as u32,
+        vector,
+    })
+}
+
+pub fn release_msi(message: MsiMessage) {
+    release_interrupt(message.vector);
+}
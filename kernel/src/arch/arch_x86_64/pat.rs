@@ -0,0 +1,60 @@
+use x86::msr::{rdmsr, wrmsr, IA32_PAT};
+use x86_64::structures::paging::PageTableFlags;
+
+use crate::debug;
+
+const PAT_UNCACHEABLE: u64 = 0x00;
+const PAT_WRITE_COMBINING: u64 = 0x01;
+const PAT_WRITE_THROUGH: u64 = 0x04;
+const PAT_WRITE_PROTECTED: u64 = 0x05;
+const PAT_WRITE_BACK: u64 = 0x06;
+const PAT_UNCACHED: u64 = 0x07;
+
+// Same layout as Linux: the power on default, except entry 1 (PWT) is write combining instead of
+// write through, and write through moves to entry 7.
+const PAT_LAYOUT: [u64; 8] = [
+    PAT_WRITE_BACK,
+    PAT_WRITE_COMBINING,
+    PAT_UNCACHED,
+    PAT_UNCACHEABLE,
+    PAT_WRITE_BACK,
+    PAT_WRITE_PROTECTED,
+    PAT_UNCACHED,
+    PAT_WRITE_THROUGH,
+];
+
+/// Page table flags that select write combining, once init has run on every CPU.
+pub const WRITE_COMBINING: PageTableFlags = PageTableFlags::WRITE_THROUGH;
+
+/// Names the memory type a page's caching flags, and its PAT bit, select.
+pub fn memory_type_name(flags: PageTableFlags, pat_bit: bool) -> &'static str {
+    let index = (pat_bit as usize) << 2
+        | (flags.contains(PageTableFlags::NO_CACHE) as usize) << 1
+        | flags.contains(PageTableFlags::WRITE_THROUGH) as usize;
+    match PAT_LAYOUT[index] {
+        PAT_UNCACHEABLE => "UC",
+        PAT_WRITE_COMBINING => "WC",
+        PAT_WRITE_THROUGH => "WT",
+        PAT_WRITE_PROTECTED => "WP",
+        PAT_WRITE_BACK => "WB",
+        _ => "UC-",
+    }
+}
+
+/// Programs the page attribute table, this must be done on every CPU, with the same layout.
+pub fn init() {
+    let value = PAT_LAYOUT
+        .iter()
+        .enumerate()
+        .fold(0u64, |pat, (i, entry)| pat | (entry << (i * 8)));
+    unsafe {
+        if rdmsr(IA32_PAT) == value {
+            return;
+        }
+        wrmsr(IA32_PAT, value);
+        // Cached lines and TLB entries may have been created with the old memory types.
+        core::arch::asm!("wbinvd", options(nostack, preserves_flags));
+        x86_64::instructions::tlb::flush_all();
+    }
+    debug!("PAT programmed: {:#018x}", value);
+}
@@ -0,0 +1,99 @@
+use alloc::collections::BTreeMap;
+use spin::Mutex;
+use x86_64::{
+    instructions::{interrupts, port::Port},
+    structures::paging::PageTableFlags,
+    PhysAddr, VirtAddr,
+};
+
+use crate::{arch::arch_x86_64::acpi::acpi_tables, memory::virtual_area};
+
+const CONFIG_ADDRESS_PORT: u16 = 0xCF8;
+const CONFIG_DATA_PORT: u16 = 0xCFC;
+const CONFIG_ENABLE: u32 = 1 << 31;
+
+// The address and data ports are used as a pair, so accesses from different CPUs, or from an
+// interrupt handler, must not interleave.
+static CONFIG_LOCK: Mutex<()> = Mutex::new(());
+
+const FUNCTION_CONFIG_SIZE: usize = 4096;
+const LEGACY_CONFIG_SIZE: u16 = 256;
+const NO_DEVICE: u32 = 0xFFFF_FFFF;
+
+// Each function's memory mapped configuration space, found through the ACPI MCFG table and
+// mapped the first time it's used. None for functions outside every MCFG region.
+static EXTENDED_CONFIG: Mutex<BTreeMap<(u8, u8, u8), Option<VirtAddr>>> =
+    Mutex::new(BTreeMap::new());
+
+fn config_address(bus: u8, device: u8, function: u8, offset: u16) -> u32 {
+    CONFIG_ENABLE
+        | (bus as u32) << 16
+        | ((device & 0x1F) as u32) << 11
+        | ((function & 0x07) as u32) << 8
+        | (offset & 0xFC) as u32
+}
+
+fn extended_config(bus: u8, device: u8, function: u8) -> Option<VirtAddr> {
+    // Also used from interrupt handlers, which must not find the map already locked.
+    interrupts::without_interrupts(|| {
+        *EXTENDED_CONFIG
+            .lock()
+            .entry((bus, device, function))
+            .or_insert_with(|| {
+                let regions = acpi::mcfg::PciConfigRegions::new(acpi_tables()?).ok()?;
+                let physical = regions.physical_address(0, bus, device, function)?;
+                virtual_area::ioremap(
+                    PhysAddr::new(physical),
+                    FUNCTION_CONFIG_SIZE,
+                    PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+                )
+            })
+    })
+}
+
+fn extended_register(bus: u8, device: u8, function: u8, offset: u16) -> Option<*mut u32> {
+    if offset as usize >= FUNCTION_CONFIG_SIZE {
+        return None;
+    }
+    let base = extended_config(bus, device, function)?;
+    Some((base + (offset & !3) as u64).as_mut_ptr())
+}
+
+/// Reads the aligned dword of configuration space containing offset. The first 256 bytes of
+/// each function go through the legacy configuration mechanism, the extended space past them
+/// through the memory mapped one, and read as all ones without it.
+pub fn read_config(bus: u8, device: u8, function: u8, offset: u16) -> u32 {
+    if offset >= LEGACY_CONFIG_SIZE {
+        return match extended_register(bus, device, function, offset) {
+            Some(register) => unsafe { register.read_volatile() },
+            None => NO_DEVICE,
+        };
+    }
+    let mut address: Port<u32> = Port::new(CONFIG_ADDRESS_PORT);
+    let mut data: Port<u32> = Port::new(CONFIG_DATA_PORT);
+    interrupts::without_interrupts(|| {
+        let _lock = CONFIG_LOCK.lock();
+        unsafe {
+            address.write(config_address(bus, device, function, offset));
+            data.read()
+        }
+    })
+}
+
+pub fn write_config(bus: u8, device: u8, function: u8, offset: u16, value: u32) {
+    if offset >= LEGACY_CONFIG_SIZE {
+        if let Some(register) = extended_register(bus, device, function, offset) {
+            unsafe { register.write_volatile(value) };
+        }
+        return;
+    }
+    let mut address: Port<u32> = Port::new(CONFIG_ADDRESS_PORT);
+    let mut data: Port<u32> = Port::new(CONFIG_DATA_PORT);
+    interrupts::without_interrupts(|| {
+        let _lock = CONFIG_LOCK.lock();
+        unsafe {
+            address.write(config_address(bus, device, function, offset));
+            data.write(value);
+        }
+    })
+}
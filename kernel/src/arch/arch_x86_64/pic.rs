@@ -0,0 +1,70 @@
+//! The legacy 8259 PICs. With a local APIC they're remapped out of the way of the exceptions and
+//! masked. Without one, they deliver the PIT's tick on IRQ0, the APIC timer's vector, and nothing
+//! else: there are no MSIs, and only the boot CPU runs.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use pic8259::ChainedPics;
+use spin::Mutex;
+use x86_64::instructions::{interrupts, port::Port};
+
+use crate::debug;
+
+use super::{pit, PIC_1_OFFSET, PIC_2_OFFSET};
+
+const PIC_1_COMMAND_PORT: u16 = 0x20;
+const PIC_2_COMMAND_PORT: u16 = 0xA0;
+const END_OF_INTERRUPT: u8 = 0x20;
+// Only IRQ0, the PIT, and IRQ2, the cascade, unmasked.
+const PIC_1_TIMER_ONLY_MASK: u8 = !0b101;
+const PIC_2_ALL_MASKED: u8 = 0xFF;
+// The same rate as the APIC's TSC deadline tick.
+const TICK_FREQUENCY: u32 = 1000;
+
+static PICS: Mutex<ChainedPics> =
+    Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
+static IN_USE: AtomicBool = AtomicBool::new(false);
+
+pub(crate) fn disable() {
+    debug!(
+        "Remapping PIC1 and 2 interrupts offsets to {} and {}",
+        PIC_1_OFFSET, PIC_2_OFFSET
+    );
+    interrupts::without_interrupts(|| {
+        let mut pics = PICS.lock();
+        unsafe {
+            pics.initialize(); // initialize the pics, and immediately disable them.
+            pics.disable();
+        }
+    });
+    debug!("8529 PICs have been disabled successfully.");
+}
+
+/// Ticks the boot CPU from the PIT, in place of the local APIC timer.
+pub(crate) fn start_timer() {
+    interrupts::without_interrupts(|| {
+        let mut pics = PICS.lock();
+        unsafe {
+            pics.initialize();
+            pics.write_masks(PIC_1_TIMER_ONLY_MASK, PIC_2_ALL_MASKED);
+        }
+        pit::start_periodic(TICK_FREQUENCY);
+        IN_USE.store(true, Ordering::Relaxed);
+    });
+    debug!("PIT tick started on IRQ0 (Vector {})", PIC_1_OFFSET);
+}
+
+/// True if the PICs deliver interrupts, rather than the local APIC.
+pub(crate) fn in_use() -> bool {
+    IN_USE.load(Ordering::Relaxed)
+}
+
+/// Acknowledges the IRQ delivered on vector. Doesn't lock, it's called from interrupt handlers.
+pub(crate) fn end_of_interrupt(vector: u8) {
+    unsafe {
+        if vector >= PIC_2_OFFSET {
+            Port::<u8>::new(PIC_2_COMMAND_PORT).write(END_OF_INTERRUPT);
+        }
+        Port::<u8>::new(PIC_1_COMMAND_PORT).write(END_OF_INTERRUPT);
+    }
+}
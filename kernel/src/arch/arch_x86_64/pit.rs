@@ -0,0 +1,54 @@
+use x86_64::instructions::port::Port;
+
+pub(crate) const PIT_FREQUENCY: u64 = 1_193_182;
+const PIT_CHANNEL_0_DATA_PORT: u16 = 0x40;
+pub(crate) const PIT_CHANNEL_2_DATA_PORT: u16 = 0x42;
+pub(crate) const PIT_COMMAND_PORT: u16 = 0x43;
+// Bit 0 gates PIT channel 2, bit 1 connects it to the speaker, bit 5 reads back its output.
+pub(crate) const PIT_CHANNEL_2_GATE_PORT: u16 = 0x61;
+pub(crate) const PIT_CHANNEL_2_GATE: u8 = 1 << 0;
+pub(crate) const PIT_CHANNEL_2_SPEAKER: u8 = 1 << 1;
+pub(crate) const PIT_CHANNEL_2_OUTPUT: u8 = 1 << 5;
+// Channel 2, lobyte/hibyte access, mode 0 (interrupt on terminal count), binary.
+pub(crate) const PIT_CHANNEL_2_ONE_SHOT: u8 = 0b1011_0000;
+// Channel 0, lobyte/hibyte access, mode 2 (rate generator), binary.
+const PIT_CHANNEL_0_RATE_GENERATOR: u8 = 0b0011_0100;
+// Channel 2, lobyte/hibyte access, mode 3 (square wave), binary.
+const PIT_CHANNEL_2_SQUARE_WAVE: u8 = 0b1011_0110;
+
+/// Drives the PC speaker with a square wave from PIT channel 2. Frequencies the PIT can't divide
+/// down to are clamped.
+pub fn speaker_start(frequency: u32) {
+    let divisor = (PIT_FREQUENCY / frequency.max(19) as u64).clamp(1, u16::MAX as u64);
+    let mut gate: Port<u8> = Port::new(PIT_CHANNEL_2_GATE_PORT);
+    let mut command: Port<u8> = Port::new(PIT_COMMAND_PORT);
+    let mut data: Port<u8> = Port::new(PIT_CHANNEL_2_DATA_PORT);
+    unsafe {
+        command.write(PIT_CHANNEL_2_SQUARE_WAVE);
+        data.write(divisor as u8);
+        data.write((divisor >> 8) as u8);
+        let gate_value = gate.read();
+        gate.write(gate_value | PIT_CHANNEL_2_GATE | PIT_CHANNEL_2_SPEAKER);
+    }
+}
+
+/// Raises IRQ0 frequency times a second from PIT channel 0, the tick when there's no local APIC
+/// timer.
+pub fn start_periodic(frequency: u32) {
+    let divisor = (PIT_FREQUENCY / frequency.max(19) as u64).clamp(1, u16::MAX as u64);
+    let mut command: Port<u8> = Port::new(PIT_COMMAND_PORT);
+    let mut data: Port<u8> = Port::new(PIT_CHANNEL_0_DATA_PORT);
+    unsafe {
+        command.write(PIT_CHANNEL_0_RATE_GENERATOR);
+        data.write(divisor as u8);
+        data.write((divisor >> 8) as u8);
+    }
+}
+
+pub fn speaker_stop() {
+    let mut gate: Port<u8> = Port::new(PIT_CHANNEL_2_GATE_PORT);
+    unsafe {
+        let gate_value = gate.read();
+        gate.write(gate_value & !(PIT_CHANNEL_2_GATE | PIT_CHANNEL_2_SPEAKER));
+    }
+}
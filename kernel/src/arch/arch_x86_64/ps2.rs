@@ -0,0 +1,141 @@
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use x86_64::instructions::port::Port;
+
+const PS2_DATA_PORT: u16 = 0x60;
+const PS2_STATUS_PORT: u16 = 0x64;
+const PS2_COMMAND_PORT: u16 = 0x64;
+const STATUS_OUTPUT_FULL: u8 = 0x01;
+const STATUS_INPUT_FULL: u8 = 0x02;
+// Set when the waiting byte came from the second (mouse) port.
+const STATUS_AUXILIARY: u8 = 0x20;
+
+const COMMAND_SELF_TEST: u8 = 0xAA;
+const SELF_TEST_PASSED: u8 = 0x55;
+const COMMAND_ENABLE_AUXILIARY: u8 = 0xA8;
+const COMMAND_WRITE_AUXILIARY: u8 = 0xD4;
+const MOUSE_SET_DEFAULTS: u8 = 0xF6;
+const MOUSE_ENABLE_REPORTING: u8 = 0xF4;
+const MOUSE_ACKNOWLEDGE: u8 = 0xFA;
+
+// Controller reads and writes are bounded, the controller may not exist.
+const MAX_POLLS: usize = 100_000;
+// Nothing drives the bus when there's no controller, so the status port reads all ones.
+const STATUS_FLOATING: u8 = 0xFF;
+
+const CONTROLLER_UNKNOWN: u8 = 0;
+const CONTROLLER_PRESENT: u8 = 1;
+const CONTROLLER_ABSENT: u8 = 2;
+static CONTROLLER: AtomicU8 = AtomicU8::new(CONTROLLER_UNKNOWN);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ps2Byte {
+    Keyboard(u8),
+    Mouse(u8),
+}
+
+/// Looks for the controller, and has it test itself. Only probes once, later calls return what
+/// the first one found.
+pub fn probe() -> bool {
+    match CONTROLLER.load(Ordering::Acquire) {
+        CONTROLLER_UNKNOWN => {}
+        state => return state == CONTROLLER_PRESENT,
+    }
+    let present = self_test();
+    let state = match present {
+        true => CONTROLLER_PRESENT,
+        false => CONTROLLER_ABSENT,
+    };
+    CONTROLLER.store(state, Ordering::Release);
+    present
+}
+
+fn self_test() -> bool {
+    let mut status: Port<u8> = Port::new(PS2_STATUS_PORT);
+    let mut data: Port<u8> = Port::new(PS2_DATA_PORT);
+    if unsafe { status.read() } == STATUS_FLOATING {
+        return false;
+    }
+    // Throw away anything already waiting, so it isn't taken for the reply.
+    for _ in 0..MAX_POLLS {
+        unsafe {
+            if status.read() & STATUS_OUTPUT_FULL == 0 {
+                break;
+            }
+            data.read();
+        }
+    }
+    if !write(PS2_COMMAND_PORT, COMMAND_SELF_TEST) {
+        return false;
+    }
+    for _ in 0..MAX_POLLS {
+        unsafe {
+            if status.read() & STATUS_OUTPUT_FULL != 0 {
+                return data.read() == SELF_TEST_PASSED;
+            }
+        }
+        core::hint::spin_loop();
+    }
+    false
+}
+
+/// Reads the next waiting byte from either PS/2 port, without waiting. Always None if probe
+/// didn't find the controller.
+pub fn read_byte() -> Option<Ps2Byte> {
+    if CONTROLLER.load(Ordering::Relaxed) != CONTROLLER_PRESENT {
+        return None;
+    }
+    let mut status: Port<u8> = Port::new(PS2_STATUS_PORT);
+    let mut data: Port<u8> = Port::new(PS2_DATA_PORT);
+    unsafe {
+        let status = status.read();
+        if status & STATUS_OUTPUT_FULL == 0 {
+            return None;
+        }
+        let byte = data.read();
+        match status & STATUS_AUXILIARY {
+            0 => Some(Ps2Byte::Keyboard(byte)),
+            _ => Some(Ps2Byte::Mouse(byte)),
+        }
+    }
+}
+
+fn write(port: u16, value: u8) -> bool {
+    let mut status: Port<u8> = Port::new(PS2_STATUS_PORT);
+    let mut out: Port<u8> = Port::new(port);
+    for _ in 0..MAX_POLLS {
+        unsafe {
+            if status.read() & STATUS_INPUT_FULL == 0 {
+                out.write(value);
+                return true;
+            }
+        }
+        core::hint::spin_loop();
+    }
+    false
+}
+
+fn read_mouse_reply() -> Option<u8> {
+    for _ in 0..MAX_POLLS {
+        match read_byte() {
+            Some(Ps2Byte::Mouse(byte)) => return Some(byte),
+            // Keyboard bytes can't be put back, a key pressed right now is lost.
+            _ => core::hint::spin_loop(),
+        }
+    }
+    None
+}
+
+fn send_mouse_command(command: u8) -> bool {
+    write(PS2_COMMAND_PORT, COMMAND_WRITE_AUXILIARY)
+        && write(PS2_DATA_PORT, command)
+        && read_mouse_reply() == Some(MOUSE_ACKNOWLEDGE)
+}
+
+/// Turns on the second port, and has the mouse start sending movement packets.
+pub fn enable_mouse() -> bool {
+    probe()
+        && write(PS2_COMMAND_PORT, COMMAND_ENABLE_AUXILIARY)
+        && send_mouse_command(MOUSE_SET_DEFAULTS)
+        && send_mouse_command(MOUSE_ENABLE_REPORTING)
+}
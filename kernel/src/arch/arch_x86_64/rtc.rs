@@ -0,0 +1,99 @@
+use x86_64::instructions::port::Port;
+
+const CMOS_ADDRESS_PORT: u16 = 0x70;
+const CMOS_DATA_PORT: u16 = 0x71;
+// Keeps NMIs disabled while a register is selected.
+const CMOS_NMI_DISABLE: u8 = 0x80;
+
+const RTC_SECONDS: u8 = 0x00;
+const RTC_MINUTES: u8 = 0x02;
+const RTC_HOURS: u8 = 0x04;
+const RTC_DAY: u8 = 0x07;
+const RTC_MONTH: u8 = 0x08;
+const RTC_YEAR: u8 = 0x09;
+const RTC_STATUS_A: u8 = 0x0A;
+const RTC_STATUS_B: u8 = 0x0B;
+const RTC_STATUS_D: u8 = 0x0D;
+const STATUS_A_UPDATE_IN_PROGRESS: u8 = 0x80;
+const STATUS_B_24_HOUR: u8 = 0x02;
+const STATUS_B_BINARY: u8 = 0x04;
+const HOUR_PM: u8 = 0x80;
+// An update takes under 2ms, this is well past it, and bounds the wait if there's no RTC.
+const MAX_UPDATE_POLLS: usize = 100_000;
+
+fn read_register(register: u8) -> u8 {
+    let mut address: Port<u8> = Port::new(CMOS_ADDRESS_PORT);
+    let mut data: Port<u8> = Port::new(CMOS_DATA_PORT);
+    unsafe {
+        address.write(CMOS_NMI_DISABLE | register);
+        let value = data.read();
+        // The selected register's bit 7 is the NMI mask, leave NMIs enabled again.
+        address.write(RTC_STATUS_D);
+        value
+    }
+}
+
+fn read_raw() -> [u8; 6] {
+    for _ in 0..MAX_UPDATE_POLLS {
+        if read_register(RTC_STATUS_A) & STATUS_A_UPDATE_IN_PROGRESS == 0 {
+            break;
+        }
+        core::hint::spin_loop();
+    }
+    [
+        RTC_SECONDS,
+        RTC_MINUTES,
+        RTC_HOURS,
+        RTC_DAY,
+        RTC_MONTH,
+        RTC_YEAR,
+    ]
+    .map(read_register)
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0x0F)
+}
+
+/// Days from 1970-01-01 to a date, in the proleptic Gregorian calendar.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// Reads the CMOS real time clock, as seconds since the Unix epoch. The RTC is assumed to be
+/// kept in UTC, and in the 21st century.
+pub fn read_unix_time() -> u64 {
+    // Read until two reads agree, so an update part way through isn't seen.
+    let mut raw = read_raw();
+    loop {
+        let again = read_raw();
+        if again == raw {
+            break;
+        }
+        raw = again;
+    }
+    let status = read_register(RTC_STATUS_B);
+    let [mut seconds, mut minutes, hours, mut day, mut month, mut year] = raw;
+    let pm = hours & HOUR_PM != 0;
+    let mut hours = hours & !HOUR_PM;
+    if status & STATUS_B_BINARY == 0 {
+        seconds = from_bcd(seconds);
+        minutes = from_bcd(minutes);
+        hours = from_bcd(hours);
+        day = from_bcd(day);
+        month = from_bcd(month);
+        year = from_bcd(year);
+    }
+    if status & STATUS_B_24_HOUR == 0 {
+        // 12 hour clock, 12 AM is midnight.
+        hours = (hours % 12) + if pm { 12 } else { 0 };
+    }
+    let days = days_from_civil(2000 + year as i64, month as i64, day as i64);
+    let seconds = days * 86400 + hours as i64 * 3600 + minutes as i64 * 60 + seconds as i64;
+    seconds.max(0) as u64
+}
@@ -1,6 +1,6 @@
 use core::arch::asm;
 
-use alloc::{collections::BTreeMap};
+use alloc::{collections::BTreeMap, vec::Vec};
 use lazy_static::lazy_static;
 use spin::RwLock;
 
@@ -38,18 +38,57 @@ fn native_default_syscall_handler(parameters: &SyscallParameters) {
 
 pub struct SyscallParameters {
     id: usize,
+    argument: usize,
 }
 
 impl SyscallParameters {
-    pub fn new(id: usize) -> Self {
-        Self { id }
+    pub fn new(id: usize, argument: usize) -> Self {
+        Self { id, argument }
     }
+
+    /// The caller's parameter, usually a pointer to a structure for the call to read or fill.
+    pub fn argument(&self) -> usize {
+        self.argument
+    }
+}
+
+/// Adds a call to the native personality.
+pub fn register_native_syscall(
+    id: usize,
+    name: &'static str,
+    arguments: u8,
+    callback: SyscallEntry,
+) {
+    let mut tables = SYSCALL_TABLES.write();
+    let mut table = tables
+        .get_personality(usize::MAX)
+        .unwrap_or_else(SyscallTable::new);
+    table.set_handler(id, name, arguments, callback);
+    tables.register_personality(usize::MAX, table);
+}
+
+pub type SyscallEntry = fn(&SyscallParameters);
+
+/// A handler, and what it's called and how many parameters it takes, for introspection.
+#[derive(Clone, Copy)]
+pub struct RegisteredSyscall {
+    pub handler: SyscallEntry,
+    pub name: &'static str,
+    pub arguments: u8,
+}
+
+/// A registered call, as listed by SyscallTables::describe.
+#[derive(Debug, Clone, Copy)]
+pub struct SyscallInfo {
+    pub personality: usize,
+    pub number: usize,
+    pub name: &'static str,
+    pub arguments: u8,
 }
 
-type SyscallEntry = fn(&SyscallParameters);
 #[derive(Clone)]
 pub struct SyscallTable {
-    calls: BTreeMap<usize, SyscallEntry>,
+    calls: BTreeMap<usize, RegisteredSyscall>,
 }
 
 impl SyscallTable {
@@ -63,20 +102,33 @@ impl SyscallTable {
         parameters: &SyscallParameters,
     ) -> Result<SyscallEntry, SyscallError> {
         if let Some(entry) = self.calls.get(&parameters.id) {
-            Ok(*entry)
+            Ok(entry.handler)
         } else if let Some(entry) = self.calls.get(&usize::MAX) {
-            Ok(*entry)
+            Ok(entry.handler)
         } else {
             Err(SyscallError::no_such_system_call())
         }
     }
 
     pub fn set_default_handler(&mut self, callback: SyscallEntry) {
-        self.set_handler(usize::MAX, callback);
+        self.set_handler(usize::MAX, "default", 1, callback);
     }
 
-    pub fn set_handler(&mut self, id: usize, callback: SyscallEntry) {
-        self.calls.insert(id, callback);
+    pub fn set_handler(
+        &mut self,
+        id: usize,
+        name: &'static str,
+        arguments: u8,
+        callback: SyscallEntry,
+    ) {
+        self.calls.insert(
+            id,
+            RegisteredSyscall {
+                handler: callback,
+                name,
+                arguments,
+            },
+        );
     }
 }
 
@@ -94,6 +146,21 @@ impl SyscallTables {
         Some(result.clone())
     }
 
+    /// Every registered call, by personality and then number.
+    pub fn describe(&self) -> Vec<SyscallInfo> {
+        self.tables
+            .iter()
+            .flat_map(|(personality, table)| {
+                table.calls.iter().map(|(number, call)| SyscallInfo {
+                    personality: *personality,
+                    number: *number,
+                    name: call.name,
+                    arguments: call.arguments,
+                })
+            })
+            .collect()
+    }
+
     pub fn update_personality(&mut self, id: usize, callback: fn(&mut SyscallTable)) {
         if let Some(mut table) = self.get_personality(id) {
             callback(&mut table);
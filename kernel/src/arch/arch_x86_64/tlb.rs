@@ -0,0 +1,103 @@
+//! TLB shootdowns. Changing a mapping only flushes the TLB of the CPU that changed it, every other
+//! online CPU is sent an IPI to flush its own, and the change isn't complete until they all have.
+
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
+use spin::Mutex;
+use x86_64::{instructions::tlb, structures::idt::InterruptStackFrame};
+
+use crate::{time::Deadline, warn};
+
+use super::{
+    apic::{end_of_interrupt, LOCAL_APIC},
+    cpu::{self, registry::apic_id},
+    gdt::MAX_CPU_COUNT,
+    idt::{request_interrupt, vectors::VectorClass, InterruptContext},
+};
+
+const NO_VECTOR: u8 = 0;
+const SHOOTDOWN_TIMEOUT_MS: u64 = 10;
+
+static SHOOTDOWN_VECTOR: AtomicU8 = AtomicU8::new(NO_VECTOR);
+// Set for each CPU that still has to flush, cleared by that CPU once it has.
+static PENDING: [AtomicBool; MAX_CPU_COUNT] = [const { AtomicBool::new(false) }; MAX_CPU_COUNT];
+// One shootdown at a time, so PENDING only ever belongs to one.
+static SHOOTDOWN_LOCK: Mutex<()> = Mutex::new(());
+
+pub fn init() -> Result<(), &'static str> {
+    let vector = request_interrupt(
+        VectorClass::InterProcessor,
+        shootdown_interrupt_handler,
+        None,
+    )
+    .ok_or("No inter-processor vector free")?;
+    SHOOTDOWN_VECTOR.store(vector, Ordering::Release);
+    Ok(())
+}
+
+fn shootdown_interrupt_handler(
+    _frame: InterruptStackFrame,
+    vector: u8,
+    _error_code: Option<u64>,
+    _context: InterruptContext,
+) {
+    acknowledge();
+    end_of_interrupt(vector);
+}
+
+/// Flushes this CPU's TLB if a shootdown is waiting on it. Code that spins with interrupts
+/// disabled, while another CPU may be shooting down, calls this so it doesn't hold it up.
+pub fn acknowledge() {
+    let pending = &PENDING[cpu::current()];
+    if pending.load(Ordering::Acquire) {
+        tlb::flush_all();
+        pending.store(false, Ordering::Release);
+    }
+}
+
+/// Flushes the TLB of every online CPU, and waits for them all to have. Returns false if one
+/// didn't in time, it may still be using the old mappings.
+pub fn shootdown() -> bool {
+    tlb::flush_all();
+    let vector = SHOOTDOWN_VECTOR.load(Ordering::Acquire);
+    if vector == NO_VECTOR {
+        // Only the boot CPU runs without the vector.
+        return true;
+    }
+    // Another CPU may be shooting down already, and waiting on this one.
+    let _guard = loop {
+        match SHOOTDOWN_LOCK.try_lock() {
+            Some(guard) => break guard,
+            None => {
+                acknowledge();
+                core::hint::spin_loop();
+            }
+        }
+    };
+    let current = cpu::current();
+    let online = *cpu::get_online_cpu_status_bits().lock();
+    for target in online.iter_ones().filter(|target| *target != current) {
+        let apic_id = match apic_id(target) {
+            Some(apic_id) => apic_id,
+            None => continue,
+        };
+        PENDING[target].store(true, Ordering::Release);
+        if unsafe { !LOCAL_APIC.send_ipi_fixed(apic_id, vector) } {
+            warn!("TLB shootdown IPI to CPU {} was not delivered", target);
+        }
+    }
+    let deadline = Deadline::after_ms(SHOOTDOWN_TIMEOUT_MS);
+    let mut flushed = true;
+    for target in online.iter_ones().filter(|target| *target != current) {
+        while PENDING[target].load(Ordering::Acquire) {
+            if deadline.is_expired() {
+                warn!("CPU {} didn't flush its TLB in time", target);
+                PENDING[target].store(false, Ordering::Release);
+                flushed = false;
+                break;
+            }
+            core::hint::spin_loop();
+        }
+    }
+    flushed
+}
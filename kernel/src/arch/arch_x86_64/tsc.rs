@@ -0,0 +1,152 @@
+use kernel_shared::fixed::mul_div;
+use lazy_static::lazy_static;
+use x86_64::instructions::port::Port;
+
+use crate::{debug, time::ClockSource};
+
+use super::{cpuid::cpuid, pit::*};
+
+const CALIBRATION_MILLISECONDS: u64 = 10;
+
+pub fn read_tsc() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+/// Busy-waits using the time stamp counter.
+#[derive(Debug, Clone, Copy)]
+pub struct TscSpinTimer {
+    frequency: u64,
+}
+
+impl TscSpinTimer {
+    pub fn calibrate() -> Self {
+        let frequency = match Self::cpuid_frequency() {
+            Some(f) => {
+                debug!("TSC frequency from CPUID: {} Hz", f);
+                f
+            }
+            None => {
+                let f = Self::pit_frequency();
+                debug!("TSC frequency calibrated against the PIT: {} Hz", f);
+                f
+            }
+        };
+        Self { frequency }
+    }
+
+    fn cpuid_frequency() -> Option<u64> {
+        let cpu_id = cpuid()?;
+        if let Some(frequency) = cpu_id.get_tsc_info().and_then(|t| t.tsc_frequency()) {
+            return Some(frequency);
+        }
+        // Fall back to the processor base frequency, which matches the TSC on most invariant TSC parts.
+        match cpu_id
+            .get_processor_frequency_info()?
+            .processor_base_frequency()
+        {
+            0 => None,
+            mhz => Some(mhz as u64 * 1_000_000),
+        }
+    }
+
+    /// Measures the TSC against a one shot countdown on PIT channel 2.
+    fn pit_frequency() -> u64 {
+        let mut gate: Port<u8> = Port::new(PIT_CHANNEL_2_GATE_PORT);
+        let mut command: Port<u8> = Port::new(PIT_COMMAND_PORT);
+        let mut data: Port<u8> = Port::new(PIT_CHANNEL_2_DATA_PORT);
+        let count = (PIT_FREQUENCY * CALIBRATION_MILLISECONDS) / 1000;
+        unsafe {
+            let gate_value = gate.read();
+            gate.write((gate_value & !PIT_CHANNEL_2_SPEAKER) | PIT_CHANNEL_2_GATE);
+            command.write(PIT_CHANNEL_2_ONE_SHOT);
+            data.write(count as u8);
+            data.write((count >> 8) as u8);
+            let start = read_tsc();
+            while gate.read() & PIT_CHANNEL_2_OUTPUT == 0 {
+                core::hint::spin_loop();
+            }
+            let end = read_tsc();
+            gate.write(gate_value);
+            ((end - start) * 1000) / CALIBRATION_MILLISECONDS
+        }
+    }
+
+    pub fn frequency(&self) -> u64 {
+        self.frequency
+    }
+
+    pub fn deadline_after_us(&self, microseconds: u64) -> u64 {
+        let ticks = mul_div(self.frequency, microseconds, 1_000_000);
+        read_tsc().saturating_add(ticks)
+    }
+
+    pub fn deadline_after_ms(&self, milliseconds: u64) -> u64 {
+        self.deadline_after_us(milliseconds.saturating_mul(1000))
+    }
+
+    pub fn is_expired(&self, deadline: u64) -> bool {
+        read_tsc() >= deadline
+    }
+
+    pub fn spin_us(&self, microseconds: u64) {
+        let deadline = self.deadline_after_us(microseconds);
+        while !self.is_expired(deadline) {
+            core::hint::spin_loop();
+        }
+    }
+
+    pub fn spin_ms(&self, milliseconds: u64) {
+        self.spin_us(milliseconds.saturating_mul(1000));
+    }
+}
+
+lazy_static! {
+    static ref TSC_CALIBRATION: TscSpinTimer = TscSpinTimer::calibrate();
+    static ref INVARIANT_TSC: bool = cpuid()
+        .and_then(|c| c.get_advanced_power_mgmt_info())
+        .map(|apm| apm.has_invariant_tsc())
+        .unwrap_or(false);
+}
+
+pub fn tsc_frequency() -> u64 {
+    TSC_CALIBRATION.frequency()
+}
+
+pub fn tsc_ticks_to_nanoseconds(ticks: u64) -> u64 {
+    mul_div(ticks, 1_000_000_000, TSC_CALIBRATION.frequency().max(1))
+}
+
+pub fn nanoseconds_to_tsc_ticks(nanoseconds: u64) -> u64 {
+    mul_div(nanoseconds, TSC_CALIBRATION.frequency(), 1_000_000_000)
+}
+
+#[deprecated(note = "use the delay and deadline functions in crate::time")]
+pub fn spin_timer() -> TscSpinTimer {
+    *TSC_CALIBRATION
+}
+
+pub struct TscClockSource;
+
+pub static TSC_CLOCK_SOURCE: TscClockSource = TscClockSource;
+
+impl ClockSource for TscClockSource {
+    fn name(&self) -> &'static str {
+        "tsc"
+    }
+
+    fn frequency(&self) -> u64 {
+        TSC_CALIBRATION.frequency()
+    }
+
+    fn read(&self) -> u64 {
+        read_tsc()
+    }
+
+    fn rating(&self) -> u32 {
+        // Without an invariant TSC the rate can change with power states.
+        match *INVARIANT_TSC {
+            true => 300,
+            false => 100,
+        }
+    }
+}
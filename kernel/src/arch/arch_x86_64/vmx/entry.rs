@@ -0,0 +1,120 @@
+use core::arch::asm;
+
+/// The guest's general purpose registers, which VM entry and exit leave alone, so they're
+/// switched by hand around them.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct GuestRegisters {
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rbp: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+}
+
+/// Launches, or resumes, the current VMCS's guest with registers loaded, and returns when it
+/// exits, with its registers saved back. Returns 0 after an exit, or 1 if VM entry itself
+/// failed, with the flags saying why lost, so check the VM instruction error field.
+///
+/// The host state must already point RIP at vm_exit, the host RSP is written here.
+#[naked]
+pub(super) unsafe extern "C" fn vm_enter(registers: *mut GuestRegisters, launched: u64) -> u64 {
+    asm!(
+        "
+    push rbx
+    push rbp
+    push r12
+    push r13
+    push r14
+    push r15
+    push rdi
+
+    // The host RSP field, vm_exit finds the registers pointer at the top of this stack.
+    mov rax, 0x6C14
+    vmwrite rax, rsp
+
+    // Nothing below changes the flags, so they still say which to use at the end.
+    test rsi, rsi
+    mov rax, [rdi]
+    mov rbx, [rdi + 8]
+    mov rcx, [rdi + 16]
+    mov rdx, [rdi + 24]
+    mov rsi, [rdi + 32]
+    mov rbp, [rdi + 48]
+    mov r8, [rdi + 56]
+    mov r9, [rdi + 64]
+    mov r10, [rdi + 72]
+    mov r11, [rdi + 80]
+    mov r12, [rdi + 88]
+    mov r13, [rdi + 96]
+    mov r14, [rdi + 104]
+    mov r15, [rdi + 112]
+    mov rdi, [rdi + 40]
+    jz 2f
+    vmresume
+    jmp 3f
+2:
+    vmlaunch
+3:
+    // Only reached when entry fails.
+    pop rdi
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbp
+    pop rbx
+    mov rax, 1
+    ret
+    ",
+        options(noreturn)
+    );
+}
+
+/// Where the CPU lands on a VM exit, with the stack vm_enter left. Returns from vm_enter.
+#[naked]
+pub(super) unsafe extern "C" fn vm_exit() {
+    asm!(
+        "
+    push rdi
+    mov rdi, [rsp + 8]
+    mov [rdi], rax
+    mov [rdi + 8], rbx
+    mov [rdi + 16], rcx
+    mov [rdi + 24], rdx
+    mov [rdi + 32], rsi
+    mov [rdi + 48], rbp
+    mov [rdi + 56], r8
+    mov [rdi + 64], r9
+    mov [rdi + 72], r10
+    mov [rdi + 80], r11
+    mov [rdi + 88], r12
+    mov [rdi + 96], r13
+    mov [rdi + 104], r14
+    mov [rdi + 112], r15
+    pop rax
+    mov [rdi + 40], rax
+
+    pop rdi
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbp
+    pop rbx
+    xor eax, eax
+    ret
+    ",
+        options(noreturn)
+    );
+}
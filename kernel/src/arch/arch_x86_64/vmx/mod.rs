@@ -0,0 +1,549 @@
+//! Intel VMX, the start of running guests under the kernel. For now that's a test guest: a few
+//! instructions of real mode code, run with EPT and unrestricted guest support, that print
+//! through the debug console port and stop, with each of its exits handled here.
+
+use alloc::{string::String, vec::Vec};
+use core::fmt::Display;
+
+use x86::{
+    bits64::vmx::{vmclear, vmptrld, vmread, vmwrite, vmxoff, vmxon},
+    msr::{
+        rdmsr, wrmsr, IA32_FEATURE_CONTROL, IA32_FS_BASE, IA32_GS_BASE, IA32_SYSENTER_CS,
+        IA32_SYSENTER_EIP, IA32_SYSENTER_ESP, IA32_VMX_BASIC, IA32_VMX_CR0_FIXED0,
+        IA32_VMX_CR0_FIXED1, IA32_VMX_CR4_FIXED0, IA32_VMX_CR4_FIXED1, IA32_VMX_ENTRY_CTLS,
+        IA32_VMX_EPT_VPID_CAP, IA32_VMX_EXIT_CTLS, IA32_VMX_PINBASED_CTLS, IA32_VMX_PROCBASED_CTLS,
+        IA32_VMX_PROCBASED_CTLS2, IA32_VMX_TRUE_ENTRY_CTLS, IA32_VMX_TRUE_EXIT_CTLS,
+        IA32_VMX_TRUE_PINBASED_CTLS, IA32_VMX_TRUE_PROCBASED_CTLS,
+    },
+    vmx::vmcs::{control, guest, host, ro},
+};
+use x86_64::{
+    instructions::{
+        interrupts,
+        segmentation::{Segment, CS, DS, ES, FS, GS, SS},
+        tables::{sgdt, sidt},
+    },
+    registers::control::{Cr0, Cr3, Cr4, Cr4Flags},
+    structures::paging::{FrameAllocator, PhysFrame, Size4KiB},
+    VirtAddr,
+};
+
+use crate::memory::{allocator::KERNEL_FRAME_ALLOCATOR, KERNEL_MEMORY_MANAGER};
+
+use self::entry::{vm_enter, vm_exit, GuestRegisters};
+
+use super::{
+    cpu::registry::current_cpu_index,
+    cpuid::cpuid,
+    gdt::{get_gdt, TASK_STATE_SEGMENTS},
+};
+
+mod entry;
+
+const FEATURE_CONTROL_LOCKED: u64 = 1 << 0;
+const FEATURE_CONTROL_VMX_OUTSIDE_SMX: u64 = 1 << 2;
+const BASIC_TRUE_CONTROLS: u64 = 1 << 55;
+
+const PIN_NMI_EXITING: u32 = 1 << 3;
+const PRIMARY_HLT_EXITING: u32 = 1 << 7;
+const PRIMARY_UNCONDITIONAL_IO_EXITING: u32 = 1 << 24;
+const PRIMARY_SECONDARY_CONTROLS: u32 = 1 << 31;
+const SECONDARY_ENABLE_EPT: u32 = 1 << 1;
+const SECONDARY_UNRESTRICTED_GUEST: u32 = 1 << 7;
+const EXIT_HOST_ADDRESS_SPACE_SIZE: u32 = 1 << 9;
+
+const EPT_PAGE_WALK_4: u64 = 1 << 6;
+const EPT_WRITE_BACK: u64 = 1 << 14;
+const EPT_READ_WRITE_EXECUTE: u64 = 0b111;
+const MEMORY_TYPE_UNCACHEABLE: u64 = 0;
+const MEMORY_TYPE_WRITE_BACK: u64 = 6;
+
+const CR0_PROTECTION_ENABLE: u64 = 1 << 0;
+const CR0_PAGING: u64 = 1 << 31;
+
+const EXIT_REASON_CPUID: u32 = 10;
+const EXIT_REASON_HLT: u32 = 12;
+const EXIT_REASON_IO: u32 = 30;
+const EXIT_REASON_ENTRY_FAILED: u32 = 1 << 31;
+const IO_DIRECTION_IN: u64 = 1 << 3;
+const IO_STRING: u64 = 1 << 4;
+
+// Real mode code segment, and data segments, present and accessed.
+const REAL_MODE_CODE: u64 = 0x9B;
+const REAL_MODE_DATA: u64 = 0x93;
+const SEGMENT_UNUSABLE: u64 = 1 << 16;
+const BUSY_TSS: u64 = 0x8B;
+
+const DEBUG_CONSOLE_PORT: u16 = 0xE9;
+const GUEST_CODE_ADDRESS: u64 = 0x1000;
+const GUEST_MESSAGE: &[u8] = b"Hello from a VMX guest\n";
+// Real mode, with CS, DS, and IP all starting at zero:
+//     mov dx, 0xE9
+//     mov si, 0x1020
+// print:
+//     lodsb
+//     test al, al
+//     jz done
+//     out dx, al
+//     jmp print
+// done:
+//     cpuid
+//     hlt
+const GUEST_CODE: [u8; 17] = [
+    0xBA, 0xE9, 0x00, 0xBE, 0x20, 0x10, 0xAC, 0x84, 0xC0, 0x74, 0x03, 0xEE, 0xEB, 0xF8, 0x0F, 0xA2,
+    0xF4,
+];
+const GUEST_MESSAGE_OFFSET: usize = 0x20;
+// A guest that keeps exiting without halting is stuck.
+const MAX_EXITS: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmxError {
+    /// The processor doesn't have VMX.
+    Unsupported,
+    /// The firmware locked VMX off.
+    DisabledByFirmware,
+    /// A control the test guest needs, like EPT or unrestricted guest, isn't available.
+    MissingControl(&'static str),
+    OutOfMemory,
+    /// A VMX instruction failed, with the VM instruction error if there's a current VMCS.
+    InstructionFailed(&'static str, Option<u64>),
+    /// The guest couldn't be entered, with the exit reason.
+    EntryFailed(u32),
+    /// The guest exited in a way this doesn't handle, with the exit reason.
+    UnexpectedExit(u32),
+    /// The guest never halted.
+    Runaway,
+}
+
+impl Display for VmxError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            VmxError::Unsupported => write!(f, "VMX is not supported"),
+            VmxError::DisabledByFirmware => write!(f, "VMX is disabled by the firmware"),
+            VmxError::MissingControl(control) => write!(f, "{} is not supported", control),
+            VmxError::OutOfMemory => write!(f, "out of memory"),
+            VmxError::InstructionFailed(instruction, Some(error)) => {
+                write!(
+                    f,
+                    "{} failed with VM instruction error {}",
+                    instruction, error
+                )
+            }
+            VmxError::InstructionFailed(instruction, None) => write!(f, "{} failed", instruction),
+            VmxError::EntryFailed(reason) => write!(f, "VM entry failed, reason {}", reason),
+            VmxError::UnexpectedExit(reason) => write!(f, "unexpected VM exit, reason {}", reason),
+            VmxError::Runaway => write!(f, "the guest never halted"),
+        }
+    }
+}
+
+/// True if the processor has VMX, and the firmware left it usable.
+pub fn supported() -> bool {
+    has_vmx() && firmware_allows_vmx()
+}
+
+fn has_vmx() -> bool {
+    cpuid()
+        .and_then(|c| c.get_feature_info())
+        .map(|f| f.has_vmx())
+        .unwrap_or(false)
+}
+
+fn firmware_allows_vmx() -> bool {
+    let control = unsafe { rdmsr(IA32_FEATURE_CONTROL) };
+    control & FEATURE_CONTROL_LOCKED == 0 || control & FEATURE_CONTROL_VMX_OUTSIDE_SMX != 0
+}
+
+// A zeroed frame, returned to the allocator when dropped.
+struct Frame(PhysFrame<Size4KiB>);
+
+impl Frame {
+    fn allocate() -> Result<Self, VmxError> {
+        let frame =
+            unsafe { KERNEL_FRAME_ALLOCATOR.allocate_frame() }.ok_or(VmxError::OutOfMemory)?;
+        let frame = Frame(frame);
+        unsafe { frame.pointer::<u8>().write_bytes(0, 4096) };
+        Ok(frame)
+    }
+
+    fn address(&self) -> u64 {
+        self.0.start_address().as_u64()
+    }
+
+    fn pointer<T>(&self) -> *mut T {
+        KERNEL_MEMORY_MANAGER
+            .lock()
+            .translate(self.0.start_address())
+            .as_mut_ptr()
+    }
+}
+
+impl Drop for Frame {
+    fn drop(&mut self) {
+        unsafe { KERNEL_FRAME_ALLOCATOR.free(self.0.start_address()) };
+    }
+}
+
+fn instruction_error() -> Option<u64> {
+    unsafe { vmread(ro::VM_INSTRUCTION_ERROR) }.ok()
+}
+
+fn write(field: u32, value: u64) -> Result<(), VmxError> {
+    unsafe { vmwrite(field, value) }
+        .map_err(|_| VmxError::InstructionFailed("VMWRITE", instruction_error()))
+}
+
+fn read(field: u32) -> Result<u64, VmxError> {
+    unsafe { vmread(field) }.map_err(|_| VmxError::InstructionFailed("VMREAD", instruction_error()))
+}
+
+// Sets the wanted bits in a control, along with any the processor requires, failing if it
+// doesn't allow one of them.
+fn adjust_control(capability: u32, wanted: u32, name: &'static str) -> Result<u64, VmxError> {
+    let capability = unsafe { rdmsr(capability) };
+    let required = capability as u32;
+    let allowed = (capability >> 32) as u32;
+    if wanted & !allowed != 0 {
+        return Err(VmxError::MissingControl(name));
+    }
+    Ok(((wanted | required) & allowed) as u64)
+}
+
+/// The guest's memory, and the extended page tables mapping it, one page at GUEST_CODE_ADDRESS.
+struct GuestMemory {
+    tables: [Frame; 4],
+    page: Frame,
+}
+
+impl GuestMemory {
+    fn new() -> Result<Self, VmxError> {
+        let memory = GuestMemory {
+            tables: [
+                Frame::allocate()?,
+                Frame::allocate()?,
+                Frame::allocate()?,
+                Frame::allocate()?,
+            ],
+            page: Frame::allocate()?,
+        };
+        // Every table's first entry points at the next, the address is in the first 2MiB.
+        for level in 0..3 {
+            unsafe {
+                memory.tables[level]
+                    .pointer::<u64>()
+                    .write(memory.tables[level + 1].address() | EPT_READ_WRITE_EXECUTE)
+            };
+        }
+        let capabilities = unsafe { rdmsr(IA32_VMX_EPT_VPID_CAP) };
+        let memory_type = match capabilities & EPT_WRITE_BACK {
+            0 => MEMORY_TYPE_UNCACHEABLE,
+            _ => MEMORY_TYPE_WRITE_BACK,
+        };
+        unsafe {
+            memory.tables[3]
+                .pointer::<u64>()
+                .add((GUEST_CODE_ADDRESS >> 12) as usize)
+                .write(memory.page.address() | EPT_READ_WRITE_EXECUTE | memory_type << 3);
+            let page = memory.page.pointer::<u8>();
+            page.copy_from_nonoverlapping(GUEST_CODE.as_ptr(), GUEST_CODE.len());
+            page.add(GUEST_MESSAGE_OFFSET)
+                .copy_from_nonoverlapping(GUEST_MESSAGE.as_ptr(), GUEST_MESSAGE.len());
+        }
+        Ok(memory)
+    }
+
+    fn ept_pointer(&self) -> Result<u64, VmxError> {
+        let capabilities = unsafe { rdmsr(IA32_VMX_EPT_VPID_CAP) };
+        if capabilities & EPT_PAGE_WALK_4 == 0 {
+            return Err(VmxError::MissingControl("4 level EPT"));
+        }
+        let memory_type = match capabilities & EPT_WRITE_BACK {
+            0 => MEMORY_TYPE_UNCACHEABLE,
+            _ => MEMORY_TYPE_WRITE_BACK,
+        };
+        // The walk length, less one, above the memory type.
+        Ok(self.tables[0].address() | 3 << 3 | memory_type)
+    }
+}
+
+fn write_controls(memory: &GuestMemory) -> Result<(), VmxError> {
+    let true_controls = unsafe { rdmsr(IA32_VMX_BASIC) } & BASIC_TRUE_CONTROLS != 0;
+    let (pin, primary, exit, entry) = match true_controls {
+        true => (
+            IA32_VMX_TRUE_PINBASED_CTLS,
+            IA32_VMX_TRUE_PROCBASED_CTLS,
+            IA32_VMX_TRUE_EXIT_CTLS,
+            IA32_VMX_TRUE_ENTRY_CTLS,
+        ),
+        false => (
+            IA32_VMX_PINBASED_CTLS,
+            IA32_VMX_PROCBASED_CTLS,
+            IA32_VMX_EXIT_CTLS,
+            IA32_VMX_ENTRY_CTLS,
+        ),
+    };
+    write(
+        control::PINBASED_EXEC_CONTROLS,
+        adjust_control(pin, PIN_NMI_EXITING, "NMI exiting")?,
+    )?;
+    write(
+        control::PRIMARY_PROCBASED_EXEC_CONTROLS,
+        adjust_control(
+            primary,
+            PRIMARY_HLT_EXITING | PRIMARY_UNCONDITIONAL_IO_EXITING | PRIMARY_SECONDARY_CONTROLS,
+            "secondary controls",
+        )?,
+    )?;
+    write(
+        control::SECONDARY_PROCBASED_EXEC_CONTROLS,
+        adjust_control(
+            IA32_VMX_PROCBASED_CTLS2,
+            SECONDARY_ENABLE_EPT | SECONDARY_UNRESTRICTED_GUEST,
+            "unrestricted guest",
+        )?,
+    )?;
+    write(
+        control::VMEXIT_CONTROLS,
+        adjust_control(exit, EXIT_HOST_ADDRESS_SPACE_SIZE, "64 bit hosts")?,
+    )?;
+    write(control::VMENTRY_CONTROLS, adjust_control(entry, 0, "")?)?;
+    write(control::EXCEPTION_BITMAP, 0)?;
+    write(control::CR3_TARGET_COUNT, 0)?;
+    write(control::EPTP_FULL, memory.ept_pointer()?)
+}
+
+// The host state VM exits return to: this CPU, as it is now, landing in vm_exit.
+fn write_host_state() -> Result<(), VmxError> {
+    let selector = |value: u16| (value & !0x7) as u64;
+    let gdt = get_gdt(current_cpu_index());
+    write(host::CR0, Cr0::read_raw())?;
+    write(host::CR3, Cr3::read().0.start_address().as_u64())?;
+    write(host::CR4, Cr4::read_raw())?;
+    write(host::CS_SELECTOR, selector(CS::get_reg().0))?;
+    write(host::SS_SELECTOR, selector(SS::get_reg().0))?;
+    write(host::DS_SELECTOR, selector(DS::get_reg().0))?;
+    write(host::ES_SELECTOR, selector(ES::get_reg().0))?;
+    write(host::FS_SELECTOR, selector(FS::get_reg().0))?;
+    write(host::GS_SELECTOR, selector(GS::get_reg().0))?;
+    write(host::TR_SELECTOR, selector(gdt.get_task_state_segment().0))?;
+    unsafe {
+        write(host::FS_BASE, rdmsr(IA32_FS_BASE))?;
+        write(host::GS_BASE, rdmsr(IA32_GS_BASE))?;
+        write(host::IA32_SYSENTER_CS, rdmsr(IA32_SYSENTER_CS))?;
+        write(host::IA32_SYSENTER_ESP, rdmsr(IA32_SYSENTER_ESP))?;
+        write(host::IA32_SYSENTER_EIP, rdmsr(IA32_SYSENTER_EIP))?;
+    }
+    let tss = &TASK_STATE_SEGMENTS[current_cpu_index()];
+    write(host::TR_BASE, VirtAddr::from_ptr(tss).as_u64())?;
+    write(host::GDTR_BASE, sgdt().base.as_u64())?;
+    write(host::IDTR_BASE, sidt().base.as_u64())?;
+    write(host::RIP, vm_exit as *const () as u64)
+}
+
+// Real mode, at the start of the guest's code, with interrupts off.
+fn write_guest_state() -> Result<(), VmxError> {
+    let cr0 = unsafe { rdmsr(IA32_VMX_CR0_FIXED0) & rdmsr(IA32_VMX_CR0_FIXED1) };
+    let cr4 = unsafe { rdmsr(IA32_VMX_CR4_FIXED0) & rdmsr(IA32_VMX_CR4_FIXED1) };
+    // Unrestricted guests may leave protection and paging off, whatever the fixed bits say.
+    write(guest::CR0, cr0 & !(CR0_PROTECTION_ENABLE | CR0_PAGING))?;
+    write(guest::CR3, 0)?;
+    write(guest::CR4, cr4)?;
+    write(guest::DR7, 0x400)?;
+    write(guest::RFLAGS, 0x2)?;
+    write(guest::RIP, GUEST_CODE_ADDRESS)?;
+    write(guest::RSP, 0)?;
+    let segments = [
+        (
+            guest::CS_SELECTOR,
+            guest::CS_BASE,
+            guest::CS_LIMIT,
+            guest::CS_ACCESS_RIGHTS,
+            REAL_MODE_CODE,
+        ),
+        (
+            guest::SS_SELECTOR,
+            guest::SS_BASE,
+            guest::SS_LIMIT,
+            guest::SS_ACCESS_RIGHTS,
+            REAL_MODE_DATA,
+        ),
+        (
+            guest::DS_SELECTOR,
+            guest::DS_BASE,
+            guest::DS_LIMIT,
+            guest::DS_ACCESS_RIGHTS,
+            REAL_MODE_DATA,
+        ),
+        (
+            guest::ES_SELECTOR,
+            guest::ES_BASE,
+            guest::ES_LIMIT,
+            guest::ES_ACCESS_RIGHTS,
+            REAL_MODE_DATA,
+        ),
+        (
+            guest::FS_SELECTOR,
+            guest::FS_BASE,
+            guest::FS_LIMIT,
+            guest::FS_ACCESS_RIGHTS,
+            REAL_MODE_DATA,
+        ),
+        (
+            guest::GS_SELECTOR,
+            guest::GS_BASE,
+            guest::GS_LIMIT,
+            guest::GS_ACCESS_RIGHTS,
+            REAL_MODE_DATA,
+        ),
+        (
+            guest::LDTR_SELECTOR,
+            guest::LDTR_BASE,
+            guest::LDTR_LIMIT,
+            guest::LDTR_ACCESS_RIGHTS,
+            SEGMENT_UNUSABLE,
+        ),
+        (
+            guest::TR_SELECTOR,
+            guest::TR_BASE,
+            guest::TR_LIMIT,
+            guest::TR_ACCESS_RIGHTS,
+            BUSY_TSS,
+        ),
+    ];
+    for (selector, base, limit, access_rights, rights) in segments {
+        write(selector, 0)?;
+        write(base, 0)?;
+        write(limit, 0xFFFF)?;
+        write(access_rights, rights)?;
+    }
+    write(guest::GDTR_BASE, 0)?;
+    write(guest::GDTR_LIMIT, 0xFFFF)?;
+    write(guest::IDTR_BASE, 0)?;
+    write(guest::IDTR_LIMIT, 0xFFFF)?;
+    write(guest::IA32_DEBUGCTL_FULL, 0)?;
+    write(guest::IA32_SYSENTER_CS, 0)?;
+    write(guest::IA32_SYSENTER_ESP, 0)?;
+    write(guest::IA32_SYSENTER_EIP, 0)?;
+    write(guest::INTERRUPTIBILITY_STATE, 0)?;
+    write(guest::ACTIVITY_STATE, 0)?;
+    write(guest::PENDING_DBG_EXCEPTIONS, 0)?;
+    // No shadow VMCS.
+    write(guest::LINK_PTR_FULL, u64::MAX)
+}
+
+fn skip_instruction() -> Result<(), VmxError> {
+    let rip = read(guest::RIP)?;
+    write(guest::RIP, rip + read(ro::VMEXIT_INSTRUCTION_LEN)?)
+}
+
+// Runs the guest until it halts, collecting what it writes to the debug console.
+fn run_guest() -> Result<String, VmxError> {
+    let mut registers = GuestRegisters::default();
+    let mut output = Vec::new();
+    let mut launched = false;
+    for _ in 0..MAX_EXITS {
+        if unsafe { vm_enter(&mut registers, launched as u64) } != 0 {
+            let instruction = match launched {
+                true => "VMRESUME",
+                false => "VMLAUNCH",
+            };
+            return Err(VmxError::InstructionFailed(
+                instruction,
+                instruction_error(),
+            ));
+        }
+        launched = true;
+        let reason = read(ro::EXIT_REASON)? as u32;
+        if reason & EXIT_REASON_ENTRY_FAILED != 0 {
+            return Err(VmxError::EntryFailed(reason & 0xFFFF));
+        }
+        match reason & 0xFFFF {
+            EXIT_REASON_HLT => return Ok(String::from_utf8_lossy(&output).into_owned()),
+            EXIT_REASON_CPUID => {
+                // Tell the guest who it's running under.
+                registers.rax = 0;
+                registers.rbx = u32::from_le_bytes(*b"Oxid") as u64;
+                registers.rcx = u32::from_le_bytes(*b"ized") as u64;
+                registers.rdx = u32::from_le_bytes(*b" VMX") as u64;
+            }
+            EXIT_REASON_IO => {
+                let qualification = read(ro::EXIT_QUALIFICATION)?;
+                let port = (qualification >> 16) as u16;
+                if qualification & IO_STRING != 0 {
+                    return Err(VmxError::UnexpectedExit(reason));
+                }
+                match qualification & IO_DIRECTION_IN {
+                    0 if port == DEBUG_CONSOLE_PORT => output.push(registers.rax as u8),
+                    0 => {}
+                    // Nothing is there, so reads float high.
+                    _ => registers.rax |= 0xFF,
+                }
+            }
+            _ => return Err(VmxError::UnexpectedExit(reason)),
+        }
+        skip_instruction()?;
+    }
+    Err(VmxError::Runaway)
+}
+
+// Enters VMX operation, runs the test guest, and leaves again.
+fn run_in_vmx_operation() -> Result<String, VmxError> {
+    let revision = unsafe { rdmsr(IA32_VMX_BASIC) } as u32 & 0x7FFF_FFFF;
+    let vmxon_region = Frame::allocate()?;
+    let vmcs = Frame::allocate()?;
+    let memory = GuestMemory::new()?;
+    unsafe {
+        vmxon_region.pointer::<u32>().write(revision);
+        vmcs.pointer::<u32>().write(revision);
+    }
+
+    unsafe {
+        let control = rdmsr(IA32_FEATURE_CONTROL);
+        if control & FEATURE_CONTROL_LOCKED == 0 {
+            wrmsr(
+                IA32_FEATURE_CONTROL,
+                control | FEATURE_CONTROL_LOCKED | FEATURE_CONTROL_VMX_OUTSIDE_SMX,
+            );
+        }
+        let cr0 = (Cr0::read_raw() | rdmsr(IA32_VMX_CR0_FIXED0)) & rdmsr(IA32_VMX_CR0_FIXED1);
+        Cr0::write_raw(cr0);
+    }
+    let cr4 = Cr4::read();
+    unsafe { Cr4::write(cr4 | Cr4Flags::VIRTUAL_MACHINE_EXTENSIONS) };
+    if unsafe { vmxon(vmxon_region.address()) }.is_err() {
+        unsafe { Cr4::write(cr4) };
+        return Err(VmxError::InstructionFailed("VMXON", None));
+    }
+
+    let result = (|| {
+        unsafe {
+            vmclear(vmcs.address()).map_err(|_| VmxError::InstructionFailed("VMCLEAR", None))?;
+            vmptrld(vmcs.address()).map_err(|_| VmxError::InstructionFailed("VMPTRLD", None))?;
+        }
+        write_controls(&memory)?;
+        write_host_state()?;
+        write_guest_state()?;
+        let output = run_guest();
+        unsafe { vmclear(vmcs.address()) }.ok();
+        output
+    })();
+
+    unsafe {
+        vmxoff().ok();
+        Cr4::write(cr4);
+    }
+    result
+}
+
+/// Runs the test guest on this CPU, returning what it printed. Interrupts stay off
+/// throughout, so the guest is never interrupted, and the CPU can't be switched away from.
+pub fn run_test_guest() -> Result<String, VmxError> {
+    if !has_vmx() {
+        return Err(VmxError::Unsupported);
+    }
+    if !firmware_allows_vmx() {
+        return Err(VmxError::DisabledByFirmware);
+    }
+    interrupts::without_interrupts(run_in_vmx_operation)
+}
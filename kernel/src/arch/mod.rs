@@ -1,18 +1,30 @@
-use alloc::string::String;
-
-use bootloader_api::BootInfo;
+use alloc::{string::String, vec::Vec};
 
 #[cfg(target_arch = "x86_64")]
 use arch_x86_64::*;
 
-use self::arch_x86_64::idt::get_timer_ticks_hardware;
+use crate::initcall::InitCall;
+
+use self::arch_x86_64::idt::{get_timer_ticks_hardware, interrupt_depth};
 
 #[cfg(target_arch = "x86_64")]
 pub(crate) mod arch_x86_64;
 
+/// Installs exception handlers that work without the heap, call this first.
+#[inline]
+pub fn early_init() {
+    early_init_hardware();
+}
+
+/// The architecture's init calls, registered before init runs them.
 #[inline]
-pub fn init(boot_info: &BootInfo) {
-    init_hardware(boot_info);
+pub fn initcalls() -> &'static [InitCall] {
+    &INITCALLS
+}
+
+#[inline]
+pub fn init() {
+    init_hardware();
 }
 
 #[inline]
@@ -35,17 +47,170 @@ pub fn enable_interrupts() {
     enable_interrupts_hardware();
 }
 
+#[inline]
+pub fn interrupts_enabled() -> bool {
+    interrupts_enabled_hardware()
+}
+
 #[inline]
 pub fn wait_for_interrupt() {
     wait_for_interrupt_hardware();
 }
 
+/// Halts until an interrupt, or until the monotonic clock reaches the deadline. Idle CPUs stop
+/// their periodic tick while halted, when the hardware allows it.
+#[inline]
+pub fn idle_until(deadline_nanoseconds: Option<u64>) {
+    idle_until_hardware(deadline_nanoseconds);
+}
+
 #[inline]
 pub fn get_timer_ticks() -> usize {
     get_timer_ticks_hardware()
 }
 
+pub use self::arch_x86_64::apic::TIMER_TICK_NANOSECONDS;
+pub use self::arch_x86_64::gdt::MAX_CPU_COUNT;
+pub use self::arch_x86_64::idt::latency::LatencySummary;
+pub use self::arch_x86_64::msi::MsiMessage;
+pub use self::arch_x86_64::ps2::Ps2Byte as InputByte;
+pub use self::arch_x86_64::syscall::{SyscallInfo, SyscallParameters};
+pub use self::arch_x86_64::vmx::VmxError as VirtualizationError;
+
+/// Interrupt latency for a vector. None unless measurement was turned on with the `irqlatency`
+/// command line flag, and the vector has had a measured interrupt.
+#[inline]
+pub fn interrupt_latency(vector: u8) -> Option<LatencySummary> {
+    arch_x86_64::idt::latency::summary(vector)
+}
+
+/// Handles a native system call with the given number, replacing any existing handler. The
+/// name, and number of parameters it uses, are only for introspection.
+#[inline]
+pub fn register_syscall(
+    id: usize,
+    name: &'static str,
+    arguments: u8,
+    handler: fn(&SyscallParameters),
+) {
+    arch_x86_64::syscall::register_native_syscall(id, name, arguments, handler);
+}
+
+/// Every registered system call, in every personality.
+#[inline]
+pub fn syscalls() -> Vec<SyscallInfo> {
+    arch_x86_64::syscall::SYSCALL_TABLES.read().describe()
+}
+
+/// Looks for the keyboard and mouse controller, returns false if there isn't one.
+#[inline]
+pub fn probe_input() -> bool {
+    probe_input_hardware()
+}
+
+/// Reads the next byte from the keyboard (a set 1 scancode) or the mouse, without waiting.
+#[inline]
+pub fn read_input_byte() -> Option<InputByte> {
+    read_input_byte_hardware()
+}
+
+#[inline]
+pub fn enable_mouse() -> bool {
+    enable_mouse_hardware()
+}
+
+/// Reads the hardware clock, in seconds since the Unix epoch.
+#[inline]
+pub fn read_wall_clock() -> u64 {
+    read_wall_clock_hardware()
+}
+
+/// Starts a tone on the PC speaker, it plays until stopped.
+#[inline]
+pub fn speaker_start(frequency: u32) {
+    speaker_start_hardware(frequency);
+}
+
+#[inline]
+pub fn speaker_stop() {
+    speaker_stop_hardware();
+}
+
+/// Reads the aligned dword of a PCI function's configuration space containing offset.
+#[inline]
+pub fn pci_read_config(bus: u8, device: u8, function: u8, offset: u16) -> u32 {
+    pci_read_config_hardware(bus, device, function, offset)
+}
+
+#[inline]
+pub fn pci_write_config(bus: u8, device: u8, function: u8, offset: u16, value: u32) {
+    pci_write_config_hardware(bus, device, function, offset, value);
+}
+
+/// Reads a byte from an I/O port, for drivers of devices decoded in I/O space.
+#[inline]
+pub fn io_read_u8(port: u16) -> u8 {
+    io_read_u8_hardware(port)
+}
+
+#[inline]
+pub fn io_write_u8(port: u16, value: u8) {
+    io_write_u8_hardware(port, value);
+}
+
+/// Allocates an interrupt for a device to raise with a message signaled interrupt. The handler
+/// runs in interrupt context, and is given the vector.
+#[inline]
+pub fn request_msi(handler: fn(u8)) -> Option<MsiMessage> {
+    arch_x86_64::msi::request_msi(handler)
+}
+
+#[inline]
+pub fn release_msi(message: MsiMessage) {
+    arch_x86_64::msi::release_msi(message);
+}
+
+/// True if the processor can run guests.
+#[inline]
+pub fn virtualization_supported() -> bool {
+    arch_x86_64::vmx::supported()
+}
+
+/// Runs a tiny built in guest on this CPU, and returns what it printed.
+#[inline]
+pub fn run_test_guest() -> Result<String, VirtualizationError> {
+    arch_x86_64::vmx::run_test_guest()
+}
+
+/// The ACPI DMAR table, which describes the IOMMUs, if the firmware has one.
+#[inline]
+pub fn dma_remapping_table() -> Option<&'static [u8]> {
+    dma_remapping_table_hardware()
+}
+
 #[inline]
 pub fn get_current_cpu() -> usize {
     current_cpu()
 }
+
+/// Returns true if the current CPU is executing an interrupt handler.
+#[inline]
+pub fn in_interrupt_context() -> bool {
+    interrupt_depth() > 0
+}
+
+#[inline]
+pub fn get_timestamp() -> u64 {
+    read_timestamp_counter()
+}
+
+#[inline]
+pub fn get_timestamp_frequency() -> Option<u64> {
+    timestamp_counter_frequency()
+}
+
+/// A random number from the processor's generator, None if it has none, or it failed.
+#[inline]
+pub fn hardware_random() -> Option<u64> {
+    read_hardware_random()
+}
@@ -0,0 +1,102 @@
+//! The audit log, a record of security relevant operations: who tried what, when, and whether
+//! it worked. It's kept in a ring in kernel memory, so the oldest records are overwritten, and
+//! only root may read it.
+
+use alloc::{collections::VecDeque, vec::Vec};
+use kernel_shared::{
+    audit::{AuditReadRequest, AuditRecord, AUDIT_READ_NOT_PERMITTED, AUDIT_READ_OK},
+    constants::SyscallNumber,
+};
+use spin::Mutex;
+
+use crate::{
+    arch::{register_syscall, SyscallParameters},
+    memory::user::{copy_from_user, copy_slice_to_user, copy_to_user},
+    thread::{credentials::current_credentials, process::current_process_id},
+    time::monotonic_nanoseconds,
+};
+
+const AUDIT_LOG_CAPACITY: usize = 256;
+
+struct AuditLog {
+    records: VecDeque<AuditRecord>,
+    next_sequence: u64,
+}
+
+static AUDIT_LOG: Mutex<AuditLog> = Mutex::new(AuditLog {
+    records: VecDeque::new(),
+    next_sequence: 1,
+});
+
+/// Records an operation by the current process. subject is what it was on, like a device id.
+pub fn record(event: u32, outcome: u32, subject: u128) {
+    record_as(
+        current_credentials().effective_user_id,
+        event,
+        outcome,
+        subject,
+    );
+}
+
+/// Like record, for operations that change who the process is, which are recorded against the
+/// user it was.
+pub fn record_as(user_id: u32, event: u32, outcome: u32, subject: u128) {
+    let mut record = AuditRecord {
+        timestamp: monotonic_nanoseconds(),
+        process_id: current_process_id(),
+        user_id,
+        event,
+        outcome,
+        subject: subject.to_le_bytes(),
+        ..Default::default()
+    };
+    let mut log = AUDIT_LOG.lock();
+    record.sequence = log.next_sequence;
+    log.next_sequence += 1;
+    if log.records.len() == AUDIT_LOG_CAPACITY {
+        log.records.pop_front();
+    }
+    log.records.push_back(record);
+}
+
+pub(crate) fn init() {
+    register_syscall(
+        SyscallNumber::ReadAuditLog as usize,
+        "read_audit_log",
+        1,
+        read_audit_log_syscall,
+    );
+}
+
+fn read_audit_log_syscall(parameters: &SyscallParameters) {
+    let pointer = parameters.argument() as *mut AuditReadRequest;
+    let mut request = match copy_from_user(pointer) {
+        Ok(request) => request,
+        Err(_) => return,
+    };
+    read_audit_log(&mut request);
+    let _ = copy_to_user(pointer, &request);
+}
+
+fn read_audit_log(request: &mut AuditReadRequest) {
+    request.count = 0;
+    if !current_credentials().is_root() {
+        request.status = AUDIT_READ_NOT_PERMITTED;
+        return;
+    }
+    request.status = AUDIT_READ_OK;
+    if request.entries.is_null() {
+        return;
+    }
+    let unread: Vec<AuditRecord> = AUDIT_LOG
+        .lock()
+        .records
+        .iter()
+        .filter(|r| r.sequence > request.after)
+        .take(request.capacity as usize)
+        .copied()
+        .collect();
+    if copy_slice_to_user(request.entries, &unread).is_ok() {
+        request.count = unread.len() as u64;
+    }
+}
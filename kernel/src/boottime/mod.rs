@@ -0,0 +1,135 @@
+use kernel_shared::fixed::mul_div;
+use spin::Mutex;
+
+use crate::{
+    arch::{get_timestamp, get_timestamp_frequency},
+    debug,
+    framebuffer::splash,
+    verbose,
+};
+
+const MAX_BOOT_STAGES: usize = 64;
+
+#[derive(Debug, Clone, Copy)]
+pub struct BootStage {
+    name: &'static str,
+    start: u64,
+    end: u64,
+}
+
+impl BootStage {
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn start(&self) -> u64 {
+        self.start
+    }
+
+    pub fn end(&self) -> u64 {
+        self.end
+    }
+
+    pub fn elapsed(&self) -> u64 {
+        self.end.saturating_sub(self.start)
+    }
+}
+
+struct BootTimeTable {
+    boot_start: u64,
+    stages: [Option<BootStage>; MAX_BOOT_STAGES],
+    count: usize,
+}
+
+impl BootTimeTable {
+    const fn new() -> Self {
+        Self {
+            boot_start: 0,
+            stages: [None; MAX_BOOT_STAGES],
+            count: 0,
+        }
+    }
+
+    fn push(&mut self, stage: BootStage) {
+        if self.count >= MAX_BOOT_STAGES {
+            return;
+        }
+        self.stages[self.count] = Some(stage);
+        self.count += 1;
+    }
+}
+
+// This is filled in before the heap exists, so it must not allocate.
+static BOOT_TIME_TABLE: Mutex<BootTimeTable> = Mutex::new(BootTimeTable::new());
+
+pub fn mark_boot_start() {
+    BOOT_TIME_TABLE.lock().boot_start = get_timestamp();
+}
+
+/// Runs an init stage, recording its start and end timestamps in the boot time table.
+pub fn stage<T>(name: &'static str, init: impl FnOnce() -> T) -> T {
+    let start = get_timestamp();
+    let result = init();
+    let end = get_timestamp();
+    let completed = {
+        let mut table = BOOT_TIME_TABLE.lock();
+        table.push(BootStage { name, start, end });
+        table.count
+    };
+    splash::boot_stage_completed(completed);
+    result
+}
+
+/// How many boot stages have completed.
+pub fn completed() -> usize {
+    BOOT_TIME_TABLE.lock().count
+}
+
+/// Returns the recorded boot stages, in the order they completed.
+pub fn stages() -> ([Option<BootStage>; MAX_BOOT_STAGES], usize) {
+    let table = BOOT_TIME_TABLE.lock();
+    (table.stages, table.count)
+}
+
+pub fn cycles_to_microseconds(cycles: u64) -> Option<u64> {
+    let frequency = get_timestamp_frequency()?;
+    if frequency == 0 {
+        return None;
+    }
+    Some(mul_div(cycles, 1_000_000, frequency))
+}
+
+struct Duration(u64);
+
+impl core::fmt::Display for Duration {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match cycles_to_microseconds(self.0) {
+            Some(us) => write!(
+                f,
+                "{:>12} cycles ({}.{:03} ms)",
+                self.0,
+                us / 1000,
+                us % 1000
+            ),
+            None => write!(f, "{:>12} cycles", self.0),
+        }
+    }
+}
+
+pub fn print_summary() {
+    let (stages, count) = stages();
+    let boot_start = BOOT_TIME_TABLE.lock().boot_start;
+    verbose!("Boot timing ({} stages):", count);
+    for stage in stages[0..count].iter().flatten() {
+        verbose!("  {:<24} {}", stage.name(), Duration(stage.elapsed()));
+    }
+    if boot_start != 0 {
+        verbose!(
+            "  {:<24} {}",
+            "Total",
+            Duration(get_timestamp().saturating_sub(boot_start))
+        );
+    } else {
+        debug!("Boot start was never marked, unable to report total boot time.");
+    }
+}
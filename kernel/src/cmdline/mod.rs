@@ -0,0 +1,29 @@
+use crate::METADATA_CMDLINE;
+
+/// The kernel command line.
+///
+/// The bootloader does not hand us a command line, so it is baked into the image at build time
+/// from the `OXIDIZED_CMDLINE` environment variable. Arguments are whitespace separated, and
+/// are either bare flags (`log.cpu_color`) or key/value pairs (`log.cpu_color=on`).
+pub fn command_line() -> &'static str {
+    METADATA_CMDLINE
+}
+
+pub fn get(key: &str) -> Option<&'static str> {
+    for argument in command_line().split_whitespace() {
+        match argument.split_once('=') {
+            Some((name, value)) if name == key => return Some(value),
+            None if argument == key => return Some(""),
+            _ => continue,
+        }
+    }
+
+    None
+}
+
+pub fn flag(key: &str) -> bool {
+    match get(key) {
+        Some("") | Some("1") | Some("on") | Some("yes") | Some("true") => true,
+        _ => false,
+    }
+}
@@ -0,0 +1,212 @@
+use crate::framebuffer::Color;
+
+const ESCAPE: char = '\x1b';
+const MAX_PARAMETERS: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ParserState {
+    Ground,
+    Escape,
+    ControlSequence,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ControlSequence {
+    parameters: [u16; MAX_PARAMETERS],
+    count: usize,
+    command: char,
+}
+
+impl ControlSequence {
+    pub(crate) fn command(&self) -> char {
+        self.command
+    }
+
+    pub(crate) fn parameters(&self) -> &[u16] {
+        &self.parameters[0..self.count]
+    }
+
+    /// Returns the parameter at index, or default if it was omitted (or zero, per ECMA-48).
+    pub(crate) fn parameter_or(&self, index: usize, default: u16) -> u16 {
+        match self.parameters().get(index) {
+            Some(0) | None => default,
+            Some(v) => *v,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum AnsiCommand {
+    Print(char),
+    ControlSequence(ControlSequence),
+}
+
+/// Incremental parser for the subset of ANSI escape sequences the console understands.
+///
+/// Characters are fed one at a time, and complete commands are returned as they are recognized.
+/// Unsupported escape sequences are consumed and silently discarded.
+pub(crate) struct AnsiParser {
+    state: ParserState,
+    sequence: ControlSequence,
+}
+
+impl AnsiParser {
+    pub(crate) const fn new() -> Self {
+        Self {
+            state: ParserState::Ground,
+            sequence: ControlSequence {
+                parameters: [0; MAX_PARAMETERS],
+                count: 0,
+                command: '\0',
+            },
+        }
+    }
+
+    pub(crate) fn feed(&mut self, c: char) -> Option<AnsiCommand> {
+        match self.state {
+            ParserState::Ground => {
+                if c == ESCAPE {
+                    self.state = ParserState::Escape;
+                    None
+                } else {
+                    Some(AnsiCommand::Print(c))
+                }
+            }
+            ParserState::Escape => {
+                if c == '[' {
+                    self.sequence.parameters = [0; MAX_PARAMETERS];
+                    self.sequence.count = 0;
+                    self.state = ParserState::ControlSequence;
+                } else {
+                    self.state = ParserState::Ground;
+                }
+                None
+            }
+            ParserState::ControlSequence => match c {
+                '0'..='9' => {
+                    if self.sequence.count == 0 {
+                        self.sequence.count = 1;
+                    }
+                    let index = self.sequence.count - 1;
+                    let digit = c as u16 - '0' as u16;
+                    let value = &mut self.sequence.parameters[index];
+                    *value = value.saturating_mul(10).saturating_add(digit);
+                    None
+                }
+                ';' => {
+                    if self.sequence.count == 0 {
+                        self.sequence.count = 1;
+                    }
+                    if self.sequence.count < MAX_PARAMETERS {
+                        self.sequence.count += 1;
+                    }
+                    None
+                }
+                '\x40'..='\x7e' => {
+                    self.state = ParserState::Ground;
+                    self.sequence.command = c;
+                    Some(AnsiCommand::ControlSequence(self.sequence))
+                }
+                // Intermediate bytes, and private parameter markers (such as '?'), are ignored.
+                _ => None,
+            },
+        }
+    }
+}
+
+/// Text attributes controlled by SGR (Select Graphic Rendition) sequences.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct TextAttributes {
+    pub foreground: Color,
+    pub background: Color,
+    bright: bool,
+    foreground_index: Option<u8>,
+}
+
+impl TextAttributes {
+    pub(crate) fn default_foreground() -> Color {
+        Color::green()
+    }
+
+    pub(crate) fn default_background() -> Color {
+        Color::black()
+    }
+
+    pub(crate) fn new() -> Self {
+        Self {
+            foreground: Self::default_foreground(),
+            background: Self::default_background(),
+            bright: false,
+            foreground_index: None,
+        }
+    }
+
+    pub(crate) fn apply_sgr(&mut self, sequence: &ControlSequence) {
+        if sequence.parameters().is_empty() {
+            *self = Self::new();
+            return;
+        }
+
+        for parameter in sequence.parameters() {
+            match *parameter {
+                0 => *self = Self::new(),
+                1 => {
+                    self.bright = true;
+                    self.refresh_foreground();
+                }
+                22 => {
+                    self.bright = false;
+                    self.refresh_foreground();
+                }
+                30..=37 => {
+                    self.foreground_index = Some((*parameter - 30) as u8);
+                    self.refresh_foreground();
+                }
+                39 => {
+                    self.foreground_index = None;
+                    self.foreground = Self::default_foreground();
+                }
+                40..=47 => self.background = ansi_color((*parameter - 40) as u8),
+                49 => self.background = Self::default_background(),
+                90..=97 => {
+                    self.foreground_index = Some((*parameter - 90) as u8 + 8);
+                    self.refresh_foreground();
+                }
+                100..=107 => self.background = ansi_color((*parameter - 100) as u8 + 8),
+                _ => {}
+            }
+        }
+    }
+
+    fn refresh_foreground(&mut self) {
+        if let Some(index) = self.foreground_index {
+            let index = match self.bright && index < 8 {
+                true => index + 8,
+                false => index,
+            };
+            self.foreground = ansi_color(index);
+        }
+    }
+}
+
+/// Maps one of the 16 standard ANSI colors to RGB, using the classic VGA palette.
+pub(crate) fn ansi_color(index: u8) -> Color {
+    match index & 0x0F {
+        0 => Color::new(0, 0, 0),
+        1 => Color::new(170, 0, 0),
+        2 => Color::new(0, 170, 0),
+        3 => Color::new(170, 85, 0),
+        4 => Color::new(0, 0, 170),
+        5 => Color::new(170, 0, 170),
+        6 => Color::new(0, 170, 170),
+        7 => Color::new(170, 170, 170),
+        8 => Color::new(85, 85, 85),
+        9 => Color::new(255, 85, 85),
+        10 => Color::new(85, 255, 85),
+        11 => Color::new(255, 255, 85),
+        12 => Color::new(85, 85, 255),
+        13 => Color::new(255, 85, 255),
+        14 => Color::new(85, 255, 255),
+        _ => Color::new(255, 255, 255),
+    }
+}
@@ -0,0 +1,100 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::{
+    framebuffer::{
+        compositor::{self, Rect, SurfaceId},
+        FRAME_BUFFER,
+    },
+    time,
+};
+
+// Above the console, below the boot splash.
+const CURSOR_Z: i32 = 1;
+const BLINK_INTERVAL_NANOSECONDS: u64 = 500_000_000;
+// Enough for a 8x32 glyph at 4 bytes per pixel.
+const MAX_CELL_BYTES: usize = 8 * 32 * 4;
+
+static NEXT_BLINK: AtomicU64 = AtomicU64::new(0);
+
+/// Where the next character will be written, in cells, and the block drawn there.
+pub(crate) struct Cursor {
+    pub column: usize,
+    pub row: usize,
+    saved: (usize, usize),
+    /// Shown or hidden with CSI ?25h and CSI ?25l.
+    pub enabled: bool,
+    blink_on: bool,
+    surface: Option<SurfaceId>,
+}
+
+impl Cursor {
+    pub(crate) const fn new() -> Self {
+        Self {
+            column: 0,
+            // Clamped to the bottom line on first use, below whatever the bootloader left on screen.
+            row: usize::MAX,
+            saved: (0, 0),
+            enabled: true,
+            blink_on: true,
+            surface: None,
+        }
+    }
+
+    pub(crate) fn save(&mut self) {
+        self.saved = (self.column, self.row);
+    }
+
+    pub(crate) fn restore(&mut self) {
+        (self.column, self.row) = self.saved;
+    }
+
+    pub(crate) fn toggle_blink(&mut self) {
+        self.blink_on = !self.blink_on;
+    }
+
+    /// Redraws the cursor block over a cell, as an inverted copy of the console's pixels there.
+    pub(crate) fn render(&mut self, cell: Rect) {
+        let mut pixels = [0u8; MAX_CELL_BYTES];
+        let length = {
+            let locked = FRAME_BUFFER.lock();
+            match locked.get_framebuffer() {
+                Some(frame_buffer) => frame_buffer.read_rect(cell, &mut pixels),
+                None => return,
+            }
+        };
+        for byte in pixels[0..length].iter_mut() {
+            *byte = !*byte;
+        }
+
+        let surface = match self.surface {
+            Some(surface) => surface,
+            None => match compositor::create_surface(cell, CURSOR_Z) {
+                Some(surface) => {
+                    self.surface = Some(surface);
+                    surface
+                }
+                None => return,
+            },
+        };
+        compositor::move_surface(surface, cell.x, cell.y);
+        compositor::with_surface(surface, |s| {
+            s.blit(0, 0, cell.width, cell.height, &pixels[0..length])
+        });
+        compositor::set_surface_visible(surface, self.enabled && self.blink_on);
+    }
+}
+
+/// True once per blink interval, across all CPUs.
+pub(crate) fn blink_due() -> bool {
+    let now = time::monotonic_nanoseconds();
+    let next = NEXT_BLINK.load(Ordering::Relaxed);
+    now >= next
+        && NEXT_BLINK
+            .compare_exchange(
+                next,
+                now + BLINK_INTERVAL_NANOSECONDS,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            )
+            .is_ok()
+}
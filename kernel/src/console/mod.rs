@@ -1,11 +1,25 @@
-use alloc::string::ToString;
-use core::fmt;
+use core::{
+    cmp::min,
+    fmt::{self, Write},
+};
 
 use lazy_static::*;
 use spin::Mutex;
 
 use crate::framebuffer::*;
 
+use crate::framebuffer::compositor::Rect;
+
+use self::{
+    ansi::{AnsiCommand, AnsiParser, ControlSequence, TextAttributes},
+    cursor::{blink_due, Cursor},
+    utf8::Utf8Decoder,
+};
+
+pub(crate) mod ansi;
+pub(crate) mod cursor;
+pub(crate) mod utf8;
+
 #[derive(Clone, Copy, Debug)]
 #[repr(C)]
 pub(crate) struct Glyph {
@@ -15,30 +29,65 @@ pub(crate) struct Glyph {
 
 pub(crate) struct Console {
     font: Font,
+    parser: AnsiParser,
+    attributes: TextAttributes,
+    decoder: Utf8Decoder,
+    cursor: Cursor,
+}
+
+/// The console's character cells. Rows are aligned to the bottom of the screen, any leftover
+/// pixels are at the top.
+#[derive(Clone, Copy)]
+struct Grid {
+    columns: usize,
+    rows: usize,
+    top: usize,
+    cell_width: usize,
+    cell_height: usize,
 }
 
-static mut CONSOLE_X_POSITION: usize = 0;
+impl Grid {
+    fn cell(&self, column: usize, row: usize) -> Rect {
+        Rect::new(
+            column * self.cell_width,
+            self.top + row * self.cell_height,
+            self.cell_width,
+            self.cell_height,
+        )
+    }
+}
 
 lazy_static! {
-    static ref CONSOLE: Mutex<Console> = Mutex::new(Console { font: Font::new() });
+    static ref CONSOLE: Mutex<Console> = Mutex::new(Console {
+        font: Font::new(),
+        parser: AnsiParser::new(),
+        attributes: TextAttributes::new(),
+        decoder: Utf8Decoder::new(),
+        cursor: Cursor::new(),
+    });
 }
 
 pub(crate) fn _print(args: fmt::Arguments) {
     {
-        let locked_console = CONSOLE.lock();
-        for c in args.to_string().chars() {
-            if !c.is_ascii() {
-                continue;
-            }
-            match c {
-                '\n' => locked_console.new_line(),
-                _ => locked_console.put_char(c),
-            };
-        }
+        let mut locked_console = CONSOLE.lock();
+        let _ = locked_console.write_fmt(args);
+        locked_console.render_cursor();
     }
     swap_framebuffer();
 }
 
+/// Called from the idle loop, blinks the cursor.
+pub(crate) fn blink_cursor() {
+    if !blink_due() {
+        return;
+    }
+    // Never wait on the console from the idle loop, the next blink will do.
+    if let Some(mut locked_console) = CONSOLE.try_lock() {
+        locked_console.cursor.toggle_blink();
+        locked_console.render_cursor();
+    }
+}
+
 #[macro_export]
 macro_rules! console_print {
     ($($arg:tt)*) => ($crate::console::_print(format_args!($($arg)*)));
@@ -50,51 +99,224 @@ macro_rules! console_println {
     ($($arg:tt)*) => ($crate::console_print!("{}\n", format_args!($($arg)*)));
 }
 
+impl fmt::Write for Console {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write_bytes(s.as_bytes());
+        Ok(())
+    }
+}
+
 impl Console {
-    pub fn new_line(self: &Self) {
-        let locked = FRAME_BUFFER.lock();
-        if let Some(frame_buffer) = locked.get_framebuffer() {
-            let glyph = self.font.glyph(b' ');
-            self.new_line_internal(frame_buffer, &glyph);
+    fn write_bytes(self: &mut Self, bytes: &[u8]) {
+        let mut decoder = core::mem::replace(&mut self.decoder, Utf8Decoder::new());
+        for byte in bytes {
+            decoder.feed(*byte, |c| self.put(c));
         }
+        self.decoder = decoder;
     }
-    fn new_line_internal(self: &Self, frame_buffer: &mut KernelFramebuffer, glyph: &Glyph) {
-        frame_buffer.shift_up(glyph.height());
-        unsafe { CONSOLE_X_POSITION = 0 };
+
+    fn put(self: &mut Self, c: char) {
+        match self.parser.feed(c) {
+            Some(AnsiCommand::Print('\n')) => self.new_line(),
+            Some(AnsiCommand::Print('\r')) => self.cursor.column = 0,
+            Some(AnsiCommand::Print('\x08')) => {
+                self.cursor.column = self.cursor.column.saturating_sub(1)
+            }
+            Some(AnsiCommand::Print('\t')) => self.tab(),
+            Some(AnsiCommand::Print('\x07')) => crate::sound::beep(),
+            Some(AnsiCommand::Print(c)) => self.put_char(c),
+            Some(AnsiCommand::ControlSequence(sequence)) => self.control_sequence(&sequence),
+            None => {}
+        }
     }
-    pub fn put_char(self: &Self, c: char) {
-        let glyph = self.font.glyph(c as u8);
-        let mut x_offset: usize = unsafe { CONSOLE_X_POSITION };
+
+    fn control_sequence(self: &mut Self, sequence: &ControlSequence) {
+        let grid = match self.grid() {
+            Some(grid) => grid,
+            None => return,
+        };
+        // The screen may have changed size since the cursor last moved.
+        self.clamp_cursor(&grid);
+        let count = sequence.parameter_or(0, 1) as usize;
+        let last_column = grid.columns - 1;
+        let last_row = grid.rows - 1;
+        let cursor = &mut self.cursor;
+        match sequence.command() {
+            'm' => self.attributes.apply_sgr(sequence),
+            'A' => cursor.row = cursor.row.saturating_sub(count),
+            'B' => cursor.row = min(cursor.row.saturating_add(count), last_row),
+            'C' => cursor.column = min(cursor.column.saturating_add(count), last_column),
+            'D' => cursor.column = cursor.column.saturating_sub(count),
+            'E' => {
+                cursor.row = min(cursor.row.saturating_add(count), last_row);
+                cursor.column = 0;
+            }
+            'F' => {
+                cursor.row = cursor.row.saturating_sub(count);
+                cursor.column = 0;
+            }
+            // Positions count from 1, an explicit 0 means the first too.
+            'G' => cursor.column = min(count.saturating_sub(1), last_column),
+            'd' => cursor.row = min(count.saturating_sub(1), last_row),
+            'H' | 'f' => {
+                cursor.row = min(
+                    (sequence.parameter_or(0, 1) as usize).saturating_sub(1),
+                    last_row,
+                );
+                cursor.column = min(
+                    (sequence.parameter_or(1, 1) as usize).saturating_sub(1),
+                    last_column,
+                );
+            }
+            'J' => self.erase_display(sequence.parameters().first().copied().unwrap_or(0)),
+            'K' => self.erase_line(sequence.parameters().first().copied().unwrap_or(0)),
+            's' => cursor.save(),
+            'u' => cursor.restore(),
+            'h' | 'l' if sequence.parameters() == [25] => {
+                cursor.enabled = sequence.command() == 'h'
+            }
+            _ => {}
+        }
+    }
+
+    fn grid(self: &Self) -> Option<Grid> {
+        let info = FRAME_BUFFER.lock().get_framebuffer()?.info()?;
+        let glyph = self.font.glyph(' ');
+        let columns = info.width / glyph.width();
+        let rows = info.height / glyph.height();
+        if columns == 0 || rows == 0 {
+            return None;
+        }
+        Some(Grid {
+            columns,
+            rows,
+            top: info.height % glyph.height(),
+            cell_width: glyph.width(),
+            cell_height: glyph.height(),
+        })
+    }
+
+    /// Keeps the cursor on screen, a column past the end is allowed until the next character
+    /// wraps it.
+    fn clamp_cursor(self: &mut Self, grid: &Grid) {
+        self.cursor.row = min(self.cursor.row, grid.rows - 1);
+        self.cursor.column = min(self.cursor.column, grid.columns);
+    }
+
+    fn fill_cells(
+        self: &Self,
+        grid: &Grid,
+        column: usize,
+        row: usize,
+        columns: usize,
+        rows: usize,
+    ) {
         let locked = FRAME_BUFFER.lock();
-        let frame_buffer_option = locked.get_framebuffer();
-        if frame_buffer_option.is_none() {
-            return;
+        if let Some(frame_buffer) = locked.get_framebuffer() {
+            let start = grid.cell(column, row);
+            frame_buffer.draw_rect(
+                start.x,
+                start.y,
+                columns * grid.cell_width,
+                rows * grid.cell_height,
+                &self.attributes.background,
+            );
         }
-        let frame_buffer = frame_buffer_option.unwrap();
-        let info = frame_buffer.info();
-        if info.is_none() {
-            return;
+    }
+
+    fn erase_line(self: &mut Self, mode: u16) {
+        let grid = match self.grid() {
+            Some(grid) => grid,
+            None => return,
+        };
+        self.clamp_cursor(&grid);
+        let (column, row) = (self.cursor.column, self.cursor.row);
+        match mode {
+            0 => self.fill_cells(&grid, column, row, grid.columns.saturating_sub(column), 1),
+            1 => self.fill_cells(&grid, 0, row, min(column + 1, grid.columns), 1),
+            _ => self.fill_cells(&grid, 0, row, grid.columns, 1),
         }
+    }
 
-        let info = info.unwrap();
+    fn erase_display(self: &mut Self, mode: u16) {
+        let grid = match self.grid() {
+            Some(grid) => grid,
+            None => return,
+        };
+        self.clamp_cursor(&grid);
+        let row = self.cursor.row;
+        match mode {
+            0 => {
+                self.erase_line(0);
+                self.fill_cells(&grid, 0, row + 1, grid.columns, grid.rows - row - 1);
+            }
+            1 => {
+                self.fill_cells(&grid, 0, 0, grid.columns, row);
+                self.erase_line(1);
+            }
+            _ => self.fill_cells(&grid, 0, 0, grid.columns, grid.rows),
+        }
+    }
 
-        let y_offset = info.height - glyph.height();
+    fn tab(self: &mut Self) {
+        if let Some(grid) = self.grid() {
+            self.clamp_cursor(&grid);
+            self.cursor.column = min((self.cursor.column / 8 + 1) * 8, grid.columns - 1);
+        }
+    }
+
+    pub fn new_line(self: &mut Self) {
+        let grid = match self.grid() {
+            Some(grid) => grid,
+            None => return,
+        };
+        self.clamp_cursor(&grid);
+        self.cursor.column = 0;
+        if self.cursor.row + 1 < grid.rows {
+            self.cursor.row += 1;
+            return;
+        }
+        let locked = FRAME_BUFFER.lock();
+        if let Some(frame_buffer) = locked.get_framebuffer() {
+            frame_buffer.shift_up(grid.cell_height);
+        }
+    }
 
-        if x_offset >= (info.width - glyph.width()) {
-            self.new_line_internal(frame_buffer, &glyph);
-            x_offset = 0;
+    pub fn put_char(self: &mut Self, c: char) {
+        let grid = match self.grid() {
+            Some(grid) => grid,
+            None => return,
+        };
+        self.clamp_cursor(&grid);
+        if self.cursor.column >= grid.columns {
+            self.new_line();
         }
+        let glyph = self.font.glyph(c);
+        let cell = grid.cell(self.cursor.column, self.cursor.row);
         {
-            glyph.draw(
-                x_offset,
-                y_offset,
-                frame_buffer,
-                &Color::green(),
-                &Color::black(),
-            );
-            x_offset += 8;
+            let locked = FRAME_BUFFER.lock();
+            if let Some(frame_buffer) = locked.get_framebuffer() {
+                glyph.draw(
+                    cell.x,
+                    cell.y,
+                    frame_buffer,
+                    &self.attributes.foreground,
+                    &self.attributes.background,
+                );
+            }
         }
-        unsafe { CONSOLE_X_POSITION = x_offset };
+        self.cursor.column += 1;
+    }
+
+    fn render_cursor(self: &mut Self) {
+        let grid = match self.grid() {
+            Some(grid) => grid,
+            None => return,
+        };
+        self.clamp_cursor(&grid);
+        // Past the last column the cursor waits for the next character to wrap, show it at the end.
+        let cell = grid.cell(min(self.cursor.column, grid.columns - 1), self.cursor.row);
+        self.cursor.render(cell);
     }
 }
 
@@ -142,8 +364,17 @@ impl FontHeader {
     }
 }
 
+const PSF1_MODE_512: u8 = 0x01;
+const PSF1_MODE_HAS_TABLE: u8 = 0x02;
+const PSF1_SEPARATOR: u16 = 0xFFFF;
+const PSF1_START_SEQUENCE: u16 = 0xFFFE;
+const MAX_UNICODE_ENTRIES: usize = 1024;
+
 pub(crate) struct Font {
     glyphs: [Glyph; 256],
+    // (code point, glyph), sorted by code point.
+    unicode: [(u16, u8); MAX_UNICODE_ENTRIES],
+    unicode_length: usize,
 }
 
 impl Font {
@@ -167,11 +398,89 @@ impl Font {
                 bytes: glyph_bytes,
             };
         }
-        Font { glyphs: glyphs }
+        let mut font = Font {
+            glyphs: glyphs,
+            unicode: [(0, 0); MAX_UNICODE_ENTRIES],
+            unicode_length: 0,
+        };
+        if header.mode & PSF1_MODE_HAS_TABLE != 0 {
+            font.load_unicode_table(bytes, &header);
+        }
+        font
     }
 
-    pub fn glyph(self: &Self, c: u8) -> Glyph {
-        self.glyphs[c as usize]
+    /// The PSF1 unicode table follows the glyphs. For each glyph, in order, it lists the UCS-2
+    /// code points it draws, then optional combining sequences, ending with a separator.
+    fn load_unicode_table(self: &mut Self, bytes: &[u8], header: &FontHeader) {
+        let glyph_count = if header.mode & PSF1_MODE_512 != 0 {
+            512
+        } else {
+            256
+        };
+        let mut offset = 4 + glyph_count * header.charsize as usize;
+        let mut glyph = 0usize;
+        let mut in_sequence = false;
+        while offset + 2 <= bytes.len() && glyph < glyph_count {
+            let entry = u16::from_le_bytes([bytes[offset], bytes[offset + 1]]);
+            offset += 2;
+            match entry {
+                PSF1_SEPARATOR => {
+                    glyph += 1;
+                    in_sequence = false;
+                }
+                PSF1_START_SEQUENCE => in_sequence = true,
+                // Sequences need combining characters, which the console doesn't do.
+                _ if in_sequence => {}
+                // Only the first 256 glyphs are loaded.
+                _ if glyph >= 256 => {}
+                code_point if self.unicode_length < MAX_UNICODE_ENTRIES => {
+                    self.unicode[self.unicode_length] = (code_point, glyph as u8);
+                    self.unicode_length += 1;
+                }
+                _ => {}
+            }
+        }
+        self.unicode[0..self.unicode_length].sort_unstable_by_key(|(code_point, _)| *code_point);
+    }
+
+    fn glyph_index(self: &Self, c: char) -> Option<u8> {
+        if self.unicode_length == 0 {
+            return u8::try_from(c as u32).ok();
+        }
+        let code_point = u16::try_from(c as u32).ok()?;
+        let table = &self.unicode[0..self.unicode_length];
+        let index = table.binary_search_by_key(&code_point, |(c, _)| *c).ok()?;
+        Some(table[index].1)
+    }
+
+    /// The glyph for a character, falling back to an ASCII look-alike, then to '?'.
+    pub fn glyph(self: &Self, c: char) -> Glyph {
+        let index = self
+            .glyph_index(c)
+            .or_else(|| ascii_fallback(c).and_then(|f| self.glyph_index(f)))
+            .or_else(|| self.glyph_index('?'))
+            .unwrap_or(b'?');
+        self.glyphs[index as usize]
+    }
+}
+
+/// Approximations for box drawing and arrows, for fonts that don't have them.
+fn ascii_fallback(c: char) -> Option<char> {
+    match c {
+        '─' | '━' | '═' | '╌' | '┄' => Some('-'),
+        '│' | '┃' | '║' | '╎' | '┆' => Some('|'),
+        '┌' | '┐' | '└' | '┘' | '├' | '┤' | '┬' | '┴' | '┼' | '╔' | '╗' | '╚' | '╝' | '╠' | '╣'
+        | '╦' | '╩' | '╬' | '╭' | '╮' | '╯' | '╰' => Some('+'),
+        '←' => Some('<'),
+        '→' => Some('>'),
+        '↑' => Some('^'),
+        '↓' => Some('v'),
+        '\u{a0}' => Some(' '),
+        '‘' | '’' => Some('\''),
+        '“' | '”' => Some('"'),
+        '–' | '—' => Some('-'),
+        '•' | '·' => Some('*'),
+        _ => None,
     }
 }
 
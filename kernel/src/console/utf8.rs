@@ -0,0 +1,55 @@
+/// Decodes UTF-8 a byte at a time, so sequences split across writes still decode.
+pub(crate) struct Utf8Decoder {
+    code_point: u32,
+    remaining: u8,
+    // Smallest code point the current sequence may encode, anything less is an overlong encoding.
+    minimum: u32,
+}
+
+impl Utf8Decoder {
+    pub(crate) const fn new() -> Self {
+        Self {
+            code_point: 0,
+            remaining: 0,
+            minimum: 0,
+        }
+    }
+
+    /// Passes each completed character to emit. Malformed input produces
+    /// char::REPLACEMENT_CHARACTER.
+    pub(crate) fn feed(&mut self, byte: u8, mut emit: impl FnMut(char)) {
+        if self.remaining > 0 {
+            if byte & 0xC0 == 0x80 {
+                self.code_point = (self.code_point << 6) | (byte & 0x3F) as u32;
+                self.remaining -= 1;
+                if self.remaining == 0 {
+                    emit(match char::from_u32(self.code_point) {
+                        Some(c) if self.code_point >= self.minimum => c,
+                        _ => char::REPLACEMENT_CHARACTER,
+                    });
+                }
+                return;
+            }
+            // The sequence ended early, report it and start over with this byte.
+            self.remaining = 0;
+            emit(char::REPLACEMENT_CHARACTER);
+        }
+        if let Some(c) = self.start(byte) {
+            emit(c);
+        }
+    }
+
+    fn start(&mut self, byte: u8) -> Option<char> {
+        let (remaining, bits, minimum) = match byte {
+            0x00..=0x7F => return Some(byte as char),
+            0xC0..=0xDF => (1, byte & 0x1F, 0x80),
+            0xE0..=0xEF => (2, byte & 0x0F, 0x800),
+            0xF0..=0xF7 => (3, byte & 0x07, 0x10000),
+            _ => return Some(char::REPLACEMENT_CHARACTER),
+        };
+        self.code_point = bits as u32;
+        self.remaining = remaining;
+        self.minimum = minimum;
+        None
+    }
+}
@@ -0,0 +1,115 @@
+use core::fmt::Display;
+
+use super::Color;
+
+const FILE_HEADER_LENGTH: usize = 14;
+const INFO_HEADER_LENGTH: usize = 40;
+const COMPRESSION_NONE: u32 = 0;
+const COMPRESSION_BITFIELDS: u32 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum BmpError {
+    NotABitmap,
+    Truncated,
+    /// Compressed, palettized, or anything other than 24 or 32 bits per pixel.
+    Unsupported,
+}
+
+impl Display for BmpError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            BmpError::NotABitmap => write!(f, "not a BMP image"),
+            BmpError::Truncated => write!(f, "BMP image is truncated"),
+            BmpError::Unsupported => write!(f, "unsupported BMP format"),
+        }
+    }
+}
+
+/// An uncompressed 24 or 32 bit BMP image, read in place.
+pub(crate) struct Bitmap<'a> {
+    pixels: &'a [u8],
+    width: usize,
+    height: usize,
+    bytes_per_pixel: usize,
+    stride: usize,
+    top_down: bool,
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        data[offset],
+        data[offset + 1],
+        data[offset + 2],
+        data[offset + 3],
+    ])
+}
+
+impl<'a> Bitmap<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Self, BmpError> {
+        if data.len() < FILE_HEADER_LENGTH + INFO_HEADER_LENGTH || &data[0..2] != b"BM" {
+            return Err(BmpError::NotABitmap);
+        }
+        if (read_u32(data, 14) as usize) < INFO_HEADER_LENGTH {
+            return Err(BmpError::Unsupported);
+        }
+        let pixel_offset = read_u32(data, 10) as usize;
+        let width = read_u32(data, 18) as i32;
+        let height = read_u32(data, 22) as i32;
+        let bits_per_pixel = read_u16(data, 28);
+        let compression = read_u32(data, 30);
+        let bytes_per_pixel = match (bits_per_pixel, compression) {
+            (24, COMPRESSION_NONE) => 3,
+            (32, COMPRESSION_NONE) | (32, COMPRESSION_BITFIELDS) => 4,
+            _ => return Err(BmpError::Unsupported),
+        };
+        if width <= 0 || height == 0 {
+            return Err(BmpError::Unsupported);
+        }
+        let width = width as usize;
+        // Negative heights are stored top row first.
+        let top_down = height < 0;
+        let height = height.unsigned_abs() as usize;
+        // Rows are padded to 4 bytes.
+        let stride = (width * bytes_per_pixel + 3) & !3;
+        let end = pixel_offset
+            .checked_add(stride * height)
+            .ok_or(BmpError::Truncated)?;
+        if end > data.len() {
+            return Err(BmpError::Truncated);
+        }
+        Ok(Self {
+            pixels: &data[pixel_offset..end],
+            width,
+            height,
+            bytes_per_pixel,
+            stride,
+            top_down,
+        })
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn pixel(&self, x: usize, y: usize) -> Color {
+        let row = match self.top_down {
+            true => y,
+            false => self.height - 1 - y,
+        };
+        let offset = row * self.stride + x * self.bytes_per_pixel;
+        // Stored as BGR, and BGRX for 32 bits.
+        Color::new(
+            self.pixels[offset + 2],
+            self.pixels[offset + 1],
+            self.pixels[offset],
+        )
+    }
+}
@@ -0,0 +1,414 @@
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use alloc::{vec, vec::Vec};
+use bootloader_api::info::PixelFormat;
+use lazy_static::lazy_static;
+
+use crate::{
+    thread::preempt::{cond_resched, SpinLock},
+    time,
+};
+
+use super::{Color, FRAME_BUFFER};
+
+/// How often surfaces are composited onto the screen, when nothing forces it sooner.
+const COMPOSE_INTERVAL_NANOSECONDS: u64 = 16_000_000;
+const COMPOSE_BAND_ROWS: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Rect {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Rect {
+    pub const fn new(x: usize, y: usize, width: usize, height: usize) -> Self {
+        Self {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.width == 0 || self.height == 0
+    }
+
+    pub fn right(&self) -> usize {
+        self.x + self.width
+    }
+
+    pub fn bottom(&self) -> usize {
+        self.y + self.height
+    }
+
+    pub fn offset(&self, x: usize, y: usize) -> Rect {
+        Rect::new(self.x + x, self.y + y, self.width, self.height)
+    }
+
+    /// Smallest rectangle containing both.
+    pub fn union(&self, other: &Rect) -> Rect {
+        if self.is_empty() {
+            return *other;
+        }
+        if other.is_empty() {
+            return *self;
+        }
+        let x = self.x.min(other.x);
+        let y = self.y.min(other.y);
+        Rect::new(
+            x,
+            y,
+            self.right().max(other.right()) - x,
+            self.bottom().max(other.bottom()) - y,
+        )
+    }
+
+    pub fn intersection(&self, other: &Rect) -> Option<Rect> {
+        let x = self.x.max(other.x);
+        let y = self.y.max(other.y);
+        let right = self.right().min(other.right());
+        let bottom = self.bottom().min(other.bottom());
+        if right <= x || bottom <= y {
+            return None;
+        }
+        Some(Rect::new(x, y, right - x, bottom - y))
+    }
+}
+
+/// Grows an optional damage rectangle to cover another.
+pub(crate) fn add_damage(damage: &mut Option<Rect>, rect: Rect) {
+    if rect.is_empty() {
+        return;
+    }
+    *damage = Some(match damage {
+        Some(existing) => existing.union(&rect),
+        None => rect,
+    });
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub(crate) struct SurfaceId(usize);
+
+/// An off-screen, opaque image in the framebuffer's pixel format, placed on the screen by the
+/// compositor. The kernel console is always beneath every surface.
+pub(crate) struct Surface {
+    id: SurfaceId,
+    position: (usize, usize),
+    z: i32,
+    visible: bool,
+    width: usize,
+    height: usize,
+    bytes_per_pixel: usize,
+    pixel_format: PixelFormat,
+    pixels: Vec<u8>,
+    dirty: Option<Rect>,
+}
+
+impl Surface {
+    pub fn id(&self) -> SurfaceId {
+        self.id
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn bytes_per_pixel(&self) -> usize {
+        self.bytes_per_pixel
+    }
+
+    pub fn pixel_format(&self) -> PixelFormat {
+        self.pixel_format
+    }
+
+    fn bounds(&self) -> Rect {
+        Rect::new(self.position.0, self.position.1, self.width, self.height)
+    }
+
+    fn stride(&self) -> usize {
+        self.width * self.bytes_per_pixel
+    }
+
+    fn row(&self, y: usize) -> &[u8] {
+        let start = y * self.stride();
+        &self.pixels[start..start + self.stride()]
+    }
+
+    fn encode(&self, color: &Color) -> [u8; 4] {
+        let mut raw = [0u8; 4];
+        color.to_framebuffer_color(self.pixel_format, &mut raw[0..self.bytes_per_pixel.min(3)]);
+        raw
+    }
+
+    /// Marks part of the surface, in surface coordinates, as needing to be redrawn.
+    pub fn damage(&mut self, rect: Rect) {
+        if let Some(rect) = rect.intersection(&Rect::new(0, 0, self.width, self.height)) {
+            add_damage(&mut self.dirty, rect);
+        }
+    }
+
+    pub fn set_pixel(&mut self, x: usize, y: usize, color: &Color) {
+        self.fill_rect(x, y, 1, 1, color);
+    }
+
+    pub fn fill(&mut self, color: &Color) {
+        self.fill_rect(0, 0, self.width, self.height, color);
+    }
+
+    pub fn fill_rect(&mut self, x: usize, y: usize, width: usize, height: usize, color: &Color) {
+        let rect = match Rect::new(x, y, width, height).intersection(&Rect::new(
+            0,
+            0,
+            self.width,
+            self.height,
+        )) {
+            Some(rect) => rect,
+            None => return,
+        };
+        let raw = self.encode(color);
+        let bpp = self.bytes_per_pixel;
+        let stride = self.stride();
+        for row in rect.y..rect.bottom() {
+            let start = row * stride + rect.x * bpp;
+            for pixel in self.pixels[start..start + rect.width * bpp].chunks_exact_mut(bpp) {
+                pixel.copy_from_slice(&raw[0..bpp]);
+            }
+        }
+        self.damage(rect);
+    }
+
+    /// Copies rows of pixels, already in the surface's pixel format, into the surface.
+    pub fn blit(&mut self, x: usize, y: usize, width: usize, height: usize, source: &[u8]) {
+        let bpp = self.bytes_per_pixel;
+        let source_stride = width * bpp;
+        let rect = match Rect::new(x, y, width, height).intersection(&Rect::new(
+            0,
+            0,
+            self.width,
+            self.height,
+        )) {
+            Some(rect) => rect,
+            None => return,
+        };
+        let stride = self.stride();
+        for row in 0..rect.height {
+            let source_start = row * source_stride;
+            if source_start + rect.width * bpp > source.len() {
+                break;
+            }
+            let start = (rect.y + row) * stride + rect.x * bpp;
+            self.pixels[start..start + rect.width * bpp]
+                .copy_from_slice(&source[source_start..source_start + rect.width * bpp]);
+        }
+        self.damage(rect);
+    }
+
+    /// Direct access to the pixels, call damage() for whatever was changed.
+    pub fn pixels_mut(&mut self) -> &mut [u8] {
+        &mut self.pixels
+    }
+}
+
+pub(crate) struct Compositor {
+    // Sorted by z, lowest first.
+    surfaces: Vec<Surface>,
+    damage: Option<Rect>,
+}
+
+lazy_static! {
+    static ref COMPOSITOR: SpinLock<Compositor> = SpinLock::new(Compositor {
+        surfaces: Vec::new(),
+        damage: None,
+    });
+}
+
+static NEXT_SURFACE_ID: AtomicUsize = AtomicUsize::new(1);
+static NEXT_COMPOSE: AtomicU64 = AtomicU64::new(0);
+
+impl Compositor {
+    fn index_of(&self, id: SurfaceId) -> Option<usize> {
+        self.surfaces.iter().position(|s| s.id == id)
+    }
+
+    fn insert(&mut self, surface: Surface) {
+        let index = self
+            .surfaces
+            .iter()
+            .position(|s| s.z > surface.z)
+            .unwrap_or(self.surfaces.len());
+        self.surfaces.insert(index, surface);
+    }
+
+    /// Everything that needs to be recomposed, in screen coordinates.
+    fn take_damage(&mut self) -> Option<Rect> {
+        let mut damage = self.damage.take();
+        for surface in self.surfaces.iter_mut() {
+            if let Some(dirty) = surface.dirty.take() {
+                if surface.visible {
+                    add_damage(
+                        &mut damage,
+                        dirty.offset(surface.position.0, surface.position.1),
+                    );
+                }
+            }
+        }
+        damage
+    }
+}
+
+/// Creates a surface at a position on screen, surfaces with a higher z are drawn on top. Returns
+/// None if there is no framebuffer.
+pub(crate) fn create_surface(rect: Rect, z: i32) -> Option<SurfaceId> {
+    let info = FRAME_BUFFER.lock().get_framebuffer()?.info()?;
+    let id = SurfaceId(NEXT_SURFACE_ID.fetch_add(1, Ordering::Relaxed));
+    let surface = Surface {
+        id,
+        position: (rect.x, rect.y),
+        z,
+        visible: true,
+        width: rect.width,
+        height: rect.height,
+        bytes_per_pixel: info.bytes_per_pixel,
+        pixel_format: info.pixel_format,
+        pixels: vec![0; rect.width * rect.height * info.bytes_per_pixel],
+        dirty: Some(Rect::new(0, 0, rect.width, rect.height)),
+    };
+    COMPOSITOR.lock().insert(surface);
+    Some(id)
+}
+
+pub(crate) fn destroy_surface(id: SurfaceId) {
+    let mut compositor = COMPOSITOR.lock();
+    if let Some(index) = compositor.index_of(id) {
+        let surface = compositor.surfaces.remove(index);
+        add_damage(&mut compositor.damage, surface.bounds());
+    }
+}
+
+/// Runs a closure against a surface, returns None if the surface does not exist.
+pub(crate) fn with_surface<T>(id: SurfaceId, f: impl FnOnce(&mut Surface) -> T) -> Option<T> {
+    let mut compositor = COMPOSITOR.lock();
+    let index = compositor.index_of(id)?;
+    Some(f(&mut compositor.surfaces[index]))
+}
+
+pub(crate) fn move_surface(id: SurfaceId, x: usize, y: usize) {
+    let mut compositor = COMPOSITOR.lock();
+    if let Some(index) = compositor.index_of(id) {
+        let old = compositor.surfaces[index].bounds();
+        compositor.surfaces[index].position = (x, y);
+        let new = compositor.surfaces[index].bounds();
+        add_damage(&mut compositor.damage, old.union(&new));
+    }
+}
+
+pub(crate) fn set_surface_z(id: SurfaceId, z: i32) {
+    let mut compositor = COMPOSITOR.lock();
+    if let Some(index) = compositor.index_of(id) {
+        let mut surface = compositor.surfaces.remove(index);
+        surface.z = z;
+        add_damage(&mut compositor.damage, surface.bounds());
+        compositor.insert(surface);
+    }
+}
+
+pub(crate) fn set_surface_visible(id: SurfaceId, visible: bool) {
+    let mut compositor = COMPOSITOR.lock();
+    if let Some(index) = compositor.index_of(id) {
+        compositor.surfaces[index].visible = visible;
+        let bounds = compositor.surfaces[index].bounds();
+        add_damage(&mut compositor.damage, bounds);
+    }
+}
+
+/// Composites the console and every visible surface onto the screen, only redrawing what changed.
+pub(crate) fn compose() {
+    let damage = {
+        let fb = FRAME_BUFFER.lock();
+        let frame_buffer = match fb.get_framebuffer() {
+            Some(frame_buffer) => frame_buffer,
+            None => return,
+        };
+        let info = match frame_buffer.info() {
+            Some(info) => info,
+            None => return,
+        };
+        let mut damage = COMPOSITOR.lock().take_damage();
+        if let Some(dirty) = frame_buffer.take_dirty() {
+            add_damage(&mut damage, dirty);
+        }
+        match damage.and_then(|d| d.intersection(&Rect::new(0, 0, info.width, info.height))) {
+            Some(damage) => damage,
+            None => return,
+        }
+    };
+
+    // A band at a time, so a full screen redraw doesn't hold the locks, or the CPU, throughout.
+    let mut band = damage.y;
+    while band < damage.bottom() {
+        let rows = COMPOSE_BAND_ROWS.min(damage.bottom() - band);
+        compose_band(Rect::new(damage.x, band, damage.width, rows));
+        band += rows;
+        cond_resched();
+    }
+}
+
+fn compose_band(damage: Rect) {
+    let fb = FRAME_BUFFER.lock();
+    let frame_buffer = match fb.get_framebuffer() {
+        Some(frame_buffer) => frame_buffer,
+        None => return,
+    };
+    let bpp = match frame_buffer.info() {
+        Some(info) => info.bytes_per_pixel,
+        None => return,
+    };
+    let compositor = COMPOSITOR.lock();
+    let visible: Vec<&Surface> = compositor.surfaces.iter().filter(|s| s.visible).collect();
+    for y in damage.y..damage.bottom() {
+        let row = frame_buffer.composition_row(y, damage.x, damage.width);
+        for surface in visible.iter() {
+            let span = match surface
+                .bounds()
+                .intersection(&Rect::new(damage.x, y, damage.width, 1))
+            {
+                Some(span) => span,
+                None => continue,
+            };
+            let source_start = (span.x - surface.position.0) * bpp;
+            let source =
+                &surface.row(y - surface.position.1)[source_start..source_start + span.width * bpp];
+            let destination_start = (span.x - damage.x) * bpp;
+            row[destination_start..destination_start + span.width * bpp].copy_from_slice(source);
+        }
+        frame_buffer.present_row(y, damage.x, damage.width);
+    }
+}
+
+/// Called from the idle loop, composites at most once per interval across all CPUs.
+pub(crate) fn compose_periodically() {
+    let now = time::monotonic_nanoseconds();
+    let next = NEXT_COMPOSE.load(Ordering::Relaxed);
+    if now < next {
+        return;
+    }
+    if NEXT_COMPOSE
+        .compare_exchange(
+            next,
+            now + COMPOSE_INTERVAL_NANOSECONDS,
+            Ordering::Relaxed,
+            Ordering::Relaxed,
+        )
+        .is_ok()
+    {
+        compose();
+    }
+}
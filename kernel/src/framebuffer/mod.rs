@@ -1,17 +1,39 @@
 extern crate alloc;
 
 use alloc::{boxed::Box, string::String};
-use uuid::Uuid;
 use core::{alloc::Layout, cmp::min, slice, str::FromStr};
+use uuid::Uuid;
 
 use bootloader_api::info::*;
 use lazy_static::*;
-use spin::Mutex;
 
-use kernel_shared::memory::*;
+use kernel_shared::{
+    device::{FramebufferDescription, DEVICE_FUNCTION_DESCRIBE},
+    memory::*,
+    serialization::to_bytes,
+};
+use x86_64::{structures::paging::PageTableFlags, VirtAddr};
+
+use crate::{
+    arch::arch_x86_64::pat,
+    debug,
+    memory::{
+        allocator::{kmalloc, PAGE_SIZE},
+        KERNEL_MEMORY_MANAGER,
+    },
+    thread::preempt::SpinLock,
+};
+use devices::{
+    get_mut_device_tree,
+    well_known::{self, IPL},
+    Device, DeviceError, DeviceErrorCode,
+};
+
+pub(crate) mod bmp;
+pub(crate) mod compositor;
+pub(crate) mod splash;
 
-use devices::{Device, well_known::{self, IPL}, get_mut_device_tree};
-use crate::{memory::allocator::kmalloc};
+use self::compositor::{add_damage, Rect};
 
 #[derive(Debug, Clone, Copy)]
 pub(crate) struct Point(pub usize, pub usize);
@@ -54,6 +76,7 @@ static mut FRAME_BUFFER_INTERNAL: KernelFramebuffer = KernelFramebuffer {
     buffer: 0 as *mut u8,
     shadow_buffer: 0 as *mut u8,
     surface: 0 as *mut u8,
+    dirty: None,
 };
 pub struct FrameBufferWrapper {}
 impl FrameBufferWrapper {
@@ -92,30 +115,81 @@ impl FrameBufferWrapper {
 }
 
 lazy_static! {
-    pub static ref FRAME_BUFFER: Mutex<FrameBufferWrapper> = Mutex::new(FrameBufferWrapper {});
+    pub static ref FRAME_BUFFER: SpinLock<FrameBufferWrapper> =
+        SpinLock::new(FrameBufferWrapper {});
 }
 
 pub fn swap_framebuffer() {
-    let fb = FRAME_BUFFER.lock();
-    if let Some(kfb) = fb.get_framebuffer() {
-        kfb.swap_buffer();
-    }
+    compositor::compose();
 }
 
 pub fn init_framebuffer(frame_buffer: Option<&'static mut FrameBuffer>) {
     FRAME_BUFFER.lock().set_framebuffer(frame_buffer);
-    get_mut_device_tree().register(FramebufferDevice{parent: IPL.as_u128()});
+    let description = map_write_combining();
+    let mut device = FramebufferDevice {
+        parent: IPL.as_u128(),
+        description: [0; FRAMEBUFFER_DESCRIPTION_LENGTH],
+        description_length: 0,
+    };
+    if let Some(description) = description {
+        device.description_length = to_bytes(&description, &mut device.description)
+            .expect("Framebuffer description does not fit in its buffer")
+            .len();
+    }
+    get_mut_device_tree().register(device);
+}
+
+/// Switches the hardware framebuffer to write combining, and describes it for anything that wants
+/// to map it.
+fn map_write_combining() -> Option<FramebufferDescription> {
+    let fb = FRAME_BUFFER.lock();
+    let kfb = fb.get_framebuffer()?;
+    let info = kfb.info?;
+    let start = VirtAddr::from_ptr(kfb.buffer);
+    let pages = (info.byte_len + PAGE_SIZE - 1) / PAGE_SIZE;
+    let mut memory_manager = KERNEL_MEMORY_MANAGER.lock();
+    let physical_address = memory_manager.physical_address(start)?;
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::NO_EXECUTE
+        | pat::WRITE_COMBINING;
+    let updated = memory_manager.update_flags(start, pages, flags);
+    debug!(
+        "Framebuffer at {:#x}, {} of {} pages mapped write combining",
+        physical_address.as_u64(),
+        updated,
+        pages
+    );
+    Some(FramebufferDescription {
+        width: info.width,
+        height: info.height,
+        stride: info.stride,
+        bytes_per_pixel: info.bytes_per_pixel,
+        pixel_format: match info.pixel_format {
+            PixelFormat::Rgb => kernel_shared::device::PixelFormat::Rgb,
+            PixelFormat::Bgr => kernel_shared::device::PixelFormat::Bgr,
+            PixelFormat::U8 => kernel_shared::device::PixelFormat::Greyscale,
+            _ => kernel_shared::device::PixelFormat::Unknown,
+        },
+        physical_address: physical_address.as_u64(),
+        byte_length: info.byte_len,
+    })
 }
 
+const FRAMEBUFFER_DESCRIPTION_LENGTH: usize = 96;
+
 #[derive(Clone, Copy)]
 struct FramebufferDevice {
-    parent: u128
+    parent: u128,
+    description: [u8; FRAMEBUFFER_DESCRIPTION_LENGTH],
+    description_length: usize,
 }
 
 impl Device for FramebufferDevice {
     fn name(&self) -> alloc::string::String {
         String::from_str("FRAMEBUFFER").unwrap()
     }
+
     fn ready(&self) -> bool {
         true
     }
@@ -125,7 +199,17 @@ impl Device for FramebufferDevice {
     }
 
     fn uuid(&self) -> Uuid {
-        *well_known::FRAMEBUFFER
+        well_known::FRAMEBUFFER
+    }
+
+    fn function(&self, id: usize, _args: &[usize]) -> Result<&[u8], DeviceError> {
+        match id {
+            DEVICE_FUNCTION_DESCRIBE if self.description_length > 0 => {
+                Ok(&self.description[0..self.description_length])
+            }
+            DEVICE_FUNCTION_DESCRIBE => Err(DeviceError::new(DeviceErrorCode::Malfunction)),
+            _ => Err(DeviceError::new(DeviceErrorCode::NotImplemented)),
+        }
     }
 }
 
@@ -134,6 +218,8 @@ pub(crate) struct KernelFramebuffer {
     buffer: *mut u8,
     shadow_buffer: *mut u8,
     surface: *mut u8,
+    // Console drawing that hasn't been composited yet.
+    dirty: Option<Rect>,
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -143,9 +229,8 @@ pub(crate) struct Color {
     pub b: u8,
 }
 
-fn to_percent(num: u8) -> f32 {
-    (num as f32) / 255.0 as f32
-}
+// Luma weights for red, green, and blue, in 256ths, summing to 256.
+const GREYSCALE_WEIGHTS: (u32, u32, u32) = (77, 151, 28);
 
 impl Color {
     pub fn new(r: u8, g: u8, b: u8) -> Color {
@@ -180,16 +265,9 @@ impl Color {
             buffer[1] = self.g;
             buffer[2] = self.b;
         } else if pixel_format == PixelFormat::U8 {
-            let r_percent = to_percent(self.r) * 0.3;
-            let g_percent = to_percent(self.g) * 0.59;
-            let b_percent = to_percent(self.b) * 0.11;
-            let mut greyscale_percent = r_percent + g_percent + b_percent;
-            if greyscale_percent > 1.0 {
-                greyscale_percent = 1.0;
-            } else if greyscale_percent < 0.0 {
-                greyscale_percent = 0.0;
-            }
-            let final_greyscale_value = (255.0 * greyscale_percent) as u8;
+            let (r, g, b) = GREYSCALE_WEIGHTS;
+            let final_greyscale_value =
+                ((self.r as u32 * r + self.g as u32 * g + self.b as u32 * b) >> 8) as u8;
             buffer[0] = final_greyscale_value;
         } else {
             buffer.fill(0);
@@ -211,43 +289,61 @@ impl KernelFramebuffer {
         unsafe { FRAME_BUFFER_INTERNAL.info }
     }
 
-    pub (crate) fn swap_buffer(&self) {
+    pub(crate) fn take_dirty(&mut self) -> Option<Rect> {
+        self.dirty.take()
+    }
+
+    /// Starts composing part of a row, by copying the console's pixels for it into the shadow
+    /// buffer. Returns that part of the shadow buffer, for surfaces to be drawn over.
+    pub(crate) fn composition_row(&mut self, y: usize, x: usize, width: usize) -> &mut [u8] {
         let info = self.info.unwrap();
+        let start = Self::get_buffer_start_offset(x, y, info);
+        let length = width * info.bytes_per_pixel;
         unsafe {
-            if info.byte_len % 8 == 0 {
-                let len = info.byte_len / 8;
-                let buffer = slice::from_raw_parts_mut(self.buffer as *mut u64, len);
-                let shadow = slice::from_raw_parts_mut(self.shadow_buffer as *mut u64, len);
-                let surface = slice::from_raw_parts_mut(self.surface as *mut u64, len);
-                for i in 0..surface.len() {
-                    if shadow[i] != surface[i] {
-                        buffer[i] = surface[i];
-                        shadow[i] = surface[i];
-                    }
-                }
-            } else if info.byte_len % 4 == 0 {
-                let len = info.byte_len / 4;
-                let buffer = slice::from_raw_parts_mut(self.buffer as *mut u32, len);
-                let shadow = slice::from_raw_parts_mut(self.shadow_buffer as *mut u32, len);
-                let surface = slice::from_raw_parts_mut(self.surface as *mut u32, len);
-                for i in 0..surface.len() {
-                    if shadow[i] != surface[i] {
-                        buffer[i] = surface[i];
-                        shadow[i] = surface[i];
-                    }
-                }
-            } else {
-                let buffer = slice::from_raw_parts_mut(self.buffer, info.byte_len);
-                let shadow = slice::from_raw_parts_mut(self.shadow_buffer, info.byte_len);
-                let surface = slice::from_raw_parts_mut(self.surface, info.byte_len);
-                for i in 0..surface.len() {
-                    if shadow[i] != surface[i] {
-                        buffer[i] = surface[i];
-                        shadow[i] = surface[i];
-                    }
-                }
+            memcpy(
+                self.shadow_buffer.add(start),
+                self.surface.add(start),
+                length,
+            );
+            slice::from_raw_parts_mut(self.shadow_buffer.add(start), length)
+        }
+    }
+
+    /// Copies what the console drew in a rectangle, row after row with no padding, in the
+    /// framebuffer's pixel format. Returns the number of bytes copied, whole rows that don't fit
+    /// are left out.
+    pub(crate) fn read_rect(&self, rect: Rect, out: &mut [u8]) -> usize {
+        let info = self.info.unwrap();
+        let rect = match rect.intersection(&Rect::new(0, 0, info.width, info.height)) {
+            Some(rect) => rect,
+            None => return 0,
+        };
+        let row_length = rect.width * info.bytes_per_pixel;
+        let rows = min(rect.height, out.len() / row_length);
+        for row in 0..rows {
+            let start = Self::get_buffer_start_offset(rect.x, rect.y + row, info);
+            unsafe {
+                memcpy(
+                    out[row * row_length..].as_mut_ptr(),
+                    self.surface.add(start),
+                    row_length,
+                );
             }
         }
+        rows * row_length
+    }
+
+    /// Copies a composed part of a row to the screen.
+    pub(crate) fn present_row(&mut self, y: usize, x: usize, width: usize) {
+        let info = self.info.unwrap();
+        let start = Self::get_buffer_start_offset(x, y, info);
+        unsafe {
+            memcpy(
+                self.buffer.add(start),
+                self.shadow_buffer.add(start),
+                width * info.bytes_per_pixel,
+            );
+        }
     }
 
     fn get_buffer_start_offset(x: usize, y: usize, frame_buffer_info: FrameBufferInfo) -> usize {
@@ -283,6 +379,7 @@ impl KernelFramebuffer {
 
         let count = min(fbi.bytes_per_pixel, color.len());
         Self::copy_range(fb_buffer, color, 0, start, count);
+        add_damage(&mut self.dirty, Rect::new(x, y, 1, 1));
     }
     #[inline]
     fn to_framebuffer_color(self: &mut Self, color: &Color) -> Option<Box<[u8]>> {
@@ -341,6 +438,7 @@ impl KernelFramebuffer {
         let end_offset = Self::get_buffer_start_offset(info.width - 1, info.height - 1, info);
         let copy_length = end_offset - start_offset;
         Self::copy_range_self(mut_framebuffer, start_offset, 0, copy_length);
+        add_damage(&mut self.dirty, Rect::new(0, 0, info.width, info.height));
         let clear_color = &Color::black();
         self.draw_rect(0, info.height - lines, info.width, lines, clear_color);
     }
@@ -0,0 +1,132 @@
+use spin::Mutex;
+
+use crate::{boottime, cmdline, debug, initcall, warn};
+
+use super::{
+    bmp::{Bitmap, BmpError},
+    compositor::{self, Rect, SurfaceId},
+    Color, FRAME_BUFFER,
+};
+
+static SPLASH_IMAGE: &[u8] = include_bytes!("splash.bmp");
+
+// Above the console, below anything else.
+const SPLASH_Z: i32 = 100;
+const PROGRESS_BAR_MAX_WIDTH: usize = 400;
+const PROGRESS_BAR_HEIGHT: usize = 8;
+const PROGRESS_BAR_GAP: usize = 24;
+
+struct Splash {
+    surface: SurfaceId,
+    progress_bar: Rect,
+    // The boot stages done when the bar is full: those already done, and an init call each.
+    expected_stages: usize,
+}
+
+static SPLASH: Mutex<Option<Splash>> = Mutex::new(None);
+
+/// Shows the boot splash over the console, if it was asked for with the `splash` flag. If the
+/// image can't be shown, the console is left as it is.
+pub(crate) fn show() {
+    if !cmdline::flag("splash") {
+        return;
+    }
+    match create_splash() {
+        Ok(Some(splash)) => *SPLASH.lock() = Some(splash),
+        Ok(None) => {
+            debug!("No framebuffer, not showing the boot splash");
+        }
+        Err(error) => {
+            warn!("Unable to show the boot splash, {}", error);
+        }
+    }
+    compositor::compose();
+}
+
+fn create_splash() -> Result<Option<Splash>, BmpError> {
+    let image = Bitmap::parse(SPLASH_IMAGE)?;
+    let info = match FRAME_BUFFER
+        .lock()
+        .get_framebuffer()
+        .and_then(|fb| fb.info())
+    {
+        Some(info) => info,
+        None => return Ok(None),
+    };
+    if image.width() > info.width
+        || image.height() + PROGRESS_BAR_GAP + PROGRESS_BAR_HEIGHT > info.height
+    {
+        return Err(BmpError::Unsupported);
+    }
+    let surface =
+        match compositor::create_surface(Rect::new(0, 0, info.width, info.height), SPLASH_Z) {
+            Some(surface) => surface,
+            None => return Ok(None),
+        };
+
+    let image_x = (info.width - image.width()) / 2;
+    let image_y = (info.height - image.height() - PROGRESS_BAR_GAP - PROGRESS_BAR_HEIGHT) / 2;
+    let bar_width = (info.width / 3).min(PROGRESS_BAR_MAX_WIDTH);
+    let progress_bar = Rect::new(
+        (info.width - bar_width) / 2,
+        image_y + image.height() + PROGRESS_BAR_GAP,
+        bar_width,
+        PROGRESS_BAR_HEIGHT,
+    );
+    compositor::with_surface(surface, |s| {
+        s.fill(&Color::black());
+        for y in 0..image.height() {
+            for x in 0..image.width() {
+                s.set_pixel(image_x + x, image_y + y, &image.pixel(x, y));
+            }
+        }
+        draw_outline(s, progress_bar);
+    });
+    Ok(Some(Splash {
+        surface,
+        progress_bar,
+        expected_stages: (boottime::completed() + initcall::pending()).max(1),
+    }))
+}
+
+fn draw_outline(surface: &mut compositor::Surface, rect: Rect) {
+    let white = Color::white();
+    surface.fill_rect(rect.x, rect.y, rect.width, 1, &white);
+    surface.fill_rect(rect.x, rect.bottom() - 1, rect.width, 1, &white);
+    surface.fill_rect(rect.x, rect.y, 1, rect.height, &white);
+    surface.fill_rect(rect.right() - 1, rect.y, 1, rect.height, &white);
+}
+
+/// Called by boottime as each init stage finishes.
+pub(crate) fn boot_stage_completed(completed: usize) {
+    let guard = SPLASH.lock();
+    let splash = match guard.as_ref() {
+        Some(splash) => splash,
+        None => return,
+    };
+    let bar = splash.progress_bar;
+    let inner_width = bar.width - 2;
+    let expected = splash.expected_stages;
+    let filled = inner_width * completed.min(expected) / expected;
+    compositor::with_surface(splash.surface, |s| {
+        s.fill_rect(
+            bar.x + 1,
+            bar.y + 1,
+            filled,
+            bar.height - 2,
+            &Color::white(),
+        )
+    });
+    drop(guard);
+    compositor::compose();
+}
+
+/// Removes the splash, uncovering the console. Safe to call when no splash is showing, it is
+/// called when boot finishes, and should be called on a key press.
+pub(crate) fn dismiss() {
+    let splash = SPLASH.lock().take();
+    if let Some(splash) = splash {
+        compositor::destroy_surface(splash.surface);
+        compositor::compose();
+    }
+}
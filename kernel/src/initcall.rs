@@ -0,0 +1,210 @@
+//! Ordered subsystem initialization. Subsystems register init calls, each with a level and the
+//! names of the calls it depends on, and each level is run in turn: Early and Arch as the boot
+//! CPU's hardware comes up, Driver and Late once the kernel main loop starts. Within a level, a
+//! call runs once everything it depends on has, in the order calls were registered otherwise.
+//!
+//! Every call is timed as a boot stage. A call that fails is reported, and the calls depending on
+//! it are skipped, but boot carries on unless the failed call is required.
+
+use alloc::{collections::BTreeMap, vec::Vec};
+use spin::Mutex;
+
+use crate::{boottime, debug, error, warn};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum InitLevel {
+    /// The boot CPU itself: clocks, descriptor tables.
+    Early,
+    /// The platform: firmware tables, interrupt controllers, other CPUs.
+    Arch,
+    /// Kernel services and devices.
+    Driver,
+    /// Whatever serves userspace, once everything it uses is up.
+    Late,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum InitFunction {
+    Infallible(fn()),
+    /// Returns why it failed.
+    Fallible(fn() -> Result<(), &'static str>),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct InitCall {
+    pub name: &'static str,
+    pub level: InitLevel,
+    /// Calls that must have succeeded first, at this level or an earlier one.
+    pub dependencies: &'static [&'static str],
+    pub function: InitFunction,
+    /// Boot can't carry on if this fails.
+    pub required: bool,
+}
+
+impl InitCall {
+    pub const fn new(
+        name: &'static str,
+        level: InitLevel,
+        dependencies: &'static [&'static str],
+        function: fn(),
+    ) -> Self {
+        Self {
+            name,
+            level,
+            dependencies,
+            function: InitFunction::Infallible(function),
+            required: false,
+        }
+    }
+
+    pub const fn fallible(
+        name: &'static str,
+        level: InitLevel,
+        dependencies: &'static [&'static str],
+        function: fn() -> Result<(), &'static str>,
+    ) -> Self {
+        Self {
+            name,
+            level,
+            dependencies,
+            function: InitFunction::Fallible(function),
+            required: false,
+        }
+    }
+
+    pub const fn required(self) -> Self {
+        Self {
+            required: true,
+            ..self
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InitOutcome {
+    Succeeded,
+    Failed(&'static str),
+    /// Not run, because the named dependency didn't succeed.
+    Skipped(&'static str),
+}
+
+struct InitCalls {
+    calls: Vec<&'static InitCall>,
+    outcomes: BTreeMap<&'static str, InitOutcome>,
+}
+
+static INIT_CALLS: Mutex<InitCalls> = Mutex::new(InitCalls {
+    calls: Vec::new(),
+    outcomes: BTreeMap::new(),
+});
+
+/// Registers init calls, to run when their level does.
+pub fn register(calls: &'static [InitCall]) {
+    let mut init_calls = INIT_CALLS.lock();
+    for call in calls {
+        if init_calls.calls.iter().any(|c| c.name == call.name) {
+            panic!("Init call {} registered twice", call.name);
+        }
+        init_calls.calls.push(call);
+    }
+}
+
+/// How an init call went, None if it hasn't run.
+pub fn outcome(name: &str) -> Option<InitOutcome> {
+    INIT_CALLS.lock().outcomes.get(name).copied()
+}
+
+/// How many registered calls haven't run yet, each one is a boot stage still to come.
+pub fn pending() -> usize {
+    let init_calls = INIT_CALLS.lock();
+    init_calls
+        .calls
+        .iter()
+        .filter(|call| !init_calls.outcomes.contains_key(call.name))
+        .count()
+}
+
+pub fn succeeded(name: &str) -> bool {
+    outcome(name) == Some(InitOutcome::Succeeded)
+}
+
+/// Runs every init call registered at level, in dependency order. Panics if the dependencies
+/// can't be satisfied: an unknown name, one from a later level, or a cycle.
+pub(crate) fn run_level(level: InitLevel) {
+    debug!("Running {:?} init calls", level);
+    while let Some((call, blocked_by)) = next_call(level) {
+        let outcome = match blocked_by {
+            Some(dependency) => {
+                warn!(
+                    "Skipping init of {}, {} did not succeed",
+                    call.name, dependency
+                );
+                InitOutcome::Skipped(dependency)
+            }
+            // Without the lock, so init calls can register more.
+            None => {
+                debug!("Initializing {}", call.name);
+                boottime::stage(call.name, || match call.function {
+                    InitFunction::Infallible(function) => {
+                        function();
+                        InitOutcome::Succeeded
+                    }
+                    InitFunction::Fallible(function) => match function() {
+                        Ok(()) => InitOutcome::Succeeded,
+                        Err(reason) => InitOutcome::Failed(reason),
+                    },
+                })
+            }
+        };
+        if outcome != InitOutcome::Succeeded && call.required {
+            panic!("Init of {} is required, but it {:?}", call.name, outcome);
+        }
+        if let InitOutcome::Failed(reason) = outcome {
+            error!("Init of {} failed: {}", call.name, reason);
+        }
+        INIT_CALLS.lock().outcomes.insert(call.name, outcome);
+    }
+}
+
+// The next call at level whose dependencies have all run, and the first of them that didn't
+// succeed, if any.
+fn next_call(level: InitLevel) -> Option<(&'static InitCall, Option<&'static str>)> {
+    let init_calls = INIT_CALLS.lock();
+    let level_of = |name: &str| {
+        init_calls
+            .calls
+            .iter()
+            .find(|c| c.name == name)
+            .map(|c| c.level)
+    };
+    let mut pending = init_calls
+        .calls
+        .iter()
+        .filter(|c| c.level == level && !init_calls.outcomes.contains_key(c.name))
+        .peekable();
+    pending.peek()?;
+    for call in pending.clone() {
+        for dependency in call.dependencies {
+            match level_of(dependency) {
+                Some(dependency_level) if dependency_level <= level => {}
+                Some(_) => panic!(
+                    "Init of {} depends on {}, which is at a later level",
+                    call.name, dependency
+                ),
+                None => panic!("Init of {} depends on unknown {}", call.name, dependency),
+            }
+        }
+        let mut outcomes = call
+            .dependencies
+            .iter()
+            .map(|d| (*d, init_calls.outcomes.get(d)));
+        if outcomes.clone().all(|(_, outcome)| outcome.is_some()) {
+            let blocked_by = outcomes
+                .find(|(_, outcome)| *outcome != Some(&InitOutcome::Succeeded))
+                .map(|(dependency, _)| dependency);
+            return Some((call, blocked_by));
+        }
+    }
+    let names: Vec<&str> = pending.map(|c| c.name).collect();
+    panic!("Init calls depend on each other in a cycle: {:?}", names);
+}
@@ -0,0 +1,89 @@
+use alloc::{
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use kernel_shared::{
+    channel::Channel,
+    input::{InputEvent, EV_SYN, SYN_DROPPED},
+};
+use spin::Mutex;
+
+use crate::time;
+
+const READER_QUEUE_LENGTH: usize = 256;
+
+struct ReaderQueue {
+    events: Channel<InputEvent, READER_QUEUE_LENGTH>,
+    // Set when an event couldn't be queued. Nothing more is queued until the reader has had what
+    // was queued before the loss, then a SYN_DROPPED.
+    overflowed: AtomicBool,
+}
+
+static READERS: Mutex<Vec<Weak<ReaderQueue>>> = Mutex::new(Vec::new());
+
+/// A reader's view of every input event, from when it was opened. Each reader has its own
+/// queue, a slow reader only loses its own events.
+pub(crate) struct InputReader {
+    queue: Arc<ReaderQueue>,
+}
+
+pub(crate) fn open_reader() -> InputReader {
+    let queue = Arc::new(ReaderQueue {
+        events: Channel::new(),
+        overflowed: AtomicBool::new(false),
+    });
+    READERS.lock().push(Arc::downgrade(&queue));
+    InputReader { queue }
+}
+
+/// Queues an event for every open reader.
+pub(crate) fn publish(event: InputEvent) {
+    let mut readers = READERS.lock();
+    readers.retain(|reader| match reader.upgrade() {
+        Some(queue) => {
+            // Once an event is lost the rest are too, until the reader catches up.
+            if !queue.overflowed.load(Ordering::Acquire) && queue.events.try_send(event).is_err() {
+                queue.overflowed.store(true, Ordering::Release);
+            }
+            true
+        }
+        // The reader was closed.
+        None => false,
+    });
+}
+
+impl InputReader {
+    /// Returns the next event, or None if there isn't one waiting.
+    pub(crate) fn read(&self) -> Option<InputEvent> {
+        // Read first, once it's set nothing more is queued, so an empty queue stays empty.
+        let overflowed = self.queue.overflowed.load(Ordering::Acquire);
+        // Only this reader takes from its queue, so claiming the receiver always succeeds.
+        if let Some(event) = self.queue.events.receiver().and_then(|mut r| r.try_recv()) {
+            return Some(event);
+        }
+        if overflowed {
+            self.queue.overflowed.store(false, Ordering::Release);
+            // Events were lost after the ones already read, the reader has to resynchronize.
+            return Some(InputEvent::new(
+                time::monotonic_nanoseconds(),
+                EV_SYN,
+                SYN_DROPPED,
+                0,
+            ));
+        }
+        None
+    }
+
+    /// Waits for the next event. Input is polled, so this polls while it waits.
+    pub(crate) fn read_blocking(&self) -> InputEvent {
+        loop {
+            if let Some(event) = self.read() {
+                return event;
+            }
+            super::poll();
+            core::hint::spin_loop();
+        }
+    }
+}
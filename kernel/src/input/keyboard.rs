@@ -0,0 +1,160 @@
+use kernel_shared::input::*;
+
+use super::keymap::{compose, Keymap};
+
+const EXTENDED_PREFIX: u8 = 0xE0;
+const RELEASED: u8 = 0x80;
+
+/// Key codes for scancodes after the extended prefix. Those not listed are ignored.
+const EXTENDED_KEYS: [(u8, u16); 17] = [
+    (0x1C, KEY_KPENTER),
+    (0x1D, KEY_RIGHTCTRL),
+    (0x35, KEY_KPSLASH),
+    (0x38, KEY_RIGHTALT),
+    (0x47, KEY_HOME),
+    (0x48, KEY_UP),
+    (0x49, KEY_PAGEUP),
+    (0x4B, KEY_LEFT),
+    (0x4D, KEY_RIGHT),
+    (0x4F, KEY_END),
+    (0x50, KEY_DOWN),
+    (0x51, KEY_PAGEDOWN),
+    (0x52, KEY_INSERT),
+    (0x53, KEY_DELETE),
+    (0x5B, KEY_LEFTMETA),
+    (0x5C, KEY_RIGHTMETA),
+    (0x5D, KEY_COMPOSE),
+];
+
+/// Turns set 1 scancodes into key codes, and tells repeats from presses.
+pub(crate) struct ScancodeDecoder {
+    extended: bool,
+    held: [u64; (KEY_MAX as usize + 1) / 64],
+}
+
+impl ScancodeDecoder {
+    pub(crate) const fn new() -> Self {
+        Self {
+            extended: false,
+            held: [0; (KEY_MAX as usize + 1) / 64],
+        }
+    }
+
+    /// Returns the key code, and its EV_KEY value, once a whole scancode has been read.
+    pub(crate) fn feed(&mut self, scancode: u8) -> Option<(u16, i32)> {
+        if scancode == EXTENDED_PREFIX {
+            self.extended = true;
+            return None;
+        }
+        let extended = core::mem::replace(&mut self.extended, false);
+        let released = scancode & RELEASED != 0;
+        let scancode = scancode & !RELEASED;
+        let code = match extended {
+            // The main block's key codes are its scancodes.
+            false => scancode as u16,
+            true => EXTENDED_KEYS.iter().find(|(s, _)| *s == scancode)?.1,
+        };
+
+        let (word, bit) = (code as usize / 64, 1u64 << (code % 64));
+        let value = match (released, self.held[word] & bit != 0) {
+            (true, _) => {
+                self.held[word] &= !bit;
+                KEY_RELEASED
+            }
+            (false, true) => KEY_REPEATED,
+            (false, false) => {
+                self.held[word] |= bit;
+                KEY_PRESSED
+            }
+        };
+        Some((code, value))
+    }
+}
+
+/// Turns key events into characters for the console, tracking modifiers and dead keys.
+pub(crate) struct Keyboard {
+    left_shift: bool,
+    right_shift: bool,
+    control: bool,
+    alt_gr: bool,
+    caps_lock: bool,
+    dead_key: Option<char>,
+}
+
+impl Keyboard {
+    pub(crate) const fn new() -> Self {
+        Self {
+            left_shift: false,
+            right_shift: false,
+            control: false,
+            alt_gr: false,
+            caps_lock: false,
+            dead_key: None,
+        }
+    }
+
+    /// Passes whatever the key types to emit. Keys without a character, like the arrows, are sent
+    /// as ANSI escape sequences.
+    pub(crate) fn feed(
+        &mut self,
+        code: u16,
+        value: i32,
+        keymap: &Keymap,
+        mut emit: impl FnMut(char),
+    ) {
+        let down = value != KEY_RELEASED;
+        match code {
+            KEY_LEFTSHIFT => self.left_shift = down,
+            KEY_RIGHTSHIFT => self.right_shift = down,
+            KEY_LEFTCTRL | KEY_RIGHTCTRL => self.control = down,
+            KEY_RIGHTALT => self.alt_gr = down,
+            KEY_CAPSLOCK if value == KEY_PRESSED => self.caps_lock = !self.caps_lock,
+            _ if !down => {}
+            KEY_ENTER | KEY_KPENTER => emit('\n'),
+            KEY_UP => Self::escape_sequence('A', &mut emit),
+            KEY_DOWN => Self::escape_sequence('B', &mut emit),
+            KEY_RIGHT => Self::escape_sequence('C', &mut emit),
+            KEY_LEFT => Self::escape_sequence('D', &mut emit),
+            KEY_ESC => emit('\x1b'),
+            KEY_BACKSPACE => emit('\x08'),
+            KEY_TAB => emit('\t'),
+            KEY_SPACE => self.type_char(' ', &mut emit),
+            KEY_KPSLASH => self.type_char('/', &mut emit),
+            code if code < 0x80 => {
+                let shift = self.left_shift || self.right_shift;
+                if let Some(c) = keymap.translate(code as u8, shift, self.caps_lock, self.alt_gr) {
+                    if self.dead_key.is_none() && keymap.is_dead_key(c) {
+                        self.dead_key = Some(c);
+                    } else if self.control && c.is_ascii_alphabetic() {
+                        emit((c.to_ascii_lowercase() as u8 & 0x1F) as char);
+                    } else {
+                        self.type_char(c, &mut emit);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Types a character, accented by a pending dead key if there is one. A dead key followed by
+    /// something it can't accent types both.
+    fn type_char(&mut self, c: char, emit: &mut impl FnMut(char)) {
+        match self.dead_key.take() {
+            None => emit(c),
+            Some(dead) if c == ' ' || c == dead => emit(dead),
+            Some(dead) => match compose(dead, c) {
+                Some(composed) => emit(composed),
+                None => {
+                    emit(dead);
+                    emit(c);
+                }
+            },
+        }
+    }
+
+    fn escape_sequence(command: char, emit: &mut impl FnMut(char)) {
+        emit('\x1b');
+        emit('[');
+        emit(command);
+    }
+}
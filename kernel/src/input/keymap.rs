@@ -0,0 +1,187 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use kernel_shared::device::KEYBOARD_KEYMAPS;
+
+// First scancode (set 1) of each row of printing keys, the row strings run left to right from it.
+const ROW_STARTS: [u8; 5] = [0x02, 0x10, 0x1E, 0x2B, 0x56];
+
+/// Translates the printing keys, by position, into characters. Everything else (enter, arrows,
+/// modifiers) is the same on every layout, and handled by the keyboard.
+pub(crate) struct Keymap {
+    pub name: &'static str,
+    normal: [&'static str; 5],
+    shift: [&'static str; 5],
+    alt_gr: &'static [(u8, char)],
+    /// Characters that don't print, but accent the next key.
+    dead_keys: &'static [char],
+}
+
+impl Keymap {
+    fn find(rows: &[&'static str; 5], scancode: u8) -> Option<char> {
+        let (row, start) = ROW_STARTS
+            .iter()
+            .enumerate()
+            .filter(|(_, start)| scancode >= **start)
+            .last()?;
+        rows[row].chars().nth((scancode - start) as usize)
+    }
+
+    pub(crate) fn translate(
+        &self,
+        scancode: u8,
+        shift: bool,
+        caps_lock: bool,
+        alt_gr: bool,
+    ) -> Option<char> {
+        if alt_gr {
+            return self
+                .alt_gr
+                .iter()
+                .find(|(code, _)| *code == scancode)
+                .map(|(_, c)| *c);
+        }
+        let normal = Self::find(&self.normal, scancode)?;
+        // Caps lock only shifts letters.
+        let shift = shift ^ (caps_lock && normal.is_alphabetic());
+        match shift {
+            true => Self::find(&self.shift, scancode),
+            false => Some(normal),
+        }
+    }
+
+    pub(crate) fn is_dead_key(&self, c: char) -> bool {
+        self.dead_keys.contains(&c)
+    }
+}
+
+/// Accents a character with a dead key, None if there is no such combination.
+pub(crate) fn compose(dead: char, c: char) -> Option<char> {
+    let (from, to) = match dead {
+        '´' => ("aeiouyAEIOUY", "áéíóúýÁÉÍÓÚÝ"),
+        '`' => ("aeiouAEIOU", "àèìòùÀÈÌÒÙ"),
+        '^' => ("aeiouAEIOU", "âêîôûÂÊÎÔÛ"),
+        '¨' => ("aeiouyAEIOU", "äëïöüÿÄËÏÖÜ"),
+        '~' => ("anoANO", "ãñõÃÑÕ"),
+        _ => return None,
+    };
+    let index = from.chars().position(|f| f == c)?;
+    to.chars().nth(index)
+}
+
+static US: Keymap = Keymap {
+    name: "us",
+    normal: [
+        "1234567890-=",
+        "qwertyuiop[]",
+        "asdfghjkl;'`",
+        "\\zxcvbnm,./",
+        "\\",
+    ],
+    shift: [
+        "!@#$%^&*()_+",
+        "QWERTYUIOP{}",
+        "ASDFGHJKL:\"~",
+        "|ZXCVBNM<>?",
+        "|",
+    ],
+    alt_gr: &[],
+    dead_keys: &[],
+};
+
+static UK: Keymap = Keymap {
+    name: "uk",
+    normal: [
+        "1234567890-=",
+        "qwertyuiop[]",
+        "asdfghjkl;'`",
+        "#zxcvbnm,./",
+        "\\",
+    ],
+    shift: [
+        "!\"£$%^&*()_+",
+        "QWERTYUIOP{}",
+        "ASDFGHJKL:@¬",
+        "~ZXCVBNM<>?",
+        "|",
+    ],
+    alt_gr: &[(0x05, '€'), (0x29, '¦')],
+    dead_keys: &[],
+};
+
+static DE: Keymap = Keymap {
+    name: "de",
+    normal: [
+        "1234567890ß´",
+        "qwertzuiopü+",
+        "asdfghjklöä^",
+        "#yxcvbnm,.-",
+        "<",
+    ],
+    shift: [
+        "!\"§$%&/()=?`",
+        "QWERTZUIOPÜ*",
+        "ASDFGHJKLÖÄ°",
+        "'YXCVBNM;:_",
+        ">",
+    ],
+    alt_gr: &[
+        (0x03, '²'),
+        (0x04, '³'),
+        (0x08, '{'),
+        (0x09, '['),
+        (0x0A, ']'),
+        (0x0B, '}'),
+        (0x0C, '\\'),
+        (0x10, '@'),
+        (0x12, '€'),
+        (0x1B, '~'),
+        (0x32, 'µ'),
+        (0x56, '|'),
+    ],
+    dead_keys: &['´', '`', '^'],
+};
+
+static DVORAK: Keymap = Keymap {
+    name: "dvorak",
+    normal: [
+        "1234567890[]",
+        "',.pyfgcrl/=",
+        "aoeuidhtns-`",
+        "\\;qjkxbmwvz",
+        "\\",
+    ],
+    shift: [
+        "!@#$%^&*(){}",
+        "\"<>PYFGCRL?+",
+        "AOEUIDHTNS_~",
+        "|:QJKXBMWVZ",
+        "|",
+    ],
+    alt_gr: &[],
+    dead_keys: &[],
+};
+
+// Same order as KEYBOARD_KEYMAPS.
+static KEYMAPS: [&Keymap; KEYBOARD_KEYMAPS.len()] = [&US, &UK, &DE, &DVORAK];
+static ACTIVE_KEYMAP: AtomicUsize = AtomicUsize::new(0);
+
+pub(crate) fn active_keymap() -> &'static Keymap {
+    KEYMAPS[ACTIVE_KEYMAP.load(Ordering::Relaxed)]
+}
+
+/// The active layout's index in KEYBOARD_KEYMAPS.
+pub(crate) fn active_keymap_index() -> usize {
+    ACTIVE_KEYMAP.load(Ordering::Relaxed)
+}
+
+/// Switches to the layout at an index in KEYBOARD_KEYMAPS.
+pub(crate) fn set_keymap(index: usize) -> Option<&'static Keymap> {
+    let keymap = KEYMAPS.get(index)?;
+    ACTIVE_KEYMAP.store(index, Ordering::Relaxed);
+    Some(keymap)
+}
+
+pub(crate) fn set_keymap_by_name(name: &str) -> Option<&'static Keymap> {
+    let index = KEYMAPS.iter().position(|k| k.name == name)?;
+    set_keymap(index)
+}
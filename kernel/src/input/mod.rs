@@ -0,0 +1,175 @@
+use alloc::string::{String, ToString};
+use devices::{
+    get_mut_device_tree,
+    well_known::{self, IPL},
+    Device, DeviceError, DeviceErrorCode,
+};
+use kernel_shared::{
+    channel::{Channel, Receiver},
+    device::{
+        KeyboardDescription, DEVICE_FUNCTION_DESCRIBE, KEYBOARD_FUNCTION_SET_KEYMAP,
+        KEYBOARD_KEYMAPS,
+    },
+    input::{InputEvent, EV_KEY},
+    serialization::to_bytes,
+};
+use spin::Mutex;
+use uuid::Uuid;
+
+use crate::{
+    arch::{self, InputByte},
+    cmdline, debug,
+    framebuffer::splash,
+    time, warn,
+};
+
+use self::{
+    keyboard::{Keyboard, ScancodeDecoder},
+    mouse::MouseDecoder,
+};
+
+pub(crate) mod events;
+pub(crate) mod keyboard;
+pub(crate) mod keymap;
+pub(crate) mod mouse;
+
+struct InputState {
+    scancodes: ScancodeDecoder,
+    mouse: MouseDecoder,
+    // The console's reader, it sees the same events as everyone else.
+    keyboard: Keyboard,
+}
+
+static INPUT_STATE: Mutex<InputState> = Mutex::new(InputState {
+    scancodes: ScancodeDecoder::new(),
+    mouse: MouseDecoder::new(),
+    keyboard: Keyboard::new(),
+});
+static KEYBOARD_INPUT: Channel<char, 128> = Channel::new();
+const MAX_BYTES_PER_POLL: usize = 256;
+
+pub(crate) fn init() {
+    if let Some(name) = cmdline::get("keymap") {
+        match keymap::set_keymap_by_name(name) {
+            Some(keymap) => {
+                debug!("Using the {} keyboard layout", keymap.name);
+            }
+            None => {
+                warn!(
+                    "Unknown keyboard layout {}, using {}",
+                    name,
+                    keymap::active_keymap().name
+                );
+            }
+        }
+    }
+    if !arch::probe_input() {
+        debug!("No PS/2 controller found");
+    } else if !arch::enable_mouse() {
+        debug!("No PS/2 mouse found");
+    }
+    get_mut_device_tree().register(KeyboardDevice::new(IPL.as_u128()));
+}
+
+/// Called from the idle loop, there's no input interrupt routing yet. Events are published to
+/// every reader, and typed characters are queued for the console's reader.
+pub(crate) fn poll() {
+    // Only one CPU needs to poll, and bytes must be decoded in order.
+    let mut state = match INPUT_STATE.try_lock() {
+        Some(state) => state,
+        None => return,
+    };
+    let mut pressed = false;
+    // Bounded, so a controller that never runs dry can't hold the idle loop here.
+    for byte in core::iter::from_fn(arch::read_input_byte).take(MAX_BYTES_PER_POLL) {
+        let timestamp = time::monotonic_nanoseconds();
+        match byte {
+            InputByte::Keyboard(scancode) => {
+                let (code, value) = match state.scancodes.feed(scancode) {
+                    Some(key) => key,
+                    None => continue,
+                };
+                pressed = true;
+                events::publish(InputEvent::new(timestamp, EV_KEY, code, value));
+                events::publish(InputEvent::sync(timestamp));
+                state
+                    .keyboard
+                    .feed(code, value, keymap::active_keymap(), |c| {
+                        // Dropped if nobody is reading.
+                        let _ = KEYBOARD_INPUT.try_send(c);
+                    });
+            }
+            InputByte::Mouse(byte) => state.mouse.feed(byte, |kind, code, value| {
+                events::publish(InputEvent::new(timestamp, kind, code, value))
+            }),
+        }
+    }
+    drop(state);
+    if pressed {
+        splash::dismiss();
+    }
+}
+
+/// The reader of typed characters, there can only be one at a time.
+pub(crate) fn keyboard_reader() -> Option<Receiver<'static, char, 128>> {
+    KEYBOARD_INPUT.receiver()
+}
+
+const KEYBOARD_DESCRIPTION_LENGTH: usize = 32;
+
+struct KeyboardDevice {
+    parent: u128,
+    // One per layout, in KEYBOARD_KEYMAPS order, describe returns the active one's.
+    descriptions: [([u8; KEYBOARD_DESCRIPTION_LENGTH], usize); KEYBOARD_KEYMAPS.len()],
+}
+
+impl KeyboardDevice {
+    fn new(parent: u128) -> Self {
+        let descriptions = KEYBOARD_KEYMAPS.map(|keymap| {
+            let mut description = [0u8; KEYBOARD_DESCRIPTION_LENGTH];
+            let length = to_bytes(&KeyboardDescription { keymap }, &mut description)
+                .map_or(0, |bytes| bytes.len());
+            (description, length)
+        });
+        Self {
+            parent,
+            descriptions,
+        }
+    }
+}
+
+impl Device for KeyboardDevice {
+    fn name(&self) -> String {
+        "KEYBOARD".to_string()
+    }
+
+    fn ready(&self) -> bool {
+        true
+    }
+
+    fn parent_id(&self) -> Option<u128> {
+        Some(self.parent)
+    }
+
+    fn uuid(&self) -> Uuid {
+        well_known::KEYBOARD
+    }
+
+    fn function(&self, id: usize, args: &[usize]) -> Result<&[u8], DeviceError> {
+        match id {
+            DEVICE_FUNCTION_DESCRIBE => {
+                match self.descriptions.get(keymap::active_keymap_index()) {
+                    Some((description, length)) if *length > 0 => Ok(&description[0..*length]),
+                    _ => Err(DeviceError::new(DeviceErrorCode::Malfunction)),
+                }
+            }
+            KEYBOARD_FUNCTION_SET_KEYMAP => {
+                match args.first().and_then(|index| keymap::set_keymap(*index)) {
+                    Some(keymap) => Ok(keymap.name.as_bytes()),
+                    None => Err(DeviceError::new(DeviceErrorCode::Malfunction)),
+                }
+            }
+            _ => Err(DeviceError::new(DeviceErrorCode::NotImplemented)),
+        }
+    }
+}
@@ -0,0 +1,64 @@
+use kernel_shared::input::*;
+
+const ALWAYS_SET: u8 = 0x08;
+const X_SIGN: u8 = 0x10;
+const Y_SIGN: u8 = 0x20;
+const OVERFLOW: u8 = 0xC0;
+const BUTTONS: [(u8, u16); 3] = [(0x01, BTN_LEFT), (0x02, BTN_RIGHT), (0x04, BTN_MIDDLE)];
+
+/// Assembles standard 3 byte PS/2 mouse packets into events.
+pub(crate) struct MouseDecoder {
+    packet: [u8; 3],
+    length: usize,
+    buttons: u8,
+}
+
+impl MouseDecoder {
+    pub(crate) const fn new() -> Self {
+        Self {
+            packet: [0; 3],
+            length: 0,
+            buttons: 0,
+        }
+    }
+
+    /// Passes the events for each whole packet to emit, as (kind, code, value), ending with a
+    /// SYN_REPORT.
+    pub(crate) fn feed(&mut self, byte: u8, mut emit: impl FnMut(u16, u16, i32)) {
+        // The first byte always has bit 3 set, skip anything else to get back in step.
+        if self.length == 0 && byte & ALWAYS_SET == 0 {
+            return;
+        }
+        self.packet[self.length] = byte;
+        self.length += 1;
+        if self.length < self.packet.len() {
+            return;
+        }
+        self.length = 0;
+
+        let [flags, x, y] = self.packet;
+        if flags & OVERFLOW == 0 {
+            let dx = x as i32 - if flags & X_SIGN != 0 { 0x100 } else { 0 };
+            let dy = y as i32 - if flags & Y_SIGN != 0 { 0x100 } else { 0 };
+            if dx != 0 {
+                emit(EV_REL, REL_X, dx);
+            }
+            // PS/2 counts up as positive, events count down.
+            if dy != 0 {
+                emit(EV_REL, REL_Y, -dy);
+            }
+        }
+        for (mask, code) in BUTTONS {
+            if (flags ^ self.buttons) & mask != 0 {
+                let value = if flags & mask != 0 {
+                    KEY_PRESSED
+                } else {
+                    KEY_RELEASED
+                };
+                emit(EV_KEY, code, value);
+            }
+        }
+        self.buttons = flags & 0x07;
+        emit(EV_SYN, SYN_REPORT, 0);
+    }
+}
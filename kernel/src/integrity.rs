@@ -0,0 +1,125 @@
+//! Checks that the kernel's code and constants are what was built, catching corruption from stray
+//! writes and DMA. The runner's build script seals the image with a digest of its read-only
+//! segments, see kernel_shared::integrity, which is checked at boot, and every
+//! `integrity.interval_s` seconds after if that tunable is set.
+
+use core::{ptr::addr_of, slice};
+
+use kernel_shared::{
+    integrity::{IntegrityRecord, ReadOnlySegments, Sha256, INTEGRITY_MAGIC},
+    tunable::TunableValue,
+};
+use spin::Mutex;
+
+use crate::{
+    debug, error,
+    time::work::{cancel_work, schedule_periodic_work, WorkId},
+    tunables, warn,
+};
+
+// The ELF64 header.
+const HEADER_LENGTH: usize = 64;
+const MAX_INTERVAL_SECONDS: i64 = 24 * 60 * 60;
+
+// Written by the build script, in writable data so it's outside what's hashed. Only ever read
+// volatile, the compiler would otherwise fold in the unsealed value.
+#[no_mangle]
+#[used]
+static mut OXIDIZED_INTEGRITY: IntegrityRecord = IntegrityRecord::unsealed();
+
+extern "C" {
+    // Defined by the linker, the ELF header as loaded, at the start of the first segment.
+    static __ehdr_start: u8;
+}
+
+static PERIODIC_CHECK: Mutex<Option<WorkId>> = Mutex::new(None);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IntegrityStatus {
+    Intact,
+    /// Not built by the runner, so there's nothing to compare against.
+    Unsealed,
+    /// The loaded image isn't what was built.
+    Mismatch,
+    /// The loaded ELF headers don't make sense.
+    Unreadable,
+}
+
+pub(crate) fn init() {
+    match check() {
+        IntegrityStatus::Intact => {
+            debug!("Kernel image integrity verified");
+        }
+        IntegrityStatus::Unsealed => {
+            warn!("Kernel image is unsealed, integrity is not checked");
+        }
+        status => report(status),
+    }
+    tunables::register_integer(
+        "integrity.interval_s",
+        0,
+        0,
+        MAX_INTERVAL_SECONDS,
+        set_interval,
+    );
+}
+
+fn set_interval(value: &TunableValue) -> bool {
+    let mut periodic_check = PERIODIC_CHECK.lock();
+    if let Some(id) = periodic_check.take() {
+        cancel_work(id);
+    }
+    if value.integer > 0 {
+        let period = value.integer as u64 * 1_000_000_000;
+        *periodic_check = Some(schedule_periodic_work(period, check_periodically, 0));
+    }
+    true
+}
+
+fn check_periodically(_context: usize) {
+    match check() {
+        IntegrityStatus::Intact | IntegrityStatus::Unsealed => {}
+        status => report(status),
+    }
+}
+
+fn report(status: IntegrityStatus) {
+    error!(
+        "Kernel image integrity check failed: {:?}, code or constants have been overwritten",
+        status
+    );
+}
+
+/// Hashes the loaded image, and compares it with the digest it was sealed with.
+pub fn check() -> IntegrityStatus {
+    let record = unsafe { core::ptr::read_volatile(addr_of!(OXIDIZED_INTEGRITY)) };
+    if record.magic != INTEGRITY_MAGIC || record.sealed == 0 {
+        return IntegrityStatus::Unsealed;
+    }
+    match hash_loaded_image() {
+        Some((digest, length)) if digest == record.digest && length == record.length => {
+            IntegrityStatus::Intact
+        }
+        Some(_) => IntegrityStatus::Mismatch,
+        None => IntegrityStatus::Unreadable,
+    }
+}
+
+// The digest of the read-only segments where they were loaded, and how many bytes it covers.
+fn hash_loaded_image() -> Option<([u8; 32], u64)> {
+    let start = unsafe { addr_of!(__ehdr_start) };
+    let header = unsafe { slice::from_raw_parts(start, HEADER_LENGTH) };
+    let headers_length = ReadOnlySegments::headers_length(header)?;
+    let headers = unsafe { slice::from_raw_parts(start, headers_length) };
+    // How far from its link address the image was loaded.
+    let load_offset = (start as u64).wrapping_sub(ReadOnlySegments::header_address(headers)?);
+
+    let mut sha = Sha256::new();
+    let mut length = 0;
+    for segment in ReadOnlySegments::new(headers)? {
+        let address = segment.virtual_address.wrapping_add(load_offset) as *const u8;
+        sha.update(unsafe { slice::from_raw_parts(address, segment.file_size as usize) });
+        length += segment.file_size;
+    }
+    Some((sha.finish(), length))
+}
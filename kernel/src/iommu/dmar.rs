@@ -0,0 +1,157 @@
+//! The ACPI DMA remapping table, which lists the remapping units, the functions each one
+//! translates for, and memory the firmware needs those functions to keep reaching.
+
+use alloc::vec::Vec;
+use kernel_shared::memory_range::MemoryRange;
+
+use crate::pci::{PciAddress, SECONDARY_BUS, SUBORDINATE_BUS};
+
+// The standard table header, then the host address width, flags, and reserved bytes.
+const HOST_ADDRESS_WIDTH: usize = 36;
+const STRUCTURES_START: usize = 48;
+
+const STRUCTURE_HARDWARE_UNIT: u16 = 0;
+const STRUCTURE_RESERVED_MEMORY: u16 = 1;
+const HARDWARE_UNIT_SCOPES: usize = 16;
+const RESERVED_MEMORY_SCOPES: usize = 24;
+const INCLUDE_PCI_ALL: u8 = 1 << 0;
+
+const SCOPE_ENDPOINT: u8 = 1;
+const SCOPE_BRIDGE: u8 = 2;
+const SCOPE_PATH: usize = 6;
+
+/// A function, or a bridge and everything behind it, by the path from a bus on the first
+/// segment.
+#[derive(Debug, Clone)]
+pub struct DeviceScope {
+    kind: u8,
+    start_bus: u8,
+    path: Vec<(u8, u8)>,
+}
+
+impl DeviceScope {
+    /// The function the path ends at, following each bridge along it to its secondary bus.
+    fn function(&self) -> Option<PciAddress> {
+        let (last, bridges) = self.path.split_last()?;
+        let mut bus = self.start_bus;
+        for (device, function) in bridges {
+            bus = PciAddress::new(bus, *device, *function).read_u8(SECONDARY_BUS);
+        }
+        Some(PciAddress::new(bus, last.0, last.1))
+    }
+
+    pub fn covers(&self, target: PciAddress) -> bool {
+        let function = match self.function() {
+            Some(function) => function,
+            None => return false,
+        };
+        match self.kind {
+            SCOPE_ENDPOINT => function == target,
+            SCOPE_BRIDGE => {
+                let buses = function.read_u8(SECONDARY_BUS)..=function.read_u8(SUBORDINATE_BUS);
+                function == target || buses.contains(&target.bus)
+            }
+            // IOAPICs and HPETs, which aren't PCI functions.
+            _ => false,
+        }
+    }
+}
+
+/// A remapping unit, and the functions it translates for.
+#[derive(Debug, Clone)]
+pub struct HardwareUnit {
+    pub segment: u16,
+    pub register_base: u64,
+    pub register_pages: usize,
+    /// Translates for every function on the segment not claimed by another unit.
+    pub include_all: bool,
+    pub scopes: Vec<DeviceScope>,
+}
+
+/// Memory the firmware keeps using for DMA after boot, like USB keyboard emulation, which
+/// must stay identity mapped for the functions in scope.
+#[derive(Debug, Clone)]
+pub struct ReservedMemory {
+    pub segment: u16,
+    pub range: MemoryRange,
+    pub scopes: Vec<DeviceScope>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Dmar {
+    /// The widest DMA address the platform supports, in bits.
+    pub host_address_width: u8,
+    pub units: Vec<HardwareUnit>,
+    pub reserved: Vec<ReservedMemory>,
+}
+
+fn read_u16(table: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(
+        table.get(offset..offset + 2)?.try_into().ok()?,
+    ))
+}
+
+fn read_u64(table: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(
+        table.get(offset..offset + 8)?.try_into().ok()?,
+    ))
+}
+
+fn parse_scopes(mut scopes: &[u8]) -> Vec<DeviceScope> {
+    let mut parsed = Vec::new();
+    while scopes.len() >= SCOPE_PATH {
+        let length = scopes[1] as usize;
+        if length < SCOPE_PATH || length > scopes.len() {
+            break;
+        }
+        parsed.push(DeviceScope {
+            kind: scopes[0],
+            start_bus: scopes[5],
+            path: scopes[SCOPE_PATH..length]
+                .chunks_exact(2)
+                .map(|entry| (entry[0], entry[1]))
+                .collect(),
+        });
+        scopes = &scopes[length..];
+    }
+    parsed
+}
+
+/// Parses the table, None if it's malformed. Structures of other types are skipped.
+pub fn parse(table: &[u8]) -> Option<Dmar> {
+    let mut dmar = Dmar {
+        host_address_width: *table.get(HOST_ADDRESS_WIDTH)? + 1,
+        units: Vec::new(),
+        reserved: Vec::new(),
+    };
+    let mut offset = STRUCTURES_START;
+    while offset + 4 <= table.len() {
+        let kind = read_u16(table, offset)?;
+        let length = read_u16(table, offset + 2)? as usize;
+        if length < 4 {
+            return None;
+        }
+        let structure = table.get(offset..offset + length)?;
+        match kind {
+            STRUCTURE_HARDWARE_UNIT => dmar.units.push(HardwareUnit {
+                segment: read_u16(structure, 6)?,
+                register_base: read_u64(structure, 8)?,
+                register_pages: 1 << (*structure.get(5)? & 0xF),
+                include_all: structure[4] & INCLUDE_PCI_ALL != 0,
+                scopes: parse_scopes(structure.get(HARDWARE_UNIT_SCOPES..)?),
+            }),
+            STRUCTURE_RESERVED_MEMORY => {
+                let base = read_u64(structure, 8)?;
+                let limit = read_u64(structure, 16)?;
+                dmar.reserved.push(ReservedMemory {
+                    segment: read_u16(structure, 6)?,
+                    range: MemoryRange::new(base, limit.checked_add(1)?).ok()?,
+                    scopes: parse_scopes(structure.get(RESERVED_MEMORY_SCOPES..)?),
+                });
+            }
+            _ => {}
+        }
+        offset += length;
+    }
+    Some(dmar)
+}
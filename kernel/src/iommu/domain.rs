@@ -0,0 +1,194 @@
+use alloc::collections::BTreeMap;
+use kernel_shared::memory_range::MemoryRange;
+use x86_64::{
+    structures::paging::{FrameAllocator, PhysFrame, Size4KiB},
+    PhysAddr,
+};
+
+use crate::memory::{
+    allocator::{KERNEL_FRAME_ALLOCATOR, PAGE_SIZE},
+    KERNEL_MEMORY_MANAGER,
+};
+
+const ENTRIES_PER_TABLE: usize = 512;
+const ENTRY_READ: u64 = 1 << 0;
+const ENTRY_WRITE: u64 = 1 << 1;
+const ENTRY_ADDRESS: u64 = 0x000F_FFFF_FFFF_F000;
+
+// Device addresses start above the first megabyte, so a device DMAing to a small, likely
+// uninitialized, address faults rather than landing in a mapping.
+const IOVA_START: u64 = 0x10_0000;
+
+/// Writes back the cache line holding an entry, for remapping units that read tables from
+/// memory without snooping the CPU's caches.
+pub(super) fn flush_entry(entry: *const u64, coherent: bool) {
+    if !coherent {
+        unsafe { core::arch::x86_64::_mm_clflush(entry as *const u8) };
+    }
+}
+
+pub(super) fn allocate_table() -> Option<PhysFrame<Size4KiB>> {
+    let frame = unsafe { KERNEL_FRAME_ALLOCATOR.allocate_frame() }?;
+    unsafe {
+        table_pointer(frame).write_bytes(0, ENTRIES_PER_TABLE);
+    }
+    Some(frame)
+}
+
+pub(super) fn table_pointer(frame: PhysFrame<Size4KiB>) -> *mut u64 {
+    KERNEL_MEMORY_MANAGER
+        .lock()
+        .translate(frame.start_address())
+        .as_mut_ptr()
+}
+
+/// The addresses one function, or several sharing a requester id, may DMA to, and the second
+/// level page tables the remapping unit translates them with.
+pub(super) struct Domain {
+    pub id: u16,
+    /// The index of the remapping unit translating for the domain.
+    pub unit: usize,
+    pub root: PhysFrame<Size4KiB>,
+    levels: u8,
+    coherent: bool,
+    // Allocated device addresses, by start, with their length in pages.
+    allocated: BTreeMap<u64, u64>,
+    // Identity mapped ranges, the same way. Kept apart from allocated, so they can't be freed.
+    reserved: BTreeMap<u64, u64>,
+    limit: u64,
+}
+
+impl Domain {
+    pub fn new(id: u16, unit: usize, levels: u8, coherent: bool) -> Option<Self> {
+        Some(Self {
+            id,
+            unit,
+            root: allocate_table()?,
+            levels,
+            coherent,
+            allocated: BTreeMap::new(),
+            reserved: BTreeMap::new(),
+            limit: 1 << (12 + 9 * levels as u64),
+        })
+    }
+
+    // The entry for address at the lowest level, creating tables on the way down.
+    fn entry(&mut self, address: u64, create: bool) -> Option<*mut u64> {
+        let mut table = self.root;
+        for level in (1..self.levels).rev() {
+            let index = (address >> (12 + 9 * level as u64)) as usize % ENTRIES_PER_TABLE;
+            let entry = unsafe { table_pointer(table).add(index) };
+            let value = unsafe { entry.read_volatile() };
+            table = match value & (ENTRY_READ | ENTRY_WRITE) {
+                0 if !create => return None,
+                0 => {
+                    let next = allocate_table()?;
+                    unsafe {
+                        entry.write_volatile(
+                            next.start_address().as_u64() | ENTRY_READ | ENTRY_WRITE,
+                        );
+                    }
+                    flush_entry(entry, self.coherent);
+                    next
+                }
+                _ => PhysFrame::containing_address(PhysAddr::new(value & ENTRY_ADDRESS)),
+            };
+        }
+        let index = (address >> 12) as usize % ENTRIES_PER_TABLE;
+        Some(unsafe { table_pointer(table).add(index) })
+    }
+
+    pub fn map_page(&mut self, address: u64, frame: PhysFrame<Size4KiB>, writable: bool) -> bool {
+        let entry = match self.entry(address, true) {
+            Some(entry) => entry,
+            None => return false,
+        };
+        let mut value = frame.start_address().as_u64() | ENTRY_READ;
+        if writable {
+            value |= ENTRY_WRITE;
+        }
+        unsafe { entry.write_volatile(value) };
+        flush_entry(entry, self.coherent);
+        true
+    }
+
+    pub fn unmap_page(&mut self, address: u64) {
+        if let Some(entry) = self.entry(address, false) {
+            unsafe { entry.write_volatile(0) };
+            flush_entry(entry, self.coherent);
+        }
+    }
+
+    /// Finds room for pages of device addresses, the lowest that fits, so devices limited to
+    /// 32 bit DMA are served first.
+    pub fn allocate(&mut self, pages: u64) -> Option<u64> {
+        let length = pages * PAGE_SIZE as u64;
+        let mut candidate = IOVA_START;
+        loop {
+            if candidate + length > self.limit {
+                return None;
+            }
+            let end = candidate + length;
+            let taken = overlap(&self.allocated, candidate, end)
+                .or_else(|| overlap(&self.reserved, candidate, end));
+            match taken {
+                Some((start, pages)) => candidate = start + pages * PAGE_SIZE as u64,
+                None => break,
+            }
+        }
+        self.allocated.insert(candidate, pages);
+        Some(candidate)
+    }
+
+    pub fn free(&mut self, start: u64) -> Option<u64> {
+        self.allocated.remove(&start)
+    }
+
+    /// The length in pages of the device addresses allocated at start.
+    pub fn allocated(&self, start: u64) -> Option<u64> {
+        self.allocated.get(&start).copied()
+    }
+
+    /// Maps a range at the same device and physical address, and keeps it from being
+    /// allocated. Returns false, without mapping anything, if part of the range is already
+    /// allocated.
+    pub fn identity_map(&mut self, range: MemoryRange) -> bool {
+        let range = match range.align_outward(PAGE_SIZE as u64) {
+            Ok(range) => range,
+            Err(_) => return false,
+        };
+        let (mut start, mut end) = (range.start(), range.start() + range.length());
+        if overlap(&self.allocated, start, end).is_some() {
+            return false;
+        }
+        for page in 0..range.length() / PAGE_SIZE as u64 {
+            let address = start + page * PAGE_SIZE as u64;
+            if !self.map_page(
+                address,
+                PhysFrame::containing_address(PhysAddr::new(address)),
+                true,
+            ) {
+                return false;
+            }
+        }
+        // Firmware's reserved ranges may overlap, they're merged into one.
+        while let Some((other, pages)) = overlap(&self.reserved, start, end) {
+            self.reserved.remove(&other);
+            start = start.min(other);
+            end = end.max(other + pages * PAGE_SIZE as u64);
+        }
+        let pages = (end - start) / PAGE_SIZE as u64;
+        self.reserved.insert(start, pages);
+        true
+    }
+}
+
+// The last range in ranges, by start, overlapping start to end. The ranges don't overlap each
+// other, so any other overlapping one ends before it starts.
+fn overlap(ranges: &BTreeMap<u64, u64>, start: u64, end: u64) -> Option<(u64, u64)> {
+    ranges
+        .range(..end)
+        .next_back()
+        .map(|(other, pages)| (*other, *pages))
+        .filter(|(other, pages)| other + pages * PAGE_SIZE as u64 > start)
+}
@@ -0,0 +1,322 @@
+//! DMA remapping through Intel VT-d. Each function a driver maps DMA buffers for gets a domain
+//! of its own, so it can only reach those buffers, at the device addresses map hands back.
+//!
+//! Off unless the command line has `iommu=on`, where functions without a domain keep reaching
+//! physical memory directly, on units that support pass through, or `iommu=strict`, where
+//! they're blocked, and every unmap is invalidated before it returns. On units without pass
+//! through, functions without a domain are blocked with `iommu=on` too.
+
+use alloc::{collections::BTreeMap, vec::Vec};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use kernel_shared::channel::Channel;
+use spin::{Mutex, Once};
+use x86_64::{structures::paging::PhysFrame, PhysAddr};
+
+use crate::{
+    arch::{dma_remapping_table, request_msi},
+    cmdline, debug, error,
+    memory::allocator::PAGE_SIZE,
+    pci::{self, PciAddress},
+    warn,
+};
+
+use self::{
+    dmar::Dmar,
+    domain::Domain,
+    remapping::{Fault, FaultRecorder, RemappingUnit},
+};
+
+mod dmar;
+mod domain;
+mod remapping;
+
+// Domain 0 is reserved on units in caching mode, and 1 is shared by pass through functions.
+const PASS_THROUGH_DOMAIN: u16 = 1;
+const FIRST_DOMAIN: u16 = 2;
+
+const FAULT_NAMES: [&str; 14] = [
+    "unknown",
+    "root entry not present",
+    "context entry not present",
+    "invalid context entry",
+    "address beyond the address width",
+    "write to a read only page",
+    "read from a page that can't be read",
+    "error reading a page table",
+    "error reading the root table",
+    "error reading a context table",
+    "reserved bits set in a root entry",
+    "reserved bits set in a context entry",
+    "reserved bits set in a page table entry",
+    "translation type blocked",
+];
+
+struct Iommu {
+    units: Vec<RemappingUnit>,
+    domains: BTreeMap<PciAddress, Domain>,
+    dmar: Dmar,
+    next_domain: u16,
+    // Device addresses unmapped lazily, not reused until the invalidation covering them.
+    deferred: Vec<(PciAddress, u64)>,
+}
+
+static IOMMU: Mutex<Option<Iommu>> = Mutex::new(None);
+static STRICT: AtomicBool = AtomicBool::new(false);
+// Kept apart from the units, so the fault interrupt never needs their lock.
+static FAULT_RECORDERS: Once<Vec<FaultRecorder>> = Once::new();
+static FAULTS: Channel<Fault, 16> = Channel::new();
+static FAULTS_DROPPED: AtomicUsize = AtomicUsize::new(0);
+
+impl Iommu {
+    // The unit that translates for the function: the one listing it, or the segment's catch
+    // all.
+    fn unit_for(&self, function: PciAddress) -> Option<usize> {
+        self.units
+            .iter()
+            .position(|u| u.unit.segment == 0 && u.unit.scopes.iter().any(|s| s.covers(function)))
+            .or_else(|| {
+                self.units
+                    .iter()
+                    .position(|u| u.unit.segment == 0 && u.unit.include_all)
+            })
+    }
+
+    fn attach(&mut self, function: PciAddress) -> Option<&mut Domain> {
+        if !self.domains.contains_key(&function) {
+            let index = self.unit_for(function)?;
+            let unit = &mut self.units[index];
+            if self.next_domain as usize >= unit.domain_count() {
+                warn!("IOMMU: out of domains for {}", function);
+                return None;
+            }
+            let mut domain = Domain::new(self.next_domain, index, unit.levels()?, unit.coherent())?;
+            // Firmware keeps using its reserved memory, through whichever functions it named.
+            for reserved in self.dmar.reserved.iter().filter(|r| r.segment == 0) {
+                if reserved.scopes.iter().any(|s| s.covers(function)) {
+                    domain.identity_map(reserved.range);
+                }
+            }
+            if !unit.set_context(function, Some(&domain), domain.id) {
+                return None;
+            }
+            debug!("IOMMU: {} has domain {}", function, domain.id);
+            self.next_domain += 1;
+            self.domains.insert(function, domain);
+        }
+        self.domains.get_mut(&function)
+    }
+}
+
+fn handle_fault_interrupt(_vector: u8) {
+    for recorder in FAULT_RECORDERS.get().into_iter().flatten() {
+        recorder.take(|fault| {
+            if FAULTS.try_send(fault).is_err() {
+                FAULTS_DROPPED.fetch_add(1, Ordering::Relaxed);
+            }
+        });
+    }
+}
+
+pub(crate) fn init() {
+    let strict = match cmdline::get("iommu") {
+        Some("on") => false,
+        Some("strict") => true,
+        _ => return,
+    };
+    let dmar = match dma_remapping_table().and_then(dmar::parse) {
+        Some(dmar) => dmar,
+        None => {
+            warn!("IOMMU: no usable DMA remapping table, DMA stays untranslated");
+            return;
+        }
+    };
+    let mut units = Vec::new();
+    for unit in dmar.units.iter().filter(|u| u.segment == 0) {
+        match RemappingUnit::new(unit.clone()) {
+            Some(unit) if unit.levels().is_some() => units.push(unit),
+            _ => {
+                warn!(
+                    "IOMMU: unusable remapping unit at {:#x}",
+                    unit.register_base
+                );
+            }
+        }
+    }
+    if units.is_empty() {
+        return;
+    }
+    let mut iommu = Iommu {
+        units,
+        domains: BTreeMap::new(),
+        dmar,
+        next_domain: FIRST_DOMAIN,
+        deferred: Vec::new(),
+    };
+    for function in pci::functions() {
+        let index = match iommu.unit_for(function) {
+            Some(index) => index,
+            None => continue,
+        };
+        // Until drivers map their buffers, functions DMA to physical addresses, unless strict.
+        let unit = &mut iommu.units[index];
+        let passed_through = !strict
+            && unit.supports_pass_through()
+            && unit.set_context(function, None, PASS_THROUGH_DOMAIN);
+        if !strict && !passed_through {
+            warn!(
+                "IOMMU: {} can't be passed through, its DMA is blocked",
+                function
+            );
+        }
+        // Functions that would be blocked still need firmware's reserved memory.
+        let reserved = iommu
+            .dmar
+            .reserved
+            .iter()
+            .any(|r| r.segment == 0 && r.scopes.iter().any(|s| s.covers(function)));
+        if !passed_through && reserved {
+            iommu.attach(function);
+        }
+    }
+    let recorders: Vec<FaultRecorder> = iommu.units.iter().map(|u| u.fault_recorder()).collect();
+    FAULT_RECORDERS.call_once(|| recorders);
+    let message = request_msi(handle_fault_interrupt);
+    for unit in iommu.units.iter() {
+        if let Some(message) = message {
+            unit.enable_fault_interrupt(&message);
+        }
+        if !unit.enable() {
+            error!(
+                "IOMMU: remapping unit at {:#x} didn't enable",
+                unit.unit.register_base
+            );
+        }
+    }
+    debug!(
+        "IOMMU: {} remapping units translating, {}",
+        iommu.units.len(),
+        match strict {
+            true => "strict",
+            false => "passing through unmapped functions",
+        }
+    );
+    STRICT.store(strict, Ordering::Relaxed);
+    *IOMMU.lock() = Some(iommu);
+}
+
+pub fn enabled() -> bool {
+    IOMMU.lock().is_some()
+}
+
+/// Maps a buffer for function to DMA to, and returns the address the function should use for
+/// it. Without an IOMMU, that's the physical address.
+pub fn map(function: PciAddress, physical: PhysAddr, length: usize, writable: bool) -> Option<u64> {
+    let mut iommu = IOMMU.lock();
+    let iommu = match iommu.as_mut() {
+        Some(iommu) => iommu,
+        None => return Some(physical.as_u64()),
+    };
+    let offset = physical.as_u64() % PAGE_SIZE as u64;
+    let pages = (offset + length as u64 + PAGE_SIZE as u64 - 1) / PAGE_SIZE as u64;
+    let domain = iommu.attach(function)?;
+    let start = domain.allocate(pages)?;
+    let first_frame = PhysFrame::containing_address(physical);
+    for page in 0..pages {
+        if !domain.map_page(
+            start + page * PAGE_SIZE as u64,
+            first_frame + page,
+            writable,
+        ) {
+            for mapped in 0..page {
+                domain.unmap_page(start + mapped * PAGE_SIZE as u64);
+            }
+            domain.free(start);
+            return None;
+        }
+    }
+    // Emulated units may have cached that the pages weren't present.
+    let (id, unit) = (domain.id, domain.unit);
+    if iommu.units[unit].caching_mode() {
+        iommu.units[unit].invalidate_iotlb(Some(id));
+    }
+    Some(start + offset)
+}
+
+/// Unmaps a buffer mapped by map, at the address map returned.
+pub fn unmap(function: PciAddress, address: u64) {
+    let mut iommu = IOMMU.lock();
+    let iommu = match iommu.as_mut() {
+        Some(iommu) => iommu,
+        None => return,
+    };
+    let domain = match iommu.domains.get_mut(&function) {
+        Some(domain) => domain,
+        None => return,
+    };
+    let start = address - address % PAGE_SIZE as u64;
+    let unmapped = iommu.deferred.contains(&(function, start));
+    let pages = match domain.allocated(start).filter(|_| !unmapped) {
+        Some(pages) => pages,
+        None => {
+            warn!(
+                "IOMMU: {} unmapped {:#x}, which it never mapped",
+                function, address
+            );
+            return;
+        }
+    };
+    for page in 0..pages {
+        domain.unmap_page(start + page * PAGE_SIZE as u64);
+    }
+    // Until invalidated, the function may still reach the pages through cached translations.
+    // Strict mode closes that window before returning, otherwise it's left to the next flush,
+    // and the addresses aren't handed out again until then.
+    let (id, unit) = (domain.id, domain.unit);
+    if STRICT.load(Ordering::Relaxed) {
+        iommu.units[unit].invalidate_iotlb(Some(id));
+        domain.free(start);
+    } else {
+        iommu.deferred.push((function, start));
+    }
+}
+
+/// Called from the idle loop, invalidates lazily unmapped pages, and logs blocked DMA.
+pub(crate) fn report_periodically() {
+    if let Some(iommu) = IOMMU.lock().as_mut() {
+        if !iommu.deferred.is_empty() {
+            for unit in iommu.units.iter() {
+                unit.invalidate_iotlb(None);
+            }
+            for (function, start) in iommu.deferred.drain(..) {
+                if let Some(domain) = iommu.domains.get_mut(&function) {
+                    domain.free(start);
+                }
+            }
+        }
+    }
+    // Units without an interrupt are polled here too.
+    handle_fault_interrupt(0);
+    let mut receiver = match FAULTS.receiver() {
+        Some(receiver) => receiver,
+        None => return,
+    };
+    for fault in receiver.drain() {
+        error!(
+            "IOMMU: blocked {} of {:#x} by {}: {}",
+            match fault.read {
+                true => "read",
+                false => "write",
+            },
+            fault.address,
+            fault.source,
+            FAULT_NAMES
+                .get(fault.reason as usize)
+                .unwrap_or(&FAULT_NAMES[0])
+        );
+    }
+    let dropped = FAULTS_DROPPED.swap(0, Ordering::Relaxed);
+    if dropped > 0 {
+        warn!("IOMMU: {} fault reports were dropped", dropped);
+    }
+}
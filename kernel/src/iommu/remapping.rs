@@ -0,0 +1,344 @@
+//! An Intel VT-d remapping unit, programmed through its registers, with legacy mode root and
+//! context tables and register based invalidation.
+
+use alloc::collections::BTreeMap;
+use x86_64::{
+    structures::paging::{PageTableFlags, PhysFrame, Size4KiB},
+    PhysAddr, VirtAddr,
+};
+
+use crate::{
+    arch::MsiMessage,
+    memory::{allocator::PAGE_SIZE, virtual_area},
+    pci::PciAddress,
+    time,
+};
+
+use super::{
+    dmar::HardwareUnit,
+    domain::{allocate_table, flush_entry, table_pointer, Domain},
+};
+
+const CAPABILITY: usize = 0x08;
+const EXTENDED_CAPABILITY: usize = 0x10;
+const GLOBAL_COMMAND: usize = 0x18;
+const GLOBAL_STATUS: usize = 0x1C;
+const ROOT_TABLE_ADDRESS: usize = 0x20;
+const CONTEXT_COMMAND: usize = 0x28;
+const FAULT_STATUS: usize = 0x34;
+const FAULT_EVENT_CONTROL: usize = 0x38;
+const FAULT_EVENT_DATA: usize = 0x3C;
+const FAULT_EVENT_ADDRESS: usize = 0x40;
+const FAULT_EVENT_UPPER_ADDRESS: usize = 0x44;
+
+const CAPABILITY_CACHING_MODE: u64 = 1 << 7;
+const CAPABILITY_WRITE_BUFFER_FLUSH: u64 = 1 << 4;
+const EXTENDED_COHERENT: u64 = 1 << 0;
+const EXTENDED_PASS_THROUGH: u64 = 1 << 6;
+
+const COMMAND_TRANSLATION_ENABLE: u32 = 1 << 31;
+const COMMAND_SET_ROOT_TABLE: u32 = 1 << 30;
+const COMMAND_WRITE_BUFFER_FLUSH: u32 = 1 << 27;
+// The status bits that reflect one shot commands, which must not be written back.
+const COMMAND_ONE_SHOT: u32 = 1 << 30 | 1 << 29 | 1 << 27 | 1 << 24;
+
+const INVALIDATE: u64 = 1 << 63;
+const CONTEXT_GLOBAL: u64 = 0b01 << 61;
+const IOTLB_GLOBAL: u64 = 0b01 << 60;
+const IOTLB_DOMAIN: u64 = 0b10 << 60;
+const IOTLB_DRAIN: u64 = 1 << 49 | 1 << 48;
+
+const FAULT_OVERFLOW: u32 = 1 << 0;
+const FAULT_PENDING: u32 = 1 << 1;
+const FAULT_INTERRUPT_MASK: u32 = 1 << 31;
+const FAULT_RECORDED: u64 = 1 << 63;
+const FAULT_READ: u64 = 1 << 62;
+
+const ENTRY_PRESENT: u64 = 1 << 0;
+const CONTEXT_PASS_THROUGH: u64 = 0b10 << 2;
+const ENTRY_ADDRESS: u64 = 0x000F_FFFF_FFFF_F000;
+
+const COMMAND_TIMEOUT_NANOSECONDS: u64 = 10_000_000;
+
+/// A DMA the remapping unit blocked.
+#[derive(Debug, Clone, Copy)]
+pub(super) struct Fault {
+    pub source: PciAddress,
+    pub address: u64,
+    pub read: bool,
+    pub reason: u8,
+}
+
+pub(super) struct RemappingUnit {
+    pub unit: HardwareUnit,
+    registers: VirtAddr,
+    capabilities: u64,
+    extended: u64,
+    root_table: PhysFrame<Size4KiB>,
+    // Context tables, by bus, allocated as functions on the bus are given domains.
+    context_tables: BTreeMap<u8, PhysFrame<Size4KiB>>,
+}
+
+impl RemappingUnit {
+    pub fn new(unit: HardwareUnit) -> Option<Self> {
+        let registers = virtual_area::ioremap(
+            PhysAddr::new(unit.register_base),
+            unit.register_pages * PAGE_SIZE,
+            PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+        )?;
+        let mut remapping = Self {
+            unit,
+            registers,
+            capabilities: 0,
+            extended: 0,
+            root_table: allocate_table()?,
+            context_tables: BTreeMap::new(),
+        };
+        remapping.capabilities = remapping.read_u64(CAPABILITY);
+        remapping.extended = remapping.read_u64(EXTENDED_CAPABILITY);
+        Some(remapping)
+    }
+
+    fn read_u32(&self, register: usize) -> u32 {
+        unsafe { (self.registers + register).as_ptr::<u32>().read_volatile() }
+    }
+
+    fn write_u32(&self, register: usize, value: u32) {
+        unsafe {
+            (self.registers + register)
+                .as_mut_ptr::<u32>()
+                .write_volatile(value)
+        }
+    }
+
+    fn read_u64(&self, register: usize) -> u64 {
+        unsafe { (self.registers + register).as_ptr::<u64>().read_volatile() }
+    }
+
+    fn write_u64(&self, register: usize, value: u64) {
+        unsafe {
+            (self.registers + register)
+                .as_mut_ptr::<u64>()
+                .write_volatile(value)
+        }
+    }
+
+    fn wait(&self, done: impl Fn() -> bool) -> bool {
+        let deadline = time::monotonic_nanoseconds() + COMMAND_TIMEOUT_NANOSECONDS;
+        while !done() {
+            if time::monotonic_nanoseconds() > deadline {
+                return false;
+            }
+            core::hint::spin_loop();
+        }
+        true
+    }
+
+    // Sets a command bit, keeping the persistent ones, and waits for the status to match.
+    fn command(&self, bit: u32) -> bool {
+        let status = self.read_u32(GLOBAL_STATUS) & !COMMAND_ONE_SHOT;
+        self.write_u32(GLOBAL_COMMAND, status | bit);
+        self.wait(|| self.read_u32(GLOBAL_STATUS) & bit != 0)
+    }
+
+    pub fn coherent(&self) -> bool {
+        self.extended & EXTENDED_COHERENT != 0
+    }
+
+    pub fn supports_pass_through(&self) -> bool {
+        self.extended & EXTENDED_PASS_THROUGH != 0
+    }
+
+    /// Page table levels for second level translation, the most this unit supports, up to 4.
+    pub fn levels(&self) -> Option<u8> {
+        // Supported adjusted guest address widths, bit 1 for 39 bits and 3 levels, bit 2 for
+        // 48 bits and 4 levels.
+        let widths = (self.capabilities >> 8) & 0x1F;
+        match (widths & 0b100 != 0, widths & 0b010 != 0) {
+            (true, _) => Some(4),
+            (false, true) => Some(3),
+            _ => None,
+        }
+    }
+
+    pub fn domain_count(&self) -> usize {
+        1 << (4 + 2 * (self.capabilities & 0x7))
+    }
+
+    fn fault_records(&self) -> (usize, usize) {
+        let offset = ((self.capabilities >> 24) & 0x3FF) as usize * 16;
+        let count = ((self.capabilities >> 40) & 0xFF) as usize + 1;
+        (offset, count)
+    }
+
+    fn iotlb_register(&self) -> usize {
+        ((self.extended >> 8) & 0x3FF) as usize * 16 + 8
+    }
+
+    fn context_entry(&mut self, function: PciAddress) -> Option<*mut u64> {
+        let context_table = match self.context_tables.get(&function.bus) {
+            Some(table) => *table,
+            None => {
+                let table = allocate_table()?;
+                let root_entry =
+                    unsafe { table_pointer(self.root_table).add(function.bus as usize * 2) };
+                unsafe {
+                    root_entry.write_volatile(table.start_address().as_u64() | ENTRY_PRESENT)
+                };
+                flush_entry(root_entry, self.coherent());
+                self.context_tables.insert(function.bus, table);
+                table
+            }
+        };
+        let index = (function.device as usize) << 3 | function.function as usize;
+        Some(unsafe { table_pointer(context_table).add(index * 2) })
+    }
+
+    /// Points the function's context entry at the domain's tables, or at no translation at
+    /// all, which needs pass through support.
+    pub fn set_context(
+        &mut self,
+        function: PciAddress,
+        domain: Option<&Domain>,
+        domain_id: u16,
+    ) -> bool {
+        let levels = match self.levels() {
+            Some(levels) => levels,
+            None => return false,
+        };
+        let entry = match self.context_entry(function) {
+            Some(entry) => entry,
+            None => return false,
+        };
+        let low = match domain {
+            Some(domain) => domain.root.start_address().as_u64() & ENTRY_ADDRESS,
+            None => CONTEXT_PASS_THROUGH,
+        } | ENTRY_PRESENT;
+        let high = (domain_id as u64) << 8 | (levels as u64 - 2);
+        unsafe {
+            // Present is in the low half, so the entry is never live half written.
+            entry.write_volatile(0);
+            entry.add(1).write_volatile(high);
+            entry.write_volatile(low);
+        }
+        flush_entry(entry, self.coherent());
+        self.invalidate_context();
+        self.invalidate_iotlb(None);
+        true
+    }
+
+    fn flush_write_buffer(&self) {
+        if self.capabilities & CAPABILITY_WRITE_BUFFER_FLUSH != 0 {
+            let status = self.read_u32(GLOBAL_STATUS) & !COMMAND_ONE_SHOT;
+            self.write_u32(GLOBAL_COMMAND, status | COMMAND_WRITE_BUFFER_FLUSH);
+            self.wait(|| self.read_u32(GLOBAL_STATUS) & COMMAND_WRITE_BUFFER_FLUSH == 0);
+        }
+    }
+
+    fn invalidate_context(&self) {
+        self.flush_write_buffer();
+        self.write_u64(CONTEXT_COMMAND, INVALIDATE | CONTEXT_GLOBAL);
+        self.wait(|| self.read_u64(CONTEXT_COMMAND) & INVALIDATE == 0);
+    }
+
+    /// Drops cached translations, for one domain or all of them.
+    pub fn invalidate_iotlb(&self, domain_id: Option<u16>) {
+        self.flush_write_buffer();
+        let register = self.iotlb_register();
+        let granularity = match domain_id {
+            Some(id) => IOTLB_DOMAIN | (id as u64) << 32,
+            None => IOTLB_GLOBAL,
+        };
+        self.write_u64(register, INVALIDATE | IOTLB_DRAIN | granularity);
+        self.wait(|| self.read_u64(register) & INVALIDATE == 0);
+    }
+
+    /// Whether new mappings need invalidating too, as when the unit is emulated and shadows
+    /// the tables.
+    pub fn caching_mode(&self) -> bool {
+        self.capabilities & CAPABILITY_CACHING_MODE != 0
+    }
+
+    /// Installs the root table and turns translation on. Functions without a context entry
+    /// can't DMA at all from here on.
+    pub fn enable(&self) -> bool {
+        self.write_u64(ROOT_TABLE_ADDRESS, self.root_table.start_address().as_u64());
+        if !self.command(COMMAND_SET_ROOT_TABLE) {
+            return false;
+        }
+        self.invalidate_context();
+        self.invalidate_iotlb(None);
+        self.command(COMMAND_TRANSLATION_ENABLE)
+    }
+
+    /// Has the unit raise message when it records a fault.
+    pub fn enable_fault_interrupt(&self, message: &MsiMessage) {
+        self.write_u32(FAULT_EVENT_DATA, message.data);
+        self.write_u32(FAULT_EVENT_ADDRESS, message.address as u32);
+        self.write_u32(FAULT_EVENT_UPPER_ADDRESS, (message.address >> 32) as u32);
+        self.write_u32(
+            FAULT_EVENT_CONTROL,
+            self.read_u32(FAULT_EVENT_CONTROL) & !FAULT_INTERRUPT_MASK,
+        );
+    }
+
+    pub fn fault_recorder(&self) -> FaultRecorder {
+        let (offset, count) = self.fault_records();
+        FaultRecorder {
+            registers: self.registers,
+            first_record: offset,
+            records: count,
+        }
+    }
+}
+
+/// Reads a unit's fault records. Only touches registers, so it's safe in interrupt context,
+/// without the lock the unit is kept under.
+#[derive(Clone, Copy)]
+pub(super) struct FaultRecorder {
+    registers: VirtAddr,
+    first_record: usize,
+    records: usize,
+}
+
+impl FaultRecorder {
+    /// Takes the pending faults, clearing each record as it's read.
+    pub fn take(&self, mut found: impl FnMut(Fault)) {
+        let status = unsafe {
+            (self.registers + FAULT_STATUS)
+                .as_ptr::<u32>()
+                .read_volatile()
+        };
+        if status & (FAULT_PENDING | FAULT_OVERFLOW) == 0 {
+            return;
+        }
+        let mut index = ((status >> 8) & 0xFF) as usize % self.records;
+        loop {
+            let record = self.registers + self.first_record + index * 16;
+            let high = unsafe { (record + 8u64).as_ptr::<u64>().read_volatile() };
+            if high & FAULT_RECORDED == 0 {
+                break;
+            }
+            let low = unsafe { record.as_ptr::<u64>().read_volatile() };
+            let source = high as u16;
+            found(Fault {
+                source: PciAddress::new(
+                    (source >> 8) as u8,
+                    (source >> 3) as u8 & 0x1F,
+                    source as u8 & 0x7,
+                ),
+                address: low & !0xFFF,
+                read: high & FAULT_READ != 0,
+                reason: (high >> 32) as u8,
+            });
+            // Cleared by writing a one, through the top dword alone.
+            unsafe { (record + 12u64).as_mut_ptr::<u32>().write_volatile(1 << 31) };
+            index = (index + 1) % self.records;
+        }
+        unsafe {
+            (self.registers + FAULT_STATUS)
+                .as_mut_ptr::<u32>()
+                .write_volatile(FAULT_OVERFLOW)
+        };
+    }
+}
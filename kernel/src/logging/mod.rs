@@ -1,6 +1,14 @@
-use core::fmt::Display;
+use core::{
+    fmt::{Display, Write},
+    sync::atomic::{AtomicU8, AtomicUsize, Ordering},
+};
 
-#[derive(Debug)]
+use kernel_shared::{channel::Channel, tunable::TunableValue};
+use lazy_static::lazy_static;
+
+use crate::{arch::in_interrupt_context, cmdline, serial::debugcon, tunables};
+
+#[derive(Debug, Clone, Copy)]
 pub enum LogLevel {
     DEBUG,
     VERBOSE,
@@ -9,10 +17,205 @@ pub enum LogLevel {
     ERROR,
     FATAL,
 }
+
+// Tunable: log.cpu_color, colors the CPU prefix of each log line by CPU number. Lines logged
+// before the tunable is registered read the command line instead.
+const CPU_COLORS_UNSET: u8 = u8::MAX;
+static CPU_COLORS: AtomicU8 = AtomicU8::new(CPU_COLORS_UNSET);
+
+lazy_static! {
+    // Boot parameter: log.sink, where log lines go besides the screen. Falls back to serial
+    // when the debug console isn't there.
+    static ref LOG_SINK: LogSink = match cmdline::get("log.sink") {
+        Some("debugcon") if debugcon::present() => LogSink::DebugCon,
+        Some("both") if debugcon::present() => LogSink::Both,
+        _ => LogSink::Serial,
+    };
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LogSink {
+    Serial,
+    /// QEMU's debug console, much faster than the emulated serial port.
+    DebugCon,
+    Both,
+}
+
+// SGR foreground colors used to tell CPUs apart, red and yellow are left out to avoid
+// confusion with warnings and errors.
+const CPU_PREFIX_COLORS: [&str; 6] = ["32", "34", "35", "36", "92", "94"];
+
+/// Wraps a value with an ANSI SGR color sequence, and a reset after it.
+struct Colored<T: Display>(Option<&'static str>, T);
+
+impl<T: Display> Display for Colored<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self.0 {
+            Some(sgr) => write!(f, "\x1b[{}m{}\x1b[0m", sgr, self.1),
+            None => write!(f, "{}", self.1),
+        }
+    }
+}
+
+/// Registers the logging tunables, once there's a heap to keep them.
+pub(crate) fn init() {
+    tunables::register_bool("log.cpu_color", false, set_cpu_colors);
+}
+
+fn set_cpu_colors(value: &TunableValue) -> bool {
+    CPU_COLORS.store(value.integer as u8, Ordering::Relaxed);
+    true
+}
+
+fn cpu_colors() -> bool {
+    match CPU_COLORS.load(Ordering::Relaxed) {
+        CPU_COLORS_UNSET => cmdline::flag("log.cpu_color"),
+        colors => colors != 0,
+    }
+}
+
+fn cpu_color(cpu: usize) -> Option<&'static str> {
+    if !cpu_colors() {
+        return None;
+    }
+    Some(CPU_PREFIX_COLORS[cpu % CPU_PREFIX_COLORS.len()])
+}
+
+const DEFERRED_MESSAGE_LENGTH: usize = 192;
+const DEFERRED_LOG_CAPACITY: usize = 64;
+
+/// A log line written from interrupt context, held until it is safe to take the console locks.
+struct DeferredRecord {
+    cpu: usize,
+    log_level: LogLevel,
+    length: usize,
+    message: [u8; DEFERRED_MESSAGE_LENGTH],
+}
+
+impl DeferredRecord {
+    fn message(&self) -> &str {
+        // Writes are truncated on character boundaries, so this is always valid.
+        core::str::from_utf8(&self.message[0..self.length]).unwrap_or("<invalid utf-8>")
+    }
+}
+
+impl Write for DeferredRecord {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let mut end = s.len().min(DEFERRED_MESSAGE_LENGTH - self.length);
+        while !s.is_char_boundary(end) {
+            end -= 1;
+        }
+        self.message[self.length..self.length + end].copy_from_slice(&s.as_bytes()[0..end]);
+        self.length += end;
+        Ok(())
+    }
+}
+
+static DEFERRED_LOG: Channel<DeferredRecord, DEFERRED_LOG_CAPACITY> = Channel::new();
+static DEFERRED_LOG_DROPPED: AtomicUsize = AtomicUsize::new(0);
+
+fn defer(cpu: usize, log_level: LogLevel, args: core::fmt::Arguments) {
+    let mut record = DeferredRecord {
+        cpu,
+        log_level,
+        length: 0,
+        message: [0; DEFERRED_MESSAGE_LENGTH],
+    };
+    let _ = record.write_fmt(args);
+    if DEFERRED_LOG.try_send(record).is_err() {
+        DEFERRED_LOG_DROPPED.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Where the log lines queued from interrupt context are kept, and its size, for the runner to
+/// save when the kernel panics before they're printed.
+pub(crate) fn deferred_log_region() -> (usize, usize) {
+    (
+        &DEFERRED_LOG as *const _ as usize,
+        core::mem::size_of_val(&DEFERRED_LOG),
+    )
+}
+
+/// Prints log lines that were queued from interrupt context.
+pub(crate) fn flush_deferred() {
+    if in_interrupt_context() {
+        return;
+    }
+    // Someone else is already flushing.
+    let mut receiver = match DEFERRED_LOG.receiver() {
+        Some(receiver) => receiver,
+        None => return,
+    };
+    for record in receiver.drain() {
+        emit(
+            record.cpu,
+            record.log_level,
+            format_args!("{}", record.message()),
+        );
+    }
+    let dropped = DEFERRED_LOG_DROPPED.swap(0, Ordering::Relaxed);
+    if dropped > 0 {
+        let cpu = super::arch::get_current_cpu();
+        emit(
+            cpu,
+            LogLevel::WARNING,
+            format_args!(
+                "{} log messages from interrupt context were dropped",
+                dropped
+            ),
+        );
+    }
+}
+
 pub(crate) fn _print(log_level: LogLevel, args: core::fmt::Arguments) {
     let cpu = super::arch::get_current_cpu();
-    crate::println!("[C:{:03}][{}]: {}", cpu, log_level, args);
-    crate::console_println!("[C:{:03}][{}]: {}", cpu, log_level, args);
+    // Printing takes the serial and console locks, which the interrupted code may hold. Fatal
+    // messages are printed anyway, they may be the last thing we do.
+    if in_interrupt_context() && !matches!(log_level, LogLevel::FATAL) {
+        defer(cpu, log_level, args);
+        return;
+    }
+    flush_deferred();
+    emit(cpu, log_level, args);
+}
+
+fn emit(cpu: usize, log_level: LogLevel, args: core::fmt::Arguments) {
+    let cpu_color = cpu_color(cpu);
+    let level_color = log_level.color();
+    let sink = *LOG_SINK;
+    if sink != LogSink::DebugCon {
+        crate::println!(
+            "[{}][{}]: {}",
+            Colored(cpu_color, format_args!("C:{:03}", cpu)),
+            Colored(level_color, &log_level),
+            Colored(level_color, args)
+        );
+    }
+    if sink != LogSink::Serial {
+        crate::debugcon_println!(
+            "[{}][{}]: {}",
+            Colored(cpu_color, format_args!("C:{:03}", cpu)),
+            Colored(level_color, &log_level),
+            Colored(level_color, args)
+        );
+    }
+    crate::console_println!(
+        "[{}][{}]: {}",
+        Colored(cpu_color, format_args!("C:{:03}", cpu)),
+        Colored(level_color, &log_level),
+        Colored(level_color, args)
+    );
+}
+
+impl LogLevel {
+    fn color(&self) -> Option<&'static str> {
+        match self {
+            LogLevel::WARNING => Some("93"),
+            LogLevel::ERROR => Some("91"),
+            LogLevel::FATAL => Some("97;41"),
+            _ => None,
+        }
+    }
 }
 
 impl Display for LogLevel {
@@ -14,48 +14,64 @@
 #![feature(error_in_core)]
 extern crate alloc;
 
-use core::ptr::NonNull;
+use core::sync::atomic::{AtomicU64, Ordering};
 
 use alloc::{
     format,
     string::{String, ToString},
 };
-use arch::arch_x86_64::cpu::{cpu_apic_id, CPU_STACK_PAGES};
+use arch::arch_x86_64::cpu::CPU_STACK_PAGES;
 use bootloader_api::{config::Mapping, BootInfo};
-use devices::{Device, get_mut_device_tree, well_known::DEVICE_TREE};
+use devices::{get_device_tree, get_mut_device_tree, readiness, well_known::DEVICE_TREE, Device};
 use spin::Mutex;
 use uuid::Uuid;
 use x86_64::VirtAddr;
 
 use framebuffer::*;
+use initcall::{InitCall, InitLevel};
 use memory::{
     allocator::{KERNEL_HEAP_START, PAGE_SIZE},
     *,
 };
 
-use crate::{
-    arch::{
-        arch_x86_64::{get_cpu_brand_string, get_cpu_vendor_string},
-        get_current_cpu, wait_for_interrupt,
-    }
+use crate::arch::{
+    arch_x86_64::{get_cpu_brand_string, get_cpu_vendor_string},
+    get_current_cpu,
 };
 
 include!(concat!(env!("OUT_DIR"), "/metadata_constants.rs"));
 pub(crate) mod arch;
+pub(crate) mod audit;
+pub(crate) mod boottime;
+pub(crate) mod cmdline;
 pub(crate) mod console;
 pub(crate) mod framebuffer;
+pub(crate) mod initcall;
+pub(crate) mod input;
+pub(crate) mod integrity;
+pub(crate) mod iommu;
 pub(crate) mod logging;
 
 pub mod errors;
 mod loader;
 mod memory;
 mod panic;
+pub(crate) mod pci;
+pub(crate) mod random;
 pub(crate) mod serial;
+pub(crate) mod smbus;
+pub(crate) mod sound;
+pub(crate) mod state;
+pub(crate) mod syscalls;
+pub(crate) mod sysinfo;
 pub mod thread;
+pub(crate) mod time;
+pub(crate) mod tunables;
 
 const CONFIG: bootloader_api::BootloaderConfig = {
     let mut config = bootloader_api::BootloaderConfig::new_default();
-    config.mappings.aslr = true;
+    // Deterministic runs load the kernel at the same address every time.
+    config.mappings.aslr = !cfg!(feature = "deterministic");
     config.kernel_stack_size = CPU_STACK_PAGES as u64 * PAGE_SIZE as u64;
     config.mappings.physical_memory = Some(Mapping::Dynamic);
     config.mappings.dynamic_range_end = Some(KERNEL_HEAP_START as u64);
@@ -64,18 +80,20 @@ const CONFIG: bootloader_api::BootloaderConfig = {
 };
 
 bootloader_api::entry_point!(kernel_boot, config = &CONFIG);
-static mut BOOT_INFO: Option<NonNull<BootInfo>> = None;
 
 #[allow(unreachable_code)]
 fn kernel_boot(boot_info: &'static mut bootloader_api::BootInfo) -> ! {
+    arch::early_init();
+    boottime::mark_boot_start();
     println!("Booting");
-    unsafe {
-        println!("Creating boot info pointer");
-        BOOT_INFO = NonNull::new(boot_info);
-        println!("Starting early init");
-        early_init(BOOT_INFO.unwrap().as_mut());
-        hardware_init(BOOT_INFO.unwrap().as_mut());
-    }
+    // Copied out before early init takes the BootInfo, registered once there's a heap to keep it.
+    let boot_data = state::BootData::new(boot_info);
+    println!("Starting early init");
+    early_init(boot_info);
+    state::register_owned(boot_data);
+    initcall::register(arch::initcalls());
+    initcall::register(&INITCALLS);
+    hardware_init();
     kernel_main();
     unreachable!();
 }
@@ -84,23 +102,19 @@ fn kernel_boot(boot_info: &'static mut bootloader_api::BootInfo) -> ! {
 fn early_init(boot_info: &'static mut BootInfo) {
     println!("Initializing virtual memory");
     initialize_virtual_memory(
-        VirtAddr::new(
-            boot_info
-                .physical_memory_offset
-                .into_option()
-                .expect("No physical memory offset available!"),
-        ),
+        VirtAddr::new(state::BootData::new(boot_info).physical_memory_offset),
         &boot_info.memory_regions,
     );
     let fb_option: Option<&'static mut bootloader_api::info::FrameBuffer> =
         boot_info.framebuffer.as_mut();
-    init_framebuffer(fb_option);
+    boottime::stage("Framebuffer", || init_framebuffer(fb_option));
+    splash::show();
 }
 
-fn hardware_init(boot_info: &BootInfo) {
+fn hardware_init() {
     let cpu = get_current_cpu();
-    debug!("Initializing hardware on boot CPU (ACPI ID: {})", cpu);
-    arch::init(boot_info);
+    debug!("Initializing hardware on boot CPU {}", cpu);
+    arch::init();
 }
 
 fn clear() {
@@ -125,10 +139,69 @@ impl Device for KernelDevice {
     }
 
     fn uuid(&self) -> Uuid {
-        *devices::well_known::IPL
+        devices::well_known::IPL
     }
 }
 
+/// The kernel's own init calls, run from kernel_main, see initcall.rs.
+static INITCALLS: [InitCall; 20] = [
+    InitCall::new("Tunables", InitLevel::Driver, &["Syscalls"], tunables::init),
+    InitCall::new("Logging", InitLevel::Driver, &["Tunables"], logging::init),
+    InitCall::new("Scheduler", InitLevel::Driver, &[], thread::scheduler::init).required(),
+    InitCall::new(
+        "Preemption",
+        InitLevel::Driver,
+        &["Scheduler", "Tunables"],
+        thread::preempt::init,
+    ),
+    InitCall::new("Serial", InitLevel::Driver, &[], serial::init),
+    InitCall::new("Memory", InitLevel::Driver, &[], memory::init),
+    InitCall::new(
+        "Time",
+        InitLevel::Driver,
+        &["TSC calibration", "Tunables"],
+        time::init,
+    ),
+    InitCall::new(
+        "Page merging",
+        InitLevel::Driver,
+        &["Memory", "Time", "Tunables"],
+        memory::merge::init,
+    ),
+    InitCall::new("Input", InitLevel::Driver, &[], input::init),
+    InitCall::new("Sound", InitLevel::Driver, &[], sound::init),
+    InitCall::new("PCI", InitLevel::Driver, &[], pci::init),
+    InitCall::new("IOMMU", InitLevel::Driver, &["ACPI", "PCI"], iommu::init),
+    InitCall::new("SMBus", InitLevel::Driver, &["PCI"], smbus::init),
+    InitCall::new(
+        "Integrity check",
+        InitLevel::Driver,
+        &["Tunables"],
+        integrity::init,
+    ),
+    InitCall::new("System info", InitLevel::Late, &["Syscalls"], sysinfo::init),
+    InitCall::new(
+        "Kernel syscalls",
+        InitLevel::Late,
+        &["Syscalls"],
+        syscalls::init,
+    ),
+    InitCall::new(
+        "Processes",
+        InitLevel::Late,
+        &["Syscalls"],
+        thread::process::init,
+    ),
+    InitCall::new(
+        "Credentials",
+        InitLevel::Late,
+        &["Processes"],
+        thread::credentials::init,
+    ),
+    InitCall::new("Audit", InitLevel::Late, &["Credentials"], audit::init),
+    InitCall::new("Random", InitLevel::Late, &["Syscalls"], random::init),
+];
+
 fn kernel_main() -> ! {
     let status_bits = arch::arch_x86_64::cpu::get_online_cpu_status_bits();
     {
@@ -143,29 +216,83 @@ fn kernel_main() -> ! {
     verbose!("CPU Vendor: {}", get_cpu_vendor_string());
     verbose!("CPU Brand : {}", get_cpu_brand_string());
 
-    let mut device_tree = get_mut_device_tree();
-    let root_device = device_tree.register(KernelDevice{});
-    debug!("Registered kernel device ({}) as {:032X}", devices::well_known::IPL.as_hyphenated(), root_device);
-    debug!("Enumerating device tree");
-    for i in device_tree.keys().iter() {
-        let dev = device_tree.get(i).expect("UNKNOWN DEVICE");
-        let path = device_tree.get_device_path(dev);
-        // The third URI
+    readiness::subscribe(|id, ready| {
         debug!(
-            "Found: {} at sys://device/uuid/{}, sys://device/id/{:032x}, and  sys://device/path/{}/{:032x}",
-            dev.name(),
-            dev.uuid().as_hyphenated(),
-            i,
-            path,
-            i
+            "Device {:032x} is {}",
+            id,
+            if ready { "ready" } else { "no longer ready" }
         );
+    });
+    initcall::run_level(InitLevel::Driver);
+    initcall::run_level(InitLevel::Late);
+
+    let root_device = get_mut_device_tree().register(KernelDevice {});
+    debug!(
+        "Registered kernel device ({}) as {:032X}",
+        devices::well_known::IPL.as_hyphenated(),
+        root_device
+    );
+    await_devices();
+
+    {
+        let device_tree = get_device_tree();
+        debug!("Enumerating device tree");
+        for i in device_tree.keys().iter() {
+            let dev = device_tree.get(i).expect("UNKNOWN DEVICE");
+            let path = device_tree.get_device_path(dev.as_ref());
+            // The third URI
+            debug!(
+                "Found: {} ({}) at sys://device/uuid/{}, sys://device/id/{:032x}, and  sys://device/path/{}/{:032x}",
+                dev.name(),
+                device_tree.names().name(*i).unwrap_or("unnamed"),
+                dev.uuid().as_hyphenated(),
+                i,
+                path,
+                i
+            );
+        }
     }
 
+    boottime::print_summary();
+    memory::stack::report();
+    memory::rmap::report();
+    if cmdline::flag("ptdump") {
+        memory::ptdump::dump_current();
+    }
+    if cmdline::flag("syscalls") {
+        syscalls::dump();
+    }
+    if cmdline::flag("vmx.test") {
+        match arch::run_test_guest() {
+            Ok(output) => {
+                info!("VMX: test guest printed {:?}", output);
+            }
+            Err(err) => {
+                warn!("VMX: test guest failed: {}", err);
+            }
+        }
+    }
+    splash::dismiss();
     set_kernel_ready();
+    // Boot ran with interrupts disabled, from here on the device tree's writers shouldn't.
+    devices::set_interrupts_enabled_check(arch::interrupts_enabled);
     // Join the APIs in their halt loop glory.
     kernel_cpu_main();
 }
 
+const DEVICE_READY_TIMEOUT_MS: u64 = 1000;
+
+/// Gives every registered device a chance to finish initializing before boot completes.
+fn await_devices() {
+    let ids = get_device_tree().keys();
+    let deadline = time::Deadline::after_ms(DEVICE_READY_TIMEOUT_MS);
+    for id in ids {
+        if !readiness::await_ready(id, || deadline.is_expired()) {
+            warn!("Device {:032x} did not become ready during boot", id);
+        }
+    }
+}
+
 fn kernel_cpu_main() -> ! {
     // TODO: Enter the scheduler here.
     if !kernel_ready() {
@@ -174,12 +301,77 @@ fn kernel_cpu_main() -> ! {
             core::hint::spin_loop();
         }
     }
-    let cpu = cpu_apic_id();
+    let cpu = get_current_cpu();
     debug!("Entered kernel_cpu_main on CPU #{}", cpu);
     loop {
         // let ticks = get_timer_ticks();
         // debug!("Tick: {}", ticks);
-        wait_for_interrupt();
+        time::idle::idle();
+        logging::flush_deferred();
+        input::poll();
+        sound::poll();
+        time::timer::run_expired();
+        console::blink_cursor();
+        framebuffer::compositor::compose_periodically();
+        check_stacks_periodically();
+        report_interrupt_latency_periodically();
+        time::idle::report_periodically();
+        time::timer::report_periodically();
+        pci::aer::report_periodically();
+        iommu::report_periodically();
+    }
+}
+
+// Paced by the clock, idle CPUs stop ticking.
+const STACK_CHECK_INTERVAL_NANOSECONDS: u64 = 1_000_000_000;
+static NEXT_STACK_CHECK: AtomicU64 = AtomicU64::new(0);
+
+fn check_stacks_periodically() {
+    let now = time::monotonic_nanoseconds();
+    let next = NEXT_STACK_CHECK.load(Ordering::Relaxed);
+    if now < next {
+        return;
+    }
+    // Only one CPU needs to do the check each interval.
+    if NEXT_STACK_CHECK
+        .compare_exchange(
+            next,
+            now + STACK_CHECK_INTERVAL_NANOSECONDS,
+            Ordering::Relaxed,
+            Ordering::Relaxed,
+        )
+        .is_ok()
+    {
+        memory::stack::check_stacks();
+    }
+}
+
+const LATENCY_REPORT_INTERVAL_NANOSECONDS: u64 = 10_000_000_000;
+static NEXT_LATENCY_REPORT: AtomicU64 = AtomicU64::new(LATENCY_REPORT_INTERVAL_NANOSECONDS);
+
+fn report_interrupt_latency_periodically() {
+    let now = time::monotonic_nanoseconds();
+    let next = NEXT_LATENCY_REPORT.load(Ordering::Relaxed);
+    if now < next {
+        return;
+    }
+    if NEXT_LATENCY_REPORT
+        .compare_exchange(
+            next,
+            now + LATENCY_REPORT_INTERVAL_NANOSECONDS,
+            Ordering::Relaxed,
+            Ordering::Relaxed,
+        )
+        .is_ok()
+    {
+        for vector in 0..=u8::MAX {
+            if let Some(latency) = arch::interrupt_latency(vector) {
+                debug!(
+                    "Interrupt {:#04x} latency: worst {} ns, p99 {} ns, {} samples",
+                    vector, latency.worst_nanoseconds, latency.p99_nanoseconds, latency.samples
+                );
+            }
+        }
     }
 }
 
@@ -2,6 +2,10 @@ use core::alloc::{GlobalAlloc, Layout};
 
 use bitvec::prelude::*;
 use bootloader_api::info::{MemoryRegionKind, MemoryRegions};
+use kernel_shared::{
+    kernel_state::{ZoneState, MEMORY_ZONE_COUNT},
+    memory_range::MemoryRange,
+};
 
 use linked_list_allocator::LockedHeap;
 use x86_64::{
@@ -11,7 +15,7 @@ use x86_64::{
     PhysAddr, VirtAddr,
 };
 
-use crate::{debug, println};
+use crate::{arch::in_interrupt_context, debug, println, state};
 
 use super::KERNEL_MEMORY_MANAGER;
 
@@ -20,19 +24,65 @@ fn alloc_error_handler(layout: alloc::alloc::Layout) -> ! {
     panic!("allocation error: {:?}", layout);
 }
 
-struct KernelAllocator(LockedHeap);
+/// Allocation context flags, in the spirit of Linux GFP flags.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AllocationFlags(u32);
+
+impl AllocationFlags {
+    pub const NONE: AllocationFlags = AllocationFlags(0);
+    /// Never block on, or extend, the heap, using the emergency reserve while another CPU holds
+    /// it. Required from interrupt context.
+    pub const ATOMIC: AllocationFlags = AllocationFlags(1 << 0);
+    /// Zero the returned memory.
+    pub const ZERO: AllocationFlags = AllocationFlags(1 << 1);
+    /// Physically contiguous memory below 4GiB, suitable for 32 bit DMA engines.
+    pub const DMA32: AllocationFlags = AllocationFlags(1 << 2);
+    /// High priority, fall back to the emergency reserve and panic instead of returning null.
+    pub const NOFAIL: AllocationFlags = AllocationFlags(1 << 3);
+
+    pub const fn contains(&self, flags: AllocationFlags) -> bool {
+        self.0 & flags.0 == flags.0
+    }
+}
+
+impl core::ops::BitOr for AllocationFlags {
+    type Output = AllocationFlags;
+
+    fn bitor(self, rhs: Self) -> Self::Output {
+        AllocationFlags(self.0 | rhs.0)
+    }
+}
+
+struct KernelAllocator {
+    heap: LockedHeap,
+    // Carved out of the heap at init, and only handed out to NOFAIL allocations.
+    reserve: LockedHeap,
+}
 
 impl KernelAllocator {
     pub fn init(&mut self) {
-        let mut locked_allocator = self.0.lock();
+        let mut locked_allocator = self.heap.lock();
         let heap_space = Self::allocate_heap_space(KERNEL_HEAP_PAGES);
         unsafe {
             locked_allocator.init(heap_space, KERNEL_HEAP_PAGES * Size4KiB::SIZE as usize);
         }
+        let reserve_layout =
+            Layout::from_size_align(KERNEL_RESERVE_PAGES * PAGE_SIZE, PAGE_SIZE).unwrap();
+        let reserve_space = locked_allocator
+            .allocate_first_fit(reserve_layout)
+            .expect("Failed to carve the emergency reserve out of the kernel heap!");
+        unsafe {
+            self.reserve
+                .lock()
+                .init(reserve_space.as_ptr(), reserve_layout.size());
+        }
     }
 
     pub const fn empty() -> KernelAllocator {
-        KernelAllocator(LockedHeap::empty())
+        KernelAllocator {
+            heap: LockedHeap::empty(),
+            reserve: LockedHeap::empty(),
+        }
     }
 
     fn allocate_heap_space(pages: usize) -> *mut u8 {
@@ -47,7 +97,7 @@ impl KernelAllocator {
     }
 
     fn extend_heap(&self, needed_bytes: usize) {
-        let mut locked_allocator = self.0.lock();
+        let mut locked_allocator = self.heap.lock();
         let current_size = locked_allocator.size();
         if current_size == 0 {
             panic!("Attempted to extend an uninitialized heap!");
@@ -66,6 +116,67 @@ impl KernelAllocator {
 
         unsafe { locked_allocator.extend(pages_to_allocate * PAGE_SIZE) };
     }
+
+    fn is_reserve_allocation(&self, ptr: *mut u8) -> bool {
+        let reserve = self.reserve.lock();
+        ptr >= reserve.bottom() && ptr < reserve.top()
+    }
+
+    unsafe fn allocate(&self, layout: Layout, flags: AllocationFlags) -> *mut u8 {
+        let ret = if flags.contains(AllocationFlags::DMA32) {
+            allocate_dma32(layout, flags)
+        } else if flags.contains(AllocationFlags::ATOMIC) {
+            // Never spin on the heap lock here, the holder may be the code we interrupted. More
+            // often it's another CPU, the allocation comes from the emergency reserve instead.
+            match self.heap.try_lock().or_else(|| self.reserve.try_lock()) {
+                Some(mut heap) => heap
+                    .allocate_first_fit(layout)
+                    .map_or(0 as *mut u8, |p| p.as_ptr()),
+                None => 0 as *mut u8,
+            }
+        } else {
+            let mut ret = self.heap.alloc(layout);
+            if ret as usize == 0 {
+                let needed_size = self.calculate_heap_expansion(layout);
+                self.extend_heap(needed_size);
+                ret = self.heap.alloc(layout);
+            }
+            ret
+        };
+
+        let ret = if ret as usize == 0 && flags.contains(AllocationFlags::NOFAIL) {
+            match self.reserve.alloc(layout) {
+                p if p as usize == 0 => panic!(
+                    "NOFAIL allocation of {:?} failed, emergency reserve exhausted!",
+                    layout
+                ),
+                p => {
+                    debug!(
+                        "Satisfied NOFAIL allocation of {:?} from the emergency reserve",
+                        layout
+                    );
+                    p
+                }
+            }
+        } else {
+            ret
+        };
+
+        if ret as usize != 0 && flags.contains(AllocationFlags::ZERO) {
+            ret.write_bytes(0, layout.size());
+        }
+        ret
+    }
+
+    unsafe fn deallocate(&self, ptr: *mut u8, layout: Layout, flags: AllocationFlags) {
+        if flags.contains(AllocationFlags::DMA32) {
+            free_dma32(ptr, layout);
+        } else if self.is_reserve_allocation(ptr) {
+            self.reserve.dealloc(ptr, layout);
+        } else {
+            self.heap.dealloc(ptr, layout);
+        }
+    }
 }
 
 #[global_allocator]
@@ -73,7 +184,14 @@ static mut ALLOCATOR: KernelAllocator = KernelAllocator::empty();
 
 impl KernelAllocator {
     pub fn get_heap_size(&self) -> usize {
-        self.0.lock().size()
+        self.heap.lock().size()
+    }
+
+    /// Size and usage, in bytes, of the heap and then the emergency reserve.
+    pub fn usage(&self) -> ((usize, usize), (usize, usize)) {
+        let heap = self.heap.lock();
+        let reserve = self.reserve.lock();
+        ((heap.size(), heap.used()), (reserve.size(), reserve.used()))
     }
 
     pub fn calculate_heap_expansion(&self, layout: Layout) -> usize {
@@ -83,24 +201,58 @@ impl KernelAllocator {
 
 unsafe impl GlobalAlloc for KernelAllocator {
     unsafe fn alloc(&self, layout: core::alloc::Layout) -> *mut u8 {
-        let ret = self.0.alloc(layout);
-        if ret as usize != 0 {
-            return ret;
-        }
-        let needed_size = self.calculate_heap_expansion(layout);
-        self.extend_heap(needed_size);
-        let ret = self.0.alloc(layout);
-        ret
+        // Extending the heap takes the memory manager lock, which is not safe from an interrupt handler.
+        let flags = match in_interrupt_context() {
+            true => AllocationFlags::ATOMIC,
+            false => AllocationFlags::NONE,
+        };
+        self.allocate(layout, flags)
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: core::alloc::Layout) {
-        self.0.dealloc(ptr, layout);
+        self.deallocate(ptr, layout, AllocationFlags::NONE);
+    }
+}
+
+fn dma32_frame_count(layout: Layout) -> usize {
+    (layout.size().max(1) + PAGE_SIZE - 1) / PAGE_SIZE
+}
+
+unsafe fn allocate_dma32(layout: Layout, flags: AllocationFlags) -> *mut u8 {
+    // Frames are page aligned, anything stricter than that can't be satisfied.
+    if layout.align() > PAGE_SIZE {
+        return 0 as *mut u8;
+    }
+    let memory_manager = match flags.contains(AllocationFlags::ATOMIC) {
+        true => match KERNEL_MEMORY_MANAGER.try_lock() {
+            Some(m) => m,
+            None => return 0 as *mut u8,
+        },
+        false => KERNEL_MEMORY_MANAGER.lock(),
+    };
+    match KERNEL_FRAME_ALLOCATOR.allocate_contiguous_frames(dma32_frame_count(layout), DMA32_LIMIT)
+    {
+        Some(frame) => memory_manager.translate(frame.start_address()).as_mut_ptr(),
+        None => 0 as *mut u8,
+    }
+}
+
+unsafe fn free_dma32(ptr: *mut u8, layout: Layout) {
+    let physical_offset = KERNEL_MEMORY_MANAGER.lock().translate(PhysAddr::zero());
+    let start = ptr as u64 - physical_offset.as_u64();
+    for i in 0..dma32_frame_count(layout) {
+        KERNEL_FRAME_ALLOCATOR.free(PhysAddr::new(start + (i * PAGE_SIZE) as u64));
     }
 }
 
 pub const PAGE_SIZE: usize = 4096;
 pub const KERNEL_HEAP_START: usize = 0x_F000_0000_0000;
 pub const KERNEL_HEAP_PAGES: usize = 128;
+pub const KERNEL_RESERVE_PAGES: usize = 16;
+pub const DMA32_LIMIT: u64 = 0x1_0000_0000;
+pub const DMA_LIMIT: u64 = 0x100_0000;
+// Where each zone ends, in the same order as the MEMORY_ZONE constants.
+const ZONE_LIMITS: [u64; MEMORY_ZONE_COUNT] = [DMA_LIMIT, DMA32_LIMIT, u64::MAX];
 pub const ONE_MEGABYTE: usize = 1024 * 1024;
 pub const ONE_GIGABTYE: usize = ONE_MEGABYTE * 1024;
 pub const ONE_TERABYTE: usize = ONE_GIGABTYE * 1024;
@@ -227,6 +379,108 @@ impl BootInfoFrameAllocator {
         None
     }
 
+    /// Allocates count physically contiguous frames, all of which end below the limit address.
+    pub fn allocate_contiguous_frames(
+        &mut self,
+        count: usize,
+        limit: u64,
+    ) -> Option<PhysFrame<Size4KiB>> {
+        let run_start = self.find_run(count, limit, |page| !self.used_pages[page])?;
+        let first_page = Self::get_page(run_start as usize);
+        for page in first_page..first_page + count {
+            self.used_pages.set(page, true);
+        }
+        Some(PhysFrame::containing_address(PhysAddr::new(run_start)))
+    }
+
+    /// Finds count contiguous frames below the limit address that are each either free, or used
+    /// but movable, without allocating them.
+    pub fn find_compactable_frames(
+        &self,
+        count: usize,
+        limit: u64,
+        movable: impl Fn(PhysAddr) -> bool,
+    ) -> Option<PhysFrame<Size4KiB>> {
+        let run_start = self.find_run(count, limit, |page| {
+            !self.used_pages[page] || movable(PhysAddr::new((page * PAGE_SIZE) as u64))
+        })?;
+        Some(PhysFrame::containing_address(PhysAddr::new(run_start)))
+    }
+
+    pub fn is_used(&self, frame: PhysFrame<Size4KiB>) -> bool {
+        let page = Self::get_page(frame.start_address().as_u64() as usize);
+        self.used_pages.get(page).map_or(true, |used| *used)
+    }
+
+    // The start of the first run of count usable pages below limit that are all available.
+    fn find_run(&self, count: usize, limit: u64, available: impl Fn(usize) -> bool) -> Option<u64> {
+        let memory_map = self.memory_map?;
+        for region in memory_map
+            .iter()
+            .filter(|r| r.kind == MemoryRegionKind::Usable)
+        {
+            // Conventional memory must be explicitly allocated, same as allocate_frame.
+            let start = PhysAddr::new(region.start.max(0x100000)).align_up(PAGE_SIZE as u64);
+            let end = region.end.min(limit);
+            let mut run_start = start.as_u64();
+            let mut run_length = 0;
+            let mut address = start.as_u64();
+            while address + PAGE_SIZE as u64 <= end {
+                let page = Self::get_page(address as usize);
+                if page >= self.used_pages.len() {
+                    break;
+                }
+                if !available(page) {
+                    run_length = 0;
+                    run_start = address + PAGE_SIZE as u64;
+                } else {
+                    run_length += 1;
+                    if run_length == count {
+                        return Some(run_start);
+                    }
+                }
+                address += PAGE_SIZE as u64;
+            }
+        }
+
+        None
+    }
+
+    /// Usable and free pages in each zone, and the number of pages that are never usable.
+    pub fn count_pages(&self) -> ([ZoneState; MEMORY_ZONE_COUNT], u64) {
+        let mut zones = [ZoneState::default(); MEMORY_ZONE_COUNT];
+        let mut reserved = 0;
+        let memory_map = match self.memory_map {
+            Some(memory_map) => memory_map,
+            None => return (zones, reserved),
+        };
+        for region in memory_map.iter() {
+            if region.kind != MemoryRegionKind::Usable {
+                reserved += (region.end - region.start) / PAGE_SIZE as u64;
+                continue;
+            }
+            let usable = match MemoryRange::new(region.start, region.end) {
+                Ok(usable) => usable,
+                Err(_) => continue,
+            };
+            let mut zone_start = 0;
+            for (zone, limit) in zones.iter_mut().zip(ZONE_LIMITS) {
+                let zone_range = MemoryRange::new(zone_start, limit).unwrap();
+                zone_start = limit;
+                let range = match usable.intersection(&zone_range) {
+                    Some(range) => range,
+                    None => continue,
+                };
+                // Anything past the bitmap is not addressable, and not counted.
+                let first = Self::get_page(range.start() as usize).min(self.used_pages.len());
+                let last = Self::get_page(range.end() as usize).min(self.used_pages.len());
+                zone.total_pages += (last - first) as u64;
+                zone.free_pages += self.used_pages[first..last].count_zeros() as u64;
+            }
+        }
+        (zones, reserved)
+    }
+
     pub fn force_allocate(&mut self, frame: PhysFrame) -> Option<PhysFrame> {
         let page = Self::get_page(frame.start_address().as_u64() as usize);
         if self
@@ -288,7 +542,10 @@ pub fn init_frame_allocator(memory_map: &'static MemoryRegions) {
 }
 pub fn init_kernel_heap() -> Result<(), MapToError<Size4KiB>> {
     println!("Initializing heap");
-    unsafe { ALLOCATOR.init() };
+    unsafe {
+        ALLOCATOR.init();
+        state::register(&ALLOCATOR);
+    }
     debug!(
         "Kernel heap Allocated heap with {} pages ({} bytes)",
         KERNEL_HEAP_PAGES,
@@ -297,10 +554,25 @@ pub fn init_kernel_heap() -> Result<(), MapToError<Size4KiB>> {
     Ok(())
 }
 
+/// Size and usage, in bytes, of the kernel heap and then its emergency reserve. All zeros before
+/// the heap is initialized.
+pub fn heap_usage() -> ((usize, usize), (usize, usize)) {
+    state::try_get::<KernelAllocator>().map_or(((0, 0), (0, 0)), KernelAllocator::usage)
+}
+
 pub fn kmalloc(layout: Layout) -> *mut u8 {
     unsafe { ALLOCATOR.alloc(layout) }
 }
 
+pub fn kmalloc_flags(layout: Layout, flags: AllocationFlags) -> *mut u8 {
+    unsafe { ALLOCATOR.allocate(layout, flags) }
+}
+
 pub fn kfree(ptr: *mut u8, layout: Layout) {
     unsafe { ALLOCATOR.dealloc(ptr, layout) }
 }
+
+/// Frees memory from kmalloc_flags, flags must match the ones it was allocated with.
+pub fn kfree_flags(ptr: *mut u8, layout: Layout, flags: AllocationFlags) {
+    unsafe { ALLOCATOR.deallocate(ptr, layout, flags) }
+}
@@ -0,0 +1,222 @@
+//! Page migration, and allocating physically contiguous memory by migrating movable frames out
+//! of the way. Frames are marked and migrated with the memory manager held, so the movable table
+//! has a fixed size and never allocates: growing the heap takes the memory manager too.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use spin::Mutex;
+use x86_64::{
+    structures::paging::{FrameAllocator, Page, PageTableFlags, PhysFrame, Size4KiB},
+    PhysAddr,
+};
+
+use crate::{arch::arch_x86_64::tlb, debug, warn};
+
+use super::{
+    allocator::{KERNEL_FRAME_ALLOCATOR, PAGE_SIZE},
+    frame_table::FrameTable,
+    rmap, MemoryManager, KERNEL_MEMORY_MANAGER,
+};
+
+const MOVABLE_SLOTS: usize = 1 << 13;
+const NOT_MIGRATING: u64 = 0;
+
+// Frames that can be moved, by physical address, and the one page each is mapped at. Only
+// changed with the memory manager held.
+static MOVABLE: Mutex<FrameTable<Page<Size4KiB>, MOVABLE_SLOTS>> = Mutex::new(FrameTable::new());
+
+// The page being migrated, write protected until it's remapped. Writes to it wait on this
+// rather than the memory manager, which the migration holds.
+static MIGRATING: AtomicU64 = AtomicU64::new(NOT_MIGRATING);
+
+/// Marks a frame as movable, it must be mapped at page and nowhere else, and nothing may hold
+/// its physical address. Once the table is full frames stay put, they just aren't marked.
+pub(crate) fn mark_movable(frame: PhysFrame<Size4KiB>, page: Page<Size4KiB>) {
+    let mut movable = MOVABLE.lock();
+    movable.remove(frame.start_address().as_u64(), |_| true);
+    movable.insert(frame.start_address().as_u64(), page);
+}
+
+pub(crate) fn clear_movable(frame: PhysFrame<Size4KiB>) {
+    MOVABLE
+        .lock()
+        .remove(frame.start_address().as_u64(), |_| true);
+}
+
+/// Write protects a page on every CPU, so it can be moved to another frame. Writes to it wait in
+/// the fault handler until end_migration. Returns false, with the page left writable, if a CPU
+/// didn't confirm the write protection. Called with the memory manager held, which makes
+/// migrations one at a time.
+pub(super) fn begin_migration(
+    memory_manager: &mut MemoryManager,
+    page: Page<Size4KiB>,
+    flags: PageTableFlags,
+) -> bool {
+    MIGRATING.store(page.start_address().as_u64(), Ordering::Release);
+    memory_manager.update_flags(page.start_address(), 1, flags - PageTableFlags::WRITABLE);
+    if tlb::shootdown() {
+        return true;
+    }
+    // A CPU may still be writing to the old frame, copying it now could lose the write.
+    memory_manager.update_flags(page.start_address(), 1, flags);
+    tlb::shootdown();
+    end_migration();
+    false
+}
+
+pub(super) fn end_migration() {
+    MIGRATING.store(NOT_MIGRATING, Ordering::Release);
+}
+
+/// Waits out the migration of the page, if it's being migrated. Returns true if it was, and the
+/// write that faulted can be retried. Called from the page fault handler, without any locks.
+pub(crate) fn wait_for_migration(page: Page<Size4KiB>) -> bool {
+    if MIGRATING.load(Ordering::Acquire) != page.start_address().as_u64() {
+        return false;
+    }
+    while MIGRATING.load(Ordering::Acquire) == page.start_address().as_u64() {
+        // Interrupts are off in the fault handler, the migration's shootdowns are waiting on us.
+        tlb::acknowledge();
+        core::hint::spin_loop();
+    }
+    true
+}
+
+// Copies a movable frame to a new one, and remaps its page there. The page is write protected,
+// on every CPU, before the copy, so no write lands in the old frame after it's been copied, and
+// the old frame is only freed once no CPU can still reach it. If a CPU doesn't confirm the
+// write protection the page is left where it was, and if one doesn't confirm the remap the old
+// frame is never freed. The new frame is allocated normally, so frames the caller wants to keep
+// free must already be allocated.
+fn migrate(memory_manager: &mut MemoryManager, frame: PhysFrame<Size4KiB>) -> bool {
+    let mut movable = MOVABLE.lock();
+    let page = match movable.get(frame.start_address().as_u64()).next() {
+        Some(page) => page,
+        None => return false,
+    };
+    // Only the one page may map it, any other mapping would be left on the old frame.
+    if rmap::map_count(frame) > 1 {
+        return false;
+    }
+    let flags = match memory_manager.flags(page) {
+        Some(flags) => flags,
+        None => return false,
+    };
+    let new_frame = match unsafe { KERNEL_FRAME_ALLOCATOR.allocate_frame() } {
+        Some(new_frame) => new_frame,
+        None => return false,
+    };
+    if !begin_migration(memory_manager, page, flags) {
+        unsafe { KERNEL_FRAME_ALLOCATOR.free(new_frame.start_address()) };
+        return false;
+    }
+    unsafe {
+        core::ptr::copy_nonoverlapping(
+            memory_manager
+                .translate(frame.start_address())
+                .as_ptr::<u8>(),
+            memory_manager
+                .translate(new_frame.start_address())
+                .as_mut_ptr::<u8>(),
+            PAGE_SIZE,
+        );
+    }
+    memory_manager.remap(page, new_frame);
+    memory_manager.update_flags(page.start_address(), 1, flags);
+    let flushed = tlb::shootdown();
+    end_migration();
+    // Removed first, so there's always room for the new frame.
+    movable.remove(frame.start_address().as_u64(), |_| true);
+    movable.insert(new_frame.start_address().as_u64(), page);
+    if !flushed {
+        // A CPU may still reach the old frame through a stale mapping, it's leaked rather than
+        // handed to someone else.
+        warn!(
+            "Leaking frame {:#x}, a CPU may still be using its old mapping",
+            frame.start_address().as_u64()
+        );
+        return false;
+    }
+    unsafe { KERNEL_FRAME_ALLOCATOR.free(frame.start_address()) };
+    true
+}
+
+/// Moves the contents of each movable frame to another frame, remapping the page it's mapped
+/// at, and returns how many were moved. The old frames are freed.
+pub fn migrate_pages(frames: &[PhysFrame<Size4KiB>]) -> usize {
+    let mut memory_manager = KERNEL_MEMORY_MANAGER.lock();
+    frames
+        .iter()
+        .filter(|frame| migrate(&mut memory_manager, **frame))
+        .count()
+}
+
+/// Allocates nr_pages physically contiguous frames below the limit address. If no free run is
+/// long enough, movable frames are migrated out of the way to make one.
+pub fn alloc_contiguous(nr_pages: usize, limit: u64) -> Option<PhysFrame<Size4KiB>> {
+    let mut memory_manager = KERNEL_MEMORY_MANAGER.lock();
+    if let Some(frame) =
+        unsafe { KERNEL_FRAME_ALLOCATOR.allocate_contiguous_frames(nr_pages, limit) }
+    {
+        return Some(frame);
+    }
+    let start = {
+        let movable = MOVABLE.lock();
+        unsafe {
+            KERNEL_FRAME_ALLOCATOR.find_compactable_frames(nr_pages, limit, |address| {
+                movable.contains(address.as_u64())
+            })
+        }
+    }?;
+    // Take the free frames first, so nothing migrates into the run. Every other frame in it is
+    // movable.
+    for i in 0..nr_pages as u64 {
+        let frame = start + i;
+        if unsafe { !KERNEL_FRAME_ALLOCATOR.is_used(frame) } {
+            unsafe { KERNEL_FRAME_ALLOCATOR.force_allocate(frame) };
+        }
+    }
+    let mut migrated = 0;
+    for i in 0..nr_pages as u64 {
+        let frame = start + i;
+        if !is_movable(frame.start_address()) {
+            continue;
+        }
+        if migrate(&mut memory_manager, frame) {
+            unsafe { KERNEL_FRAME_ALLOCATOR.force_allocate(frame) };
+            migrated += 1;
+            continue;
+        }
+        // Out of memory to migrate to, or the frame couldn't be given up safely. Give back what
+        // was taken, everything that isn't still mapped movable, but the frame that failed.
+        for j in (0..nr_pages as u64).filter(|j| *j != i) {
+            let frame = start + j;
+            if !is_movable(frame.start_address()) {
+                unsafe { KERNEL_FRAME_ALLOCATOR.free(frame.start_address()) };
+            }
+        }
+        return None;
+    }
+    debug!(
+        "Compacted {} pages to allocate {} contiguous pages at {:#x}",
+        migrated,
+        nr_pages,
+        start.start_address().as_u64()
+    );
+    Some(start)
+}
+
+/// Frees frames returned by alloc_contiguous.
+pub fn free_contiguous(start: PhysFrame<Size4KiB>, nr_pages: usize) {
+    let _memory_manager = KERNEL_MEMORY_MANAGER.lock();
+    for i in 0..nr_pages as u64 {
+        unsafe { KERNEL_FRAME_ALLOCATOR.free((start + i).start_address()) };
+    }
+}
+
+/// True if the frame at this address can be migrated.
+pub fn is_movable(address: PhysAddr) -> bool {
+    MOVABLE
+        .lock()
+        .contains(address.align_down(PAGE_SIZE as u64).as_u64())
+}
@@ -0,0 +1,105 @@
+use alloc::string::{String, ToString};
+use core::mem::size_of;
+use devices::{
+    well_known::{self, IPL},
+    Device, DeviceError, DeviceErrorCode,
+};
+use kernel_shared::{
+    device::{MemoryDescription, DEVICE_FUNCTION_DESCRIBE, MEMORY_FUNCTION_READ_STATE},
+    kernel_state::{MemoryState, PublishedRecord, Record},
+    serialization::to_bytes,
+};
+use spin::Mutex;
+use uuid::Uuid;
+
+use super::{
+    allocator::{heap_usage, PAGE_SIZE},
+    page_counts,
+};
+
+const MEMORY_DESCRIPTION_LENGTH: usize = 48;
+
+/// Physical memory and heap usage, as a device. MEMORY_FUNCTION_READ_STATE returns a MemoryState
+/// record.
+pub(super) struct MemoryDevice {
+    parent: u128,
+    description: [u8; MEMORY_DESCRIPTION_LENGTH],
+    description_length: usize,
+    state: PublishedRecord<MemoryState>,
+    // Serializes refreshes, PublishedRecord allows only one writer.
+    refresh_lock: Mutex<()>,
+}
+
+impl MemoryDevice {
+    pub(super) fn new() -> Self {
+        let mut description = [0u8; MEMORY_DESCRIPTION_LENGTH];
+        let description_length = to_bytes(
+            &MemoryDescription {
+                record_kind: MemoryState::KIND,
+                record_version: MemoryState::VERSION,
+                record_length: size_of::<MemoryState>(),
+                page_size: PAGE_SIZE,
+            },
+            &mut description,
+        )
+        .map_or(0, |bytes| bytes.len());
+        let ret = Self {
+            parent: IPL.as_u128(),
+            description,
+            description_length,
+            state: PublishedRecord::new(MemoryState::default()),
+            refresh_lock: Mutex::new(()),
+        };
+        ret.refresh();
+        ret
+    }
+
+    fn refresh(&self) {
+        let _guard = self.refresh_lock.lock();
+        let (zones, reserved_pages) = page_counts();
+        let ((heap_bytes, heap_used_bytes), (reserve_bytes, reserve_used_bytes)) = heap_usage();
+        self.state.update(|state| {
+            state.page_size = PAGE_SIZE as u64;
+            state.total_pages = zones.iter().map(|z| z.total_pages).sum();
+            state.free_pages = zones.iter().map(|z| z.free_pages).sum();
+            state.reserved_pages = reserved_pages;
+            state.heap_bytes = heap_bytes as u64;
+            state.heap_used_bytes = heap_used_bytes as u64;
+            state.heap_reserve_bytes = reserve_bytes as u64;
+            state.heap_reserve_used_bytes = reserve_used_bytes as u64;
+            state.zones = zones;
+        });
+    }
+}
+
+impl Device for MemoryDevice {
+    fn name(&self) -> String {
+        "MEMORY".to_string()
+    }
+
+    fn ready(&self) -> bool {
+        true
+    }
+
+    fn parent_id(&self) -> Option<u128> {
+        Some(self.parent)
+    }
+
+    fn uuid(&self) -> Uuid {
+        well_known::MEMORY
+    }
+
+    fn function(&self, id: usize, _args: &[usize]) -> Result<&[u8], DeviceError> {
+        match id {
+            DEVICE_FUNCTION_DESCRIBE if self.description_length > 0 => {
+                Ok(&self.description[0..self.description_length])
+            }
+            DEVICE_FUNCTION_DESCRIBE => Err(DeviceError::new(DeviceErrorCode::Malfunction)),
+            MEMORY_FUNCTION_READ_STATE => {
+                self.refresh();
+                Ok(self.state.as_bytes())
+            }
+            _ => Err(DeviceError::new(DeviceErrorCode::NotImplemented)),
+        }
+    }
+}
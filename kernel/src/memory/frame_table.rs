@@ -0,0 +1,94 @@
+//! A fixed size hash table keyed by frame address, for bookkeeping done with the memory manager
+//! held, where allocating isn't allowed: growing the heap takes the memory manager too. A frame
+//! may have any number of entries.
+
+const HASH_MULTIPLIER: u64 = 0x9e37_79b9_7f4a_7c15;
+
+// Open addressed, linearly probed from the frame's hash. Kept at most three quarters full, so
+// probe runs stay short. SLOTS must be a power of two.
+pub(super) struct FrameTable<V, const SLOTS: usize> {
+    slots: [Option<(u64, V)>; SLOTS],
+    used: usize,
+}
+
+impl<V: Copy, const SLOTS: usize> FrameTable<V, SLOTS> {
+    const MAX_USED: usize = SLOTS / 4 * 3;
+
+    pub(super) const fn new() -> Self {
+        assert!(SLOTS.is_power_of_two());
+        Self {
+            slots: [None; SLOTS],
+            used: 0,
+        }
+    }
+
+    fn home(frame: u64) -> usize {
+        // Fibonacci hashing, frame addresses are page aligned so their low bits are all zero.
+        (frame.wrapping_mul(HASH_MULTIPLIER) >> (64 - SLOTS.trailing_zeros())) as usize
+    }
+
+    fn position(&self, frame: u64, mut matches: impl FnMut(&V) -> bool) -> Option<usize> {
+        let mut index = Self::home(frame);
+        loop {
+            match &self.slots[index] {
+                None => return None,
+                Some((address, value)) if *address == frame && matches(value) => {
+                    return Some(index)
+                }
+                Some(_) => index = (index + 1) % SLOTS,
+            }
+        }
+    }
+
+    /// Every entry for the frame.
+    pub(super) fn get(&self, frame: u64) -> impl Iterator<Item = V> + '_ {
+        let start = Self::home(frame);
+        (0..SLOTS)
+            .map(move |offset| self.slots[(start + offset) % SLOTS])
+            .take_while(|slot| slot.is_some())
+            .flatten()
+            .filter(move |(address, _)| *address == frame)
+            .map(|(_, value)| value)
+    }
+
+    pub(super) fn contains(&self, frame: u64) -> bool {
+        self.position(frame, |_| true).is_some()
+    }
+
+    /// Adds an entry for the frame, alongside any it already has. False if the table is full.
+    pub(super) fn insert(&mut self, frame: u64, value: V) -> bool {
+        if self.used == Self::MAX_USED {
+            return false;
+        }
+        let mut index = Self::home(frame);
+        while self.slots[index].is_some() {
+            index = (index + 1) % SLOTS;
+        }
+        self.slots[index] = Some((frame, value));
+        self.used += 1;
+        true
+    }
+
+    /// Removes the frame's first entry that matches, returning false if none did.
+    pub(super) fn remove(&mut self, frame: u64, matches: impl FnMut(&V) -> bool) -> bool {
+        let mut hole = match self.position(frame, matches) {
+            Some(index) => index,
+            None => return false,
+        };
+        self.slots[hole] = None;
+        self.used -= 1;
+        // Shift back any slot after the hole that probing would no longer reach.
+        let mut next = (hole + 1) % SLOTS;
+        while let Some((address, value)) = self.slots[next] {
+            let distance_from_home = next.wrapping_sub(Self::home(address)) % SLOTS;
+            let distance_to_hole = next.wrapping_sub(hole) % SLOTS;
+            if distance_from_home >= distance_to_hole {
+                self.slots[hole] = Some((address, value));
+                self.slots[next] = None;
+                hole = next;
+            }
+            next = (next + 1) % SLOTS;
+        }
+        true
+    }
+}
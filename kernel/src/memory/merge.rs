@@ -0,0 +1,62 @@
+//! Merges anonymous memory that's been written back to all zeros into the shared zero page, so
+//! it stops holding a frame of its own. Off unless the `memory.merge_interval_ms` tunable is set,
+//! then every interval a few pages are scanned, carrying on from where the last scan stopped.
+//!
+//! Only zero pages are merged. Sharing any other identical page would need a count of the pages
+//! mapping each frame, to know when a write can keep it, and frames don't have one.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use kernel_shared::tunable::TunableValue;
+use spin::Mutex;
+
+use crate::{
+    debug,
+    time::work::{cancel_work, schedule_periodic_work, WorkId},
+    tunables,
+};
+
+use super::virtual_area;
+
+const MAX_INTERVAL_MILLISECONDS: i64 = 60_000;
+// Pages scanned each interval. The scan holds the memory manager, so it's kept short.
+const PAGES_PER_SCAN: u64 = 64;
+
+static PERIODIC_SCAN: Mutex<Option<WorkId>> = Mutex::new(None);
+// Where the next scan starts.
+static CURSOR: AtomicU64 = AtomicU64::new(0);
+// Merged since the scan last reached the end of the region.
+static MERGED: AtomicU64 = AtomicU64::new(0);
+
+pub(crate) fn init() {
+    tunables::register_integer(
+        "memory.merge_interval_ms",
+        0,
+        0,
+        MAX_INTERVAL_MILLISECONDS,
+        set_interval,
+    );
+}
+
+fn set_interval(value: &TunableValue) -> bool {
+    let mut periodic_scan = PERIODIC_SCAN.lock();
+    if let Some(id) = periodic_scan.take() {
+        cancel_work(id);
+    }
+    if value.integer > 0 {
+        let period = value.integer as u64 * 1_000_000;
+        *periodic_scan = Some(schedule_periodic_work(period, scan, 0));
+    }
+    true
+}
+
+fn scan(_context: usize) {
+    let (next, merged) =
+        virtual_area::merge_zero_pages(CURSOR.load(Ordering::Relaxed), PAGES_PER_SCAN);
+    CURSOR.store(next, Ordering::Relaxed);
+    let total = MERGED.fetch_add(merged, Ordering::Relaxed) + merged;
+    if next == 0 && total > 0 {
+        debug!("Merged {} zero pages into the zero page", total);
+        MERGED.store(0, Ordering::Relaxed);
+    }
+}
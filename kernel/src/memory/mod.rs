@@ -1,32 +1,56 @@
 use bootloader_api::info::MemoryRegions;
+use devices::get_mut_device_tree;
+use kernel_shared::kernel_state::{ZoneState, MEMORY_ZONE_COUNT};
 use lazy_static::lazy_static;
 use spin::Mutex;
 use x86_64::{
     instructions::tlb, registers::control::Cr3, structures::paging::*, PhysAddr, VirtAddr,
 };
 
-use crate::{println, verbose};
+use crate::{boottime, println, verbose};
 
 use self::allocator::{init_frame_allocator, init_kernel_heap, KERNEL_FRAME_ALLOCATOR, PAGE_SIZE};
 
 pub(crate) mod allocator;
+pub(crate) mod compaction;
+mod device;
+mod frame_table;
+pub(crate) mod merge;
+pub(crate) mod policy;
+pub(crate) mod ptdump;
+pub(crate) mod rmap;
+pub(crate) mod stack;
+pub(crate) mod user;
+pub(crate) mod virtual_area;
+pub(crate) mod zero_page;
 
 pub(crate) struct MemoryManager {
     page_table: Option<OffsetPageTable<'static>>,
     physical_offset: VirtAddr,
     next_free_page: VirtAddr,
+    // The level 4 table, naming this address space in the reverse map.
+    address_space: Option<PhysFrame<Size4KiB>>,
 }
 
 impl MemoryManager {
     pub fn init(self: &mut Self, page_table: OffsetPageTable<'static>) {
         self.page_table = Some(page_table);
         self.physical_offset = self.page_table.as_ref().unwrap().phys_offset();
+        self.address_space = Some(Cr3::read().0);
+    }
+
+    fn mapping(&self, page: Page<Size4KiB>) -> rmap::Mapping {
+        rmap::Mapping {
+            address_space: self.address_space.unwrap(),
+            page,
+        }
     }
 
     // pub fn map_page(&mut self, physical_address: PhysAddr) {
 
     // }
 
+    #[track_caller]
     pub fn allocate_contigious_address_range(
         &mut self,
         pages: usize,
@@ -63,7 +87,9 @@ impl MemoryManager {
         }
 
         self.next_free_page = (start_page + index as u64).start_address();
+        // Not recorded in the reverse map, which has a fixed size the heap alone could fill.
         for i in 0..index {
+            policy::enforce(start_page + i as u64, flags);
             let frame = unsafe { KERNEL_FRAME_ALLOCATOR.allocate_frame()? };
             let flush = unsafe {
                 page_table.map_to(
@@ -88,27 +114,118 @@ impl MemoryManager {
         return Some(start_page.start_address().as_mut_ptr());
     }
 
+    #[track_caller]
     pub fn identity_map(&mut self, frame: PhysFrame<Size4KiB>, flags: PageTableFlags) {
+        policy::enforce(
+            Page::containing_address(VirtAddr::new(frame.start_address().as_u64())),
+            flags,
+        );
+        unsafe { self.identity_map_unchecked(frame, flags) }
+    }
+
+    /// Like identity_map, without checking the mapping policy. Only for the AP trampoline, which
+    /// must be writable and executable, as the APs run it and write their boot progress to it.
+    pub unsafe fn identity_map_unchecked(
+        &mut self,
+        frame: PhysFrame<Size4KiB>,
+        flags: PageTableFlags,
+    ) {
+        self.page_table
+            .as_mut()
+            .unwrap()
+            .identity_map(frame, flags, &mut KERNEL_FRAME_ALLOCATOR)
+            .expect("Unable to identity map memory!")
+            .flush();
+    }
+
+    #[track_caller]
+    pub fn map(&mut self, page: Page<Size4KiB>, frame: PhysFrame<Size4KiB>, flags: PageTableFlags) {
+        policy::enforce(page, flags);
         unsafe {
             self.page_table
                 .as_mut()
                 .unwrap()
-                .identity_map(frame, flags, &mut KERNEL_FRAME_ALLOCATOR)
-                .expect("Unable to identity map memory!")
+                .map_to(page, frame, flags, &mut KERNEL_FRAME_ALLOCATOR)
+                .expect("Unable to map memory!")
                 .flush();
         }
+        rmap::add(frame, self.mapping(page));
+    }
+
+    /// Unmaps a 4KiB page, returning the frame it was mapped to.
+    pub fn unmap(&mut self, page: Page<Size4KiB>) -> Option<PhysFrame<Size4KiB>> {
+        let (frame, flush) = self.page_table.as_mut().unwrap().unmap(page).ok()?;
+        flush.flush();
+        rmap::remove(frame, self.mapping(page));
+        Some(frame)
+    }
+
+    /// Points a mapped 4KiB page at another frame, keeping its flags, returning the old frame.
+    pub fn remap(
+        &mut self,
+        page: Page<Size4KiB>,
+        frame: PhysFrame<Size4KiB>,
+    ) -> Option<PhysFrame<Size4KiB>> {
+        let page_table = self.page_table.as_mut().unwrap();
+        let flags = match page_table.translate(page.start_address()) {
+            mapper::TranslateResult::Mapped { flags, .. } => flags,
+            _ => return None,
+        };
+        let (old_frame, flush) = page_table.unmap(page).ok()?;
+        flush.ignore();
+        unsafe {
+            page_table
+                .map_to(page, frame, flags, &mut KERNEL_FRAME_ALLOCATOR)
+                .expect("Unable to remap memory!")
+                .flush();
+        }
+        let mapping = self.mapping(page);
+        rmap::remove(old_frame, mapping);
+        rmap::add(frame, mapping);
+        Some(old_frame)
     }
 
     pub fn translate(&self, physical_address: PhysAddr) -> VirtAddr {
         VirtAddr::new(physical_address.as_u64() + self.physical_offset.as_u64())
     }
+
+    /// The flags a mapped 4KiB page has.
+    pub fn flags(&self, page: Page<Size4KiB>) -> Option<PageTableFlags> {
+        match self.page_table.as_ref()?.translate(page.start_address()) {
+            mapper::TranslateResult::Mapped { flags, .. } => Some(flags),
+            _ => None,
+        }
+    }
+
+    pub fn physical_address(&self, virtual_address: VirtAddr) -> Option<PhysAddr> {
+        self.page_table.as_ref()?.translate_addr(virtual_address)
+    }
+
+    /// Replaces the flags on already mapped 4KiB pages, returning how many were updated. Pages
+    /// that aren't mapped, or are part of a huge page, are skipped.
+    #[track_caller]
+    pub fn update_flags(&mut self, start: VirtAddr, pages: usize, flags: PageTableFlags) -> usize {
+        let page_table = self.page_table.as_mut().unwrap();
+        let start_page = Page::<Size4KiB>::containing_address(start);
+        let mut updated = 0;
+        for i in 0..pages as u64 {
+            policy::enforce(start_page + i, flags);
+            if let Ok(flush) = unsafe { page_table.update_flags(start_page + i, flags) } {
+                flush.ignore();
+                updated += 1;
+            }
+        }
+        tlb::flush_all();
+        updated
+    }
 }
 
 lazy_static! {
     pub(crate) static ref KERNEL_MEMORY_MANAGER: Mutex<MemoryManager> = Mutex::new(MemoryManager {
         page_table: None,
         physical_offset: VirtAddr::zero(),
-        next_free_page: VirtAddr::new(0x100000).align_down(PAGE_SIZE as u64)
+        next_free_page: VirtAddr::new(0x100000).align_down(PAGE_SIZE as u64),
+        address_space: None,
     });
 }
 
@@ -136,9 +253,23 @@ pub(crate) fn initialize_virtual_memory(
         }
         println!("Initializing frame allocator");
         // and boot up the frame allocator
-        init_frame_allocator(memory_map);
+        boottime::stage("Frame allocator", || init_frame_allocator(memory_map));
         // And then the heap.
-        init_kernel_heap().expect("Failed to initialize kernel heap");
+        boottime::stage("Kernel heap", init_kernel_heap).expect("Failed to initialize kernel heap");
         verbose!("Heap and virtual memory initialized.");
     }
 }
+
+/// Usable and free pages in each zone, and the number of pages that are never usable.
+pub(crate) fn page_counts() -> ([ZoneState; MEMORY_ZONE_COUNT], u64) {
+    // Frames are allocated with the memory manager held, so the bitmap is stable under it.
+    let _memory_manager = KERNEL_MEMORY_MANAGER.lock();
+    unsafe { KERNEL_FRAME_ALLOCATOR.count_pages() }
+}
+
+/// Allocates the zero page, and registers the memory statistics device, once the device tree
+/// can be used.
+pub(crate) fn init() {
+    zero_page::init();
+    get_mut_device_tree().register(device::MemoryDevice::new());
+}
@@ -0,0 +1,82 @@
+//! The rules every page the kernel maps must follow, checked as it's mapped:
+//!
+//! - No page is user accessible. There's no user address space yet, every mapping is the
+//!   kernel's.
+//! - No page is both writable and executable.
+//! - Uncached pages, which are device registers, are only mapped in the MMIO window, see
+//!   KernelRegion::Mmio.
+//!
+//! A mapping that breaks them panics in debug builds, naming the caller that asked for it. Release
+//! builds log it, and map the page anyway, rather than fail a boot over it.
+
+use core::panic::Location;
+
+use x86_64::structures::paging::{Page, PageTableFlags, Size4KiB};
+
+use crate::error;
+
+use super::virtual_area::KernelRegion;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Violation {
+    UserAccessible,
+    WritableExecutable,
+    MmioOutsideWindow,
+}
+
+impl Violation {
+    pub fn description(&self) -> &'static str {
+        match self {
+            Violation::UserAccessible => "user accessible kernel page",
+            Violation::WritableExecutable => "writable and executable page",
+            Violation::MmioOutsideWindow => "uncached page outside the MMIO window",
+        }
+    }
+}
+
+/// Whether page may be mapped with flags. Pages that aren't present can't be reached, so
+/// anything goes.
+pub fn check(page: Page<Size4KiB>, flags: PageTableFlags) -> Result<(), Violation> {
+    if !flags.contains(PageTableFlags::PRESENT) {
+        return Ok(());
+    }
+    if flags.contains(PageTableFlags::USER_ACCESSIBLE) {
+        return Err(Violation::UserAccessible);
+    }
+    if flags.contains(PageTableFlags::WRITABLE) && !flags.contains(PageTableFlags::NO_EXECUTE) {
+        return Err(Violation::WritableExecutable);
+    }
+    if flags.contains(PageTableFlags::NO_CACHE)
+        && KernelRegion::containing(page.start_address()) != Some(KernelRegion::Mmio)
+    {
+        return Err(Violation::MmioOutsideWindow);
+    }
+    Ok(())
+}
+
+/// Checks a mapping about to be made, see the module documentation for what happens when it
+/// breaks the rules.
+#[track_caller]
+pub(crate) fn enforce(page: Page<Size4KiB>, flags: PageTableFlags) {
+    let violation = match check(page, flags) {
+        Ok(()) => return,
+        Err(violation) => violation,
+    };
+    let caller = Location::caller();
+    if cfg!(debug_assertions) {
+        panic!(
+            "Mapping policy: {} at {:#x} ({:?}), mapped from {}",
+            violation.description(),
+            page.start_address().as_u64(),
+            flags,
+            caller
+        );
+    }
+    error!(
+        "Mapping policy: {} at {:#x} ({:?}), mapped from {}",
+        violation.description(),
+        page.start_address().as_u64(),
+        flags,
+        caller
+    );
+}
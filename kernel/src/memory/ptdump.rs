@@ -0,0 +1,215 @@
+use alloc::vec::Vec;
+use x86_64::{
+    structures::paging::{PageTable, PageTableFlags, PhysFrame},
+    PhysAddr, VirtAddr,
+};
+
+use crate::{arch::arch_x86_64::pat, println, warn};
+
+use super::KERNEL_MEMORY_MANAGER;
+
+// Bytes mapped by one entry at each level, from the level 4 table down.
+const ENTRY_SIZES: [u64; 4] = [1 << 39, 1 << 30, 1 << 21, 1 << 12];
+const RANGE_SLACK: usize = 16;
+
+/// A run of virtually and physically contiguous pages with the same effective permissions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MappedRange {
+    pub start: VirtAddr,
+    pub physical_start: PhysAddr,
+    pub length: u64,
+    pub writable: bool,
+    pub executable: bool,
+    pub user: bool,
+    pub global: bool,
+    /// The PAT memory type, like WB or UC.
+    pub memory_type: &'static str,
+}
+
+impl MappedRange {
+    /// Wraps to zero for a range that ends at the top of the address space.
+    pub fn end(&self) -> u64 {
+        self.start.as_u64().wrapping_add(self.length)
+    }
+
+    pub fn is_identity_mapped(&self) -> bool {
+        self.start.as_u64() == self.physical_start.as_u64()
+    }
+
+    pub fn violates_w_xor_x(&self) -> bool {
+        self.writable && self.executable
+    }
+
+    fn extends_to(&self, next: &MappedRange) -> bool {
+        self.end() == next.start.as_u64()
+            && self.physical_start + self.length == next.physical_start
+            && (
+                self.writable,
+                self.executable,
+                self.user,
+                self.global,
+                self.memory_type,
+            ) == (
+                next.writable,
+                next.executable,
+                next.user,
+                next.global,
+                next.memory_type,
+            )
+    }
+}
+
+// Permissions are only granted if every level grants them, NX at any level wins.
+#[derive(Clone, Copy)]
+struct Effective {
+    writable: bool,
+    executable: bool,
+    user: bool,
+}
+
+// Ranges are only kept while ranges has room left, the walk runs under the memory manager lock,
+// and growing the heap takes it too. count is how many there are in all.
+struct Walker {
+    physical_offset: VirtAddr,
+    ranges: Vec<MappedRange>,
+    last: Option<MappedRange>,
+    count: usize,
+}
+
+impl Walker {
+    fn table(&self, frame: PhysAddr) -> &'static PageTable {
+        unsafe { &*(self.physical_offset + frame.as_u64()).as_ptr::<PageTable>() }
+    }
+
+    fn walk(&mut self, table: PhysAddr, level: usize, base: u64, parent: Effective) {
+        for (index, entry) in self.table(table).iter().enumerate() {
+            let flags = entry.flags();
+            if !flags.contains(PageTableFlags::PRESENT) {
+                continue;
+            }
+            let address = base + index as u64 * ENTRY_SIZES[level];
+            // Sign extend, so the upper half prints as the canonical addresses it's used at.
+            let address = VirtAddr::new_truncate(address).as_u64();
+            let effective = Effective {
+                writable: parent.writable && flags.contains(PageTableFlags::WRITABLE),
+                executable: parent.executable && !flags.contains(PageTableFlags::NO_EXECUTE),
+                user: parent.user && flags.contains(PageTableFlags::USER_ACCESSIBLE),
+            };
+            let leaf = level == ENTRY_SIZES.len() - 1;
+            let huge = !leaf && level > 0 && flags.contains(PageTableFlags::HUGE_PAGE);
+            if leaf || huge {
+                // The PAT bit is bit 7 of a 4KiB entry, a huge entry uses bit 7 to say it is huge,
+                // and moves the PAT bit to bit 12, in its address field.
+                let pat_bit = match huge {
+                    true => entry.addr().as_u64() & (1 << 12) != 0,
+                    false => flags.contains(PageTableFlags::HUGE_PAGE),
+                };
+                self.push(MappedRange {
+                    start: VirtAddr::new(address),
+                    physical_start: PhysAddr::new(
+                        entry.addr().as_u64() & !(ENTRY_SIZES[level] - 1),
+                    ),
+                    length: ENTRY_SIZES[level],
+                    writable: effective.writable,
+                    executable: effective.executable,
+                    user: effective.user,
+                    global: flags.contains(PageTableFlags::GLOBAL),
+                    memory_type: pat::memory_type_name(flags, pat_bit),
+                });
+            } else {
+                self.walk(entry.addr(), level + 1, address, effective);
+            }
+        }
+    }
+
+    fn push(&mut self, range: MappedRange) {
+        match &mut self.last {
+            Some(last) if last.extends_to(&range) => last.length += range.length,
+            _ => {
+                self.finish();
+                self.last = Some(range);
+                self.count += 1;
+            }
+        }
+    }
+
+    fn finish(&mut self) {
+        if let Some(last) = self.last.take() {
+            if self.ranges.len() < self.ranges.capacity() {
+                self.ranges.push(last);
+            }
+        }
+    }
+}
+
+/// Collects the mappings of the address space rooted at level_4_table. The tables are walked
+/// under the memory manager lock, so the result can be printed, or searched, without it. The
+/// ranges are counted first, and the buffer for them allocated with the lock dropped.
+pub fn mapped_ranges(level_4_table: PhysFrame) -> Vec<MappedRange> {
+    let all = Effective {
+        writable: true,
+        executable: true,
+        user: true,
+    };
+    let mut capacity = 0;
+    loop {
+        let ranges = Vec::with_capacity(capacity);
+        let memory_manager = KERNEL_MEMORY_MANAGER.lock();
+        let mut walker = Walker {
+            physical_offset: memory_manager.translate(PhysAddr::zero()),
+            ranges,
+            last: None,
+            count: 0,
+        };
+        walker.walk(level_4_table.start_address(), 0, 0, all);
+        walker.finish();
+        drop(memory_manager);
+        if walker.count <= walker.ranges.capacity() {
+            return walker.ranges;
+        }
+        // Room for a few more, in case mappings are added before the next walk.
+        capacity = walker.count + RANGE_SLACK;
+    }
+}
+
+/// Prints every mapping of an address space, flagging writable and executable ranges, and
+/// identity mappings.
+pub fn dump(level_4_table: PhysFrame) {
+    let ranges = mapped_ranges(level_4_table);
+    println!(
+        "Page tables at {:#x}:",
+        level_4_table.start_address().as_u64()
+    );
+    for range in ranges.iter() {
+        println!(
+            "{:#018x}-{:#018x} -> {:#014x} {:>10} {}{}{}{} {}{}",
+            range.start.as_u64(),
+            range.end(),
+            range.physical_start.as_u64(),
+            range.length / 1024,
+            if range.writable { 'W' } else { '-' },
+            if range.executable { 'X' } else { '-' },
+            if range.user { 'U' } else { '-' },
+            if range.global { 'G' } else { '-' },
+            range.memory_type,
+            if range.is_identity_mapped() {
+                " identity"
+            } else {
+                ""
+            },
+        );
+    }
+    let violations = ranges.iter().filter(|r| r.violates_w_xor_x()).count();
+    if violations > 0 {
+        warn!(
+            "{} mapped ranges are both writable and executable",
+            violations
+        );
+    }
+}
+
+/// Dumps the address space the current CPU is running in.
+pub fn dump_current() {
+    let (level_4_table, _) = x86_64::registers::control::Cr3::read();
+    dump(level_4_table);
+}
@@ -0,0 +1,135 @@
+//! The reverse map, every place each frame is mapped. It's updated as the memory manager maps
+//! and unmaps, with the memory manager held, so it never allocates: growing the heap takes the
+//! memory manager too. The mappings live in a fixed size hash table, keyed by frame, and the
+//! resident page counts in a fixed table of address spaces.
+//!
+//! The zero frame is mapped wherever anonymous memory hasn't been written yet, too many places
+//! to list, so only how many is kept for it.
+
+use spin::Mutex;
+use x86_64::structures::paging::{Page, PhysFrame, Size4KiB};
+
+use crate::{debug, warn};
+
+use super::{frame_table::FrameTable, zero_page::is_zero_frame};
+
+const MAPPING_SLOTS: usize = 1 << 14;
+const MAX_ADDRESS_SPACES: usize = 64;
+
+/// One place a frame is mapped: the address space, named by its level 4 table, and the page.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mapping {
+    pub address_space: PhysFrame<Size4KiB>,
+    pub page: Page<Size4KiB>,
+}
+
+struct ReverseMap {
+    mappings: FrameTable<Mapping, MAPPING_SLOTS>,
+    // Pages mapped in each address space, by level 4 table address.
+    resident: [Option<(u64, u64)>; MAX_ADDRESS_SPACES],
+    zero_frame_mappings: usize,
+    // Mappings that didn't fit, so the map is missing them.
+    dropped_mappings: usize,
+    // Pages left out of the resident counts, their address space didn't fit.
+    uncounted_pages: usize,
+}
+
+static REVERSE_MAP: Mutex<ReverseMap> = Mutex::new(ReverseMap {
+    mappings: FrameTable::new(),
+    resident: [None; MAX_ADDRESS_SPACES],
+    zero_frame_mappings: 0,
+    dropped_mappings: 0,
+    uncounted_pages: 0,
+});
+
+impl ReverseMap {
+    fn count_resident(&mut self, address_space: u64, added: bool) {
+        let existing = self
+            .resident
+            .iter()
+            .position(|entry| matches!(entry, Some((space, _)) if *space == address_space));
+        match (existing, added) {
+            (Some(index), true) => {
+                if let Some((_, pages)) = &mut self.resident[index] {
+                    *pages += 1;
+                }
+            }
+            (Some(index), false) => {
+                if let Some((_, pages)) = &mut self.resident[index] {
+                    *pages -= 1;
+                    if *pages == 0 {
+                        self.resident[index] = None;
+                    }
+                }
+            }
+            (None, true) => match self.resident.iter().position(|entry| entry.is_none()) {
+                Some(index) => self.resident[index] = Some((address_space, 1)),
+                None => {
+                    self.uncounted_pages += 1;
+                    if self.uncounted_pages == 1 {
+                        warn!("Too many address spaces, resident pages are undercounted");
+                    }
+                }
+            },
+            (None, false) => {}
+        }
+    }
+}
+
+pub(crate) fn add(frame: PhysFrame<Size4KiB>, mapping: Mapping) {
+    let mut reverse_map = REVERSE_MAP.lock();
+    if is_zero_frame(frame) {
+        reverse_map.zero_frame_mappings += 1;
+    } else if !reverse_map
+        .mappings
+        .insert(frame.start_address().as_u64(), mapping)
+    {
+        reverse_map.dropped_mappings += 1;
+        if reverse_map.dropped_mappings == 1 {
+            warn!("The reverse map is full, it's missing mappings from now on");
+        }
+        return;
+    }
+    reverse_map.count_resident(mapping.address_space.start_address().as_u64(), true);
+}
+
+pub(crate) fn remove(frame: PhysFrame<Size4KiB>, mapping: Mapping) {
+    let mut reverse_map = REVERSE_MAP.lock();
+    let removed = match is_zero_frame(frame) {
+        true if reverse_map.zero_frame_mappings > 0 => {
+            reverse_map.zero_frame_mappings -= 1;
+            true
+        }
+        true => false,
+        false => reverse_map
+            .mappings
+            .remove(frame.start_address().as_u64(), |m| *m == mapping),
+    };
+    if removed {
+        reverse_map.count_resident(mapping.address_space.start_address().as_u64(), false);
+    }
+}
+
+/// How many places the frame is mapped.
+pub fn map_count(frame: PhysFrame<Size4KiB>) -> usize {
+    let reverse_map = REVERSE_MAP.lock();
+    match is_zero_frame(frame) {
+        true => reverse_map.zero_frame_mappings,
+        false => reverse_map
+            .mappings
+            .get(frame.start_address().as_u64())
+            .count(),
+    }
+}
+
+pub fn report() {
+    let reverse_map = REVERSE_MAP.lock();
+    debug!("Resident pages:");
+    for (space, pages) in reverse_map.resident.iter().flatten() {
+        debug!("  Address space {:#x}: {} pages", space, pages);
+    }
+    debug!(
+        "  {} mappings of the zero page, {} mappings and {} resident pages not tracked",
+        reverse_map.zero_frame_mappings, reverse_map.dropped_mappings, reverse_map.uncounted_pages
+    );
+}
@@ -0,0 +1,146 @@
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+use crate::{debug, warn};
+
+/// Pattern written over every kernel stack when it is created, used to find the deepest point the
+/// stack has ever reached.
+pub const STACK_POISON: u64 = 0x57AC_57AC_57AC_57AC;
+// Warn once a stack has used this percentage of its space.
+const STACK_USAGE_WARNING_PERCENT: usize = 75;
+
+#[derive(Debug, Clone, Copy)]
+pub struct StackRecord {
+    name: &'static str,
+    index: usize,
+    cpu: usize,
+    base: usize,
+    length: usize,
+    high_watermark: usize,
+    warned: bool,
+}
+
+impl StackRecord {
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    pub fn cpu(&self) -> usize {
+        self.cpu
+    }
+
+    pub fn length(&self) -> usize {
+        self.length
+    }
+
+    /// The most bytes this stack has ever had in use, as of the last check.
+    pub fn high_watermark(&self) -> usize {
+        self.high_watermark
+    }
+
+    fn usage_percent(&self) -> usize {
+        (self.high_watermark * 100) / self.length.max(1)
+    }
+}
+
+lazy_static! {
+    static ref STACKS: Mutex<Vec<StackRecord>> = Mutex::new(Vec::new());
+}
+
+/// Fills a stack that is not yet in use with the poison pattern.
+///
+/// # Safety
+/// The range must be valid for writes, and must not be in use as a stack.
+pub unsafe fn poison_stack(base: *mut u8, length: usize) {
+    let words = length / core::mem::size_of::<u64>();
+    let stack = core::slice::from_raw_parts_mut(base as *mut u64, words);
+    stack.fill(STACK_POISON);
+}
+
+/// Poisons, and starts tracking, a kernel stack.
+///
+/// # Safety
+/// The range must be valid for writes, and must not be in use as a stack.
+pub unsafe fn register_stack(
+    name: &'static str,
+    index: usize,
+    cpu: usize,
+    base: *mut u8,
+    length: usize,
+) {
+    poison_stack(base, length);
+    STACKS.lock().push(StackRecord {
+        name,
+        index,
+        cpu,
+        base: base as usize,
+        length,
+        high_watermark: 0,
+        warned: false,
+    });
+}
+
+/// Stops tracking a stack, before it is freed.
+pub fn unregister_stack(base: *mut u8) {
+    STACKS.lock().retain(|s| s.base != base as usize);
+}
+
+/// Measures how much of a poisoned stack has been used. Stacks grow down, so the scan starts at
+/// the base (lowest address) and stops at the first word that no longer holds the poison.
+pub fn measure_stack_usage(base: usize, length: usize) -> usize {
+    let words = length / core::mem::size_of::<u64>();
+    let stack = unsafe { core::slice::from_raw_parts(base as *const u64, words) };
+    let untouched = stack
+        .iter()
+        .take_while(|w| unsafe { core::ptr::read_volatile(*w) } == STACK_POISON)
+        .count();
+    length - (untouched * core::mem::size_of::<u64>())
+}
+
+/// Updates the high watermark of every tracked stack, warning about any that are getting full.
+pub fn check_stacks() {
+    let mut stacks = STACKS.lock();
+    for stack in stacks.iter_mut() {
+        let used = measure_stack_usage(stack.base, stack.length);
+        if used > stack.high_watermark {
+            stack.high_watermark = used;
+        }
+        if !stack.warned && stack.usage_percent() >= STACK_USAGE_WARNING_PERCENT {
+            stack.warned = true;
+            warn!(
+                "Stack {}{} on CPU {} has used {} of {} bytes ({}%)",
+                stack.name,
+                stack.index,
+                stack.cpu,
+                stack.high_watermark,
+                stack.length,
+                stack.usage_percent()
+            );
+        }
+    }
+}
+
+pub fn stacks() -> Vec<StackRecord> {
+    STACKS.lock().clone()
+}
+
+pub fn report() {
+    check_stacks();
+    debug!("Kernel stack high watermarks:");
+    for stack in stacks().iter().filter(|s| s.high_watermark() > 0) {
+        debug!(
+            "  CPU {:03} {}{}: {} / {} bytes ({}%)",
+            stack.cpu(),
+            stack.name(),
+            stack.index(),
+            stack.high_watermark(),
+            stack.length(),
+            stack.usage_percent()
+        );
+    }
+}
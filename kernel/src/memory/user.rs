@@ -0,0 +1,77 @@
+//! Copies between the kernel and the buffers system calls are handed. Every pointer and length a
+//! caller passes goes through these, which check the whole buffer lies in the user half of the
+//! address space, so a caller can't have the kernel read or write the heap, device registers, or
+//! anything else the kernel keeps in the upper half.
+
+use alloc::vec::Vec;
+use core::mem::{align_of, size_of};
+
+/// The end of the lower canonical half, where user buffers live.
+pub const USER_END: usize = 0x0000_8000_0000_0000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UserCopyError {
+    Null,
+    Misaligned,
+    /// Some of the buffer is outside the user half, or its length overflows.
+    OutsideUserHalf,
+}
+
+/// Checks count T's at address lie entirely in the user half, for callers that fill a buffer a
+/// piece at a time.
+pub fn check_user_buffer<T>(address: usize, count: usize) -> Result<(), UserCopyError> {
+    if address == 0 {
+        return Err(UserCopyError::Null);
+    }
+    if address % align_of::<T>() != 0 {
+        return Err(UserCopyError::Misaligned);
+    }
+    let end = count
+        .checked_mul(size_of::<T>())
+        .and_then(|length| address.checked_add(length))
+        .ok_or(UserCopyError::OutsideUserHalf)?;
+    match end <= USER_END {
+        true => Ok(()),
+        false => Err(UserCopyError::OutsideUserHalf),
+    }
+}
+
+/// Reads a T from a caller's buffer.
+pub fn copy_from_user<T: Copy>(source: *const T) -> Result<T, UserCopyError> {
+    check_user_buffer::<T>(source as usize, 1)?;
+    Ok(unsafe { source.read() })
+}
+
+/// Reads count T's from a caller's buffer. count is usually the caller's too, bound it first.
+pub fn copy_slice_from_user<T: Copy>(
+    source: *const T,
+    count: usize,
+) -> Result<Vec<T>, UserCopyError> {
+    if count == 0 {
+        return Ok(Vec::new());
+    }
+    check_user_buffer::<T>(source as usize, count)?;
+    let mut values = Vec::with_capacity(count);
+    unsafe {
+        source.copy_to_nonoverlapping(values.as_mut_ptr(), count);
+        values.set_len(count);
+    }
+    Ok(values)
+}
+
+/// Writes value to a caller's buffer.
+pub fn copy_to_user<T: Copy>(destination: *mut T, value: &T) -> Result<(), UserCopyError> {
+    check_user_buffer::<T>(destination as usize, 1)?;
+    unsafe { destination.write(*value) };
+    Ok(())
+}
+
+/// Writes values to a caller's buffer, which must have room for all of them.
+pub fn copy_slice_to_user<T: Copy>(destination: *mut T, values: &[T]) -> Result<(), UserCopyError> {
+    if values.is_empty() {
+        return Ok(());
+    }
+    check_user_buffer::<T>(destination as usize, values.len())?;
+    unsafe { destination.copy_from_nonoverlapping(values.as_ptr(), values.len()) };
+    Ok(())
+}
@@ -0,0 +1,425 @@
+use alloc::collections::BTreeMap;
+use kernel_shared::memory_range::MemoryRange;
+use spin::{Mutex, MutexGuard};
+use x86_64::{
+    structures::paging::{FrameAllocator, Page, PageTableFlags, PhysFrame, Size4KiB},
+    PhysAddr, VirtAddr,
+};
+
+use crate::{arch::arch_x86_64::tlb, time::Deadline, warn};
+
+use super::{
+    allocator::{KERNEL_FRAME_ALLOCATOR, KERNEL_HEAP_START, PAGE_SIZE},
+    compaction, zero_page, MemoryManager, KERNEL_MEMORY_MANAGER,
+};
+
+const REGION_SIZE: u64 = 1 << 40;
+// Unmapped pages left after every area, so running off the end of one faults instead of
+// corrupting the next.
+const RED_ZONE_PAGES: u64 = 1;
+const FAULT_LOCK_TIMEOUT_MS: u64 = 100;
+// Pages unmapped between shootdowns when an area is released.
+const RELEASE_BATCH: usize = 64;
+
+/// Fixed windows of the kernel's address space, each with its own areas.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KernelRegion {
+    /// Grown in place by the kernel heap, never handed out as areas.
+    Heap = 0,
+    /// Device registers, mapped uncached.
+    Mmio = 1,
+    /// Large allocations that are virtually contiguous, but not physically.
+    Vmalloc = 2,
+    PerCpu = 3,
+}
+
+const REGIONS: [KernelRegion; 4] = [
+    KernelRegion::Heap,
+    KernelRegion::Mmio,
+    KernelRegion::Vmalloc,
+    KernelRegion::PerCpu,
+];
+
+impl KernelRegion {
+    pub const fn start(&self) -> VirtAddr {
+        VirtAddr::new_truncate(KERNEL_HEAP_START as u64 + *self as u64 * REGION_SIZE)
+    }
+
+    pub const fn size(&self) -> u64 {
+        REGION_SIZE
+    }
+
+    pub fn range(&self) -> MemoryRange {
+        MemoryRange::from_length(self.start().as_u64(), self.size()).unwrap()
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            KernelRegion::Heap => "heap",
+            KernelRegion::Mmio => "mmio",
+            KernelRegion::Vmalloc => "vmalloc",
+            KernelRegion::PerCpu => "percpu",
+        }
+    }
+
+    /// The region an address falls in, if any.
+    pub fn containing(address: VirtAddr) -> Option<KernelRegion> {
+        REGIONS
+            .into_iter()
+            .find(|r| r.range().contains(address.as_u64()))
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Area {
+    pages: u64,
+    // Frames mapped by the area itself, instead of by the caller, are freed with it.
+    owns_frames: bool,
+    // For anonymous memory, the flags a page is mapped with once it's written.
+    anonymous: Option<PageTableFlags>,
+}
+
+impl Area {
+    fn new(pages: u64, owns_frames: bool) -> Self {
+        Self {
+            pages,
+            owns_frames,
+            anonymous: None,
+        }
+    }
+
+    // The addresses used by an area starting at start, and the red zone after it.
+    fn range(&self, start: u64) -> MemoryRange {
+        MemoryRange::from_length(start, (self.pages + RED_ZONE_PAGES) * PAGE_SIZE as u64).unwrap()
+    }
+
+    fn contains(&self, start: u64, address: VirtAddr) -> bool {
+        MemoryRange::from_length(start, self.pages * PAGE_SIZE as u64)
+            .unwrap()
+            .contains(address.as_u64())
+    }
+}
+
+/// Areas reserved in each region, by start address.
+struct VirtualAreas {
+    areas: [BTreeMap<u64, Area>; REGIONS.len()],
+}
+
+impl VirtualAreas {
+    /// First fit, keeping a red zone between areas.
+    fn reserve(&mut self, region: KernelRegion, area: Area) -> Option<VirtAddr> {
+        let pages = area.pages;
+        if region == KernelRegion::Heap || pages == 0 || pages >= region.size() / PAGE_SIZE as u64 {
+            return None;
+        }
+        let areas = &mut self.areas[region as usize];
+        let mut candidate = area.range(region.start().as_u64());
+        for (start, existing) in areas.iter() {
+            let existing = existing.range(*start);
+            if existing.start() >= candidate.end() {
+                break;
+            }
+            if candidate.overlaps(&existing) {
+                candidate = area.range(existing.end());
+            }
+        }
+        if !region.range().contains_range(&candidate) {
+            return None;
+        }
+        areas.insert(candidate.start(), area);
+        Some(VirtAddr::new(candidate.start()))
+    }
+
+    fn release(&mut self, start: VirtAddr) -> Option<Area> {
+        let region = KernelRegion::containing(start)?;
+        self.areas[region as usize].remove(&start.as_u64())
+    }
+
+    fn find(&self, address: VirtAddr) -> Option<(u64, Area)> {
+        let region = KernelRegion::containing(address)?;
+        let (start, area) = self.areas[region as usize]
+            .range(..=address.as_u64())
+            .next_back()?;
+        area.contains(*start, address).then_some((*start, *area))
+    }
+}
+
+static VIRTUAL_AREAS: Mutex<VirtualAreas> = Mutex::new(VirtualAreas {
+    areas: [
+        BTreeMap::new(),
+        BTreeMap::new(),
+        BTreeMap::new(),
+        BTreeMap::new(),
+    ],
+});
+
+/// Reserves pages of address space in a region, without mapping anything. Returns None if the
+/// region is full, and always for the heap.
+pub fn reserve(region: KernelRegion, pages: usize) -> Option<VirtAddr> {
+    VIRTUAL_AREAS
+        .lock()
+        .reserve(region, Area::new(pages as u64, false))
+}
+
+/// Maps pages of newly allocated memory, each backed by whichever frame is free, so large
+/// allocations don't need physically contiguous memory. The frames are movable, compaction may
+/// move them to other frames, so their physical addresses must not be handed to devices.
+#[track_caller]
+pub fn vmalloc(pages: usize, flags: PageTableFlags) -> Option<VirtAddr> {
+    let mut areas = VIRTUAL_AREAS.lock();
+    let start = areas.reserve(KernelRegion::Vmalloc, Area::new(pages as u64, true))?;
+    let mut memory_manager = KERNEL_MEMORY_MANAGER.lock();
+    let first = Page::<Size4KiB>::containing_address(start);
+    for i in 0..pages as u64 {
+        let frame = match unsafe { KERNEL_FRAME_ALLOCATOR.allocate_frame() } {
+            Some(frame) => frame,
+            None => {
+                // Undo what was mapped so far.
+                for page in 0..i {
+                    if let Some(frame) = memory_manager.unmap(first + page) {
+                        compaction::clear_movable(frame);
+                        unsafe { KERNEL_FRAME_ALLOCATOR.free(frame.start_address()) };
+                    }
+                }
+                areas.release(start);
+                return None;
+            }
+        };
+        memory_manager.map(first + i, frame, flags | PageTableFlags::PRESENT);
+        compaction::mark_movable(frame, first + i);
+    }
+    Some(start)
+}
+
+/// Maps a physical range of device registers, uncached. The address returned has the same
+/// offset into its page as physical_address.
+#[track_caller]
+pub fn ioremap(
+    physical_address: PhysAddr,
+    length: usize,
+    flags: PageTableFlags,
+) -> Option<VirtAddr> {
+    let first_frame = PhysFrame::<Size4KiB>::containing_address(physical_address);
+    let offset = physical_address - first_frame.start_address();
+    let pages = (offset + length as u64 + PAGE_SIZE as u64 - 1) / PAGE_SIZE as u64;
+    let start = VIRTUAL_AREAS
+        .lock()
+        .reserve(KernelRegion::Mmio, Area::new(pages, false))?;
+    let mut memory_manager = KERNEL_MEMORY_MANAGER.lock();
+    let first = Page::<Size4KiB>::containing_address(start);
+    for i in 0..pages {
+        memory_manager.map(
+            first + i,
+            first_frame + i,
+            flags | PageTableFlags::PRESENT | PageTableFlags::NO_CACHE,
+        );
+    }
+    Some(start + offset)
+}
+
+/// Maps pages of anonymous memory, which read as zero. Every page starts out mapped read only
+/// to the shared zero page, and is only given a frame of its own when first written. Must not
+/// be written with the memory manager held, the write fault needs it.
+#[track_caller]
+pub fn map_anonymous(pages: usize, flags: PageTableFlags) -> Option<VirtAddr> {
+    let mut areas = VIRTUAL_AREAS.lock();
+    let area = Area {
+        anonymous: Some(flags | PageTableFlags::PRESENT),
+        ..Area::new(pages as u64, true)
+    };
+    let start = areas.reserve(KernelRegion::Vmalloc, area)?;
+    let mut memory_manager = KERNEL_MEMORY_MANAGER.lock();
+    let first = Page::<Size4KiB>::containing_address(start);
+    let read_only = (flags | PageTableFlags::PRESENT) - PageTableFlags::WRITABLE;
+    for i in 0..pages as u64 {
+        memory_manager.map(first + i, zero_page::zero_frame(), read_only);
+    }
+    Some(start)
+}
+
+/// Handles a write to anonymous memory still mapped to the zero page, giving the page a zeroed
+/// frame of its own, or to a page write protected while it's migrated. Returns false if the
+/// fault was for anything else.
+pub(crate) fn handle_write_fault(address: VirtAddr) -> bool {
+    if compaction::wait_for_migration(Page::containing_address(address)) {
+        return true;
+    }
+    let areas = lock_for_fault(&VIRTUAL_AREAS, "virtual areas");
+    let flags = match areas.find(address) {
+        Some((
+            _,
+            Area {
+                anonymous: Some(flags),
+                ..
+            },
+        )) => flags,
+        _ => return false,
+    };
+    let mut memory_manager = lock_for_fault(&*KERNEL_MEMORY_MANAGER, "memory manager");
+    let page = Page::<Size4KiB>::containing_address(address);
+    match memory_manager.physical_address(page.start_address()) {
+        Some(physical) if zero_page::is_zero_frame(PhysFrame::containing_address(physical)) => {}
+        // Another CPU faulted on the same page first, and already gave it a frame.
+        Some(_) if is_writable(&memory_manager, page) => return true,
+        _ => return false,
+    }
+    let frame = match unsafe { KERNEL_FRAME_ALLOCATOR.allocate_frame() } {
+        Some(frame) => frame,
+        None => return false,
+    };
+    unsafe {
+        memory_manager
+            .translate(frame.start_address())
+            .as_mut_ptr::<u8>()
+            .write_bytes(0, PAGE_SIZE);
+    }
+    memory_manager.unmap(page);
+    memory_manager.map(page, frame, flags);
+    compaction::mark_movable(frame, page);
+    // Other CPUs would otherwise keep reading the zero frame, and miss what's written here.
+    tlb::shootdown();
+    true
+}
+
+/// Merges anonymous pages that are all zeros back into the shared zero page, and frees their
+/// frames. Scans up to budget pages of the vmalloc region, from the address from, and returns
+/// where to carry on, 0 once it's reached the end, and how many pages were merged.
+pub(super) fn merge_zero_pages(from: u64, budget: u64) -> (u64, u64) {
+    let areas = VIRTUAL_AREAS.lock();
+    let mut memory_manager = KERNEL_MEMORY_MANAGER.lock();
+    let (mut scanned, mut merged) = (0, 0);
+    for (start, area) in areas.areas[KernelRegion::Vmalloc as usize].iter() {
+        if area.anonymous.is_none() {
+            continue;
+        }
+        let first = Page::<Size4KiB>::containing_address(VirtAddr::new(*start));
+        let skipped = from.saturating_sub(*start) / PAGE_SIZE as u64;
+        for page in (skipped..area.pages).map(|i| first + i) {
+            if scanned == budget {
+                return (page.start_address().as_u64(), merged);
+            }
+            scanned += 1;
+            match merge_zero_page(&mut memory_manager, page) {
+                Some(true) => merged += 1,
+                Some(false) => {}
+                // A CPU is slow to flush, try again next time rather than hold the locks.
+                None => return (page.start_address().as_u64(), merged),
+            }
+        }
+    }
+    (0, merged)
+}
+
+// Maps the page to the zero frame if its own frame is all zeros. None if a CPU didn't confirm a
+// TLB shootdown.
+fn merge_zero_page(memory_manager: &mut MemoryManager, page: Page<Size4KiB>) -> Option<bool> {
+    let frame = match memory_manager.physical_address(page.start_address()) {
+        Some(physical) => PhysFrame::<Size4KiB>::containing_address(physical),
+        None => return Some(false),
+    };
+    let flags = match memory_manager.flags(page) {
+        Some(flags) => flags,
+        None => return Some(false),
+    };
+    if zero_page::is_zero_frame(frame) || !is_zero_filled(memory_manager, frame) {
+        return Some(false);
+    }
+    if !compaction::begin_migration(memory_manager, page, flags) {
+        return None;
+    }
+    // Written to before it was write protected.
+    if !is_zero_filled(memory_manager, frame) {
+        memory_manager.update_flags(page.start_address(), 1, flags);
+        compaction::end_migration();
+        return Some(false);
+    }
+    // Still write protected, so the next write gives it a frame of its own again.
+    memory_manager.remap(page, zero_page::zero_frame());
+    let flushed = tlb::shootdown();
+    compaction::end_migration();
+    compaction::clear_movable(frame);
+    if !flushed {
+        warn!(
+            "Leaking frame {:#x}, a CPU may still be using its old mapping",
+            frame.start_address().as_u64()
+        );
+        return None;
+    }
+    unsafe { KERNEL_FRAME_ALLOCATOR.free(frame.start_address()) };
+    Some(true)
+}
+
+fn is_zero_filled(memory_manager: &MemoryManager, frame: PhysFrame<Size4KiB>) -> bool {
+    let words = memory_manager
+        .translate(frame.start_address())
+        .as_ptr::<u64>();
+    unsafe { core::slice::from_raw_parts(words, PAGE_SIZE / 8) }
+        .iter()
+        .all(|word| *word == 0)
+}
+
+fn is_writable(memory_manager: &MemoryManager, page: Page<Size4KiB>) -> bool {
+    memory_manager
+        .flags(page)
+        .map_or(false, |flags| flags.contains(PageTableFlags::WRITABLE))
+}
+
+// The write fault can't wait on a lock indefinitely: if this CPU already holds it, the write
+// was made with it held, and waiting would hang without a trace. Another CPU only holds it
+// briefly, so anything longer is treated as that.
+fn lock_for_fault<'a, T>(mutex: &'a Mutex<T>, name: &str) -> MutexGuard<'a, T> {
+    let deadline = Deadline::after_ms(FAULT_LOCK_TIMEOUT_MS);
+    loop {
+        if let Some(guard) = mutex.try_lock() {
+            return guard;
+        }
+        if deadline.is_expired() {
+            panic!(
+                "Write fault on anonymous memory while the {} lock is held, was it written with the lock held?",
+                name
+            );
+        }
+        // Whoever holds it may be waiting on this CPU to flush its TLB.
+        tlb::acknowledge();
+        core::hint::spin_loop();
+    }
+}
+
+/// Unmaps an area returned by vmalloc, map_anonymous, ioremap, or reserve, and frees its address
+/// space. Memory the area allocated itself is freed, any other frames the caller mapped are left
+/// to it.
+pub fn release(address: VirtAddr) -> bool {
+    let start = address.align_down(PAGE_SIZE as u64);
+    // Held until the area is unmapped everywhere, so its addresses can't be handed out again
+    // before then.
+    let mut areas = VIRTUAL_AREAS.lock();
+    let area = match areas.find(start) {
+        Some((area_start, area)) if area_start == start.as_u64() => area,
+        _ => return false,
+    };
+    let mut memory_manager = KERNEL_MEMORY_MANAGER.lock();
+    let first = Page::<Size4KiB>::containing_address(start);
+    let mut frames = [None; RELEASE_BATCH];
+    for batch in (0..area.pages).step_by(RELEASE_BATCH) {
+        let pages = (area.pages - batch).min(RELEASE_BATCH as u64);
+        for i in 0..pages {
+            frames[i as usize] = memory_manager
+                .unmap(first + batch + i)
+                .filter(|frame| area.owns_frames && !zero_page::is_zero_frame(*frame));
+        }
+        // Other CPUs may still reach the frames through their TLBs until they've flushed.
+        if !tlb::shootdown() {
+            warn!("Leaking the frames of a released area, a CPU may still be using them");
+            continue;
+        }
+        for frame in frames[0..pages as usize]
+            .iter_mut()
+            .filter_map(Option::take)
+        {
+            compaction::clear_movable(frame);
+            unsafe { KERNEL_FRAME_ALLOCATOR.free(frame.start_address()) };
+        }
+    }
+    areas.release(start);
+    true
+}
@@ -0,0 +1,33 @@
+use spin::Once;
+use x86_64::structures::paging::{FrameAllocator, PhysFrame, Size4KiB};
+
+use super::{
+    allocator::{KERNEL_FRAME_ALLOCATOR, PAGE_SIZE},
+    KERNEL_MEMORY_MANAGER,
+};
+
+// Mapped read only wherever anonymous memory hasn't been written yet, and never freed.
+static ZERO_FRAME: Once<PhysFrame<Size4KiB>> = Once::new();
+
+pub(super) fn init() {
+    ZERO_FRAME.call_once(|| {
+        let memory_manager = KERNEL_MEMORY_MANAGER.lock();
+        let frame = unsafe { KERNEL_FRAME_ALLOCATOR.allocate_frame() }
+            .expect("Failed to allocate the zero page!");
+        unsafe {
+            memory_manager
+                .translate(frame.start_address())
+                .as_mut_ptr::<u8>()
+                .write_bytes(0, PAGE_SIZE);
+        }
+        frame
+    });
+}
+
+pub fn zero_frame() -> PhysFrame<Size4KiB> {
+    *ZERO_FRAME.get().expect("The zero page is not initialized")
+}
+
+pub fn is_zero_frame(frame: PhysFrame<Size4KiB>) -> bool {
+    ZERO_FRAME.get() == Some(&frame)
+}
@@ -5,6 +5,14 @@ fn panic(info: &PanicInfo) -> ! {
     use crate::fatal;
 
     fatal!("PANIC: {}", info);
+    // Unformatted, and straight to the serial port past any locks the panic left held, for the
+    // runner to spot and triage, see src/triage.rs.
+    let (log_address, log_length) = crate::logging::deferred_log_region();
+    crate::raw_println!(
+        "OXIDIZED-PANIC log={:#x} length={}",
+        log_address,
+        log_length
+    );
     loop {
         x86_64::instructions::interrupts::disable();
         x86_64::instructions::hlt();
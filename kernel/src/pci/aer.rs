@@ -0,0 +1,321 @@
+//! PCI Express Advanced Error Reporting. Root ports collect errors from the functions below
+//! them and raise an interrupt, which reads and clears the error registers. The reports are
+//! logged later, outside interrupt context, so they can name the device from the device tree.
+
+use alloc::{collections::BTreeMap, format, string::String, vec::Vec};
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use devices::try_get_device_tree;
+use kernel_shared::channel::Channel;
+use spin::Once;
+
+use crate::{arch::request_msi, debug, error, time, warn};
+
+use super::{PciAddress, CAPABILITY_EXPRESS, EXTENDED_CAPABILITY_AER};
+
+// Registers in the AER capability.
+const UNCORRECTABLE_STATUS: u16 = 0x04;
+const UNCORRECTABLE_SEVERITY: u16 = 0x0C;
+const CORRECTABLE_STATUS: u16 = 0x10;
+const HEADER_LOG: u16 = 0x1C;
+const ROOT_ERROR_COMMAND: u16 = 0x2C;
+const ROOT_ERROR_STATUS: u16 = 0x30;
+const ERROR_SOURCE_ID: u16 = 0x34;
+
+const ROOT_REPORT_CORRECTABLE: u32 = 1 << 0;
+const ROOT_REPORT_NON_FATAL: u32 = 1 << 1;
+const ROOT_REPORT_FATAL: u32 = 1 << 2;
+const ROOT_CORRECTABLE_RECEIVED: u32 = 1 << 0;
+const ROOT_UNCORRECTABLE_RECEIVED: u32 = 1 << 2;
+
+// Registers in the PCI Express capability.
+const EXPRESS_CAPABILITIES: u16 = 0x02;
+const DEVICE_CONTROL: u16 = 0x08;
+const PORT_TYPE_ROOT_PORT: u16 = 0x4;
+const REPORT_CORRECTABLE: u16 = 1 << 0;
+const REPORT_NON_FATAL: u16 = 1 << 1;
+const REPORT_FATAL: u16 = 1 << 2;
+const REPORT_UNSUPPORTED_REQUEST: u16 = 1 << 3;
+
+const UNCORRECTABLE_ERRORS: [(u32, &str); 12] = [
+    (1 << 4, "data link protocol error"),
+    (1 << 5, "surprise down"),
+    (1 << 12, "poisoned TLP"),
+    (1 << 13, "flow control protocol error"),
+    (1 << 14, "completion timeout"),
+    (1 << 15, "completer abort"),
+    (1 << 16, "unexpected completion"),
+    (1 << 17, "receiver overflow"),
+    (1 << 18, "malformed TLP"),
+    (1 << 19, "ECRC error"),
+    (1 << 20, "unsupported request"),
+    (1 << 21, "ACS violation"),
+];
+
+const CORRECTABLE_ERRORS: [(u32, &str); 8] = [
+    (1 << 0, "receiver error"),
+    (1 << 6, "bad TLP"),
+    (1 << 7, "bad DLLP"),
+    (1 << 8, "replay number rollover"),
+    (1 << 12, "replay timer timeout"),
+    (1 << 13, "advisory non-fatal error"),
+    (1 << 14, "corrected internal error"),
+    (1 << 15, "header log overflow"),
+];
+
+/// What a root port, and the function it names as the source, reported for one interrupt.
+#[derive(Debug, Clone, Copy)]
+struct ErrorReport {
+    root_port: PciAddress,
+    source: PciAddress,
+    correctable: u32,
+    uncorrectable: u32,
+    severity: u32,
+    header: [u32; 4],
+}
+
+// Every function with an AER capability, and where it is, found at boot so the interrupt
+// handler never has to map configuration space.
+static AER_FUNCTIONS: Once<BTreeMap<PciAddress, u16>> = Once::new();
+// Each root port, and whether it's polled rather than interrupting. Each is only ever collected
+// from one way, so the interrupt and the poll never race over its registers.
+static ROOT_PORTS: Once<Vec<(PciAddress, bool)>> = Once::new();
+
+const REPORT_CAPACITY: usize = 16;
+static REPORTS: Channel<ErrorReport, REPORT_CAPACITY> = Channel::new();
+static REPORTS_DROPPED: AtomicUsize = AtomicUsize::new(0);
+static CORRECTABLE_COUNT: AtomicUsize = AtomicUsize::new(0);
+static UNCORRECTABLE_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+// Root ports without MSI can't interrupt, so they're polled, paced by the clock.
+const POLL_INTERVAL_NANOSECONDS: u64 = 1_000_000_000;
+static NEXT_POLL: AtomicU64 = AtomicU64::new(0);
+
+fn enable_reporting(function: PciAddress) -> bool {
+    let express = match function.find_capability(CAPABILITY_EXPRESS) {
+        Some(express) => express,
+        None => return false,
+    };
+    let control = function.read_u16(express + DEVICE_CONTROL);
+    function.write_u16(
+        express + DEVICE_CONTROL,
+        control | REPORT_CORRECTABLE | REPORT_NON_FATAL | REPORT_FATAL | REPORT_UNSUPPORTED_REQUEST,
+    );
+    (function.read_u16(express + EXPRESS_CAPABILITIES) >> 4) & 0xF == PORT_TYPE_ROOT_PORT
+}
+
+pub(crate) fn init() {
+    let mut functions = BTreeMap::new();
+    let mut root_ports = Vec::new();
+    for function in super::functions().into_iter().filter(|f| f.is_express()) {
+        let aer = match function.find_extended_capability(EXTENDED_CAPABILITY_AER) {
+            Some(aer) => aer,
+            None => continue,
+        };
+        functions.insert(function, aer);
+        if enable_reporting(function) {
+            root_ports.push(function);
+        }
+    }
+    debug!(
+        "AER: {} functions report errors, through {} root ports",
+        functions.len(),
+        root_ports.len()
+    );
+    let functions = AER_FUNCTIONS.call_once(|| functions);
+    if root_ports.is_empty() {
+        return;
+    }
+    let message = request_msi(handle_interrupt);
+    let root_ports = ROOT_PORTS.call_once(|| {
+        root_ports
+            .into_iter()
+            .map(|root_port| match message {
+                Some(message) if root_port.enable_msi(&message) => (root_port, false),
+                _ => {
+                    warn!("AER: {} can't interrupt, polling it instead", root_port);
+                    (root_port, true)
+                }
+            })
+            .collect()
+    });
+    // Only now that the ports are recorded can they report.
+    for (root_port, _) in root_ports.iter() {
+        let aer = functions[root_port];
+        root_port.write_u32(
+            aer + ROOT_ERROR_COMMAND,
+            ROOT_REPORT_CORRECTABLE | ROOT_REPORT_NON_FATAL | ROOT_REPORT_FATAL,
+        );
+    }
+}
+
+fn source_address(id: u16) -> PciAddress {
+    PciAddress::new((id >> 8) as u8, (id >> 3) as u8 & 0x1F, id as u8 & 0x7)
+}
+
+// Reads a function's error registers, and clears what was read.
+fn read_errors(source: PciAddress, root_port: PciAddress) -> Option<ErrorReport> {
+    let aer = *AER_FUNCTIONS.get()?.get(&source)?;
+    let correctable = source.read_u32(aer + CORRECTABLE_STATUS);
+    let uncorrectable = source.read_u32(aer + UNCORRECTABLE_STATUS);
+    let mut header = [0; 4];
+    for (i, dword) in header.iter_mut().enumerate() {
+        *dword = source.read_u32(aer + HEADER_LOG + i as u16 * 4);
+    }
+    // Both are cleared by writing ones, so writing back what was read clears only those.
+    source.write_u32(aer + CORRECTABLE_STATUS, correctable);
+    source.write_u32(aer + UNCORRECTABLE_STATUS, uncorrectable);
+    Some(ErrorReport {
+        root_port,
+        source,
+        correctable,
+        uncorrectable,
+        severity: source.read_u32(aer + UNCORRECTABLE_SEVERITY),
+        header,
+    })
+}
+
+// Called in interrupt context, so it only reads registers and queues what it finds. Collects
+// from the polled root ports, or the ones that interrupt.
+fn collect(polled: bool) {
+    let (functions, root_ports) = match (AER_FUNCTIONS.get(), ROOT_PORTS.get()) {
+        (Some(functions), Some(root_ports)) => (functions, root_ports),
+        _ => return,
+    };
+    let root_ports = root_ports
+        .iter()
+        .filter(|(_, is_polled)| *is_polled == polled)
+        .map(|(root_port, _)| root_port);
+    for root_port in root_ports {
+        let aer = functions[root_port];
+        let status = root_port.read_u32(aer + ROOT_ERROR_STATUS);
+        if status & (ROOT_CORRECTABLE_RECEIVED | ROOT_UNCORRECTABLE_RECEIVED) == 0 {
+            continue;
+        }
+        let sources = root_port.read_u32(aer + ERROR_SOURCE_ID);
+        let mut found = [None, None];
+        if status & ROOT_CORRECTABLE_RECEIVED != 0 {
+            found[0] = Some(source_address(sources as u16));
+        }
+        if status & ROOT_UNCORRECTABLE_RECEIVED != 0 {
+            found[1] = Some(source_address((sources >> 16) as u16));
+        }
+        // The same function often reports both.
+        if found[0] == found[1] {
+            found[1] = None;
+        }
+        for source in found.into_iter().flatten() {
+            // The root port logs its own errors when the source has no AER capability.
+            let report =
+                read_errors(source, *root_port).or_else(|| read_errors(*root_port, *root_port));
+            if let Some(report) = report {
+                if REPORTS.try_send(report).is_err() {
+                    REPORTS_DROPPED.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+        root_port.write_u32(aer + ROOT_ERROR_STATUS, status);
+    }
+}
+
+fn handle_interrupt(_vector: u8) {
+    collect(false);
+}
+
+fn decode(status: u32, names: &[(u32, &str)]) -> String {
+    let decoded: Vec<&str> = names
+        .iter()
+        .filter(|(bit, _)| status & bit != 0)
+        .map(|(_, name)| *name)
+        .collect();
+    match decoded.is_empty() {
+        true => format!("{:#010x}", status),
+        false => decoded.join(", "),
+    }
+}
+
+// The function's path in the device tree if a driver registered it, otherwise its location.
+// Errors are logged without waiting on the device tree, the location is enough while it changes.
+fn device_path(function: PciAddress) -> String {
+    let location = function.location();
+    let device_tree = match try_get_device_tree() {
+        Some(device_tree) => device_tree,
+        None => return location,
+    };
+    match device_tree.resolve(&format!("by-path/{}", location)) {
+        Some(device) => device_tree.get_device_path(device.as_ref()),
+        None => location,
+    }
+}
+
+fn log_report(report: &ErrorReport) {
+    let path = device_path(report.source);
+    if report.correctable != 0 {
+        CORRECTABLE_COUNT.fetch_add(1, Ordering::Relaxed);
+        warn!(
+            "AER: corrected error on {} ({}), reported by {}: {}",
+            path,
+            report.source,
+            report.root_port,
+            decode(report.correctable, &CORRECTABLE_ERRORS)
+        );
+    }
+    if report.uncorrectable != 0 {
+        UNCORRECTABLE_COUNT.fetch_add(1, Ordering::Relaxed);
+        let severity = match report.uncorrectable & report.severity {
+            0 => "non-fatal",
+            _ => "fatal",
+        };
+        error!(
+            "AER: uncorrected {} error on {} ({}), reported by {}: {}, TLP header {:08x} {:08x} {:08x} {:08x}",
+            severity,
+            path,
+            report.source,
+            report.root_port,
+            decode(report.uncorrectable, &UNCORRECTABLE_ERRORS),
+            report.header[0],
+            report.header[1],
+            report.header[2],
+            report.header[3]
+        );
+    }
+}
+
+/// Errors logged since boot, corrected and uncorrected.
+pub fn error_counts() -> (usize, usize) {
+    (
+        CORRECTABLE_COUNT.load(Ordering::Relaxed),
+        UNCORRECTABLE_COUNT.load(Ordering::Relaxed),
+    )
+}
+
+/// Called from the idle loop, polls the root ports that can't interrupt, and logs the reports
+/// queued.
+pub(crate) fn report_periodically() {
+    let now = time::monotonic_nanoseconds();
+    let next = NEXT_POLL.load(Ordering::Relaxed);
+    if now >= next
+        && NEXT_POLL
+            .compare_exchange(
+                next,
+                now + POLL_INTERVAL_NANOSECONDS,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            )
+            .is_ok()
+    {
+        collect(true);
+    }
+    // Someone else is already logging.
+    let mut receiver = match REPORTS.receiver() {
+        Some(receiver) => receiver,
+        None => return,
+    };
+    for report in receiver.drain() {
+        log_report(&report);
+    }
+    let dropped = REPORTS_DROPPED.swap(0, Ordering::Relaxed);
+    if dropped > 0 {
+        warn!("AER: {} error reports were dropped", dropped);
+    }
+}
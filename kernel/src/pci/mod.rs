@@ -0,0 +1,398 @@
+use alloc::{format, string::String, vec::Vec};
+use core::fmt::Display;
+
+use crate::{
+    arch::{pci_read_config, pci_write_config, MsiMessage},
+    time,
+};
+
+pub mod aer;
+
+pub const VENDOR_ID: u16 = 0x00;
+pub const DEVICE_ID: u16 = 0x02;
+pub const COMMAND: u16 = 0x04;
+pub const STATUS: u16 = 0x06;
+pub const REVISION_ID: u16 = 0x08;
+pub const HEADER_TYPE: u16 = 0x0E;
+pub const BAR0: u16 = 0x10;
+pub const CAPABILITIES_POINTER: u16 = 0x34;
+// In the header of PCI to PCI bridges.
+pub const SECONDARY_BUS: u16 = 0x19;
+pub const SUBORDINATE_BUS: u16 = 0x1A;
+
+pub const COMMAND_IO_SPACE: u16 = 1 << 0;
+pub const COMMAND_MEMORY_SPACE: u16 = 1 << 1;
+pub const COMMAND_BUS_MASTER: u16 = 1 << 2;
+pub const COMMAND_INTERRUPT_DISABLE: u16 = 1 << 10;
+const STATUS_CAPABILITIES_LIST: u16 = 1 << 4;
+
+pub const CAPABILITY_POWER_MANAGEMENT: u8 = 0x01;
+pub const CAPABILITY_MSI: u8 = 0x05;
+pub const CAPABILITY_EXPRESS: u8 = 0x10;
+pub const EXTENDED_CAPABILITY_AER: u16 = 0x0001;
+// Extended capabilities start right after the legacy configuration space.
+const EXTENDED_CAPABILITIES_START: u16 = 0x100;
+const CONFIG_SPACE_SIZE: u16 = 0x1000;
+// The control and status register, after the capability's header and its capabilities.
+const POWER_MANAGEMENT_CONTROL: u16 = 4;
+const POWER_STATE_MASK: u16 = 0b11;
+// Cleared by writing a one, so never written back as read.
+const PME_STATUS: u16 = 1 << 15;
+// The longest the spec lets a function take to settle after leaving D3hot, and D2.
+const D3_RECOVERY_MILLISECONDS: u64 = 10;
+const D2_RECOVERY_MICROSECONDS: u64 = 200;
+
+// Registers in the MSI capability. The data register follows a 64 bit address, when the
+// function takes one.
+const MSI_CONTROL: u16 = 2;
+const MSI_ADDRESS: u16 = 4;
+const MSI_ADDRESS_HIGH: u16 = 8;
+const MSI_DATA_32: u16 = 8;
+const MSI_DATA_64: u16 = 12;
+const MSI_ENABLE: u16 = 1 << 0;
+const MSI_MULTIPLE_MESSAGE_ENABLE: u16 = 0b111 << 4;
+const MSI_64_BIT: u16 = 1 << 7;
+
+const NO_VENDOR: u16 = 0xFFFF;
+const HEADER_TYPE_MULTIFUNCTION: u8 = 0x80;
+const BAR_IO_SPACE: u32 = 1 << 0;
+const BAR_64_BIT: u32 = 0b10 << 1;
+
+/// Where a base address register points.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bar {
+    Io(u16),
+    Memory(u64),
+}
+
+/// Device power states, D0 is fully on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PowerState {
+    D0 = 0,
+    D1 = 1,
+    D2 = 2,
+    D3Hot = 3,
+}
+
+/// A PCI function, on the first segment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PciAddress {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl PciAddress {
+    pub const fn new(bus: u8, device: u8, function: u8) -> Self {
+        Self {
+            bus,
+            device,
+            function,
+        }
+    }
+
+    pub fn read_u32(&self, offset: u16) -> u32 {
+        pci_read_config(self.bus, self.device, self.function, offset)
+    }
+
+    pub fn read_u16(&self, offset: u16) -> u16 {
+        (self.read_u32(offset) >> ((offset & 2) * 8)) as u16
+    }
+
+    pub fn read_u8(&self, offset: u16) -> u8 {
+        (self.read_u32(offset) >> ((offset & 3) * 8)) as u8
+    }
+
+    pub fn write_u32(&self, offset: u16, value: u32) {
+        pci_write_config(self.bus, self.device, self.function, offset, value);
+    }
+
+    /// Writes part of a dword, the rest of it is read and written back unchanged. Registers
+    /// where writing a one clears a bit, like the status register, need write_u32 instead.
+    pub fn write_u16(&self, offset: u16, value: u16) {
+        let shift = (offset & 2) * 8;
+        let dword = self.read_u32(offset) & !(0xFFFF << shift);
+        self.write_u32(offset, dword | (value as u32) << shift);
+    }
+
+    pub fn write_u8(&self, offset: u16, value: u8) {
+        let shift = (offset & 3) * 8;
+        let dword = self.read_u32(offset) & !(0xFF << shift);
+        self.write_u32(offset, dword | (value as u32) << shift);
+    }
+
+    pub fn vendor_id(&self) -> u16 {
+        self.read_u16(VENDOR_ID)
+    }
+
+    pub fn device_id(&self) -> u16 {
+        self.read_u16(DEVICE_ID)
+    }
+
+    pub fn exists(&self) -> bool {
+        self.vendor_id() != NO_VENDOR
+    }
+
+    /// The class, subclass and programming interface.
+    pub fn class(&self) -> (u8, u8, u8) {
+        let value = self.read_u32(REVISION_ID);
+        ((value >> 24) as u8, (value >> 16) as u8, (value >> 8) as u8)
+    }
+
+    pub fn header_type(&self) -> u8 {
+        self.read_u8(HEADER_TYPE) & !HEADER_TYPE_MULTIFUNCTION
+    }
+
+    pub fn is_multifunction(&self) -> bool {
+        self.read_u8(HEADER_TYPE) & HEADER_TYPE_MULTIFUNCTION != 0
+    }
+
+    /// A base address register, None if it's unused. A 64 bit memory BAR takes the next
+    /// register too.
+    pub fn bar(&self, index: u16) -> Option<Bar> {
+        let offset = BAR0 + index * 4;
+        let value = self.read_u32(offset);
+        if value & BAR_IO_SPACE != 0 {
+            let port = (value & !0x3) as u16;
+            return (port != 0).then_some(Bar::Io(port));
+        }
+        let mut address = (value & !0xF) as u64;
+        if value & BAR_64_BIT != 0 {
+            address |= (self.read_u32(offset + 4) as u64) << 32;
+        }
+        (address != 0).then_some(Bar::Memory(address))
+    }
+
+    pub fn command(&self) -> u16 {
+        self.read_u16(COMMAND)
+    }
+
+    /// Sets, or clears, bits in the command register.
+    pub fn set_command(&self, bits: u16, enabled: bool) {
+        let command = self.command();
+        let updated = match enabled {
+            true => command | bits,
+            false => command & !bits,
+        };
+        if updated != command {
+            // Status shares the dword, and its bits are cleared by writing ones, so write zeros.
+            self.write_u32(COMMAND & !3, updated as u32);
+        }
+    }
+
+    /// Lets the function start DMA, which it needs for anything but programmed I/O.
+    pub fn enable_bus_mastering(&self) {
+        self.set_command(COMMAND_BUS_MASTER, true);
+    }
+
+    pub fn disable_bus_mastering(&self) {
+        self.set_command(COMMAND_BUS_MASTER, false);
+    }
+
+    /// Turns on decoding of the function's I/O and memory BARs.
+    pub fn enable_decoding(&self, io: bool, memory: bool) {
+        let mut bits = 0;
+        if io {
+            bits |= COMMAND_IO_SPACE;
+        }
+        if memory {
+            bits |= COMMAND_MEMORY_SPACE;
+        }
+        self.set_command(bits, true);
+    }
+
+    /// The offset of each capability in the function's list, by id.
+    pub fn capabilities(&self) -> Vec<(u8, u16)> {
+        let mut capabilities = Vec::new();
+        if self.read_u16(STATUS) & STATUS_CAPABILITIES_LIST == 0 {
+            return capabilities;
+        }
+        let mut offset = (self.read_u8(CAPABILITIES_POINTER) & !3) as u16;
+        // Capabilities live after the header, in the first 256 bytes, which bounds a looped list.
+        while offset >= 0x40 && capabilities.len() < 48 {
+            let header = self.read_u16(offset);
+            capabilities.push((header as u8, offset));
+            offset = ((header >> 8) as u8 & !3) as u16;
+        }
+        capabilities
+    }
+
+    pub fn find_capability(&self, id: u8) -> Option<u16> {
+        self.capabilities()
+            .into_iter()
+            .find(|(capability, _)| *capability == id)
+            .map(|(_, offset)| offset)
+    }
+
+    /// The offset of each extended capability, by id, with its version. Only PCI Express
+    /// functions have them, and only memory mapped configuration reaches them.
+    pub fn extended_capabilities(&self) -> Vec<(u16, u8, u16)> {
+        let mut capabilities = Vec::new();
+        let mut offset = EXTENDED_CAPABILITIES_START;
+        // Each is at least a dword, which bounds a looped list.
+        while (EXTENDED_CAPABILITIES_START..CONFIG_SPACE_SIZE).contains(&offset)
+            && capabilities.len() < 960
+        {
+            let header = self.read_u32(offset);
+            // All ones without extended configuration, zero when there are no capabilities.
+            if header == 0 || header == u32::MAX {
+                break;
+            }
+            capabilities.push((header as u16, (header >> 16) as u8 & 0xF, offset));
+            offset = (header >> 20) as u16 & !3;
+        }
+        capabilities
+    }
+
+    pub fn find_extended_capability(&self, id: u16) -> Option<u16> {
+        self.extended_capabilities()
+            .into_iter()
+            .find(|(capability, _, _)| *capability == id)
+            .map(|(_, _, offset)| offset)
+    }
+
+    pub fn is_express(&self) -> bool {
+        self.find_capability(CAPABILITY_EXPRESS).is_some()
+    }
+
+    /// Has the function raise message, through its MSI capability, instead of its interrupt
+    /// pin. Returns false if it has no MSI capability.
+    pub fn enable_msi(&self, message: &MsiMessage) -> bool {
+        let capability = match self.find_capability(CAPABILITY_MSI) {
+            Some(capability) => capability,
+            None => return false,
+        };
+        let control = self.read_u16(capability + MSI_CONTROL);
+        self.write_u32(capability + MSI_ADDRESS, message.address as u32);
+        let data = match control & MSI_64_BIT {
+            0 => MSI_DATA_32,
+            _ => {
+                self.write_u32(
+                    capability + MSI_ADDRESS_HIGH,
+                    (message.address >> 32) as u32,
+                );
+                MSI_DATA_64
+            }
+        };
+        self.write_u16(capability + data, message.data as u16);
+        // A single message, whatever the function asked for.
+        self.write_u16(
+            capability + MSI_CONTROL,
+            (control & !MSI_MULTIPLE_MESSAGE_ENABLE) | MSI_ENABLE,
+        );
+        self.set_command(COMMAND_INTERRUPT_DISABLE, true);
+        true
+    }
+
+    /// The current power state, D0 for functions without power management.
+    pub fn power_state(&self) -> PowerState {
+        let capability = match self.find_capability(CAPABILITY_POWER_MANAGEMENT) {
+            Some(capability) => capability,
+            None => return PowerState::D0,
+        };
+        match self.read_u16(capability + POWER_MANAGEMENT_CONTROL) & POWER_STATE_MASK {
+            0 => PowerState::D0,
+            1 => PowerState::D1,
+            2 => PowerState::D2,
+            _ => PowerState::D3Hot,
+        }
+    }
+
+    /// Moves the function to another power state through its power management capability, and
+    /// waits for it to settle. Returns false if it can't be power managed. Leaving D3hot resets
+    /// most functions, so drivers set them up again afterwards.
+    pub fn set_power_state(&self, state: PowerState) -> bool {
+        let capability = match self.find_capability(CAPABILITY_POWER_MANAGEMENT) {
+            Some(capability) => capability,
+            None => return state == PowerState::D0,
+        };
+        let register = capability + POWER_MANAGEMENT_CONTROL;
+        let control = self.read_u16(register);
+        let current = control & POWER_STATE_MASK;
+        if current == state as u16 {
+            return true;
+        }
+        // The upper half of the dword holds bridge and data registers, which are read only.
+        self.write_u16(
+            register,
+            (control & !(POWER_STATE_MASK | PME_STATUS)) | state as u16,
+        );
+        let slowest = current.max(state as u16);
+        if slowest == PowerState::D3Hot as u16 {
+            time::delay_ms(D3_RECOVERY_MILLISECONDS);
+        } else if slowest == PowerState::D2 as u16 {
+            time::delay_us(D2_RECOVERY_MICROSECONDS);
+        }
+        true
+    }
+
+    /// Readies the function for a driver: powered up, decoding the BARs it uses, and
+    /// mastering the bus if it does DMA.
+    pub fn bind(&self, io: bool, memory: bool, bus_master: bool) {
+        self.set_power_state(PowerState::D0);
+        self.enable_decoding(io, memory);
+        if bus_master {
+            self.enable_bus_mastering();
+        }
+    }
+
+    /// Quiesces the function once its driver is done with it, stopping DMA before powering it
+    /// down. Drivers call it when their device is dropped, after it's been unregistered.
+    pub fn unbind(&self) {
+        self.set_command(
+            COMMAND_BUS_MASTER | COMMAND_IO_SPACE | COMMAND_MEMORY_SPACE,
+            false,
+        );
+        self.set_power_state(PowerState::D3Hot);
+    }
+
+    /// The location used for device by-path aliases, like pci-0000:00:1f.3.
+    pub fn location(&self) -> String {
+        format!("pci-{}", self)
+    }
+}
+
+impl Display for PciAddress {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "0000:{:02x}:{:02x}.{:x}",
+            self.bus, self.device, self.function
+        )
+    }
+}
+
+/// Every function on every bus, found by probing each possible address.
+pub fn functions() -> Vec<PciAddress> {
+    let mut found = Vec::new();
+    for bus in 0..=255u8 {
+        for device in 0..32u8 {
+            let first = PciAddress::new(bus, device, 0);
+            if !first.exists() {
+                continue;
+            }
+            found.push(first);
+            if !first.is_multifunction() {
+                continue;
+            }
+            for function in 1..8u8 {
+                let address = PciAddress::new(bus, device, function);
+                if address.exists() {
+                    found.push(address);
+                }
+            }
+        }
+    }
+    found
+}
+
+/// The first function with one of the vendor and device id pairs.
+pub fn find(ids: &[(u16, u16)]) -> Option<PciAddress> {
+    functions()
+        .into_iter()
+        .find(|f| ids.contains(&(f.vendor_id(), f.device_id())))
+}
+
+pub(crate) fn init() {
+    aer::init();
+}
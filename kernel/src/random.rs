@@ -0,0 +1,94 @@
+//! Random numbers, for the kernel and, through SyscallNumber::GetRandom, userspace. They come
+//! from the processor's generator where there is one, and a PRNG seeded from the timestamp
+//! counter where there isn't.
+//!
+//! Built with the deterministic feature, for reproducible test runs, they always come from the
+//! PRNG, seeded from `random.seed` on the command line, so every boot sees the same numbers.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use kernel_shared::{constants::SyscallNumber, random::RandomRequest};
+
+use crate::{
+    arch::{get_timestamp, hardware_random, register_syscall, SyscallParameters},
+    cmdline, debug,
+    memory::user::{check_user_buffer, copy_from_user, copy_slice_to_user},
+};
+
+// The splitmix64 increment.
+const GAMMA: u64 = 0x9e37_79b9_7f4a_7c15;
+const DEFAULT_SEED: u64 = 0x0123_4567_89ab_cdef;
+// How much is generated on the stack at a time, before it's copied out.
+const RANDOM_CHUNK_LENGTH: usize = 256;
+
+static STATE: AtomicU64 = AtomicU64::new(DEFAULT_SEED);
+
+pub(crate) fn init() {
+    match cfg!(feature = "deterministic") {
+        true => {
+            let seed = cmdline::get("random.seed")
+                .and_then(|s| s.parse::<u64>().ok())
+                .unwrap_or(DEFAULT_SEED);
+            debug!(
+                "Deterministic mode, random numbers are seeded with {}",
+                seed
+            );
+            STATE.store(seed, Ordering::Relaxed);
+        }
+        false => {
+            let seed = hardware_random().unwrap_or(0) ^ get_timestamp();
+            STATE.store(seed, Ordering::Relaxed);
+        }
+    }
+    register_syscall(
+        SyscallNumber::GetRandom as usize,
+        "get_random",
+        1,
+        get_random_syscall,
+    );
+}
+
+// splitmix64, one step per number, lock free so it works from interrupt context.
+fn next_pseudo_random() -> u64 {
+    let mut z = STATE
+        .fetch_add(GAMMA, Ordering::Relaxed)
+        .wrapping_add(GAMMA);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+pub fn random_u64() -> u64 {
+    if cfg!(feature = "deterministic") {
+        return next_pseudo_random();
+    }
+    hardware_random().unwrap_or_else(next_pseudo_random)
+}
+
+pub fn fill_random(buffer: &mut [u8]) {
+    for chunk in buffer.chunks_mut(8) {
+        let bytes = random_u64().to_ne_bytes();
+        chunk.copy_from_slice(&bytes[..chunk.len()]);
+    }
+}
+
+fn get_random_syscall(parameters: &SyscallParameters) {
+    let request = match copy_from_user(parameters.argument() as *const RandomRequest) {
+        Ok(request) => request,
+        Err(_) => return,
+    };
+    let length = request.length as usize;
+    // All of it, before any of it is written.
+    if check_user_buffer::<u8>(request.buffer as usize, length).is_err() {
+        return;
+    }
+    let mut chunk = [0u8; RANDOM_CHUNK_LENGTH];
+    for offset in (0..length).step_by(RANDOM_CHUNK_LENGTH) {
+        let chunk = &mut chunk[..(length - offset).min(RANDOM_CHUNK_LENGTH)];
+        fill_random(chunk);
+        let destination = request.buffer.wrapping_add(offset);
+        if copy_slice_to_user(destination, chunk).is_err() {
+            return;
+        }
+    }
+}
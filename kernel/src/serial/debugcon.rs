@@ -0,0 +1,46 @@
+//! QEMU's ISA debug console, a port that writes every byte straight to the host. Unlike the
+//! emulated 16550 there's no transmit register to wait on, so heavy logging doesn't stall.
+
+use core::fmt::Write;
+
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+use crate::arch::{io_read_u8, io_write_u8};
+
+const DEBUGCON_PORT: u16 = 0xE9;
+
+pub struct DebugCon;
+
+impl Write for DebugCon {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for byte in s.bytes() {
+            io_write_u8(DEBUGCON_PORT, byte);
+        }
+        Ok(())
+    }
+}
+
+lazy_static! {
+    // Keeps lines from different CPUs from interleaving.
+    pub static ref DEBUGCON: Mutex<DebugCon> = Mutex::new(DebugCon);
+}
+
+/// True if the debug console is there. QEMU reads back the port number from it, where
+/// nothing else decodes the port, reads float high.
+pub fn present() -> bool {
+    io_read_u8(DEBUGCON_PORT) == DEBUGCON_PORT as u8
+}
+
+#[doc(hidden)]
+pub fn _print(args: ::core::fmt::Arguments) {
+    let _ = DEBUGCON.lock().write_fmt(args);
+}
+
+/// Prints to the host through the debug console, appending a newline.
+#[macro_export]
+macro_rules! debugcon_println {
+    ($fmt:expr) => ($crate::serial::debugcon::_print(format_args!(concat!($fmt, "\n"))));
+    ($fmt:expr, $($arg:tt)*) => ($crate::serial::debugcon::_print(
+        format_args!(concat!($fmt, "\n"), $($arg)*)));
+}
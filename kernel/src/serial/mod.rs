@@ -1,15 +1,77 @@
+use alloc::{
+    format,
+    string::{String, ToString},
+};
+use devices::{
+    get_mut_device_tree,
+    well_known::{self, IPL},
+    Device,
+};
 use lazy_static::lazy_static;
 use spin::Mutex;
 use uart_16550::SerialPort;
+use uuid::Uuid;
+
+pub mod debugcon;
 
 lazy_static! {
     pub static ref SERIAL1: Mutex<SerialPort> = {
-        let mut serial_port = unsafe { SerialPort::new(0x3F8) };
+        let mut serial_port = unsafe { SerialPort::new(COM1) };
         serial_port.init();
         Mutex::new(serial_port)
     };
 }
 
+const COM1: u16 = 0x3F8;
+
+pub(crate) fn init() {
+    get_mut_device_tree().register(SerialDevice {
+        parent: IPL.as_u128(),
+        port: COM1,
+    });
+}
+
+struct SerialDevice {
+    parent: u128,
+    port: u16,
+}
+
+impl Device for SerialDevice {
+    fn name(&self) -> String {
+        "SERIAL".to_string()
+    }
+
+    fn ready(&self) -> bool {
+        true
+    }
+
+    fn parent_id(&self) -> Option<u128> {
+        Some(self.parent)
+    }
+
+    fn uuid(&self) -> Uuid {
+        well_known::SERIAL
+    }
+
+    fn location(&self) -> Option<String> {
+        Some(format!("io-{:#x}", self.port))
+    }
+}
+
+/// Sets up COM1 for raw output, before SERIAL1 can be used.
+pub fn init_raw() {
+    unsafe { SerialPort::new(COM1) }.init();
+}
+
+/// Writes straight to COM1, without taking a lock or touching SERIAL1. Only for when nothing
+/// else works: early boot, and fatal exceptions.
+#[doc(hidden)]
+pub fn _print_raw(args: ::core::fmt::Arguments) {
+    use core::fmt::Write;
+    let mut port = unsafe { SerialPort::new(COM1) };
+    let _ = port.write_fmt(args);
+}
+
 // in src/serial.rs
 
 #[doc(hidden)]
@@ -37,3 +99,11 @@ macro_rules! println {
     ($fmt:expr, $($arg:tt)*) => ($crate::print!(
         concat!($fmt, "\n"), $($arg)*));
 }
+
+/// Like println, but see _print_raw.
+#[macro_export]
+macro_rules! raw_println {
+    ($fmt:expr) => ($crate::serial::_print_raw(format_args!(concat!($fmt, "\n"))));
+    ($fmt:expr, $($arg:tt)*) => ($crate::serial::_print_raw(
+        format_args!(concat!($fmt, "\n"), $($arg)*)));
+}
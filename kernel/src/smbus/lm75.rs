@@ -0,0 +1,83 @@
+use alloc::string::{String, ToString};
+use devices::{
+    get_mut_device_tree,
+    sensor::{Sensor, SensorKind},
+    smbus::SmbusClient,
+    well_known, Device, DeviceError, DeviceErrorCode,
+};
+use uuid::Uuid;
+
+use crate::debug;
+
+// LM75 compatible temperature sensors, like the TMP105 QEMU can emulate, answer at one of these.
+const ADDRESSES: core::ops::RangeInclusive<u8> = 0x48..=0x4F;
+const TEMPERATURE: u8 = 0x00;
+
+/// An LM75 compatible temperature sensor.
+struct Lm75 {
+    client: SmbusClient,
+    location: String,
+}
+
+impl Sensor for Lm75 {
+    fn kind(&self) -> SensorKind {
+        SensorKind::Temperature
+    }
+
+    fn read(&self) -> Result<i64, DeviceError> {
+        let word = self
+            .client
+            .read_word_data(TEMPERATURE)
+            .map_err(|_| DeviceError::new(DeviceErrorCode::Malfunction))?;
+        // Sent most significant byte first, in 1/256ths of a degree, SMBus takes the low first.
+        let raw = word.swap_bytes() as i16;
+        Ok(raw as i64 * 1000 / 256)
+    }
+}
+
+impl Device for Lm75 {
+    fn name(&self) -> String {
+        "LM75".to_string()
+    }
+
+    fn ready(&self) -> bool {
+        true
+    }
+
+    fn uuid(&self) -> Uuid {
+        well_known::TEMPERATURE_SENSOR
+    }
+
+    fn parent_id(&self) -> Option<u128> {
+        Some(self.client.controller())
+    }
+
+    fn location(&self) -> Option<String> {
+        Some(self.location.clone())
+    }
+
+    fn sensor(&self) -> Option<&dyn Sensor> {
+        Some(self)
+    }
+}
+
+/// Registers a sensor for every address with something answering like an LM75.
+pub(super) fn probe(controller: u128) {
+    for address in ADDRESSES {
+        let client = SmbusClient::new(controller, address);
+        if client.read_word_data(TEMPERATURE).is_err() {
+            continue;
+        }
+        let sensor = Lm75 {
+            client,
+            location: client.location(),
+        };
+        match sensor.read() {
+            Ok(temperature) => {
+                debug!("LM75 at {:#x}: {} m°C", address, temperature);
+            }
+            Err(_) => continue,
+        }
+        get_mut_device_tree().register(sensor);
+    }
+}
@@ -0,0 +1,206 @@
+use alloc::string::{String, ToString};
+use devices::{
+    get_mut_device_tree,
+    smbus::{Smbus, SmbusError},
+    well_known, Device,
+};
+use spin::Mutex;
+use uuid::Uuid;
+
+use crate::{
+    arch::{io_read_u8, io_write_u8},
+    debug,
+    pci::{self, Bar, PciAddress},
+    time,
+};
+
+mod lm75;
+
+// The PIIX4 power management function, where QEMU's pc machine has its SMBus, and the ICH9
+// SMBus function, on the q35 machine. Both have the same host interface.
+const PIIX4_POWER_MANAGEMENT: (u16, u16) = (0x8086, 0x7113);
+const ICH9_SMBUS: (u16, u16) = (0x8086, 0x2930);
+const PIIX4_SMBUS_BASE: u16 = 0x90;
+const PIIX4_HOST_CONFIGURATION: u16 = 0xD2;
+const ICH9_SMBUS_BAR: u16 = 4;
+const ICH9_HOST_CONFIGURATION: u16 = 0x40;
+const HOST_ENABLE: u8 = 1 << 0;
+
+const HOST_STATUS: u16 = 0;
+const HOST_CONTROL: u16 = 2;
+const HOST_COMMAND: u16 = 3;
+const TRANSMIT_ADDRESS: u16 = 4;
+const HOST_DATA_0: u16 = 5;
+const HOST_DATA_1: u16 = 6;
+
+const STATUS_HOST_BUSY: u8 = 1 << 0;
+const STATUS_INTERRUPT: u8 = 1 << 1;
+const STATUS_DEVICE_ERROR: u8 = 1 << 2;
+const STATUS_BUS_ERROR: u8 = 1 << 3;
+const STATUS_FAILED: u8 = 1 << 4;
+const STATUS_DONE: u8 = STATUS_INTERRUPT | STATUS_DEVICE_ERROR | STATUS_BUS_ERROR | STATUS_FAILED;
+
+const CONTROL_KILL: u8 = 1 << 1;
+const CONTROL_START: u8 = 1 << 6;
+const PROTOCOL_QUICK: u8 = 0b000 << 2;
+const PROTOCOL_BYTE_DATA: u8 = 0b010 << 2;
+const PROTOCOL_WORD_DATA: u8 = 0b011 << 2;
+
+const TRANSACTION_TIMEOUT_NANOSECONDS: u64 = 10_000_000;
+
+/// An Intel SMBus host controller, driven by polling.
+struct SmbusController {
+    function: PciAddress,
+    base: u16,
+    // One transaction at a time.
+    lock: Mutex<()>,
+}
+
+impl SmbusController {
+    fn read(&self, register: u16) -> u8 {
+        io_read_u8(self.base + register)
+    }
+
+    fn write(&self, register: u16, value: u8) {
+        io_write_u8(self.base + register, value);
+    }
+
+    // Runs a transaction, returning the data registers, the low byte in data 0.
+    fn transaction(
+        &self,
+        address: u8,
+        read: bool,
+        command: u8,
+        protocol: u8,
+        data: u16,
+    ) -> Result<u16, SmbusError> {
+        let _lock = self.lock.lock();
+        if self.read(HOST_STATUS) & STATUS_HOST_BUSY != 0 {
+            return Err(SmbusError::Busy);
+        }
+        self.write(HOST_STATUS, STATUS_DONE);
+        self.write(TRANSMIT_ADDRESS, (address << 1) | read as u8);
+        self.write(HOST_COMMAND, command);
+        self.write(HOST_DATA_0, data as u8);
+        self.write(HOST_DATA_1, (data >> 8) as u8);
+        self.write(HOST_CONTROL, protocol | CONTROL_START);
+        let deadline = time::monotonic_nanoseconds() + TRANSACTION_TIMEOUT_NANOSECONDS;
+        let status = loop {
+            let status = self.read(HOST_STATUS);
+            if status & STATUS_HOST_BUSY == 0 && status & STATUS_DONE != 0 {
+                break status;
+            }
+            if time::monotonic_nanoseconds() > deadline {
+                self.write(HOST_CONTROL, CONTROL_KILL);
+                self.write(HOST_CONTROL, 0);
+                self.write(HOST_STATUS, STATUS_DONE);
+                return Err(SmbusError::Timeout);
+            }
+            core::hint::spin_loop();
+        };
+        self.write(HOST_STATUS, status & STATUS_DONE);
+        if status & STATUS_DEVICE_ERROR != 0 {
+            return Err(SmbusError::NoDevice);
+        }
+        if status & (STATUS_BUS_ERROR | STATUS_FAILED) != 0 {
+            return Err(SmbusError::BusError);
+        }
+        Ok(self.read(HOST_DATA_0) as u16 | (self.read(HOST_DATA_1) as u16) << 8)
+    }
+}
+
+impl Smbus for SmbusController {
+    fn quick(&self, address: u8) -> Result<(), SmbusError> {
+        self.transaction(address, false, 0, PROTOCOL_QUICK, 0)
+            .map(|_| ())
+    }
+
+    fn read_byte_data(&self, address: u8, command: u8) -> Result<u8, SmbusError> {
+        self.transaction(address, true, command, PROTOCOL_BYTE_DATA, 0)
+            .map(|data| data as u8)
+    }
+
+    fn write_byte_data(&self, address: u8, command: u8, value: u8) -> Result<(), SmbusError> {
+        self.transaction(address, false, command, PROTOCOL_BYTE_DATA, value as u16)
+            .map(|_| ())
+    }
+
+    fn read_word_data(&self, address: u8, command: u8) -> Result<u16, SmbusError> {
+        self.transaction(address, true, command, PROTOCOL_WORD_DATA, 0)
+    }
+}
+
+impl Device for SmbusController {
+    fn name(&self) -> String {
+        "SMBUS".to_string()
+    }
+
+    fn ready(&self) -> bool {
+        true
+    }
+
+    fn uuid(&self) -> Uuid {
+        well_known::SMBUS
+    }
+
+    fn location(&self) -> Option<String> {
+        Some(self.function.location())
+    }
+
+    fn smbus(&self) -> Option<&dyn Smbus> {
+        Some(self)
+    }
+}
+
+// Once the controller is unregistered and nobody holds it any more, its function is handed back.
+impl Drop for SmbusController {
+    fn drop(&mut self) {
+        self.function.unbind();
+    }
+}
+
+// The controller's function, and the base of its host registers, enabling it if the firmware
+// left it off. The host interface is programmed I/O only, so it never masters the bus.
+fn find_controller() -> Option<(PciAddress, u16)> {
+    let function = pci::find(&[PIIX4_POWER_MANAGEMENT, ICH9_SMBUS])?;
+    let (base, host_configuration) = match (function.vendor_id(), function.device_id()) {
+        PIIX4_POWER_MANAGEMENT => (
+            (function.read_u32(PIIX4_SMBUS_BASE) & 0xFFF0) as u16,
+            PIIX4_HOST_CONFIGURATION,
+        ),
+        _ => match function.bar(ICH9_SMBUS_BAR) {
+            Some(Bar::Io(port)) => (port, ICH9_HOST_CONFIGURATION),
+            _ => return None,
+        },
+    };
+    if base == 0 {
+        return None;
+    }
+    function.bind(true, false, false);
+    let configuration = function.read_u8(host_configuration);
+    if configuration & HOST_ENABLE == 0 {
+        function.write_u8(host_configuration, configuration | HOST_ENABLE);
+    }
+    Some((function, base))
+}
+
+/// Registers the SMBus controller, if there is one, and the sensors found on it.
+pub(crate) fn init() {
+    let (function, base) = match find_controller() {
+        Some(controller) => controller,
+        None => {
+            debug!("No SMBus controller found");
+            return;
+        }
+    };
+    let controller = get_mut_device_tree().register(SmbusController {
+        function,
+        base,
+        lock: Mutex::new(()),
+    });
+    debug!(
+        "SMBus controller at {}, host registers at {:#x}",
+        function, base
+    );
+    lm75::probe(controller);
+}
@@ -0,0 +1,125 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use alloc::string::{String, ToString};
+use devices::{
+    get_mut_device_tree,
+    well_known::{self, IPL},
+    Device, DeviceError, DeviceErrorCode,
+};
+use kernel_shared::{
+    device::{
+        SoundDescription, DEVICE_FUNCTION_DESCRIBE, SOUND_FUNCTION_PLAY_TONE, SOUND_FUNCTION_STOP,
+    },
+    serialization::to_bytes,
+};
+use uuid::Uuid;
+
+use crate::{arch, time};
+
+const BEEP_FREQUENCY: u32 = 880;
+const BEEP_MILLISECONDS: u64 = 100;
+const NO_TONE: u64 = u64::MAX;
+const SPEAKER_DESCRIPTION_LENGTH: usize = 32;
+
+// When the playing tone should stop, in monotonic nanoseconds.
+static TONE_END: AtomicU64 = AtomicU64::new(NO_TONE);
+
+pub(crate) fn init() {
+    let mut description = [0u8; SPEAKER_DESCRIPTION_LENGTH];
+    let description_length = to_bytes(
+        &SoundDescription {
+            model: "pc-speaker",
+            pcm: false,
+        },
+        &mut description,
+    )
+    .map_or(0, |bytes| bytes.len());
+    get_mut_device_tree().register(SpeakerDevice {
+        parent: IPL.as_u128(),
+        description,
+        description_length,
+    });
+}
+
+/// Plays a tone on the PC speaker without waiting for it, replacing any tone that is playing.
+/// Does nothing before there's a clock source, as nothing could time the tone.
+pub(crate) fn play_tone(frequency: u32, milliseconds: u64) {
+    if frequency == 0 || milliseconds == 0 {
+        stop();
+        return;
+    }
+    if !time::has_clock_source() {
+        return;
+    }
+    let end = time::monotonic_nanoseconds().saturating_add(milliseconds.saturating_mul(1_000_000));
+    TONE_END.store(end, Ordering::Release);
+    arch::speaker_start(frequency);
+}
+
+pub(crate) fn beep() {
+    play_tone(BEEP_FREQUENCY, BEEP_MILLISECONDS);
+}
+
+pub(crate) fn stop() {
+    TONE_END.store(NO_TONE, Ordering::Release);
+    arch::speaker_stop();
+}
+
+/// Called from the idle loop, stops the tone once it has played long enough.
+pub(crate) fn poll() {
+    let end = TONE_END.load(Ordering::Acquire);
+    if end == NO_TONE || time::monotonic_nanoseconds() < end {
+        return;
+    }
+    // Only stop the tone we checked, not one started since.
+    if TONE_END
+        .compare_exchange(end, NO_TONE, Ordering::AcqRel, Ordering::Relaxed)
+        .is_ok()
+    {
+        arch::speaker_stop();
+    }
+}
+
+struct SpeakerDevice {
+    parent: u128,
+    description: [u8; SPEAKER_DESCRIPTION_LENGTH],
+    description_length: usize,
+}
+
+impl Device for SpeakerDevice {
+    fn name(&self) -> String {
+        "SPEAKER".to_string()
+    }
+
+    fn ready(&self) -> bool {
+        true
+    }
+
+    fn parent_id(&self) -> Option<u128> {
+        Some(self.parent)
+    }
+
+    fn uuid(&self) -> Uuid {
+        well_known::SPEAKER
+    }
+
+    fn function(&self, id: usize, args: &[usize]) -> Result<&[u8], DeviceError> {
+        match (id, args) {
+            (DEVICE_FUNCTION_DESCRIBE, _) if self.description_length > 0 => {
+                Ok(&self.description[0..self.description_length])
+            }
+            (DEVICE_FUNCTION_DESCRIBE, _) => Err(DeviceError::new(DeviceErrorCode::Malfunction)),
+            (SOUND_FUNCTION_PLAY_TONE, [frequency, milliseconds, ..]) => {
+                play_tone(*frequency as u32, *milliseconds as u64);
+                Ok(&[])
+            }
+            (SOUND_FUNCTION_STOP, _) => {
+                stop();
+                Ok(&[])
+            }
+            (SOUND_FUNCTION_PLAY_TONE, _) => Err(DeviceError::new(DeviceErrorCode::Malfunction)),
+            // The speaker can't play samples, SOUND_FUNCTION_SUBMIT_PCM is left for a real sound card.
+            _ => Err(DeviceError::new(DeviceErrorCode::NotImplemented)),
+        }
+    }
+}
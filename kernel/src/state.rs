@@ -0,0 +1,72 @@
+//! The kernel's singletons, registered as each subsystem starts and looked up by type. Looking
+//! one up before it's registered panics, naming the type and where it was looked up from, so
+//! initialization order mistakes show up at the first boot rather than as a null dereference.
+
+use alloc::boxed::Box;
+use core::any::Any;
+
+use bootloader_api::BootInfo;
+use kernel_shared::kernel_state::StateRegistry;
+
+use crate::debug;
+
+const MAX_KERNEL_STATE: usize = 16;
+
+static KERNEL_STATE: StateRegistry<MAX_KERNEL_STATE> = StateRegistry::new();
+
+/// What the kernel needs from the bootloader's BootInfo once it's booted.
+#[derive(Debug, Clone, Copy)]
+pub struct BootData {
+    pub physical_memory_offset: u64,
+    pub rsdp_address: Option<u64>,
+}
+
+impl BootData {
+    pub fn new(boot_info: &BootInfo) -> Self {
+        Self {
+            physical_memory_offset: boot_info
+                .physical_memory_offset
+                .into_option()
+                .expect("No physical memory offset available!"),
+            rsdp_address: boot_info.rsdp_addr.into_option(),
+        }
+    }
+}
+
+/// Registers state that lives as long as the kernel.
+///
+/// # Panics
+/// If a T is already registered, or there's no room left.
+#[track_caller]
+pub fn register<T: Any + Send + Sync>(value: &'static T) {
+    if let Err(err) = KERNEL_STATE.register(value) {
+        panic!(
+            "Unable to register {}: {:?}",
+            core::any::type_name::<T>(),
+            err
+        );
+    }
+    debug!("Registered kernel state {}", core::any::type_name::<T>());
+}
+
+/// Like register, for state built at boot rather than kept in a static.
+#[track_caller]
+pub fn register_owned<T: Any + Send + Sync>(value: T) {
+    register::<T>(Box::leak(Box::new(value)));
+}
+
+#[track_caller]
+pub fn get<T: Any + Send + Sync>() -> &'static T {
+    KERNEL_STATE.expect::<T>()
+}
+
+/// None until T is registered, for code that can run before and after.
+pub fn try_get<T: Any + Send + Sync>() -> Option<&'static T> {
+    KERNEL_STATE.get::<T>()
+}
+
+/// Asserts T was registered, for initialization that has to come after it.
+#[track_caller]
+pub fn require<T: Any + Send + Sync>() {
+    KERNEL_STATE.require::<T>();
+}
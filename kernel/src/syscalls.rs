@@ -0,0 +1,203 @@
+use alloc::{format, string::String, vec::Vec};
+use devices::{get_device_tree, DeviceErrorCode};
+use kernel_shared::{
+    audit::{AUDIT_DENIED, AUDIT_DEVICE_CALL, AUDIT_FAILED, AUDIT_SUCCEEDED},
+    constants::SyscallNumber,
+    device::{
+        DeviceCallRequest, DeviceDescription, DeviceListRequest, DEVICE_CALL_MALFUNCTION,
+        DEVICE_CALL_NATIVE_ERROR, DEVICE_CALL_NOT_IMPLEMENTED, DEVICE_CALL_NOT_PERMITTED,
+        DEVICE_CALL_NO_DEVICE, DEVICE_CALL_OK,
+    },
+    syscall::{SyscallDescription, SyscallListRequest},
+};
+
+use crate::{
+    arch::{register_syscall, syscalls, SyscallInfo, SyscallParameters},
+    audit,
+    memory::user::{copy_from_user, copy_slice_from_user, copy_slice_to_user, copy_to_user},
+    println,
+    thread::credentials::is_root,
+};
+
+// More than any device function takes, and few enough to copy in without thinking about it.
+const MAX_DEVICE_CALL_ARGUMENTS: usize = 16;
+
+pub(crate) fn init() {
+    register_syscall(
+        SyscallNumber::ListSyscalls as usize,
+        "list_syscalls",
+        1,
+        list_syscalls_syscall,
+    );
+    register_syscall(
+        SyscallNumber::ListDevices as usize,
+        "list_devices",
+        1,
+        list_devices_syscall,
+    );
+    register_syscall(
+        SyscallNumber::CallDevice as usize,
+        "call_device",
+        1,
+        call_device_syscall,
+    );
+}
+
+fn description(info: &SyscallInfo) -> SyscallDescription {
+    let mut description = SyscallDescription {
+        personality: info.personality as u64,
+        number: info.number as u64,
+        arguments: info.arguments as u64,
+        ..Default::default()
+    };
+    copy_truncated(&mut description.name, info.name);
+    description
+}
+
+fn list_syscalls_syscall(parameters: &SyscallParameters) {
+    let pointer = parameters.argument() as *mut SyscallListRequest;
+    let mut request = match copy_from_user(pointer) {
+        Ok(request) => request,
+        Err(_) => return,
+    };
+    let all = syscalls();
+    if !request.entries.is_null() {
+        let descriptions: Vec<SyscallDescription> = all
+            .iter()
+            .take(request.capacity as usize)
+            .map(description)
+            .collect();
+        // A bad entries pointer gets the count, but no entries.
+        let _ = copy_slice_to_user(request.entries, &descriptions);
+    }
+    request.count = all.len() as u64;
+    let _ = copy_to_user(pointer, &request);
+}
+
+// Copies as many whole characters of value as fit, leaving the rest of field zero padded.
+fn copy_truncated(field: &mut [u8], value: &str) {
+    let mut length = value.len().min(field.len());
+    while !value.is_char_boundary(length) {
+        length -= 1;
+    }
+    field[..length].copy_from_slice(&value.as_bytes()[..length]);
+}
+
+fn list_devices_syscall(parameters: &SyscallParameters) {
+    let pointer = parameters.argument() as *mut DeviceListRequest;
+    let mut request = match copy_from_user(pointer) {
+        Ok(request) => request,
+        Err(_) => return,
+    };
+    let device_tree = get_device_tree();
+    let ids = device_tree.keys();
+    if !request.entries.is_null() {
+        let descriptions: Vec<DeviceDescription> = ids
+            .iter()
+            .take(request.capacity as usize)
+            .filter_map(|id| {
+                let device = device_tree.get(id)?;
+                let mut description = DeviceDescription {
+                    id: id.to_le_bytes(),
+                    uuid: device.uuid().as_u128().to_le_bytes(),
+                    parent_id: device.parent_id().unwrap_or(0).to_le_bytes(),
+                    ready: devices::readiness::is_ready(*id) as u64,
+                    ..Default::default()
+                };
+                copy_truncated(&mut description.name, &device.name());
+                copy_truncated(&mut description.class, device.class().unwrap_or(""));
+                Some(description)
+            })
+            .collect();
+        // A bad entries pointer gets the count, but no entries.
+        let _ = copy_slice_to_user(request.entries, &descriptions);
+    }
+    request.count = ids.len() as u64;
+    let _ = copy_to_user(pointer, &request);
+}
+
+fn call_device_syscall(parameters: &SyscallParameters) {
+    let pointer = parameters.argument() as *mut DeviceCallRequest;
+    let mut request = match copy_from_user(pointer) {
+        Ok(request) => request,
+        Err(_) => return,
+    };
+    call_device(&mut request);
+    let _ = copy_to_user(pointer, &request);
+}
+
+fn call_device(request: &mut DeviceCallRequest) {
+    let device_id = u128::from_le_bytes(request.device_id);
+    // Raw device access is privileged.
+    if !is_root() {
+        audit::record(AUDIT_DEVICE_CALL, AUDIT_DENIED, device_id);
+        request.status = DEVICE_CALL_NOT_PERMITTED;
+        return;
+    }
+    let count = request.argument_count as usize;
+    if count > MAX_DEVICE_CALL_ARGUMENTS {
+        request.status = DEVICE_CALL_MALFUNCTION;
+        return;
+    }
+    let arguments = match copy_slice_from_user(request.arguments, count) {
+        Ok(arguments) => arguments,
+        Err(_) => {
+            request.status = DEVICE_CALL_MALFUNCTION;
+            return;
+        }
+    };
+    // Held on its own, so the device tree isn't locked while the device works.
+    let device = get_device_tree().get(&device_id);
+    let device = match device {
+        Some(device) => device,
+        None => {
+            audit::record(AUDIT_DEVICE_CALL, AUDIT_FAILED, device_id);
+            request.status = DEVICE_CALL_NO_DEVICE;
+            return;
+        }
+    };
+    match device.function(request.function as usize, &arguments) {
+        Ok(result) => {
+            if !request.result.is_null() {
+                let length = result.len().min(request.capacity as usize);
+                if copy_slice_to_user(request.result, &result[..length]).is_err() {
+                    request.length = 0;
+                    request.status = DEVICE_CALL_MALFUNCTION;
+                    audit::record(AUDIT_DEVICE_CALL, AUDIT_FAILED, device_id);
+                    return;
+                }
+            }
+            request.length = result.len() as u64;
+            request.status = DEVICE_CALL_OK;
+            audit::record(AUDIT_DEVICE_CALL, AUDIT_SUCCEEDED, device_id);
+        }
+        Err(err) => {
+            request.length = 0;
+            request.status = match err.code() {
+                DeviceErrorCode::NotImplemented => DEVICE_CALL_NOT_IMPLEMENTED,
+                DeviceErrorCode::Malfunction => DEVICE_CALL_MALFUNCTION,
+                DeviceErrorCode::DeviceNativeError(code) => DEVICE_CALL_NATIVE_ERROR | code,
+            };
+            audit::record(AUDIT_DEVICE_CALL, AUDIT_FAILED, device_id);
+        }
+    }
+}
+
+/// Prints every registered system call, for the `syscalls` command line flag.
+pub(crate) fn dump() {
+    println!("Registered system calls:");
+    for info in syscalls() {
+        let personality = match info.personality {
+            usize::MAX => String::from("native"),
+            personality => format!("{}", personality),
+        };
+        let number = match info.number {
+            usize::MAX => String::from("default"),
+            number => format!("{}", number),
+        };
+        println!(
+            "{:>8} {:>8} {:<24} {} arguments",
+            personality, number, info.name, info.arguments
+        );
+    }
+}
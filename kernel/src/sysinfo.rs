@@ -0,0 +1,48 @@
+use kernel_shared::{
+    constants::SyscallNumber,
+    memory::{MemoryInfo, SystemInfo},
+};
+
+use crate::{
+    arch::{register_syscall, SyscallParameters},
+    memory::{
+        self,
+        allocator::{heap_usage, PAGE_SIZE},
+        user::copy_to_user,
+    },
+    thread::process::process_manager,
+    time,
+};
+
+pub(crate) fn init() {
+    register_syscall(
+        SyscallNumber::SystemInfo as usize,
+        "system_info",
+        1,
+        system_info_syscall,
+    );
+}
+
+pub(crate) fn system_info() -> SystemInfo {
+    let (zones, _) = memory::page_counts();
+    let ((heap_bytes, heap_used_bytes), _) = heap_usage();
+    SystemInfo {
+        uptime_nanoseconds: time::monotonic_nanoseconds(),
+        // There is no scheduler to count runnable threads yet.
+        loads: [0; 3],
+        processes: process_manager().process_count() as u64,
+        memory: MemoryInfo {
+            total_bytes: zones.iter().map(|z| z.total_pages).sum::<u64>() * PAGE_SIZE as u64,
+            free_bytes: zones.iter().map(|z| z.free_pages).sum::<u64>() * PAGE_SIZE as u64,
+            // Nothing caches file data yet.
+            cached_bytes: 0,
+            kernel_heap_bytes: heap_bytes as u64,
+            kernel_heap_used_bytes: heap_used_bytes as u64,
+        },
+    }
+}
+
+fn system_info_syscall(parameters: &SyscallParameters) {
+    let destination = parameters.argument() as *mut SystemInfo;
+    let _ = copy_to_user(destination, &system_info());
+}
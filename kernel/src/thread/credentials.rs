@@ -0,0 +1,148 @@
+//! Who a process runs as. The kernel runs as root. Processes start with their parent's
+//! credentials, or as nobody if the kernel started them without saying otherwise, and only root
+//! may take on another identity. Privileged system calls check is_root().
+
+use kernel_shared::{
+    audit::{AUDIT_DENIED, AUDIT_SET_GROUP_ID, AUDIT_SET_USER_ID, AUDIT_SUCCEEDED},
+    constants::SyscallNumber,
+    process::{SetIdRequest, NOBODY_ID, ROOT_ID, SET_ID_NOT_PERMITTED, SET_ID_OK},
+};
+
+use crate::{
+    arch::{register_syscall, SyscallParameters},
+    audit,
+    memory::user::{copy_from_user, copy_to_user},
+};
+
+use super::process::{current_process_id, process_manager, write_result};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Credentials {
+    pub user_id: u32,
+    pub group_id: u32,
+    /// The user permission checks are made against.
+    pub effective_user_id: u32,
+    pub effective_group_id: u32,
+}
+
+impl Credentials {
+    pub const ROOT: Credentials = Credentials {
+        user_id: ROOT_ID,
+        group_id: ROOT_ID,
+        effective_user_id: ROOT_ID,
+        effective_group_id: ROOT_ID,
+    };
+
+    pub const NOBODY: Credentials = Credentials {
+        user_id: NOBODY_ID,
+        group_id: NOBODY_ID,
+        effective_user_id: NOBODY_ID,
+        effective_group_id: NOBODY_ID,
+    };
+
+    pub fn is_root(&self) -> bool {
+        self.effective_user_id == ROOT_ID
+    }
+
+    /// Sets the user, or group, the way setuid does: root sets the real and effective ids, and
+    /// anyone else may only set the effective id back to the real one.
+    fn set_id(&mut self, id: u32, group: bool) -> bool {
+        let root = self.is_root();
+        let (real, effective) = match group {
+            true => (&mut self.group_id, &mut self.effective_group_id),
+            false => (&mut self.user_id, &mut self.effective_user_id),
+        };
+        if root {
+            *real = id;
+        } else if id != *real {
+            return false;
+        }
+        *effective = id;
+        true
+    }
+}
+
+/// The current process's credentials. Anything not in the process table is nobody.
+pub fn current_credentials() -> Credentials {
+    process_manager()
+        .get_process(current_process_id())
+        .map_or(Credentials::NOBODY, |p| p.get_credentials())
+}
+
+/// For privileged system calls, like raw device access.
+pub fn is_root() -> bool {
+    current_credentials().is_root()
+}
+
+pub(crate) fn init() {
+    register_syscall(
+        SyscallNumber::GetUserId as usize,
+        "get_user_id",
+        1,
+        |parameters| write_result(parameters, current_credentials().user_id as u64),
+    );
+    register_syscall(
+        SyscallNumber::GetEffectiveUserId as usize,
+        "get_effective_user_id",
+        1,
+        |parameters| write_result(parameters, current_credentials().effective_user_id as u64),
+    );
+    register_syscall(
+        SyscallNumber::GetGroupId as usize,
+        "get_group_id",
+        1,
+        |parameters| write_result(parameters, current_credentials().group_id as u64),
+    );
+    register_syscall(
+        SyscallNumber::GetEffectiveGroupId as usize,
+        "get_effective_group_id",
+        1,
+        |parameters| write_result(parameters, current_credentials().effective_group_id as u64),
+    );
+    register_syscall(
+        SyscallNumber::SetUserId as usize,
+        "set_user_id",
+        1,
+        |parameters| set_id_syscall(parameters, false),
+    );
+    register_syscall(
+        SyscallNumber::SetGroupId as usize,
+        "set_group_id",
+        1,
+        |parameters| set_id_syscall(parameters, true),
+    );
+}
+
+fn set_id_syscall(parameters: &SyscallParameters, group: bool) {
+    let pointer = parameters.argument() as *mut SetIdRequest;
+    let mut request = match copy_from_user(pointer) {
+        Ok(request) => request,
+        Err(_) => return,
+    };
+    set_id(&mut request, group);
+    let _ = copy_to_user(pointer, &request);
+}
+
+fn set_id(request: &mut SetIdRequest, group: bool) {
+    let id = match u32::try_from(request.id) {
+        Ok(id) => id,
+        Err(_) => {
+            request.status = SET_ID_NOT_PERMITTED;
+            return;
+        }
+    };
+    let caller = current_credentials().effective_user_id;
+    let allowed = process_manager().update_credentials(current_process_id(), |credentials| {
+        credentials.set_id(id, group)
+    });
+    let event = match group {
+        true => AUDIT_SET_GROUP_ID,
+        false => AUDIT_SET_USER_ID,
+    };
+    let (status, outcome) = match allowed {
+        true => (SET_ID_OK, AUDIT_SUCCEEDED),
+        false => (SET_ID_NOT_PERMITTED, AUDIT_DENIED),
+    };
+    audit::record_as(caller, event, outcome, id as u128);
+    request.status = status;
+}
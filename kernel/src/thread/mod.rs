@@ -2,6 +2,8 @@ use alloc::{boxed::Box, vec::Vec};
 
 use x86_64::structures::{paging::PageTable, tss::TaskStateSegment};
 
+pub(crate) mod credentials;
+pub(crate) mod preempt;
 pub(crate) mod process;
 pub(crate) mod scheduler;
 
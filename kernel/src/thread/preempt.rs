@@ -0,0 +1,214 @@
+use core::{
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicBool, AtomicU64, AtomicU8, AtomicUsize, Ordering},
+};
+
+use kernel_shared::tunable::TunableValue;
+use spin::{Mutex, MutexGuard};
+
+use crate::{
+    arch::{get_current_cpu, in_interrupt_context, MAX_CPU_COUNT, TIMER_TICK_NANOSECONDS},
+    debug, state, tunables,
+};
+
+use super::scheduler::{self, Scheduler};
+
+// How long a thread runs before the tick asks it to give up the CPU, by default, and the range
+// the preempt.timeslice_us tunable may set it to.
+const DEFAULT_TIME_SLICE_MICROSECONDS: i64 = 10_000;
+const MIN_TIME_SLICE_MICROSECONDS: i64 = 100;
+const MAX_TIME_SLICE_MICROSECONDS: i64 = 1_000_000;
+
+/// When a thread that has used up its time slice gives up the CPU, set with the `preempt`
+/// tunable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum PreemptionModel {
+    /// Only when it calls schedule.
+    None = 0,
+    /// Also at cond_resched checkpoints in long running kernel paths.
+    Voluntary = 1,
+    /// Also when returning from an interrupt, or re-enabling preemption, anywhere preemption
+    /// isn't disabled.
+    Full = 2,
+}
+
+static MODEL: AtomicU8 = AtomicU8::new(PreemptionModel::Voluntary as u8);
+// The time slice, in timer ticks.
+static TIME_SLICE_TICKS: AtomicU64 =
+    AtomicU64::new(microseconds_to_ticks(DEFAULT_TIME_SLICE_MICROSECONDS));
+
+static NEED_RESCHED: [AtomicBool; MAX_CPU_COUNT] =
+    [const { AtomicBool::new(false) }; MAX_CPU_COUNT];
+static PREEMPT_COUNT: [AtomicUsize; MAX_CPU_COUNT] = [const { AtomicUsize::new(0) }; MAX_CPU_COUNT];
+// Timer ticks each CPU's current time slice has run for.
+static SLICE_TICKS: [AtomicU64; MAX_CPU_COUNT] = [const { AtomicU64::new(0) }; MAX_CPU_COUNT];
+
+pub(crate) fn init() {
+    // Preemption hands the CPU to the scheduler.
+    state::require::<Scheduler>();
+    tunables::register_string("preempt", "voluntary", set_model);
+    tunables::register_integer(
+        "preempt.timeslice_us",
+        DEFAULT_TIME_SLICE_MICROSECONDS,
+        MIN_TIME_SLICE_MICROSECONDS,
+        MAX_TIME_SLICE_MICROSECONDS,
+        set_time_slice,
+    );
+    debug!("Preemption model: {:?}", model());
+}
+
+fn set_model(value: &TunableValue) -> bool {
+    let model = match value.string() {
+        "none" => PreemptionModel::None,
+        "voluntary" => PreemptionModel::Voluntary,
+        "full" => PreemptionModel::Full,
+        _ => return false,
+    };
+    MODEL.store(model as u8, Ordering::Relaxed);
+    true
+}
+
+fn set_time_slice(value: &TunableValue) -> bool {
+    TIME_SLICE_TICKS.store(microseconds_to_ticks(value.integer), Ordering::Relaxed);
+    true
+}
+
+// Rounded up, a slice shorter than a tick still lasts one.
+const fn microseconds_to_ticks(microseconds: i64) -> u64 {
+    (microseconds as u64 * 1000).div_ceil(TIMER_TICK_NANOSECONDS)
+}
+
+pub fn model() -> PreemptionModel {
+    match MODEL.load(Ordering::Relaxed) {
+        0 => PreemptionModel::None,
+        2 => PreemptionModel::Full,
+        _ => PreemptionModel::Voluntary,
+    }
+}
+
+pub fn preempt_disable() {
+    PREEMPT_COUNT[get_current_cpu()].fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn preempt_enable() {
+    let previous = PREEMPT_COUNT[get_current_cpu()].fetch_sub(1, Ordering::Relaxed);
+    if previous == 0 {
+        panic!("preempt_enable called without a matching preempt_disable");
+    }
+    if previous == 1 && model() == PreemptionModel::Full {
+        resched_if_needed();
+    }
+}
+
+/// True if the current CPU may switch threads here.
+pub fn preemptible() -> bool {
+    PREEMPT_COUNT[get_current_cpu()].load(Ordering::Relaxed) == 0 && !in_interrupt_context()
+}
+
+pub fn need_resched() -> bool {
+    NEED_RESCHED[get_current_cpu()].load(Ordering::Relaxed)
+}
+
+/// Called when the current CPU switches threads, the next one starts a new time slice.
+pub(super) fn start_time_slice() {
+    let cpu = get_current_cpu();
+    NEED_RESCHED[cpu].store(false, Ordering::Relaxed);
+    SLICE_TICKS[cpu].store(0, Ordering::Relaxed);
+}
+
+/// Called from the timer interrupt, flags the current thread once its time slice is up.
+pub(crate) fn scheduler_tick() {
+    let cpu = get_current_cpu();
+    let elapsed = SLICE_TICKS[cpu].fetch_add(1, Ordering::Relaxed) + 1;
+    if elapsed >= TIME_SLICE_TICKS.load(Ordering::Relaxed) {
+        NEED_RESCHED[cpu].store(true, Ordering::Relaxed);
+    }
+}
+
+/// Called as an interrupt handler returns, preempts the interrupted thread under the full model.
+pub(crate) fn interrupt_exit() {
+    if model() == PreemptionModel::Full {
+        resched_if_needed();
+    }
+}
+
+/// A checkpoint for long running kernel paths, gives up the CPU if the time slice is up.
+/// Must not be called with a lock held, locks that allow it are released first.
+pub fn cond_resched() {
+    if model() != PreemptionModel::None {
+        resched_if_needed();
+    }
+}
+
+fn resched_if_needed() {
+    if need_resched() && preemptible() {
+        scheduler::schedule();
+    }
+}
+
+/// Disables preemption until dropped.
+pub struct PreemptGuard {}
+
+impl PreemptGuard {
+    pub fn new() -> Self {
+        preempt_disable();
+        Self {}
+    }
+}
+
+impl Drop for PreemptGuard {
+    fn drop(&mut self) {
+        preempt_enable();
+    }
+}
+
+/// A spin lock that disables preemption while held. A preempted holder would leave every other
+/// CPU that wants the lock spinning until it is scheduled again.
+pub struct SpinLock<T> {
+    inner: Mutex<T>,
+}
+
+pub struct SpinLockGuard<'a, T> {
+    // Dropped first, preemption is only enabled again once the lock is released.
+    guard: MutexGuard<'a, T>,
+    _preempt: PreemptGuard,
+}
+
+impl<T> SpinLock<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            inner: Mutex::new(value),
+        }
+    }
+
+    pub fn lock(&self) -> SpinLockGuard<'_, T> {
+        let preempt = PreemptGuard::new();
+        SpinLockGuard {
+            guard: self.inner.lock(),
+            _preempt: preempt,
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<SpinLockGuard<'_, T>> {
+        let preempt = PreemptGuard::new();
+        Some(SpinLockGuard {
+            guard: self.inner.try_lock()?,
+            _preempt: preempt,
+        })
+    }
+}
+
+impl<'a, T> Deref for SpinLockGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<'a, T> DerefMut for SpinLockGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
@@ -1,20 +1,39 @@
 use alloc::vec::Vec;
-use core::cell::OnceCell;
+use core::sync::atomic::{AtomicU64, Ordering};
+use kernel_shared::{constants::SyscallNumber, init_cell::InitCell};
 use spin::Mutex;
 
+use crate::{
+    arch::{get_current_cpu, register_syscall, SyscallParameters, MAX_CPU_COUNT},
+    memory::user::copy_to_user,
+};
+
+use super::credentials::Credentials;
+
+/// The kernel's own process, the parent of every process it starts.
+pub const KERNEL_PROCESS_ID: u64 = 0;
+
+// The process each CPU is running.
+static CURRENT_PROCESS: [AtomicU64; MAX_CPU_COUNT] =
+    [const { AtomicU64::new(KERNEL_PROCESS_ID) }; MAX_CPU_COUNT];
+
 #[repr(align(16))]
 #[repr(C)]
 #[derive(Debug, Clone, Copy)]
 pub struct ProcessDescriptor {
     id: u64,
+    parent_id: u64,
     control_group: u64,
+    credentials: Credentials,
 }
 
 impl ProcessDescriptor {
-    pub fn new(id: u64) -> Self {
+    pub fn new(id: u64, parent_id: u64, credentials: Credentials) -> Self {
         Self {
             control_group: 0,
             id,
+            parent_id,
+            credentials,
         }
     }
 
@@ -22,24 +41,38 @@ impl ProcessDescriptor {
     pub fn get_id(&self) -> u64 {
         self.id
     }
+    // PID of the process that started this one
+    pub fn get_parent_id(&self) -> u64 {
+        self.parent_id
+    }
     // control group, reserved, should always be 0
     pub fn get_control_group(&self) -> u64 {
         self.control_group
     }
+    // Who the process runs as
+    pub fn get_credentials(&self) -> Credentials {
+        self.credentials
+    }
 }
 
 pub struct ProcessManager {
     processes: Mutex<Vec<ProcessDescriptor>>,
-    next_process_id: u64,
+    next_process_id: AtomicU64,
 }
 
 impl ProcessManager {
     pub fn new() -> Self {
         let mut vec = Vec::new();
         vec.reserve(64);
+        // The kernel is a process like any other, so it has credentials to check and change.
+        vec.push(ProcessDescriptor::new(
+            KERNEL_PROCESS_ID,
+            KERNEL_PROCESS_ID,
+            Credentials::ROOT,
+        ));
         Self {
             processes: Mutex::new(vec),
-            next_process_id: 0,
+            next_process_id: AtomicU64::new(KERNEL_PROCESS_ID + 1),
         }
     }
 
@@ -49,39 +82,117 @@ impl ProcessManager {
         locked_processes.get(index).copied()
     }
 
-    pub fn create_process(&mut self) -> ProcessDescriptor {
+    pub fn process_count(&self) -> usize {
+        self.processes.lock().len()
+    }
+
+    /// Changes a process's credentials if update returns true, and returns what it returned.
+    pub fn update_credentials(
+        &self,
+        id: u64,
+        update: impl FnOnce(&mut Credentials) -> bool,
+    ) -> bool {
+        let mut locked_processes = self.processes.lock();
+        let index = match locked_processes.binary_search_by_key(&id, |f| f.id) {
+            Ok(index) => index,
+            Err(_) => return false,
+        };
+        let mut credentials = locked_processes[index].credentials;
+        if !update(&mut credentials) {
+            return false;
+        }
+        locked_processes[index].credentials = credentials;
+        true
+    }
+
+    /// Creates a process running as its parent does. A process the kernel starts runs as nobody,
+    /// use create_process_as to start one as anyone else.
+    pub fn create_process(&self, parent_id: u64) -> ProcessDescriptor {
+        self.create(parent_id, None)
+    }
+
+    /// Creates a process running as credentials, for the kernel to start one as root.
+    pub fn create_process_as(&self, parent_id: u64, credentials: Credentials) -> ProcessDescriptor {
+        self.create(parent_id, Some(credentials))
+    }
+
+    fn create(&self, parent_id: u64, credentials: Option<Credentials>) -> ProcessDescriptor {
         // We intentionally do not use get_process here, because we need to hold the lock the entire time.
-        let locked_processes = self.processes.get_mut();
-        let current = self.next_process_id;
+        let mut locked_processes = self.processes.lock();
+        // Only a real parent passes its credentials on, the kernel's would make everything root.
+        let inherited = match parent_id {
+            KERNEL_PROCESS_ID => None,
+            _ => locked_processes
+                .binary_search_by_key(&parent_id, |p| p.id)
+                .ok()
+                .map(|index| locked_processes[index].credentials),
+        };
+        let credentials = credentials.or(inherited).unwrap_or(Credentials::NOBODY);
+        let mut current = self.next_process_id.load(Ordering::Relaxed);
         loop {
             // this is for when we wrap.
             // Processes can come and go, but anti-collision code is forever.
-            let insert_index = locked_processes
-                .binary_search_by_key(&current, |p| p.id)
-                .err();
+            let insert_index = match locked_processes.binary_search_by_key(&current, |p| p.id) {
+                Ok(_) => {
+                    current = current.wrapping_add(1);
+                    continue;
+                }
+                Err(index) => index,
+            };
 
-            if insert_index.is_none() {
-                continue;
-            }
-
-            self.next_process_id = current.wrapping_add(1);
-            let descriptor = ProcessDescriptor::new(current);
-            locked_processes.insert(insert_index.unwrap(), descriptor);
+            self.next_process_id
+                .store(current.wrapping_add(1), Ordering::Relaxed);
+            let descriptor = ProcessDescriptor::new(current, parent_id, credentials);
+            locked_processes.insert(insert_index, descriptor);
             return descriptor;
         }
     }
 }
 
-static mut PROCESS_MANAGER: OnceCell<ProcessManager> = OnceCell::new();
+static PROCESS_MANAGER: InitCell<ProcessManager> = InitCell::new();
 
-pub fn process_manager() -> &'static mut ProcessManager {
-    unsafe {
-        match PROCESS_MANAGER.get() {
-            None => {
-                PROCESS_MANAGER.get_or_init(ProcessManager::new);
-                PROCESS_MANAGER.get_mut().unwrap()
-            }
-            Some(_) => unsafe { PROCESS_MANAGER.get_mut().unwrap() },
-        }
-    }
+pub fn process_manager() -> &'static ProcessManager {
+    PROCESS_MANAGER.get_or_init(ProcessManager::new)
+}
+
+/// The process the current CPU is running.
+pub fn current_process_id() -> u64 {
+    CURRENT_PROCESS[get_current_cpu()].load(Ordering::Relaxed)
+}
+
+/// Called when the current CPU switches to a thread of another process.
+pub(crate) fn set_current_process(id: u64) {
+    CURRENT_PROCESS[get_current_cpu()].store(id, Ordering::Relaxed);
+}
+
+pub(crate) fn init() {
+    register_syscall(
+        SyscallNumber::GetProcessId as usize,
+        "get_process_id",
+        1,
+        get_process_id_syscall,
+    );
+    register_syscall(
+        SyscallNumber::GetParentProcessId as usize,
+        "get_parent_process_id",
+        1,
+        get_parent_process_id_syscall,
+    );
+}
+
+pub(super) fn write_result(parameters: &SyscallParameters, value: u64) {
+    let _ = copy_to_user(parameters.argument() as *mut u64, &value);
+}
+
+fn get_process_id_syscall(parameters: &SyscallParameters) {
+    write_result(parameters, current_process_id());
+}
+
+fn get_parent_process_id_syscall(parameters: &SyscallParameters) {
+    let id = current_process_id();
+    // The kernel has no parent, and is its own, the same as init on other systems.
+    let parent = process_manager()
+        .get_process(id)
+        .map_or(KERNEL_PROCESS_ID, |p| p.get_parent_id());
+    write_result(parameters, parent);
 }
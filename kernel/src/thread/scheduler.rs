@@ -1,5 +1,15 @@
-use core::cell::OnceCell;
+use crate::state;
 
 pub struct Scheduler {}
 
-static mut SCHEDULER: OnceCell<Scheduler> = OnceCell::new();
+static SCHEDULER: Scheduler = Scheduler {};
+
+pub(crate) fn init() {
+    state::register(&SCHEDULER);
+}
+
+/// Gives up the CPU to the next runnable thread. Threads aren't scheduled yet, so the current one
+/// is the only candidate, and carries on with a new time slice.
+pub fn schedule() {
+    super::preempt::start_time_slice();
+}
@@ -0,0 +1,68 @@
+use alloc::string::{String, ToString};
+use devices::{
+    clock::{Clock, TimerCallback, TimerId},
+    well_known::{self, IPL},
+    Device,
+};
+use uuid::Uuid;
+
+use super::{clock_source, monotonic_nanoseconds, timer, wall_time_nanoseconds};
+
+/// The kernel's timekeeping, as a device.
+pub(super) struct ClockDevice {
+    parent: u128,
+}
+
+impl ClockDevice {
+    pub(super) fn new() -> Self {
+        Self {
+            parent: IPL.as_u128(),
+        }
+    }
+}
+
+impl Device for ClockDevice {
+    fn name(&self) -> String {
+        "CLOCK".to_string()
+    }
+
+    fn ready(&self) -> bool {
+        true
+    }
+
+    fn parent_id(&self) -> Option<u128> {
+        Some(self.parent)
+    }
+
+    fn uuid(&self) -> Uuid {
+        well_known::CLOCK
+    }
+
+    fn clock(&self) -> Option<&dyn Clock> {
+        Some(self)
+    }
+}
+
+impl Clock for ClockDevice {
+    fn monotonic_nanoseconds(&self) -> u64 {
+        monotonic_nanoseconds()
+    }
+
+    fn wall_time_nanoseconds(&self) -> Option<u64> {
+        wall_time_nanoseconds()
+    }
+
+    fn resolution_nanoseconds(&self) -> u64 {
+        // Rounded up, a 3 GHz counter still can't resolve less than a nanosecond.
+        let frequency = clock_source().frequency().max(1);
+        ((1_000_000_000 + frequency - 1) / frequency).max(1)
+    }
+
+    fn create_timer(&self, delay: u64, callback: TimerCallback, context: usize) -> TimerId {
+        timer::add_timer(delay, callback, context)
+    }
+
+    fn cancel_timer(&self, timer: TimerId) -> bool {
+        timer::cancel_timer(timer)
+    }
+}
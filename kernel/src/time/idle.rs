@@ -0,0 +1,74 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use spin::Mutex;
+
+use crate::{
+    arch::{get_current_cpu, idle_until, MAX_CPU_COUNT},
+    debug,
+};
+
+use super::{monotonic_nanoseconds, timer};
+
+// The boot CPU polls input and composes the screen from its idle loop, so it can't sleep as
+// long as the others.
+const HOUSEKEEPING_CPU: usize = 0;
+const HOUSEKEEPING_INTERVAL_NANOSECONDS: u64 = 10_000_000;
+const REPORT_INTERVAL_NANOSECONDS: u64 = 10_000_000_000;
+
+static WAKEUPS: [AtomicU64; MAX_CPU_COUNT] = [const { AtomicU64::new(0) }; MAX_CPU_COUNT];
+static NEXT_REPORT: AtomicU64 = AtomicU64::new(REPORT_INTERVAL_NANOSECONDS);
+// Wakeup counts at the last report.
+static REPORTED_WAKEUPS: Mutex<[u64; MAX_CPU_COUNT]> = Mutex::new([0; MAX_CPU_COUNT]);
+
+/// Sleeps until there is something to do: an interrupt, or the next kernel timer.
+pub(crate) fn idle() {
+    let cpu = get_current_cpu();
+    let wakeup = match (timer::next_wakeup(), cpu == HOUSEKEEPING_CPU) {
+        (wakeup, false) => wakeup,
+        (wakeup, true) => {
+            let housekeeping = monotonic_nanoseconds() + HOUSEKEEPING_INTERVAL_NANOSECONDS;
+            Some(wakeup.map_or(housekeeping, |w| w.min(housekeeping)))
+        }
+    };
+    if wakeup.map_or(true, |w| w > monotonic_nanoseconds()) {
+        idle_until(wakeup);
+    }
+    WAKEUPS[cpu].fetch_add(1, Ordering::Relaxed);
+}
+
+/// Times a CPU has woken from idle since boot.
+pub fn wakeups(cpu: usize) -> u64 {
+    WAKEUPS.get(cpu).map_or(0, |w| w.load(Ordering::Relaxed))
+}
+
+/// Called from the idle loop, logs each CPU's wakeups per second.
+pub(crate) fn report_periodically() {
+    let now = monotonic_nanoseconds();
+    let next = NEXT_REPORT.load(Ordering::Relaxed);
+    if now < next
+        || NEXT_REPORT
+            .compare_exchange(
+                next,
+                now + REPORT_INTERVAL_NANOSECONDS,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            )
+            .is_err()
+    {
+        return;
+    }
+    let seconds = REPORT_INTERVAL_NANOSECONDS / 1_000_000_000;
+    let mut reported = REPORTED_WAKEUPS.lock();
+    for (cpu, last) in reported.iter_mut().enumerate() {
+        let current = wakeups(cpu);
+        if current == 0 {
+            continue;
+        }
+        debug!(
+            "CPU {} woke {} times per second",
+            cpu,
+            (current - *last) / seconds
+        );
+        *last = current;
+    }
+}
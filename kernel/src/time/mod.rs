@@ -0,0 +1,186 @@
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use devices::get_mut_device_tree;
+use kernel_shared::fixed::mul_div;
+use spin::{Mutex, Once};
+
+use crate::{
+    arch::{in_interrupt_context, interrupts_enabled, read_wall_clock, wait_for_interrupt},
+    debug,
+};
+
+mod device;
+pub(crate) mod idle;
+pub(crate) mod timer;
+pub(crate) mod work;
+
+const MAX_CLOCK_SOURCES: usize = 8;
+// Waits shorter than this always busy wait, halting could overshoot them by a whole timer tick.
+const YIELD_THRESHOLD_US: u64 = 1000;
+
+/// A free running, monotonic counter with a known frequency.
+pub trait ClockSource: Sync {
+    fn name(&self) -> &'static str;
+
+    /// Counter frequency, in Hz.
+    fn frequency(&self) -> u64;
+
+    fn read(&self) -> u64;
+
+    /// How good this source is, the highest rated registered source is used.
+    fn rating(&self) -> u32;
+}
+
+// Sources are never unregistered, so readers don't need a lock, which keeps delays usable from
+// interrupt context.
+static CLOCK_SOURCES: [Once<&'static dyn ClockSource>; MAX_CLOCK_SOURCES] =
+    [Once::INIT; MAX_CLOCK_SOURCES];
+static REGISTRATION_LOCK: Mutex<usize> = Mutex::new(0);
+const NO_CLOCK_SOURCE: usize = usize::MAX;
+static CURRENT_CLOCK_SOURCE: AtomicUsize = AtomicUsize::new(NO_CLOCK_SOURCE);
+
+pub fn register_clock_source(source: &'static dyn ClockSource) {
+    let mut count = REGISTRATION_LOCK.lock();
+    if *count >= MAX_CLOCK_SOURCES {
+        debug!(
+            "Unable to register clock source {}, too many sources",
+            source.name()
+        );
+        return;
+    }
+    let index = *count;
+    CLOCK_SOURCES[index].call_once(|| source);
+    *count += 1;
+
+    let current = CURRENT_CLOCK_SOURCE.load(Ordering::Acquire);
+    let better = match clock_source_at(current) {
+        Some(c) => source.rating() > c.rating(),
+        None => true,
+    };
+    if better {
+        debug!(
+            "Clock source {} selected ({} Hz, rating {})",
+            source.name(),
+            source.frequency(),
+            source.rating()
+        );
+        CURRENT_CLOCK_SOURCE.store(index, Ordering::Release);
+    }
+}
+
+fn clock_source_at(index: usize) -> Option<&'static dyn ClockSource> {
+    CLOCK_SOURCES.get(index)?.get().copied()
+}
+
+/// False until the first clock source is registered, before then nothing can read the time.
+pub fn has_clock_source() -> bool {
+    clock_source_at(CURRENT_CLOCK_SOURCE.load(Ordering::Acquire)).is_some()
+}
+
+pub fn clock_source() -> &'static dyn ClockSource {
+    clock_source_at(CURRENT_CLOCK_SOURCE.load(Ordering::Acquire))
+        .expect("No clock source has been registered")
+}
+
+fn ticks_to_nanoseconds(ticks: u64, frequency: u64) -> u64 {
+    mul_div(ticks, 1_000_000_000, frequency.max(1))
+}
+
+/// Nanoseconds since the current clock source started counting.
+pub fn monotonic_nanoseconds() -> u64 {
+    let source = clock_source();
+    ticks_to_nanoseconds(source.read(), source.frequency())
+}
+
+const WALL_TIME_UNKNOWN: u64 = 0;
+// Wall time, in nanoseconds since the Unix epoch, when the monotonic clock read zero.
+static WALL_TIME_OFFSET: AtomicU64 = AtomicU64::new(WALL_TIME_UNKNOWN);
+
+/// Sets the time of day from the hardware clock, and registers the clock device.
+pub(crate) fn init() {
+    let wall_time = read_wall_clock().saturating_mul(1_000_000_000);
+    WALL_TIME_OFFSET.store(
+        wall_time.saturating_sub(monotonic_nanoseconds()),
+        Ordering::Release,
+    );
+    debug!(
+        "Wall clock is {} seconds since the epoch",
+        wall_time / 1_000_000_000
+    );
+    get_mut_device_tree().register(device::ClockDevice::new());
+    timer::init();
+}
+
+/// Nanoseconds since the Unix epoch, None until the hardware clock has been read.
+pub fn wall_time_nanoseconds() -> Option<u64> {
+    match WALL_TIME_OFFSET.load(Ordering::Acquire) {
+        WALL_TIME_UNKNOWN => None,
+        offset => Some(offset.saturating_add(monotonic_nanoseconds())),
+    }
+}
+
+/// A point in time, in monotonic nanoseconds, so it stays put if the clock source changes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Deadline(u64);
+
+impl Deadline {
+    pub fn after_us(microseconds: u64) -> Self {
+        Self(monotonic_nanoseconds().saturating_add(microseconds.saturating_mul(1000)))
+    }
+
+    pub fn after_ms(milliseconds: u64) -> Self {
+        Self::after_us(milliseconds.saturating_mul(1000))
+    }
+
+    pub fn is_expired(&self) -> bool {
+        monotonic_nanoseconds() >= self.0
+    }
+
+    /// Microseconds left until the deadline, zero once it has passed.
+    pub fn remaining_us(&self) -> u64 {
+        self.0.saturating_sub(monotonic_nanoseconds()) / 1000
+    }
+}
+
+/// Spins until the time has passed. Safe anywhere, including interrupt context.
+pub fn busy_delay_us(microseconds: u64) {
+    let deadline = Deadline::after_us(microseconds);
+    while !deadline.is_expired() {
+        core::hint::spin_loop();
+    }
+}
+
+pub fn busy_delay_ms(milliseconds: u64) {
+    busy_delay_us(milliseconds.saturating_mul(1000));
+}
+
+/// Halts until the time has passed, waking on each interrupt to check. Falls back to spinning
+/// when called from interrupt context, or with interrupts disabled, since nothing would wake us.
+pub fn yield_delay_us(microseconds: u64) {
+    if in_interrupt_context() || !interrupts_enabled() {
+        busy_delay_us(microseconds);
+        return;
+    }
+    let deadline = Deadline::after_us(microseconds);
+    while !deadline.is_expired() {
+        wait_for_interrupt();
+    }
+}
+
+pub fn yield_delay_ms(milliseconds: u64) {
+    yield_delay_us(milliseconds.saturating_mul(1000));
+}
+
+/// Waits for at least the given time, picking busy waiting or halting based on the length of
+/// the wait and the current context.
+pub fn delay_us(microseconds: u64) {
+    if microseconds < YIELD_THRESHOLD_US {
+        busy_delay_us(microseconds);
+    } else {
+        yield_delay_us(microseconds);
+    }
+}
+
+pub fn delay_ms(milliseconds: u64) {
+    delay_us(milliseconds.saturating_mul(1000));
+}
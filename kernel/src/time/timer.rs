@@ -0,0 +1,284 @@
+use core::{
+    panic::Location,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use alloc::{
+    collections::BTreeMap,
+    string::{String, ToString},
+    vec::Vec,
+};
+use devices::clock::{TimerCallback, TimerId};
+use kernel_shared::tunable::TunableValue;
+use spin::Mutex;
+
+use crate::{debug, tunables, warn};
+
+use super::monotonic_nanoseconds;
+
+const DEFAULT_SLACK_MICROSECONDS: i64 = 50;
+const MAX_SLACK_MICROSECONDS: i64 = 1_000_000;
+// How far past its slack a timer must run to count as late. Something kept the CPU from its
+// idle loop that long, a long non-preemptible section, or another timer's slow callback.
+const DEFAULT_LATE_MICROSECONDS: i64 = 1000;
+const MAX_LATE_MICROSECONDS: i64 = 10_000_000;
+const REPORT_INTERVAL_NANOSECONDS: u64 = 10_000_000_000;
+
+struct Timer {
+    id: TimerId,
+    // The source file that added the timer, its statistics are kept under it.
+    owner: &'static str,
+    // Monotonic nanoseconds.
+    deadline: u64,
+    // How late the timer may run, so it can share a wakeup with others.
+    slack: u64,
+    callback: TimerCallback,
+    context: usize,
+}
+
+// Unordered, there are only ever a handful.
+static TIMERS: Mutex<Vec<Timer>> = Mutex::new(Vec::new());
+static NEXT_TIMER_ID: AtomicU64 = AtomicU64::new(1);
+static DEFAULT_SLACK: AtomicU64 = AtomicU64::new(DEFAULT_SLACK_MICROSECONDS as u64 * 1000);
+static LATE_THRESHOLD: AtomicU64 = AtomicU64::new(DEFAULT_LATE_MICROSECONDS as u64 * 1000);
+
+/// How the timers of an owner, or every owner under a directory, have run.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TimerStatistics {
+    pub runs: u64,
+    /// Runs more than the late threshold past their slack.
+    pub late_runs: u64,
+    /// How long past their deadlines the timers ran, in total, and at worst.
+    pub total_lateness_nanoseconds: u64,
+    pub worst_lateness_nanoseconds: u64,
+    pub longest_callback_nanoseconds: u64,
+}
+
+impl TimerStatistics {
+    fn add(&mut self, other: &TimerStatistics) {
+        self.runs += other.runs;
+        self.late_runs += other.late_runs;
+        self.total_lateness_nanoseconds += other.total_lateness_nanoseconds;
+        self.worst_lateness_nanoseconds = self
+            .worst_lateness_nanoseconds
+            .max(other.worst_lateness_nanoseconds);
+        self.longest_callback_nanoseconds = self
+            .longest_callback_nanoseconds
+            .max(other.longest_callback_nanoseconds);
+    }
+}
+
+// By owner. Only the idle loop updates them, after the timers have run.
+static STATISTICS: Mutex<BTreeMap<&'static str, TimerStatistics>> = Mutex::new(BTreeMap::new());
+static NEXT_REPORT: AtomicU64 = AtomicU64::new(REPORT_INTERVAL_NANOSECONDS);
+// Late runs of each owner and directory at the last report.
+static REPORTED_LATE_RUNS: Mutex<BTreeMap<String, u64>> = Mutex::new(BTreeMap::new());
+
+pub(super) fn init() {
+    tunables::register_integer(
+        "timer_slack_us",
+        DEFAULT_SLACK_MICROSECONDS,
+        0,
+        MAX_SLACK_MICROSECONDS,
+        set_default_slack,
+    );
+    tunables::register_integer(
+        "timer.late_us",
+        DEFAULT_LATE_MICROSECONDS,
+        0,
+        MAX_LATE_MICROSECONDS,
+        set_late_threshold,
+    );
+}
+
+fn set_late_threshold(value: &TunableValue) -> bool {
+    LATE_THRESHOLD.store(value.integer as u64 * 1000, Ordering::Relaxed);
+    true
+}
+
+fn set_default_slack(value: &TunableValue) -> bool {
+    DEFAULT_SLACK.store(value.integer as u64 * 1000, Ordering::Relaxed);
+    true
+}
+
+/// How late timers may run by default, set with the `timer_slack_us` tunable.
+pub fn default_slack() -> u64 {
+    DEFAULT_SLACK.load(Ordering::Relaxed)
+}
+
+/// Calls callback with context once, no sooner than delay nanoseconds from now.
+#[track_caller]
+pub fn add_timer(delay: u64, callback: TimerCallback, context: usize) -> TimerId {
+    add_owned_timer(
+        Location::caller().file(),
+        delay,
+        default_slack(),
+        callback,
+        context,
+    )
+}
+
+/// Like add_timer, but the callback may be up to slack nanoseconds late, which lets timers that
+/// expire close together share a wakeup.
+#[track_caller]
+pub fn add_timer_with_slack(
+    delay: u64,
+    slack: u64,
+    callback: TimerCallback,
+    context: usize,
+) -> TimerId {
+    add_owned_timer(Location::caller().file(), delay, slack, callback, context)
+}
+
+/// Like add_timer_with_slack, with the timer's statistics kept under owner rather than the
+/// caller's file, for timers added on behalf of others.
+pub(crate) fn add_owned_timer(
+    owner: &'static str,
+    delay: u64,
+    slack: u64,
+    callback: TimerCallback,
+    context: usize,
+) -> TimerId {
+    let id = TimerId(NEXT_TIMER_ID.fetch_add(1, Ordering::Relaxed));
+    let deadline = monotonic_nanoseconds().saturating_add(delay);
+    TIMERS.lock().push(Timer {
+        id,
+        owner,
+        deadline,
+        slack,
+        callback,
+        context,
+    });
+    id
+}
+
+pub fn cancel_timer(id: TimerId) -> bool {
+    let mut timers = TIMERS.lock();
+    match timers.iter().position(|t| t.id == id) {
+        Some(index) => {
+            timers.swap_remove(index);
+            true
+        }
+        None => false,
+    }
+}
+
+/// When the next timers must run, as late as every pending timer's slack allows. Everything due
+/// by then runs in the same wakeup.
+pub(crate) fn next_wakeup() -> Option<u64> {
+    TIMERS
+        .lock()
+        .iter()
+        .map(|t| t.deadline.saturating_add(t.slack))
+        .min()
+}
+
+/// Called from the idle loop, runs the timers that have expired.
+pub(crate) fn run_expired() {
+    let now = monotonic_nanoseconds();
+    let mut expired: Vec<Timer> = {
+        // Another CPU is already running them.
+        let mut timers = match TIMERS.try_lock() {
+            Some(timers) => timers,
+            None => return,
+        };
+        if !timers.iter().any(|t| t.deadline <= now) {
+            return;
+        }
+        let (expired, pending) = timers.drain(..).partition(|t| t.deadline <= now);
+        *timers = pending;
+        expired
+    };
+    // Earliest first, and timers due together in the order they were added, however the list
+    // was shuffled by cancels, so runs are reproducible.
+    expired.sort_unstable_by_key(|t| (t.deadline, t.id));
+    // Without the lock, so callbacks can add timers.
+    let mut runs = Vec::with_capacity(expired.len());
+    for timer in expired {
+        let start = monotonic_nanoseconds();
+        (timer.callback)(timer.context);
+        runs.push((timer, start, monotonic_nanoseconds()));
+    }
+    record_runs(&runs);
+}
+
+fn record_runs(runs: &[(Timer, u64, u64)]) {
+    let threshold = LATE_THRESHOLD.load(Ordering::Relaxed);
+    let mut statistics = STATISTICS.lock();
+    for (timer, start, end) in runs {
+        let owner = statistics.entry(timer.owner).or_default();
+        let lateness = start.saturating_sub(timer.deadline);
+        owner.runs += 1;
+        owner.total_lateness_nanoseconds += lateness;
+        owner.longest_callback_nanoseconds = owner.longest_callback_nanoseconds.max(end - start);
+        if lateness <= timer.slack.saturating_add(threshold) {
+            owner.worst_lateness_nanoseconds = owner.worst_lateness_nanoseconds.max(lateness);
+            continue;
+        }
+        owner.late_runs += 1;
+        // Only each owner's new worst, so a stall doesn't flood the log.
+        if lateness > owner.worst_lateness_nanoseconds {
+            warn!(
+                "Timer from {} ran {} us late, {} us past its slack",
+                timer.owner,
+                lateness / 1000,
+                (lateness - timer.slack) / 1000
+            );
+        }
+        owner.worst_lateness_nanoseconds = owner.worst_lateness_nanoseconds.max(lateness);
+    }
+}
+
+/// Timer statistics by owner, the source file that added the timers, and rolled up by each
+/// directory above them, so `src/time` covers every timer added from `src/time/*`.
+pub fn timer_statistics() -> BTreeMap<String, TimerStatistics> {
+    let mut rolled_up: BTreeMap<String, TimerStatistics> = BTreeMap::new();
+    for (owner, statistics) in STATISTICS.lock().iter() {
+        rolled_up
+            .entry(owner.to_string())
+            .or_default()
+            .add(statistics);
+        for (index, _) in owner.match_indices('/') {
+            rolled_up
+                .entry(owner[..index].to_string())
+                .or_default()
+                .add(statistics);
+        }
+    }
+    rolled_up
+}
+
+/// Called from the idle loop, logs the owners and directories whose timers ran late since the
+/// last report.
+pub(crate) fn report_periodically() {
+    let now = monotonic_nanoseconds();
+    let next = NEXT_REPORT.load(Ordering::Relaxed);
+    if now < next
+        || NEXT_REPORT
+            .compare_exchange(
+                next,
+                now + REPORT_INTERVAL_NANOSECONDS,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            )
+            .is_err()
+    {
+        return;
+    }
+    let mut reported = REPORTED_LATE_RUNS.lock();
+    for (name, statistics) in timer_statistics() {
+        let last = reported.get(&name).copied().unwrap_or(0);
+        if statistics.late_runs == last {
+            continue;
+        }
+        debug!(
+            "Timers under {}: {} of {} runs late, worst {} us, longest callback {} us",
+            name,
+            statistics.late_runs - last,
+            statistics.runs,
+            statistics.worst_lateness_nanoseconds / 1000,
+            statistics.longest_callback_nanoseconds / 1000
+        );
+        reported.insert(name, statistics.late_runs);
+    }
+}
@@ -0,0 +1,146 @@
+//! Work that runs later, once or every period, built on timers. Like timers, work runs from the
+//! idle loop, and must not block.
+
+use core::{
+    panic::Location,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use alloc::collections::BTreeMap;
+use devices::clock::TimerId;
+use spin::Mutex;
+
+use super::{
+    monotonic_nanoseconds,
+    timer::{add_owned_timer, cancel_timer, default_slack},
+};
+
+/// Called with the context it was scheduled with.
+pub type WorkFunction = fn(context: usize);
+
+/// Identifies scheduled work, to cancel it or read its statistics.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct WorkId(u64);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WorkStatistics {
+    pub runs: u64,
+    pub total_nanoseconds: u64,
+    pub longest_nanoseconds: u64,
+    /// The furthest past its deadline the work has started.
+    pub latest_start_nanoseconds: u64,
+    /// Periods skipped because the work ran so late the next was already due.
+    pub missed_periods: u64,
+}
+
+struct Work {
+    // The source file that scheduled the work, its timers' statistics are kept under it.
+    owner: &'static str,
+    function: WorkFunction,
+    context: usize,
+    period: Option<u64>,
+    // Monotonic nanoseconds.
+    deadline: u64,
+    timer: TimerId,
+    statistics: WorkStatistics,
+}
+
+static WORK: Mutex<BTreeMap<WorkId, Work>> = Mutex::new(BTreeMap::new());
+static NEXT_WORK_ID: AtomicU64 = AtomicU64::new(1);
+
+#[track_caller]
+fn schedule(delay: u64, period: Option<u64>, function: WorkFunction, context: usize) -> WorkId {
+    let owner = Location::caller().file();
+    let id = WorkId(NEXT_WORK_ID.fetch_add(1, Ordering::Relaxed));
+    let deadline = monotonic_nanoseconds().saturating_add(delay);
+    // Locked first, so the timer can't run before the work is there to find.
+    let mut work = WORK.lock();
+    work.insert(
+        id,
+        Work {
+            owner,
+            function,
+            context,
+            period,
+            deadline,
+            timer: add_owned_timer(owner, delay, default_slack(), run, id.0 as usize),
+            statistics: WorkStatistics::default(),
+        },
+    );
+    id
+}
+
+/// Calls function with context once, no sooner than delay nanoseconds from now.
+#[track_caller]
+pub fn schedule_delayed_work(delay: u64, function: WorkFunction, context: usize) -> WorkId {
+    schedule(delay, None, function, context)
+}
+
+/// Calls function with context every period nanoseconds, starting one period from now.
+/// Deadlines stay on multiples of the period from the first, however late each run is, so the
+/// work doesn't drift.
+#[track_caller]
+pub fn schedule_periodic_work(period: u64, function: WorkFunction, context: usize) -> WorkId {
+    schedule(period, Some(period.max(1)), function, context)
+}
+
+/// Returns false if the work already ran, and won't again, or never existed. Work that's
+/// running when cancelled finishes that run.
+pub fn cancel_work(id: WorkId) -> bool {
+    match WORK.lock().remove(&id) {
+        Some(work) => {
+            cancel_timer(work.timer);
+            true
+        }
+        None => false,
+    }
+}
+
+/// How the work has run so far, None once one off work has run.
+pub fn work_statistics(id: WorkId) -> Option<WorkStatistics> {
+    WORK.lock().get(&id).map(|work| work.statistics)
+}
+
+// The timer callback, context is the work's id.
+fn run(context: usize) {
+    let id = WorkId(context as u64);
+    let (function, work_context, deadline) = match WORK.lock().get(&id) {
+        Some(work) => (work.function, work.context, work.deadline),
+        None => return,
+    };
+    let start = monotonic_nanoseconds();
+    // Without the lock, so the work can schedule and cancel work, itself included.
+    function(work_context);
+    let end = monotonic_nanoseconds();
+
+    let mut all_work = WORK.lock();
+    // Cancelled while it ran.
+    let work = match all_work.get_mut(&id) {
+        Some(work) => work,
+        None => return,
+    };
+    let statistics = &mut work.statistics;
+    statistics.runs += 1;
+    statistics.total_nanoseconds += end - start;
+    statistics.longest_nanoseconds = statistics.longest_nanoseconds.max(end - start);
+    statistics.latest_start_nanoseconds = statistics
+        .latest_start_nanoseconds
+        .max(start.saturating_sub(deadline));
+    let period = match work.period {
+        Some(period) => period,
+        None => {
+            all_work.remove(&id);
+            return;
+        }
+    };
+    let missed = end.saturating_sub(deadline) / period;
+    statistics.missed_periods += missed;
+    work.deadline = deadline + (missed + 1) * period;
+    work.timer = add_owned_timer(
+        work.owner,
+        work.deadline.saturating_sub(end),
+        default_slack(),
+        run,
+        context,
+    );
+}
@@ -0,0 +1,310 @@
+//! Tunables, named settings that subsystems register instead of hard coding, so they can be
+//! changed while the kernel runs. A tunable starts at the value of the same name on the command
+//! line, if there is one, and its default otherwise. Userspace lists, reads and, as root, changes
+//! them through SyscallNumber::ListTunables, GetTunable and SetTunable. There's no procfs or
+//! debug shell to expose them through yet.
+//!
+//! Every tunable has a callback, called with each new value before it's taken, which can refuse
+//! it. Subsystems keep the value they use in an atomic the callback stores to, so hot paths never
+//! take the registry lock.
+
+use alloc::{collections::BTreeMap, vec::Vec};
+use kernel_shared::{
+    audit::{AUDIT_DENIED, AUDIT_FAILED, AUDIT_SET_TUNABLE, AUDIT_SUCCEEDED},
+    constants::SyscallNumber,
+    tunable::{
+        TunableDescription, TunableListRequest, TunableRequest, TunableValue, TUNABLE_INVALID,
+        TUNABLE_KIND_BOOL, TUNABLE_KIND_INTEGER, TUNABLE_KIND_STRING, TUNABLE_NAME_LENGTH,
+        TUNABLE_NOT_FOUND, TUNABLE_NOT_PERMITTED, TUNABLE_OK, TUNABLE_STRING_LENGTH,
+    },
+};
+use spin::Mutex;
+
+use crate::{
+    arch::{register_syscall, SyscallParameters},
+    audit, cmdline, debug,
+    memory::user::{copy_from_user, copy_slice_to_user, copy_to_user},
+    thread::credentials::is_root,
+    warn,
+};
+
+/// Called with a tunable's new value, returns whether to take it. Called with the registry
+/// locked, so it must not use tunables itself.
+pub type ChangeCallback = fn(&TunableValue) -> bool;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TunableError {
+    NotFound,
+    /// The wrong kind, out of range, or refused by the callback.
+    Invalid,
+}
+
+struct Tunable {
+    value: TunableValue,
+    minimum: i64,
+    maximum: i64,
+    on_change: ChangeCallback,
+}
+
+impl Tunable {
+    fn accepts(&self, value: &TunableValue) -> bool {
+        if value.kind != self.value.kind {
+            return false;
+        }
+        match value.kind {
+            TUNABLE_KIND_INTEGER | TUNABLE_KIND_BOOL => {
+                (self.minimum..=self.maximum).contains(&value.integer)
+            }
+            _ => true,
+        }
+    }
+}
+
+static TUNABLES: Mutex<BTreeMap<&'static str, Tunable>> = Mutex::new(BTreeMap::new());
+
+pub(crate) fn init() {
+    register_syscall(
+        SyscallNumber::ListTunables as usize,
+        "list_tunables",
+        1,
+        list_tunables_syscall,
+    );
+    register_syscall(
+        SyscallNumber::GetTunable as usize,
+        "get_tunable",
+        1,
+        get_tunable_syscall,
+    );
+    register_syscall(
+        SyscallNumber::SetTunable as usize,
+        "set_tunable",
+        1,
+        set_tunable_syscall,
+    );
+}
+
+pub fn integer_value(integer: i64) -> TunableValue {
+    TunableValue {
+        kind: TUNABLE_KIND_INTEGER,
+        integer,
+        ..Default::default()
+    }
+}
+
+pub fn bool_value(value: bool) -> TunableValue {
+    TunableValue {
+        kind: TUNABLE_KIND_BOOL,
+        integer: value as i64,
+        ..Default::default()
+    }
+}
+
+/// A string value, or None if it's too long to be one.
+pub fn string_value(string: &str) -> Option<TunableValue> {
+    if string.len() > TUNABLE_STRING_LENGTH {
+        return None;
+    }
+    let mut value = TunableValue {
+        kind: TUNABLE_KIND_STRING,
+        ..Default::default()
+    };
+    value.string[..string.len()].copy_from_slice(string.as_bytes());
+    Some(value)
+}
+
+/// Registers an integer tunable between minimum and maximum, inclusive, and returns its initial
+/// value, which on_change has already been called with.
+pub fn register_integer(
+    name: &'static str,
+    default: i64,
+    minimum: i64,
+    maximum: i64,
+    on_change: ChangeCallback,
+) -> i64 {
+    let from_command_line = cmdline::get(name).map(|s| s.parse::<i64>().ok().map(integer_value));
+    register(
+        name,
+        integer_value(default),
+        from_command_line,
+        minimum,
+        maximum,
+        on_change,
+    )
+    .integer
+}
+
+/// Registers a bool tunable, and returns its initial value, which on_change has already been
+/// called with.
+pub fn register_bool(name: &'static str, default: bool, on_change: ChangeCallback) -> bool {
+    let from_command_line = cmdline::get(name).map(|_| Some(bool_value(cmdline::flag(name))));
+    register(
+        name,
+        bool_value(default),
+        from_command_line,
+        0,
+        1,
+        on_change,
+    )
+    .integer
+        != 0
+}
+
+/// Registers a string tunable, and returns its initial value, which on_change has already been
+/// called with.
+pub fn register_string(
+    name: &'static str,
+    default: &'static str,
+    on_change: ChangeCallback,
+) -> TunableValue {
+    let default = string_value(default).expect("Tunable default is too long");
+    let from_command_line = cmdline::get(name).map(string_value);
+    register(name, default, from_command_line, 0, 0, on_change)
+}
+
+// from_command_line is None when the command line doesn't set the tunable, and Some(None) when
+// it sets it to something that doesn't parse.
+fn register(
+    name: &'static str,
+    default: TunableValue,
+    from_command_line: Option<Option<TunableValue>>,
+    minimum: i64,
+    maximum: i64,
+    on_change: ChangeCallback,
+) -> TunableValue {
+    assert!(
+        name.len() <= TUNABLE_NAME_LENGTH,
+        "Tunable name {} is too long",
+        name
+    );
+    let mut tunable = Tunable {
+        value: default,
+        minimum,
+        maximum,
+        on_change,
+    };
+    let mut tunables = TUNABLES.lock();
+    assert!(
+        !tunables.contains_key(name),
+        "Tunable {} registered twice",
+        name
+    );
+    match from_command_line {
+        Some(Some(value)) if tunable.accepts(&value) && on_change(&value) => tunable.value = value,
+        Some(_) => {
+            warn!("Ignoring invalid command line value for {}", name);
+            on_change(&default);
+        }
+        None => {
+            on_change(&default);
+        }
+    }
+    debug!("Registered tunable {}", name);
+    let value = tunable.value;
+    tunables.insert(name, tunable);
+    value
+}
+
+pub fn get(name: &str) -> Option<TunableValue> {
+    TUNABLES.lock().get(name).map(|t| t.value)
+}
+
+pub fn set(name: &str, value: &TunableValue) -> Result<(), TunableError> {
+    let mut tunables = TUNABLES.lock();
+    let tunable = match tunables.get_mut(name) {
+        Some(tunable) => tunable,
+        None => return Err(TunableError::NotFound),
+    };
+    if !tunable.accepts(value) || !(tunable.on_change)(value) {
+        return Err(TunableError::Invalid);
+    }
+    tunable.value = *value;
+    Ok(())
+}
+
+fn list_tunables_syscall(parameters: &SyscallParameters) {
+    let pointer = parameters.argument() as *mut TunableListRequest;
+    let mut request = match copy_from_user(pointer) {
+        Ok(request) => request,
+        Err(_) => return,
+    };
+    // Built under the lock, and copied out after it's dropped, a fault copying to the user
+    // mustn't happen with it held.
+    let (count, descriptions) = {
+        let tunables = TUNABLES.lock();
+        let descriptions: Vec<TunableDescription> = match request.entries.is_null() {
+            true => Vec::new(),
+            false => tunables
+                .iter()
+                .take(request.capacity as usize)
+                .map(|(name, tunable)| {
+                    let mut description = TunableDescription {
+                        value: tunable.value,
+                        minimum: tunable.minimum,
+                        maximum: tunable.maximum,
+                        ..Default::default()
+                    };
+                    description.name[..name.len()].copy_from_slice(name.as_bytes());
+                    description
+                })
+                .collect(),
+        };
+        (tunables.len(), descriptions)
+    };
+    if !request.entries.is_null() {
+        // A bad entries pointer gets the count, but no entries.
+        let _ = copy_slice_to_user(request.entries, &descriptions);
+    }
+    request.count = count as u64;
+    let _ = copy_to_user(pointer, &request);
+}
+
+fn get_tunable_syscall(parameters: &SyscallParameters) {
+    let pointer = parameters.argument() as *mut TunableRequest;
+    let mut request = match copy_from_user(pointer) {
+        Ok(request) => request,
+        Err(_) => return,
+    };
+    match get(request.name()) {
+        Some(value) => {
+            request.value = value;
+            request.status = TUNABLE_OK;
+        }
+        None => request.status = TUNABLE_NOT_FOUND,
+    }
+    let _ = copy_to_user(pointer, &request);
+}
+
+fn set_tunable_syscall(parameters: &SyscallParameters) {
+    let pointer = parameters.argument() as *mut TunableRequest;
+    let mut request = match copy_from_user(pointer) {
+        Ok(request) => request,
+        Err(_) => return,
+    };
+    set_tunable(&mut request);
+    let _ = copy_to_user(pointer, &request);
+}
+
+fn set_tunable(request: &mut TunableRequest) {
+    // The audit log's subject is a u128, which holds the first 16 bytes of the name.
+    let mut subject = [0u8; 16];
+    subject.copy_from_slice(&request.name[..16]);
+    let subject = u128::from_le_bytes(subject);
+    if !is_root() {
+        audit::record(AUDIT_SET_TUNABLE, AUDIT_DENIED, subject);
+        request.status = TUNABLE_NOT_PERMITTED;
+        return;
+    }
+    request.status = match set(request.name(), &request.value) {
+        Ok(()) => {
+            audit::record(AUDIT_SET_TUNABLE, AUDIT_SUCCEEDED, subject);
+            TUNABLE_OK
+        }
+        Err(err) => {
+            audit::record(AUDIT_SET_TUNABLE, AUDIT_FAILED, subject);
+            match err {
+                TunableError::NotFound => TUNABLE_NOT_FOUND,
+                TunableError::Invalid => TUNABLE_INVALID,
+            }
+        }
+    };
+}
@@ -0,0 +1,258 @@
+//! The system call ABI, rendered as text and compared against abi.golden, so a change that would
+//! break userspace binaries built against an older kernel_shared fails the tests rather than the
+//! binaries. When a change is intended, rerun the tests with UPDATE_ABI_GOLDEN=1 set to rewrite
+//! the golden file, and commit it with the change.
+//!
+//! Every line is one item: `syscall <name> <number>`, `const <name> <value>`, `struct <name>
+//! size <bytes> align <bytes>`, or `field <struct>.<name> offset <bytes> size <bytes>`.
+
+extern crate std;
+
+use core::fmt::Write;
+use std::string::String;
+
+use crate::{
+    audit::*,
+    constants::SyscallNumber,
+    device::*,
+    input::InputEvent,
+    memory::{MemoryInfo, SystemInfo, SYSTEM_INFO_LOAD_SHIFT},
+    process::{SetIdRequest, NOBODY_ID, ROOT_ID, SET_ID_NOT_PERMITTED, SET_ID_OK},
+    random::RandomRequest,
+    syscall::*,
+    tunable::*,
+};
+
+const GOLDEN: &str = include_str!("../abi.golden");
+
+// Names every system call. The match is exhaustive, so adding one without naming it here fails
+// to compile.
+fn syscall_name(number: SyscallNumber) -> &'static str {
+    match number {
+        SyscallNumber::Invalid => "Invalid",
+        SyscallNumber::ContextSwitch => "ContextSwitch",
+        SyscallNumber::AllocatePage => "AllocatePage",
+        SyscallNumber::AllocatePageRange => "AllocatePageRange",
+        SyscallNumber::SystemInfo => "SystemInfo",
+        SyscallNumber::ListSyscalls => "ListSyscalls",
+        SyscallNumber::GetProcessId => "GetProcessId",
+        SyscallNumber::GetParentProcessId => "GetParentProcessId",
+        SyscallNumber::ListDevices => "ListDevices",
+        SyscallNumber::CallDevice => "CallDevice",
+        SyscallNumber::GetUserId => "GetUserId",
+        SyscallNumber::GetEffectiveUserId => "GetEffectiveUserId",
+        SyscallNumber::GetGroupId => "GetGroupId",
+        SyscallNumber::GetEffectiveGroupId => "GetEffectiveGroupId",
+        SyscallNumber::SetUserId => "SetUserId",
+        SyscallNumber::SetGroupId => "SetGroupId",
+        SyscallNumber::ReadAuditLog => "ReadAuditLog",
+        SyscallNumber::GetRandom => "GetRandom",
+        SyscallNumber::ListTunables => "ListTunables",
+        SyscallNumber::GetTunable => "GetTunable",
+        SyscallNumber::SetTunable => "SetTunable",
+    }
+}
+
+const SYSCALLS: [SyscallNumber; 21] = [
+    SyscallNumber::Invalid,
+    SyscallNumber::ContextSwitch,
+    SyscallNumber::AllocatePage,
+    SyscallNumber::AllocatePageRange,
+    SyscallNumber::SystemInfo,
+    SyscallNumber::ListSyscalls,
+    SyscallNumber::GetProcessId,
+    SyscallNumber::GetParentProcessId,
+    SyscallNumber::ListDevices,
+    SyscallNumber::CallDevice,
+    SyscallNumber::GetUserId,
+    SyscallNumber::GetEffectiveUserId,
+    SyscallNumber::GetGroupId,
+    SyscallNumber::GetEffectiveGroupId,
+    SyscallNumber::SetUserId,
+    SyscallNumber::SetGroupId,
+    SyscallNumber::ReadAuditLog,
+    SyscallNumber::GetRandom,
+    SyscallNumber::ListTunables,
+    SyscallNumber::GetTunable,
+    SyscallNumber::SetTunable,
+];
+
+// SYSCALLS is kept by hand, so check it lists every number in order, up to the last one. Point
+// this at the new last variant when adding a system call, or it drops out of the golden file.
+const _: () = {
+    assert!(SYSCALLS.len() == SyscallNumber::SetTunable as usize + 1);
+    let mut index = 0;
+    while index < SYSCALLS.len() {
+        assert!(SYSCALLS[index] as usize == index);
+        index += 1;
+    }
+};
+
+macro_rules! render_struct {
+    ($out: expr, $type: ty, [$($field: ident),* $(,)?]) => {{
+        let _ = writeln!(
+            $out,
+            "struct {} size {} align {}",
+            stringify!($type),
+            core::mem::size_of::<$type>(),
+            core::mem::align_of::<$type>()
+        );
+        let value = core::mem::MaybeUninit::<$type>::uninit();
+        let base = value.as_ptr();
+        $(
+            let field = unsafe { core::ptr::addr_of!((*base).$field) };
+            let _ = writeln!(
+                $out,
+                "field {}.{} offset {} size {}",
+                stringify!($type),
+                stringify!($field),
+                field as usize - base as usize,
+                size_of_pointee(field)
+            );
+        )*
+    }};
+}
+
+macro_rules! render_constants {
+    ($out: expr, [$($constant: ident),* $(,)?]) => {
+        $(let _ = writeln!($out, "const {} {:#x}", stringify!($constant), $constant);)*
+    };
+}
+
+fn size_of_pointee<T>(_: *const T) -> usize {
+    core::mem::size_of::<T>()
+}
+
+fn render() -> String {
+    let mut out = String::new();
+    for number in SYSCALLS {
+        let _ = writeln!(out, "syscall {} {}", syscall_name(number), number as usize);
+    }
+    render_constants!(
+        out,
+        [
+            NATIVE_PERSONALITY,
+            DEFAULT_SYSCALL,
+            SYSCALL_NAME_LENGTH,
+            SYSTEM_INFO_LOAD_SHIFT,
+            DEVICE_NAME_LENGTH,
+            DEVICE_CLASS_LENGTH,
+            DEVICE_CALL_OK,
+            DEVICE_CALL_NOT_IMPLEMENTED,
+            DEVICE_CALL_MALFUNCTION,
+            DEVICE_CALL_NO_DEVICE,
+            DEVICE_CALL_NOT_PERMITTED,
+            DEVICE_CALL_NATIVE_ERROR,
+            ROOT_ID,
+            NOBODY_ID,
+            SET_ID_OK,
+            SET_ID_NOT_PERMITTED,
+            AUDIT_SET_USER_ID,
+            AUDIT_SET_GROUP_ID,
+            AUDIT_DEVICE_CALL,
+            AUDIT_SET_TUNABLE,
+            AUDIT_SUCCEEDED,
+            AUDIT_DENIED,
+            AUDIT_FAILED,
+            AUDIT_READ_OK,
+            AUDIT_READ_NOT_PERMITTED,
+            TUNABLE_NAME_LENGTH,
+            TUNABLE_STRING_LENGTH,
+            TUNABLE_KIND_INTEGER,
+            TUNABLE_KIND_BOOL,
+            TUNABLE_KIND_STRING,
+            TUNABLE_OK,
+            TUNABLE_NOT_FOUND,
+            TUNABLE_NOT_PERMITTED,
+            TUNABLE_INVALID,
+        ]
+    );
+    render_struct!(
+        out,
+        SyscallDescription,
+        [personality, number, arguments, name]
+    );
+    render_struct!(out, SyscallListRequest, [entries, capacity, count]);
+    render_struct!(
+        out,
+        MemoryInfo,
+        [
+            total_bytes,
+            free_bytes,
+            cached_bytes,
+            kernel_heap_bytes,
+            kernel_heap_used_bytes,
+        ]
+    );
+    render_struct!(
+        out,
+        SystemInfo,
+        [uptime_nanoseconds, loads, processes, memory]
+    );
+    render_struct!(out, InputEvent, [timestamp, kind, code, value]);
+    render_struct!(
+        out,
+        DeviceDescription,
+        [id, uuid, parent_id, ready, name, class]
+    );
+    render_struct!(out, DeviceListRequest, [entries, capacity, count]);
+    render_struct!(
+        out,
+        DeviceCallRequest,
+        [
+            device_id,
+            function,
+            arguments,
+            argument_count,
+            result,
+            capacity,
+            length,
+            status,
+        ]
+    );
+    render_struct!(out, SetIdRequest, [id, status]);
+    render_struct!(
+        out,
+        AuditRecord,
+        [sequence, timestamp, process_id, user_id, event, outcome, reserved, subject,]
+    );
+    render_struct!(
+        out,
+        AuditReadRequest,
+        [entries, capacity, after, count, status]
+    );
+    render_struct!(out, RandomRequest, [buffer, length]);
+    render_struct!(out, TunableValue, [kind, integer, string]);
+    render_struct!(out, TunableDescription, [name, value, minimum, maximum]);
+    render_struct!(out, TunableListRequest, [entries, capacity, count]);
+    render_struct!(out, TunableRequest, [name, value, status]);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn abi_matches_golden_file() {
+        let rendered = render();
+        if std::env::var_os("UPDATE_ABI_GOLDEN").is_some() {
+            let path = concat!(env!("CARGO_MANIFEST_DIR"), "/abi.golden");
+            std::fs::write(path, &rendered).unwrap();
+            return;
+        }
+        for (line, (expected, actual)) in GOLDEN.lines().zip(rendered.lines()).enumerate() {
+            assert_eq!(
+                expected,
+                actual,
+                "the ABI changed at abi.golden line {}, see abi.rs if that's intended",
+                line + 1
+            );
+        }
+        assert_eq!(
+            GOLDEN.lines().count(),
+            rendered.lines().count(),
+            "items were added to or removed from the ABI, see abi.rs if that's intended"
+        );
+    }
+}
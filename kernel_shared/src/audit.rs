@@ -0,0 +1,51 @@
+//! Records of security relevant operations, kept by the kernel and read by root with
+//! SyscallNumber::ReadAuditLog.
+
+pub const AUDIT_SET_USER_ID: u32 = 1;
+pub const AUDIT_SET_GROUP_ID: u32 = 2;
+/// A device function called from userspace, the subject is the device's id.
+pub const AUDIT_DEVICE_CALL: u32 = 3;
+/// A tunable changed, the subject is the first 16 bytes of its name.
+pub const AUDIT_SET_TUNABLE: u32 = 4;
+
+pub const AUDIT_SUCCEEDED: u32 = 0;
+/// The caller wasn't allowed to.
+pub const AUDIT_DENIED: u32 = 1;
+/// Allowed, but it didn't work.
+pub const AUDIT_FAILED: u32 = 2;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct AuditRecord {
+    /// Counts up from 1, a gap means records were overwritten before they were read.
+    pub sequence: u64,
+    /// Monotonic nanoseconds.
+    pub timestamp: u64,
+    pub process_id: u64,
+    /// The caller's effective user.
+    pub user_id: u32,
+    /// One of the AUDIT_ events.
+    pub event: u32,
+    /// One of AUDIT_SUCCEEDED, AUDIT_DENIED, or AUDIT_FAILED.
+    pub outcome: u32,
+    pub reserved: u32,
+    /// What the operation was on, like the id being set, little endian.
+    pub subject: [u8; 16],
+}
+
+pub const AUDIT_READ_OK: u64 = 0;
+/// Only root may read the audit log.
+pub const AUDIT_READ_NOT_PERMITTED: u64 = 1;
+
+/// The parameter to SyscallNumber::ReadAuditLog. Up to capacity of the oldest records with a
+/// sequence after `after` are written to entries, and count is set to how many were.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct AuditReadRequest {
+    pub entries: *mut AuditRecord,
+    pub capacity: u64,
+    pub after: u64,
+    pub count: u64,
+    /// One of the AUDIT_READ_ statuses.
+    pub status: u64,
+}
@@ -0,0 +1,243 @@
+use core::{
+    cell::UnsafeCell,
+    mem::MaybeUninit,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+
+/// A slot is free for lap L when its stamp is 2L, and holds a value written during lap L when
+/// its stamp is 2L + 1. Starting every stamp at 0 means a channel can be zero initialized.
+struct Slot<T> {
+    stamp: AtomicUsize,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+impl<T> Slot<T> {
+    const fn empty() -> Self {
+        Self {
+            stamp: AtomicUsize::new(0),
+            value: UnsafeCell::new(MaybeUninit::uninit()),
+        }
+    }
+}
+
+/// Bounded, lock free, multi producer single consumer channel.
+///
+/// Sending never blocks or allocates, so it is safe from interrupt context. Only one
+/// [`Receiver`] can exist at a time, claim it with [`Channel::receiver`].
+pub struct Channel<T, const N: usize> {
+    slots: [Slot<T>; N],
+    head: AtomicUsize,
+    tail: AtomicUsize,
+    receiver_claimed: AtomicBool,
+}
+
+unsafe impl<T: Send, const N: usize> Send for Channel<T, N> {}
+unsafe impl<T: Send, const N: usize> Sync for Channel<T, N> {}
+
+impl<T, const N: usize> Channel<T, N> {
+    pub const fn new() -> Self {
+        Self {
+            slots: [const { Slot::empty() }; N],
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            receiver_claimed: AtomicBool::new(false),
+        }
+    }
+
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+
+    /// Number of queued values. Only a snapshot, producers may be racing with it.
+    pub fn len(&self) -> usize {
+        let tail = self.tail.load(Ordering::Acquire);
+        let head = self.head.load(Ordering::Acquire);
+        tail.saturating_sub(head).min(N)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Queues a value, handing it back if the channel is full.
+    pub fn try_send(&self, value: T) -> Result<(), T> {
+        let mut position = self.tail.load(Ordering::Relaxed);
+        loop {
+            let slot = &self.slots[position % N];
+            let lap = position / N;
+            let stamp = slot.stamp.load(Ordering::Acquire);
+            if stamp == lap * 2 {
+                match self.tail.compare_exchange_weak(
+                    position,
+                    position + 1,
+                    Ordering::AcqRel,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => {
+                        unsafe { (*slot.value.get()).write(value) };
+                        slot.stamp.store(lap * 2 + 1, Ordering::Release);
+                        return Ok(());
+                    }
+                    Err(current) => position = current,
+                }
+            } else if stamp < lap * 2 {
+                // Still holding a value from the previous lap, the consumer hasn't caught up.
+                return Err(value);
+            } else {
+                // Another producer claimed this slot, try again from the new tail.
+                position = self.tail.load(Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Claims the consuming end of the channel, returns None if it is already claimed.
+    pub fn receiver(&self) -> Option<Receiver<'_, T, N>> {
+        match self.receiver_claimed.compare_exchange(
+            false,
+            true,
+            Ordering::AcqRel,
+            Ordering::Relaxed,
+        ) {
+            Ok(_) => Some(Receiver { channel: self }),
+            Err(_) => None,
+        }
+    }
+
+    // Safety: the caller must be the only consumer.
+    unsafe fn pop(&self) -> Option<T> {
+        let position = self.head.load(Ordering::Relaxed);
+        let slot = &self.slots[position % N];
+        let lap = position / N;
+        if slot.stamp.load(Ordering::Acquire) != lap * 2 + 1 {
+            return None;
+        }
+        let value = (*slot.value.get()).assume_init_read();
+        slot.stamp.store((lap + 1) * 2, Ordering::Release);
+        self.head.store(position + 1, Ordering::Release);
+        Some(value)
+    }
+}
+
+impl<T, const N: usize> Default for Channel<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T, const N: usize> Drop for Channel<T, N> {
+    fn drop(&mut self) {
+        while unsafe { self.pop() }.is_some() {}
+    }
+}
+
+/// The consuming end of a [`Channel`], the channel can be received from again once dropped.
+pub struct Receiver<'a, T, const N: usize> {
+    channel: &'a Channel<T, N>,
+}
+
+impl<'a, T, const N: usize> Receiver<'a, T, N> {
+    pub fn try_recv(&mut self) -> Option<T> {
+        unsafe { self.channel.pop() }
+    }
+
+    /// Receives a value, calling wait between attempts until one arrives. The kernel passes
+    /// something that halts or yields, to avoid burning the CPU.
+    pub fn recv_with(&mut self, mut wait: impl FnMut()) -> T {
+        loop {
+            if let Some(value) = self.try_recv() {
+                return value;
+            }
+            wait();
+        }
+    }
+
+    /// Drains the values that are currently queued.
+    pub fn drain(&mut self) -> Drain<'_, 'a, T, N> {
+        Drain { receiver: self }
+    }
+}
+
+pub struct Drain<'r, 'a, T, const N: usize> {
+    receiver: &'r mut Receiver<'a, T, N>,
+}
+
+impl<'r, 'a, T, const N: usize> Iterator for Drain<'r, 'a, T, N> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.receiver.try_recv()
+    }
+}
+
+impl<'a, T, const N: usize> Drop for Receiver<'a, T, N> {
+    fn drop(&mut self) {
+        self.channel
+            .receiver_claimed
+            .store(false, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_channel_receives_nothing() {
+        let channel: Channel<u32, 4> = Channel::new();
+        let mut receiver = channel.receiver().unwrap();
+        assert!(channel.is_empty());
+        assert_eq!(receiver.try_recv(), None);
+    }
+
+    #[test]
+    fn full_channel_hands_the_value_back() {
+        let channel: Channel<u32, 2> = Channel::new();
+        assert_eq!(channel.try_send(1), Ok(()));
+        assert_eq!(channel.try_send(2), Ok(()));
+        assert_eq!(channel.try_send(3), Err(3));
+        assert_eq!(channel.len(), 2);
+    }
+
+    #[test]
+    fn wraps_around_in_order() {
+        let channel: Channel<usize, 3> = Channel::new();
+        let mut receiver = channel.receiver().unwrap();
+        for lap in 0..4 {
+            for i in 0..3 {
+                assert_eq!(channel.try_send(lap * 3 + i), Ok(()));
+            }
+            assert_eq!(channel.try_send(usize::MAX), Err(usize::MAX));
+            for i in 0..3 {
+                assert_eq!(receiver.try_recv(), Some(lap * 3 + i));
+            }
+            assert_eq!(receiver.try_recv(), None);
+        }
+    }
+
+    #[test]
+    fn only_one_receiver_at_a_time() {
+        let channel: Channel<u32, 1> = Channel::new();
+        let receiver = channel.receiver();
+        assert!(receiver.is_some());
+        assert!(channel.receiver().is_none());
+        drop(receiver);
+        assert!(channel.receiver().is_some());
+    }
+
+    #[test]
+    fn drops_values_left_queued() {
+        struct Counted<'a>(&'a AtomicUsize);
+        impl Drop for Counted<'_> {
+            fn drop(&mut self) {
+                self.0.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        let dropped = AtomicUsize::new(0);
+        {
+            let channel: Channel<Counted, 2> = Channel::new();
+            assert!(channel.try_send(Counted(&dropped)).is_ok());
+            assert_eq!(dropped.load(Ordering::Relaxed), 0);
+        }
+        assert_eq!(dropped.load(Ordering::Relaxed), 1);
+    }
+}
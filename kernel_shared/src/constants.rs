@@ -9,4 +9,21 @@ pub enum SyscallNumber {
     ContextSwitch,
     AllocatePage,
     AllocatePageRange,
+    SystemInfo,
+    ListSyscalls,
+    GetProcessId,
+    GetParentProcessId,
+    ListDevices,
+    CallDevice,
+    GetUserId,
+    GetEffectiveUserId,
+    GetGroupId,
+    GetEffectiveGroupId,
+    SetUserId,
+    SetGroupId,
+    ReadAuditLog,
+    GetRandom,
+    ListTunables,
+    GetTunable,
+    SetTunable,
 }
@@ -0,0 +1,242 @@
+use crate::serialization::{Decode, Decoder, Encode, Encoder, Result};
+
+pub const DEVICE_NAME_LENGTH: usize = 32;
+pub const DEVICE_CLASS_LENGTH: usize = 16;
+
+/// One device in the tree, as listed by SyscallNumber::ListDevices. Ids are little endian.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct DeviceDescription {
+    pub id: [u8; 16],
+    pub uuid: [u8; 16],
+    /// The parent's id, all zeros for the root.
+    pub parent_id: [u8; 16],
+    pub ready: u64,
+    /// UTF-8, padded with zeros.
+    pub name: [u8; DEVICE_NAME_LENGTH],
+    /// UTF-8, padded with zeros, empty for devices without a class.
+    pub class: [u8; DEVICE_CLASS_LENGTH],
+}
+
+impl Default for DeviceDescription {
+    fn default() -> Self {
+        Self {
+            id: [0; 16],
+            uuid: [0; 16],
+            parent_id: [0; 16],
+            ready: 0,
+            name: [0; DEVICE_NAME_LENGTH],
+            class: [0; DEVICE_CLASS_LENGTH],
+        }
+    }
+}
+
+/// The parameter to SyscallNumber::ListDevices. Up to capacity descriptions are written to
+/// entries, and count is set to how many devices there are, so a caller can size its buffer.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct DeviceListRequest {
+    pub entries: *mut DeviceDescription,
+    pub capacity: u64,
+    pub count: u64,
+}
+
+pub const DEVICE_CALL_OK: u64 = 0;
+pub const DEVICE_CALL_NOT_IMPLEMENTED: u64 = 1;
+pub const DEVICE_CALL_MALFUNCTION: u64 = 2;
+pub const DEVICE_CALL_NO_DEVICE: u64 = 3;
+/// Calling device functions is for root only.
+pub const DEVICE_CALL_NOT_PERMITTED: u64 = 4;
+/// Set on device specific errors, the rest of the status is the device's error.
+pub const DEVICE_CALL_NATIVE_ERROR: u64 = 1 << 63;
+
+/// The parameter to SyscallNumber::CallDevice, which calls a device function. Up to capacity
+/// bytes of the result are written to result, and length is set to the full result's length.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct DeviceCallRequest {
+    pub device_id: [u8; 16],
+    pub function: u64,
+    pub arguments: *const usize,
+    pub argument_count: u64,
+    pub result: *mut u8,
+    pub capacity: u64,
+    pub length: u64,
+    /// One of the DEVICE_CALL_ statuses.
+    pub status: u64,
+}
+
+/// Device function that returns a device's description, encoded with crate::serialization.
+pub const DEVICE_FUNCTION_DESCRIBE: usize = 0;
+
+/// Keyboard function that switches the layout, args[0] is an index into KEYBOARD_KEYMAPS. Returns
+/// the name of the new layout.
+pub const KEYBOARD_FUNCTION_SET_KEYMAP: usize = 1;
+
+/// Keyboard layouts, by index. The keyboard's describe function returns a KeyboardDescription
+/// naming the active one.
+pub const KEYBOARD_KEYMAPS: [&str; 4] = ["us", "uk", "de", "dvorak"];
+
+/// Memory function that returns the current kernel_state::MemoryState record, for monitoring
+/// tools to poll with kernel_state::read.
+pub const MEMORY_FUNCTION_READ_STATE: usize = 1;
+
+/// Sound function that plays a tone, args[0] is the frequency in Hz and args[1] the duration in
+/// milliseconds. A new tone replaces one that is playing.
+pub const SOUND_FUNCTION_PLAY_TONE: usize = 1;
+pub const SOUND_FUNCTION_STOP: usize = 2;
+/// Sound function that queues PCM samples, for devices that can play them.
+pub const SOUND_FUNCTION_SUBMIT_PCM: usize = 3;
+
+/// A sound device's description, what it is and whether it can play samples.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SoundDescription<'a> {
+    pub model: &'a str,
+    /// SOUND_FUNCTION_SUBMIT_PCM is implemented.
+    pub pcm: bool,
+}
+
+const TAG_MODEL: u16 = 1;
+const TAG_PCM: u16 = 2;
+
+impl Encode for SoundDescription<'_> {
+    fn encode(&self, encoder: &mut Encoder) -> Result<()> {
+        encoder.field(TAG_MODEL, &self.model)?;
+        encoder.field(TAG_PCM, &self.pcm)
+    }
+}
+
+impl<'a> Decode<'a> for SoundDescription<'a> {
+    fn decode(decoder: &Decoder<'a>) -> Result<Self> {
+        Ok(Self {
+            model: decoder.required(TAG_MODEL)?,
+            pcm: decoder.required(TAG_PCM)?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum PixelFormat {
+    Rgb = 0,
+    Bgr = 1,
+    Greyscale = 2,
+    Unknown = 0xFF,
+}
+
+impl From<u8> for PixelFormat {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => PixelFormat::Rgb,
+            1 => PixelFormat::Bgr,
+            2 => PixelFormat::Greyscale,
+            _ => PixelFormat::Unknown,
+        }
+    }
+}
+
+/// Framebuffer geometry and location, for a process that maps it directly. The framebuffer
+/// should be mapped write combining.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FramebufferDescription {
+    pub width: usize,
+    pub height: usize,
+    /// Pixels per line, may be larger than width.
+    pub stride: usize,
+    pub bytes_per_pixel: usize,
+    pub pixel_format: PixelFormat,
+    pub physical_address: u64,
+    pub byte_length: usize,
+}
+
+const TAG_WIDTH: u16 = 1;
+const TAG_HEIGHT: u16 = 2;
+const TAG_STRIDE: u16 = 3;
+const TAG_BYTES_PER_PIXEL: u16 = 4;
+const TAG_PIXEL_FORMAT: u16 = 5;
+const TAG_PHYSICAL_ADDRESS: u16 = 6;
+const TAG_BYTE_LENGTH: u16 = 7;
+
+impl Encode for FramebufferDescription {
+    fn encode(&self, encoder: &mut Encoder) -> Result<()> {
+        encoder.field(TAG_WIDTH, &self.width)?;
+        encoder.field(TAG_HEIGHT, &self.height)?;
+        encoder.field(TAG_STRIDE, &self.stride)?;
+        encoder.field(TAG_BYTES_PER_PIXEL, &self.bytes_per_pixel)?;
+        encoder.field(TAG_PIXEL_FORMAT, &(self.pixel_format as u8))?;
+        encoder.field(TAG_PHYSICAL_ADDRESS, &self.physical_address)?;
+        encoder.field(TAG_BYTE_LENGTH, &self.byte_length)
+    }
+}
+
+impl<'a> Decode<'a> for FramebufferDescription {
+    fn decode(decoder: &Decoder<'a>) -> Result<Self> {
+        Ok(Self {
+            width: decoder.required(TAG_WIDTH)?,
+            height: decoder.required(TAG_HEIGHT)?,
+            stride: decoder.required(TAG_STRIDE)?,
+            bytes_per_pixel: decoder.required(TAG_BYTES_PER_PIXEL)?,
+            pixel_format: decoder.required::<u8>(TAG_PIXEL_FORMAT)?.into(),
+            physical_address: decoder.required(TAG_PHYSICAL_ADDRESS)?,
+            byte_length: decoder.required(TAG_BYTE_LENGTH)?,
+        })
+    }
+}
+
+/// The memory device's description: which record MEMORY_FUNCTION_READ_STATE returns, so a reader
+/// can check it knows the layout before polling it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryDescription {
+    pub record_kind: u32,
+    pub record_version: u32,
+    pub record_length: usize,
+    pub page_size: usize,
+}
+
+const TAG_RECORD_KIND: u16 = 1;
+const TAG_RECORD_VERSION: u16 = 2;
+const TAG_RECORD_LENGTH: u16 = 3;
+const TAG_PAGE_SIZE: u16 = 4;
+
+impl Encode for MemoryDescription {
+    fn encode(&self, encoder: &mut Encoder) -> Result<()> {
+        encoder.field(TAG_RECORD_KIND, &self.record_kind)?;
+        encoder.field(TAG_RECORD_VERSION, &self.record_version)?;
+        encoder.field(TAG_RECORD_LENGTH, &self.record_length)?;
+        encoder.field(TAG_PAGE_SIZE, &self.page_size)
+    }
+}
+
+impl<'a> Decode<'a> for MemoryDescription {
+    fn decode(decoder: &Decoder<'a>) -> Result<Self> {
+        Ok(Self {
+            record_kind: decoder.required(TAG_RECORD_KIND)?,
+            record_version: decoder.required(TAG_RECORD_VERSION)?,
+            record_length: decoder.required(TAG_RECORD_LENGTH)?,
+            page_size: decoder.required(TAG_PAGE_SIZE)?,
+        })
+    }
+}
+
+/// The keyboard device's description.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyboardDescription<'a> {
+    /// The active layout, one of KEYBOARD_KEYMAPS.
+    pub keymap: &'a str,
+}
+
+const TAG_KEYMAP: u16 = 1;
+
+impl Encode for KeyboardDescription<'_> {
+    fn encode(&self, encoder: &mut Encoder) -> Result<()> {
+        encoder.field(TAG_KEYMAP, &self.keymap)
+    }
+}
+
+impl<'a> Decode<'a> for KeyboardDescription<'a> {
+    fn decode(decoder: &Decoder<'a>) -> Result<Self> {
+        Ok(Self {
+            keymap: decoder.required(TAG_KEYMAP)?,
+        })
+    }
+}
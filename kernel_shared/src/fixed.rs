@@ -0,0 +1,91 @@
+//! Integer only arithmetic, for code that shouldn't touch the FPU: the kernel doesn't save
+//! floating point state on interrupts, and future ports may not have one at all.
+//!
+//! 128 bit intermediates keep scaling from overflowing. The division they need comes from
+//! compiler_builtins, which build-std provides on every target.
+
+/// value * multiplier / divisor, without overflowing in between. Saturates if the result
+/// doesn't fit, divisor must not be zero.
+pub const fn mul_div(value: u64, multiplier: u64, divisor: u64) -> u64 {
+    let result = value as u128 * multiplier as u128 / divisor as u128;
+    if result > u64::MAX as u128 {
+        u64::MAX
+    } else {
+        result as u64
+    }
+}
+
+/// An unsigned fixed point number, with FRACTION_BITS of its 64 bits after the point.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Fixed<const FRACTION_BITS: u32>(pub u64);
+
+impl<const FRACTION_BITS: u32> Fixed<FRACTION_BITS> {
+    pub const ONE: Self = Self(1 << FRACTION_BITS);
+
+    pub const fn from_int(value: u64) -> Self {
+        Self(value << FRACTION_BITS)
+    }
+
+    /// numerator / denominator, rounded down, denominator must not be zero.
+    pub const fn from_ratio(numerator: u64, denominator: u64) -> Self {
+        Self(mul_div(numerator, 1 << FRACTION_BITS, denominator))
+    }
+
+    pub const fn saturating_add(self, other: Self) -> Self {
+        Self(self.0.saturating_add(other.0))
+    }
+
+    pub const fn saturating_sub(self, other: Self) -> Self {
+        Self(self.0.saturating_sub(other.0))
+    }
+
+    pub const fn mul(self, other: Self) -> Self {
+        Self(mul_div(self.0, other.0, 1 << FRACTION_BITS))
+    }
+
+    pub const fn mul_int(self, value: u64) -> Self {
+        Self(self.0.saturating_mul(value))
+    }
+
+    /// The whole part, rounded down.
+    pub const fn floor(self) -> u64 {
+        self.0 >> FRACTION_BITS
+    }
+
+    /// The nearest whole number, halves rounding up.
+    pub const fn round(self) -> u64 {
+        self.0.saturating_add(1 << FRACTION_BITS >> 1) >> FRACTION_BITS
+    }
+
+    /// The part after the point, in hundredths, for printing like a decimal.
+    pub const fn hundredths(self) -> u64 {
+        mul_div(self.0 & ((1 << FRACTION_BITS) - 1), 100, 1 << FRACTION_BITS)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Fixed16 = Fixed<16>;
+
+    #[test]
+    fn mul_div_does_not_overflow_in_between() {
+        assert_eq!(mul_div(u64::MAX, 1_000_000_000, 1_000_000_000), u64::MAX);
+        assert_eq!(
+            mul_div(3_000_000_000, 1_000_000_000, 3_000_000_000),
+            1_000_000_000
+        );
+        assert_eq!(mul_div(u64::MAX, 2, 1), u64::MAX);
+    }
+
+    #[test]
+    fn fixed_point_arithmetic() {
+        let half = Fixed16::from_ratio(1, 2);
+        assert_eq!(half.mul(Fixed16::from_int(3)), Fixed16::from_ratio(3, 2));
+        assert_eq!(Fixed16::from_ratio(3, 2).floor(), 1);
+        assert_eq!(Fixed16::from_ratio(3, 2).round(), 2);
+        assert_eq!(Fixed16::from_ratio(5, 4).hundredths(), 25);
+        assert_eq!(Fixed16::ONE.saturating_sub(half), half);
+    }
+}
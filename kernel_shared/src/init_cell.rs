@@ -0,0 +1,144 @@
+use core::{
+    any::type_name,
+    cell::UnsafeCell,
+    hint::spin_loop,
+    mem::MaybeUninit,
+    sync::atomic::{AtomicU8, Ordering},
+};
+
+const UNINITIALIZED: u8 = 0;
+const INITIALIZING: u8 = 1;
+const READY: u8 = 2;
+
+/// A value set once, by whichever CPU gets there first, then shared by all of them.
+///
+/// Unlike core's OnceCell it's Sync, so it can be a plain static rather than a static mut:
+/// initializers that race spin until the winner's value is ready, and readers only ever see a
+/// finished value. Using it before it's set panics with the caller's location, not this file's.
+pub struct InitCell<T> {
+    state: AtomicU8,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+unsafe impl<T: Send> Send for InitCell<T> {}
+unsafe impl<T: Send + Sync> Sync for InitCell<T> {}
+
+impl<T> InitCell<T> {
+    pub const fn new() -> Self {
+        Self {
+            state: AtomicU8::new(UNINITIALIZED),
+            value: UnsafeCell::new(MaybeUninit::uninit()),
+        }
+    }
+
+    pub fn is_initialized(&self) -> bool {
+        self.state.load(Ordering::Acquire) == READY
+    }
+
+    /// The value, or None if it isn't set yet, or is still being set.
+    pub fn get(&self) -> Option<&T> {
+        match self.is_initialized() {
+            true => Some(unsafe { (*self.value.get()).assume_init_ref() }),
+            false => None,
+        }
+    }
+
+    /// The value, for code that runs after it's set.
+    ///
+    /// # Panics
+    /// If it isn't set yet, reporting where it was used from.
+    #[track_caller]
+    pub fn get_initialized(&self) -> &T {
+        match self.get() {
+            Some(value) => value,
+            None => panic!("{} used before initialization", type_name::<Self>()),
+        }
+    }
+
+    /// Sets the value, handing it back if it was already set, or is being set.
+    pub fn set(&self, value: T) -> Result<(), T> {
+        match self.claim() {
+            true => {
+                self.publish(value);
+                Ok(())
+            }
+            false => Err(value),
+        }
+    }
+
+    /// The value, set by calling init if it isn't yet. Only one caller's init runs, the others
+    /// wait for it. init must not use this cell, it would wait on itself.
+    pub fn get_or_init(&self, init: impl FnOnce() -> T) -> &T {
+        if let Some(value) = self.get() {
+            return value;
+        }
+        if self.claim() {
+            self.publish(init());
+        }
+        while !self.is_initialized() {
+            spin_loop();
+        }
+        unsafe { (*self.value.get()).assume_init_ref() }
+    }
+
+    /// The value, mutably. Having the only reference to the cell means no one else can be using
+    /// it.
+    pub fn get_mut(&mut self) -> Option<&mut T> {
+        match *self.state.get_mut() == READY {
+            true => Some(unsafe { self.value.get_mut().assume_init_mut() }),
+            false => None,
+        }
+    }
+
+    fn claim(&self) -> bool {
+        self.state
+            .compare_exchange(
+                UNINITIALIZED,
+                INITIALIZING,
+                Ordering::Acquire,
+                Ordering::Acquire,
+            )
+            .is_ok()
+    }
+
+    fn publish(&self, value: T) {
+        unsafe { (*self.value.get()).write(value) };
+        self.state.store(READY, Ordering::Release);
+    }
+}
+
+impl<T> Default for InitCell<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Drop for InitCell<T> {
+    fn drop(&mut self) {
+        if *self.state.get_mut() == READY {
+            unsafe { self.value.get_mut().assume_init_drop() };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn initializes_once() {
+        let cell = InitCell::new();
+        assert_eq!(cell.get(), None);
+        assert_eq!(*cell.get_or_init(|| 1), 1);
+        assert_eq!(*cell.get_or_init(|| 2), 1);
+        assert_eq!(cell.set(3), Err(3));
+        assert_eq!(*cell.get_initialized(), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "used before initialization")]
+    fn panics_when_used_before_initialization() {
+        let cell: InitCell<u32> = InitCell::new();
+        cell.get_initialized();
+    }
+}
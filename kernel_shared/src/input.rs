@@ -0,0 +1,79 @@
+/// One input event, laid out like a Linux evdev record so existing code maps onto it. A device
+/// reports a group of events (a key, or the axes and buttons of a mouse packet) followed by a
+/// SYN_REPORT.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct InputEvent {
+    /// Monotonic time the event was read, in nanoseconds.
+    pub timestamp: u64,
+    pub kind: u16,
+    pub code: u16,
+    pub value: i32,
+}
+
+impl InputEvent {
+    pub const fn new(timestamp: u64, kind: u16, code: u16, value: i32) -> Self {
+        Self {
+            timestamp,
+            kind,
+            code,
+            value,
+        }
+    }
+
+    pub const fn sync(timestamp: u64) -> Self {
+        Self::new(timestamp, EV_SYN, SYN_REPORT, 0)
+    }
+}
+
+pub const EV_SYN: u16 = 0x00;
+pub const EV_KEY: u16 = 0x01;
+pub const EV_REL: u16 = 0x02;
+
+pub const SYN_REPORT: u16 = 0;
+/// Events were lost because the reader fell behind.
+pub const SYN_DROPPED: u16 = 3;
+
+pub const REL_X: u16 = 0x00;
+pub const REL_Y: u16 = 0x01;
+
+/// EV_KEY values.
+pub const KEY_RELEASED: i32 = 0;
+pub const KEY_PRESSED: i32 = 1;
+pub const KEY_REPEATED: i32 = 2;
+
+// Codes for the main keyboard block are the same as set 1 scancodes, only keys the kernel
+// handles itself are named here.
+pub const KEY_ESC: u16 = 1;
+pub const KEY_BACKSPACE: u16 = 14;
+pub const KEY_TAB: u16 = 15;
+pub const KEY_ENTER: u16 = 28;
+pub const KEY_LEFTCTRL: u16 = 29;
+pub const KEY_LEFTSHIFT: u16 = 42;
+pub const KEY_RIGHTSHIFT: u16 = 54;
+pub const KEY_LEFTALT: u16 = 56;
+pub const KEY_SPACE: u16 = 57;
+pub const KEY_CAPSLOCK: u16 = 58;
+pub const KEY_KPENTER: u16 = 96;
+pub const KEY_RIGHTCTRL: u16 = 97;
+pub const KEY_KPSLASH: u16 = 98;
+pub const KEY_RIGHTALT: u16 = 100;
+pub const KEY_HOME: u16 = 102;
+pub const KEY_UP: u16 = 103;
+pub const KEY_PAGEUP: u16 = 104;
+pub const KEY_LEFT: u16 = 105;
+pub const KEY_RIGHT: u16 = 106;
+pub const KEY_END: u16 = 107;
+pub const KEY_DOWN: u16 = 108;
+pub const KEY_PAGEDOWN: u16 = 109;
+pub const KEY_INSERT: u16 = 110;
+pub const KEY_DELETE: u16 = 111;
+pub const KEY_LEFTMETA: u16 = 125;
+pub const KEY_RIGHTMETA: u16 = 126;
+pub const KEY_COMPOSE: u16 = 127;
+/// Highest key code, key codes are below this.
+pub const KEY_MAX: u16 = 0x2FF;
+
+pub const BTN_LEFT: u16 = 0x110;
+pub const BTN_RIGHT: u16 = 0x111;
+pub const BTN_MIDDLE: u16 = 0x112;
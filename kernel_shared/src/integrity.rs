@@ -0,0 +1,287 @@
+//! The kernel image integrity check. The runner's build script hashes the kernel's read-only
+//! segments, its code and constants, and writes the digest into a record in the kernel's data,
+//! which the kernel compares against a hash of the segments as loaded. Both sides find the
+//! segments with ReadOnlySegments, and hash them with Sha256, so they agree on what's covered.
+
+/// The symbol of the kernel's IntegrityRecord, which the build script looks up to seal it.
+pub const INTEGRITY_SYMBOL: &str = "OXIDIZED_INTEGRITY";
+/// "OXIDINTG", marks the record, and gives it bytes in the image rather than zeroed memory.
+pub const INTEGRITY_MAGIC: u64 = 0x4f58_4944_494e_5447;
+
+/// Where the build script puts the digest. It lives in writable data, outside what's hashed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct IntegrityRecord {
+    pub magic: u64,
+    /// Zero until the build script has written the digest.
+    pub sealed: u64,
+    /// How many bytes were hashed.
+    pub length: u64,
+    pub digest: [u8; 32],
+}
+
+impl IntegrityRecord {
+    pub const fn unsealed() -> Self {
+        Self {
+            magic: INTEGRITY_MAGIC,
+            sealed: 0,
+            length: 0,
+            digest: [0; 32],
+        }
+    }
+
+    /// The record as it appears in the image, for the build script to write.
+    pub fn to_bytes(&self) -> [u8; 56] {
+        let mut bytes = [0u8; 56];
+        bytes[0..8].copy_from_slice(&self.magic.to_le_bytes());
+        bytes[8..16].copy_from_slice(&self.sealed.to_le_bytes());
+        bytes[16..24].copy_from_slice(&self.length.to_le_bytes());
+        bytes[24..56].copy_from_slice(&self.digest);
+        bytes
+    }
+}
+
+const PT_LOAD: u32 = 1;
+const PF_W: u32 = 2;
+
+fn read_u16(bytes: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(
+        bytes.get(offset..offset + 2)?.try_into().ok()?,
+    ))
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(
+        bytes.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(
+        bytes.get(offset..offset + 8)?.try_into().ok()?,
+    ))
+}
+
+/// A loadable segment of an ELF image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Segment {
+    /// Where it is in the file.
+    pub offset: u64,
+    /// Where it's linked to be loaded.
+    pub virtual_address: u64,
+    /// How many of its bytes come from the file.
+    pub file_size: u64,
+}
+
+/// The read-only loadable segments of a little endian ELF64 image, in program header order. They
+/// have no relocations, so they're the same loaded as in the file.
+pub struct ReadOnlySegments<'a> {
+    image: &'a [u8],
+    table: usize,
+    entry_size: usize,
+    count: usize,
+    index: usize,
+}
+
+impl<'a> ReadOnlySegments<'a> {
+    /// image must hold at least the ELF header and program headers. None if it isn't ELF64.
+    pub fn new(image: &'a [u8]) -> Option<Self> {
+        if image.get(0..4)? != b"\x7fELF" || *image.get(4)? != 2 {
+            return None;
+        }
+        Some(Self {
+            image,
+            table: read_u64(image, 0x20)? as usize,
+            entry_size: read_u16(image, 0x36)? as usize,
+            count: read_u16(image, 0x38)? as usize,
+            index: 0,
+        })
+    }
+
+    /// How many bytes of the image the ELF header and program headers take.
+    pub fn headers_length(header: &[u8]) -> Option<usize> {
+        let table = read_u64(header, 0x20)? as usize;
+        let entry_size = read_u16(header, 0x36)? as usize;
+        let count = read_u16(header, 0x38)? as usize;
+        Some(table + entry_size * count)
+    }
+
+    /// The link address of the ELF header, to tell how far the image was moved when it was
+    /// loaded.
+    pub fn header_address(image: &'a [u8]) -> Option<u64> {
+        let segments = Self::new(image)?;
+        (0..segments.count).find_map(|index| {
+            let entry = segments.table + index * segments.entry_size;
+            let loaded_from_start =
+                read_u32(image, entry)? == PT_LOAD && read_u64(image, entry + 8)? == 0;
+            match loaded_from_start {
+                true => read_u64(image, entry + 0x10),
+                false => None,
+            }
+        })
+    }
+}
+
+impl<'a> Iterator for ReadOnlySegments<'a> {
+    type Item = Segment;
+
+    fn next(&mut self) -> Option<Segment> {
+        while self.index < self.count {
+            let entry = self.table + self.index * self.entry_size;
+            self.index += 1;
+            let kind = read_u32(self.image, entry)?;
+            let flags = read_u32(self.image, entry + 4)?;
+            if kind != PT_LOAD || flags & PF_W != 0 {
+                continue;
+            }
+            return Some(Segment {
+                offset: read_u64(self.image, entry + 8)?,
+                virtual_address: read_u64(self.image, entry + 0x10)?,
+                file_size: read_u64(self.image, entry + 0x20)?,
+            });
+        }
+        None
+    }
+}
+
+const ROUND_CONSTANTS: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+const INITIAL_STATE: [u32; 8] = [
+    0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+];
+
+/// SHA-256, fed a piece at a time.
+#[derive(Debug, Clone)]
+pub struct Sha256 {
+    state: [u32; 8],
+    block: [u8; 64],
+    block_length: usize,
+    length: u64,
+}
+
+impl Sha256 {
+    pub const fn new() -> Self {
+        Self {
+            state: INITIAL_STATE,
+            block: [0; 64],
+            block_length: 0,
+            length: 0,
+        }
+    }
+
+    pub fn update(&mut self, mut data: &[u8]) {
+        self.length += data.len() as u64;
+        while !data.is_empty() {
+            let count = (64 - self.block_length).min(data.len());
+            self.block[self.block_length..self.block_length + count]
+                .copy_from_slice(&data[..count]);
+            self.block_length += count;
+            data = &data[count..];
+            if self.block_length == 64 {
+                self.compress();
+                self.block_length = 0;
+            }
+        }
+    }
+
+    pub fn finish(mut self) -> [u8; 32] {
+        let bits = self.length * 8;
+        self.update(&[0x80]);
+        while self.block_length != 56 {
+            self.update(&[0]);
+        }
+        self.update(&bits.to_be_bytes());
+        let mut digest = [0u8; 32];
+        for (chunk, word) in digest.chunks_exact_mut(4).zip(self.state) {
+            chunk.copy_from_slice(&word.to_be_bytes());
+        }
+        digest
+    }
+
+    fn compress(&mut self) {
+        let mut schedule = [0u32; 64];
+        for (word, chunk) in schedule.iter_mut().zip(self.block.chunks_exact(4)) {
+            *word = u32::from_be_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+        }
+        for i in 16..64 {
+            let s0 = schedule[i - 15].rotate_right(7)
+                ^ schedule[i - 15].rotate_right(18)
+                ^ (schedule[i - 15] >> 3);
+            let s1 = schedule[i - 2].rotate_right(17)
+                ^ schedule[i - 2].rotate_right(19)
+                ^ (schedule[i - 2] >> 10);
+            schedule[i] = schedule[i - 16]
+                .wrapping_add(s0)
+                .wrapping_add(schedule[i - 7])
+                .wrapping_add(s1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = self.state;
+        for i in 0..64 {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let choice = (e & f) ^ (!e & g);
+            let t1 = h
+                .wrapping_add(s1)
+                .wrapping_add(choice)
+                .wrapping_add(ROUND_CONSTANTS[i])
+                .wrapping_add(schedule[i]);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let majority = (a & b) ^ (a & c) ^ (b & c);
+            let t2 = s0.wrapping_add(majority);
+            h = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(t1);
+            d = c;
+            c = b;
+            b = a;
+            a = t1.wrapping_add(t2);
+        }
+        for (state, value) in self.state.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+            *state = state.wrapping_add(value);
+        }
+    }
+}
+
+impl Default for Sha256 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(digest: [u8; 32]) -> [u8; 64] {
+        let mut text = [0u8; 64];
+        for (i, byte) in digest.iter().enumerate() {
+            text[i * 2] = b"0123456789abcdef"[(byte >> 4) as usize];
+            text[i * 2 + 1] = b"0123456789abcdef"[(byte & 0xf) as usize];
+        }
+        text
+    }
+
+    #[test]
+    fn sha256_matches_known_digests() {
+        assert_eq!(
+            &hex(Sha256::new().finish()),
+            b"e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        // Split across calls, and long enough to need a second block for the length.
+        let mut sha = Sha256::new();
+        sha.update(b"abcdbcdecdefdefgefghfghighijhijkijkljklm");
+        sha.update(b"klmnlmnomnopnopq");
+        assert_eq!(
+            &hex(sha.finish()),
+            b"248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"
+        );
+    }
+}
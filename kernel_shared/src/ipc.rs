@@ -1,3 +1,5 @@
+use crate::serialization::{Decode, Decoder, Encode, Encoder, Result};
+
 #[derive(Debug, Clone, Copy)]
 #[repr(transparent)]
 pub struct Server(u128);
@@ -51,3 +53,57 @@ impl Pointer for InterProcessResult {
         self.result_address.as_mut_ptr()
     }
 }
+
+// Field tags, these are part of the userspace ABI. Add new tags, never reuse old ones.
+const TAG_SERVER: u16 = 1;
+const TAG_FUNCTION: u16 = 2;
+const TAG_METADATA: u16 = 3;
+const TAG_ADDRESS: u16 = 4;
+
+impl Encode for InterProcessCallMetadata {
+    fn encode(&self, encoder: &mut Encoder) -> Result<()> {
+        encoder.field(TAG_SERVER, &self.server.0)?;
+        encoder.field(TAG_FUNCTION, &self.function)
+    }
+}
+
+impl<'a> Decode<'a> for InterProcessCallMetadata {
+    fn decode(decoder: &Decoder<'a>) -> Result<Self> {
+        Ok(Self {
+            server: Server(decoder.required(TAG_SERVER)?),
+            function: decoder.required(TAG_FUNCTION)?,
+        })
+    }
+}
+
+impl Encode for InterProcessCall {
+    fn encode(&self, encoder: &mut Encoder) -> Result<()> {
+        encoder.nested(TAG_METADATA, &self.metadata)?;
+        encoder.field(TAG_ADDRESS, &self.parameters_address.0)
+    }
+}
+
+impl<'a> Decode<'a> for InterProcessCall {
+    fn decode(decoder: &Decoder<'a>) -> Result<Self> {
+        Ok(Self {
+            metadata: decoder.nested(TAG_METADATA)?,
+            parameters_address: InterProcessData(decoder.required(TAG_ADDRESS)?),
+        })
+    }
+}
+
+impl Encode for InterProcessResult {
+    fn encode(&self, encoder: &mut Encoder) -> Result<()> {
+        encoder.nested(TAG_METADATA, &self.metadata)?;
+        encoder.field(TAG_ADDRESS, &self.result_address.0)
+    }
+}
+
+impl<'a> Decode<'a> for InterProcessResult {
+    fn decode(decoder: &Decoder<'a>) -> Result<Self> {
+        Ok(Self {
+            metadata: decoder.nested(TAG_METADATA)?,
+            result_address: InterProcessData(decoder.required(TAG_ADDRESS)?),
+        })
+    }
+}
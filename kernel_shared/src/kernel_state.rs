@@ -0,0 +1,362 @@
+//! Fixed layout records the kernel publishes about itself, for monitoring tools to poll.
+//!
+//! Records are plain `#[repr(C)]` structures, read in place without decoding. Every record starts
+//! with a RecordHeader, and the kernel updates it under a sequence count, so a reader copies the
+//! record and retries if the count was odd or changed while copying.
+//!
+//! It also has the StateRegistry, which the kernel looks its singletons up in by type.
+
+use core::{
+    any::{type_name, Any, TypeId},
+    cell::UnsafeCell,
+    mem::{size_of, MaybeUninit},
+    ptr,
+    sync::atomic::{fence, AtomicBool, AtomicUsize, Ordering},
+};
+
+use crate::init_cell::InitCell;
+
+pub const RECORD_KIND_MEMORY: u32 = 1;
+
+// Copies that keep racing the writer give up, rather than spin forever.
+const MAX_READ_ATTEMPTS: usize = 16;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct RecordHeader {
+    pub kind: u32,
+    /// Bumped when fields are added, older fields never move.
+    pub version: u32,
+    /// Size of the whole record, header included.
+    pub length: u32,
+    /// Odd while the kernel is updating the record.
+    pub sequence: u32,
+}
+
+/// A record type, and the newest version of it this crate knows about.
+pub trait Record: Copy + Default {
+    const KIND: u32;
+    const VERSION: u32;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordError {
+    /// Shorter than the record the reader expects.
+    TooShort,
+    WrongKind,
+    /// Older than the reader needs. Newer versions are read, ignoring the fields added since.
+    UnsupportedVersion,
+    /// The kernel kept updating the record while it was being copied.
+    Busy,
+}
+
+/// Copies a record out of bytes the kernel keeps updating, like those returned by a device.
+pub fn read<T: Record>(bytes: &[u8]) -> Result<T, RecordError> {
+    if bytes.len() < size_of::<T>() {
+        return Err(RecordError::TooShort);
+    }
+    let header = bytes.as_ptr() as *const RecordHeader;
+    for _ in 0..MAX_READ_ATTEMPTS {
+        // The length was checked above, and records are plain data.
+        let before: RecordHeader = unsafe { read_volatile_unaligned(header as *const u8) };
+        if before.sequence % 2 == 1 {
+            core::hint::spin_loop();
+            continue;
+        }
+        if before.kind != T::KIND {
+            return Err(RecordError::WrongKind);
+        }
+        if before.version < T::VERSION {
+            return Err(RecordError::UnsupportedVersion);
+        }
+        if (before.length as usize) < size_of::<T>() {
+            return Err(RecordError::TooShort);
+        }
+        fence(Ordering::Acquire);
+        let record: T = unsafe { read_volatile_unaligned(bytes.as_ptr()) };
+        fence(Ordering::Acquire);
+        let after: u32 =
+            unsafe { read_volatile_unaligned(ptr::addr_of!((*header).sequence) as *const u8) };
+        if after == before.sequence {
+            return Ok(record);
+        }
+    }
+    Err(RecordError::Busy)
+}
+
+// Copies a T out of memory the kernel may be writing. The bytes came from a slice, so they may not
+// be aligned for T, and are copied one at a time.
+unsafe fn read_volatile_unaligned<T: Copy>(source: *const u8) -> T {
+    let mut value = MaybeUninit::<T>::uninit();
+    let destination = value.as_mut_ptr() as *mut u8;
+    for offset in 0..size_of::<T>() {
+        destination
+            .add(offset)
+            .write(ptr::read_volatile(source.add(offset)));
+    }
+    value.assume_init()
+}
+
+/// The kernel's side of a record, updated in place while readers copy it.
+#[repr(transparent)]
+pub struct PublishedRecord<T: Record> {
+    record: UnsafeCell<T>,
+}
+
+// Readers are never handed a reference to T, only its bytes, which they copy with read.
+unsafe impl<T: Record> Sync for PublishedRecord<T> {}
+
+impl<T: Record> PublishedRecord<T> {
+    pub fn new(record: T) -> Self {
+        let ret = Self {
+            record: UnsafeCell::new(record),
+        };
+        unsafe { ptr::write_volatile(ret.header(), Self::header_for(0)) };
+        ret
+    }
+
+    // Every record starts with its header.
+    fn header(&self) -> *mut RecordHeader {
+        self.record.get() as *mut RecordHeader
+    }
+
+    fn header_for(sequence: u32) -> RecordHeader {
+        RecordHeader {
+            kind: T::KIND,
+            version: T::VERSION,
+            length: size_of::<T>() as u32,
+            sequence,
+        }
+    }
+
+    /// Updates the record in place. Writers must be serialized by the caller.
+    pub fn update(&self, update: impl FnOnce(&mut T)) {
+        let header = self.header();
+        unsafe {
+            let sequence = ptr::read_volatile(ptr::addr_of!((*header).sequence)).wrapping_add(1);
+            ptr::write_volatile(ptr::addr_of_mut!((*header).sequence), sequence);
+            fence(Ordering::Release);
+            update(&mut *self.record.get());
+            // Whatever update did to the header, readers need the real one.
+            ptr::write_volatile(header, Self::header_for(sequence));
+            fence(Ordering::Release);
+            ptr::write_volatile(
+                ptr::addr_of_mut!((*header).sequence),
+                sequence.wrapping_add(1),
+            );
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.record.get() as *const u8, size_of::<T>()) }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct ZoneState {
+    pub total_pages: u64,
+    pub free_pages: u64,
+}
+
+pub const MEMORY_ZONE_DMA: usize = 0;
+pub const MEMORY_ZONE_DMA32: usize = 1;
+pub const MEMORY_ZONE_NORMAL: usize = 2;
+pub const MEMORY_ZONE_COUNT: usize = 3;
+
+/// Physical memory and kernel heap usage, returned by the memory device's describe function.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct MemoryState {
+    pub header: RecordHeader,
+    pub page_size: u64,
+    /// Usable pages, the ones the kernel may allocate.
+    pub total_pages: u64,
+    pub free_pages: u64,
+    /// Pages the firmware, bootloader or kernel image hold, which are never allocated.
+    pub reserved_pages: u64,
+    pub heap_bytes: u64,
+    pub heap_used_bytes: u64,
+    /// The emergency reserve for allocations that must not fail, carved out of the heap.
+    pub heap_reserve_bytes: u64,
+    pub heap_reserve_used_bytes: u64,
+    /// Indexed by the MEMORY_ZONE constants: below 16MiB, below 4GiB, and everything above.
+    pub zones: [ZoneState; MEMORY_ZONE_COUNT],
+}
+
+impl Record for MemoryState {
+    const KIND: u32 = RECORD_KIND_MEMORY;
+    const VERSION: u32 = 1;
+}
+
+struct StateEntry {
+    type_id: TypeId,
+    name: &'static str,
+    value: &'static (dyn Any + Send + Sync),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegistryError {
+    /// Each type is registered once, it's a singleton.
+    AlreadyRegistered,
+    Full,
+}
+
+/// State that lives as long as the kernel, registered once at boot and looked up by its type, so
+/// it's passed around explicitly rather than each subsystem keeping its own static.
+///
+/// Lookups before a type is registered are bugs in initialization order, expect and require
+/// panic naming the type and the caller.
+pub struct StateRegistry<const N: usize> {
+    entries: [InitCell<StateEntry>; N],
+    claimed: AtomicUsize,
+    // Held while registering, so two registrations of a type can't both pass the duplicate check.
+    registering: AtomicBool,
+}
+
+impl<const N: usize> StateRegistry<N> {
+    pub const fn new() -> Self {
+        Self {
+            entries: [const { InitCell::new() }; N],
+            claimed: AtomicUsize::new(0),
+            registering: AtomicBool::new(false),
+        }
+    }
+
+    pub fn register<T: Any + Send + Sync>(&self, value: &'static T) -> Result<(), RegistryError> {
+        while self
+            .registering
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            core::hint::spin_loop();
+        }
+        let registered = self.register_locked(value);
+        self.registering.store(false, Ordering::Release);
+        registered
+    }
+
+    // Every entry before claimed is set by the time the lock is released, so the duplicate check
+    // sees every registration.
+    fn register_locked<T: Any + Send + Sync>(
+        &self,
+        value: &'static T,
+    ) -> Result<(), RegistryError> {
+        if self.get::<T>().is_some() {
+            return Err(RegistryError::AlreadyRegistered);
+        }
+        let index = self.claimed.load(Ordering::Relaxed);
+        let entry = match self.entries.get(index) {
+            Some(entry) => entry,
+            None => return Err(RegistryError::Full),
+        };
+        let registered = entry.set(StateEntry {
+            type_id: TypeId::of::<T>(),
+            name: type_name::<T>(),
+            value,
+        });
+        // Only the lock holder sets entries, so the next one is always free.
+        debug_assert!(registered.is_ok());
+        self.claimed.store(index + 1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    pub fn get<T: Any + Send + Sync>(&self) -> Option<&'static T> {
+        let value = self
+            .entries
+            .iter()
+            .filter_map(InitCell::get)
+            .find(|entry| entry.type_id == TypeId::of::<T>())?
+            .value;
+        value.downcast_ref::<T>()
+    }
+
+    #[track_caller]
+    pub fn expect<T: Any + Send + Sync>(&self) -> &'static T {
+        match self.get::<T>() {
+            Some(value) => value,
+            None => panic!("{} used before it was registered", type_name::<T>()),
+        }
+    }
+
+    /// Asserts T is registered, for initialization that depends on it without using it
+    /// directly.
+    #[track_caller]
+    pub fn require<T: Any + Send + Sync>(&self) {
+        self.expect::<T>();
+    }
+
+    /// The names of the registered types, in the order they were registered.
+    pub fn names(&self) -> impl Iterator<Item = &'static str> + '_ {
+        self.entries
+            .iter()
+            .filter_map(InitCell::get)
+            .map(|entry| entry.name)
+    }
+}
+
+impl<const N: usize> Default for StateRegistry<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct BootData(u64);
+    struct Clock;
+
+    #[test]
+    fn registry_looks_up_by_type() {
+        static REGISTRY: StateRegistry<2> = StateRegistry::new();
+        static BOOT_DATA: BootData = BootData(42);
+        static CLOCK: Clock = Clock;
+        assert!(REGISTRY.get::<BootData>().is_none());
+        assert_eq!(REGISTRY.register(&BOOT_DATA), Ok(()));
+        assert_eq!(
+            REGISTRY.register(&BOOT_DATA),
+            Err(RegistryError::AlreadyRegistered)
+        );
+        assert_eq!(REGISTRY.expect::<BootData>().0, 42);
+        assert_eq!(REGISTRY.register(&CLOCK), Ok(()));
+        assert_eq!(REGISTRY.register(&0u32), Err(RegistryError::Full));
+        assert_eq!(REGISTRY.names().count(), 2);
+    }
+
+    #[test]
+    fn registry_registers_a_type_once_across_threads() {
+        extern crate std;
+
+        static REGISTRY: StateRegistry<8> = StateRegistry::new();
+        static CLOCK: Clock = Clock;
+        let threads: std::vec::Vec<_> = (0..8)
+            .map(|_| std::thread::spawn(|| REGISTRY.register(&CLOCK).is_ok()))
+            .collect();
+        let registered = threads
+            .into_iter()
+            .map(|thread| thread.join().unwrap())
+            .filter(|registered| *registered)
+            .count();
+        assert_eq!(registered, 1);
+        assert_eq!(REGISTRY.names().count(), 1);
+    }
+
+    #[test]
+    fn read_copes_with_unaligned_bytes() {
+        let published = PublishedRecord::new(MemoryState::default());
+        published.update(|state| state.free_pages = 7);
+        let mut buffer = [0u8; size_of::<MemoryState>() + 1];
+        buffer[1..].copy_from_slice(published.as_bytes());
+        let state = read::<MemoryState>(&buffer[1..]).unwrap();
+        assert_eq!(state.free_pages, 7);
+        assert_eq!(state.header.sequence, 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "used before it was registered")]
+    fn require_panics_when_missing() {
+        StateRegistry::<1>::new().require::<Clock>();
+    }
+}
@@ -1,7 +1,22 @@
 #![no_std]
 
+#[cfg(test)]
+mod abi;
+pub mod audit;
+pub mod channel;
 pub mod constants;
+pub mod device;
+pub mod fixed;
 pub mod handle;
+pub mod init_cell;
+pub mod input;
+pub mod integrity;
 pub mod ipc;
+pub mod kernel_state;
 pub mod memory;
+pub mod memory_range;
+pub mod process;
+pub mod random;
+pub mod serialization;
 pub mod syscall;
+pub mod tunable;
@@ -1,11 +1,40 @@
 use crate::constants::ARCH_WORD_SIZE;
 
+/// Load averages are fixed point, with this many fractional bits.
+pub const SYSTEM_INFO_LOAD_SHIFT: u32 = 16;
+
+/// Physical memory, in bytes, as returned in SystemInfo.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct MemoryInfo {
+    pub total_bytes: u64,
+    pub free_bytes: u64,
+    /// Memory holding cached data, which is freed on demand.
+    pub cached_bytes: u64,
+    pub kernel_heap_bytes: u64,
+    pub kernel_heap_used_bytes: u64,
+}
+
+/// Filled in by SyscallNumber::SystemInfo, whose parameter points to one.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct SystemInfo {
+    pub uptime_nanoseconds: u64,
+    /// Runnable threads, averaged over 1, 5 and 15 minutes, see SYSTEM_INFO_LOAD_SHIFT.
+    pub loads: [u64; 3],
+    pub processes: u64,
+    pub memory: MemoryInfo,
+}
+
 /// Memcpy
 ///
 /// Copy N bytes of memory from one location to another.
 ///
 /// This faster implementation works by copying bytes not one-by-one, but in
 /// groups of 8 bytes (or 4 bytes in the case of 32-bit architectures).
+// Not in tests, the host's libc has its own, and this one's word copies trip the debug checks
+// for misaligned pointers.
+#[cfg(not(test))]
 #[no_mangle]
 pub unsafe extern "C" fn memcpy(dest: *mut u8, src: *const u8, n: usize) -> *mut u8 {
     let n_usize: usize = n / ARCH_WORD_SIZE; // Number of word sized groups
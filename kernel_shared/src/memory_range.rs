@@ -0,0 +1,297 @@
+/// A half open range of addresses, from start up to, but not including, end.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct MemoryRange {
+    start: u64,
+    end: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryRangeError {
+    /// The end is before the start.
+    Inverted,
+    /// The range would run past the end of the address space.
+    Overflow,
+    /// An alignment that isn't a power of two.
+    InvalidAlignment,
+    /// The address is outside the range.
+    OutOfRange,
+    /// The ranges neither overlap nor touch, so can't be merged.
+    Disjoint,
+}
+
+impl MemoryRange {
+    pub const fn new(start: u64, end: u64) -> Result<Self, MemoryRangeError> {
+        if end < start {
+            return Err(MemoryRangeError::Inverted);
+        }
+        Ok(Self { start, end })
+    }
+
+    pub const fn from_length(start: u64, length: u64) -> Result<Self, MemoryRangeError> {
+        match start.checked_add(length) {
+            Some(end) => Ok(Self { start, end }),
+            None => Err(MemoryRangeError::Overflow),
+        }
+    }
+
+    pub const fn start(&self) -> u64 {
+        self.start
+    }
+
+    pub const fn end(&self) -> u64 {
+        self.end
+    }
+
+    pub const fn length(&self) -> u64 {
+        self.end - self.start
+    }
+
+    pub const fn is_empty(&self) -> bool {
+        self.start == self.end
+    }
+
+    pub const fn contains(&self, address: u64) -> bool {
+        address >= self.start && address < self.end
+    }
+
+    /// True if every address in other is in this range. Empty ranges are contained anywhere.
+    pub const fn contains_range(&self, other: &MemoryRange) -> bool {
+        other.is_empty() || (other.start >= self.start && other.end <= self.end)
+    }
+
+    /// True if the ranges share at least one address. Ranges that only touch don't overlap.
+    pub const fn overlaps(&self, other: &MemoryRange) -> bool {
+        !self.is_empty() && !other.is_empty() && self.start < other.end && other.start < self.end
+    }
+
+    /// The addresses in both ranges, if any.
+    pub fn intersection(&self, other: &MemoryRange) -> Option<MemoryRange> {
+        let start = self.start.max(other.start);
+        let end = self.end.min(other.end);
+        (start < end).then_some(MemoryRange { start, end })
+    }
+
+    /// True if both ends are multiples of alignment.
+    pub const fn is_aligned(&self, alignment: u64) -> bool {
+        alignment.is_power_of_two()
+            && self.start & (alignment - 1) == 0
+            && self.end & (alignment - 1) == 0
+    }
+
+    /// The smallest aligned range covering this one, like every page a buffer touches.
+    pub const fn align_outward(&self, alignment: u64) -> Result<Self, MemoryRangeError> {
+        if !alignment.is_power_of_two() {
+            return Err(MemoryRangeError::InvalidAlignment);
+        }
+        let start = self.start & !(alignment - 1);
+        let end = match self.end.checked_add(alignment - 1) {
+            Some(end) => end & !(alignment - 1),
+            None => return Err(MemoryRangeError::Overflow),
+        };
+        Ok(Self { start, end })
+    }
+
+    /// The largest aligned range inside this one, like the whole pages in a region of memory.
+    /// Empty if there are none.
+    pub const fn align_inward(&self, alignment: u64) -> Result<Self, MemoryRangeError> {
+        if !alignment.is_power_of_two() {
+            return Err(MemoryRangeError::InvalidAlignment);
+        }
+        let end = self.end & !(alignment - 1);
+        let start = match self.start.checked_add(alignment - 1) {
+            Some(start) => start & !(alignment - 1),
+            None => end,
+        };
+        if start >= end {
+            return Ok(Self { start: end, end });
+        }
+        Ok(Self { start, end })
+    }
+
+    /// Pages of page_size touched by the range, counting partial pages at either end.
+    pub const fn page_count(&self, page_size: u64) -> Result<u64, MemoryRangeError> {
+        match self.align_outward(page_size) {
+            Ok(_) if self.is_empty() => Ok(0),
+            Ok(aligned) => Ok(aligned.length() / page_size),
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Splits the range in two, the first half ending, and the second starting, at address.
+    /// Splitting at either end gives an empty half.
+    pub const fn split_at(&self, address: u64) -> Result<(Self, Self), MemoryRangeError> {
+        if address < self.start || address > self.end {
+            return Err(MemoryRangeError::OutOfRange);
+        }
+        Ok((
+            Self {
+                start: self.start,
+                end: address,
+            },
+            Self {
+                start: address,
+                end: self.end,
+            },
+        ))
+    }
+
+    /// The range covering both, if they overlap or touch.
+    pub fn merge(&self, other: &MemoryRange) -> Result<Self, MemoryRangeError> {
+        if self.is_empty() {
+            return Ok(*other);
+        }
+        if other.is_empty() {
+            return Ok(*self);
+        }
+        if self.start > other.end || other.start > self.end {
+            return Err(MemoryRangeError::Disjoint);
+        }
+        Ok(Self {
+            start: self.start.min(other.start),
+            end: self.end.max(other.end),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(start: u64, end: u64) -> MemoryRange {
+        MemoryRange::new(start, end).unwrap()
+    }
+
+    #[test]
+    fn new_rejects_inverted_ranges() {
+        assert_eq!(
+            MemoryRange::new(0x2000, 0x1000),
+            Err(MemoryRangeError::Inverted)
+        );
+        assert!(MemoryRange::new(0x1000, 0x1000).unwrap().is_empty());
+    }
+
+    #[test]
+    fn from_length_rejects_overflow() {
+        assert_eq!(
+            MemoryRange::from_length(u64::MAX, 2),
+            Err(MemoryRangeError::Overflow)
+        );
+        assert_eq!(
+            MemoryRange::from_length(0x1000, 0x1000),
+            Ok(range(0x1000, 0x2000))
+        );
+    }
+
+    #[test]
+    fn contains_excludes_the_end() {
+        let r = range(0x1000, 0x2000);
+        assert!(r.contains(0x1000));
+        assert!(r.contains(0x1fff));
+        assert!(!r.contains(0x2000));
+        assert!(!r.contains(0xfff));
+    }
+
+    #[test]
+    fn contains_range() {
+        let r = range(0x1000, 0x3000);
+        assert!(r.contains_range(&range(0x1000, 0x3000)));
+        assert!(r.contains_range(&range(0x2000, 0x2800)));
+        assert!(!r.contains_range(&range(0x2000, 0x3001)));
+        assert!(r.contains_range(&range(0x9000, 0x9000)));
+    }
+
+    #[test]
+    fn touching_ranges_do_not_overlap() {
+        let r = range(0x1000, 0x2000);
+        assert!(!r.overlaps(&range(0x2000, 0x3000)));
+        assert!(!r.overlaps(&range(0, 0x1000)));
+        assert!(r.overlaps(&range(0x1fff, 0x3000)));
+        assert!(r.overlaps(&range(0, 0x1001)));
+        assert!(!r.overlaps(&range(0x1800, 0x1800)));
+    }
+
+    #[test]
+    fn intersection() {
+        let r = range(0x1000, 0x3000);
+        assert_eq!(
+            r.intersection(&range(0x2000, 0x4000)),
+            Some(range(0x2000, 0x3000))
+        );
+        assert_eq!(
+            r.intersection(&range(0x1800, 0x2000)),
+            Some(range(0x1800, 0x2000))
+        );
+        assert_eq!(r.intersection(&range(0x3000, 0x4000)), None);
+    }
+
+    #[test]
+    fn alignment() {
+        let r = range(0x1800, 0x3800);
+        assert!(!r.is_aligned(0x1000));
+        assert!(r.is_aligned(0x800));
+        assert!(!r.is_aligned(0x300));
+        assert_eq!(r.align_outward(0x1000), Ok(range(0x1000, 0x4000)));
+        assert_eq!(r.align_inward(0x1000), Ok(range(0x2000, 0x3000)));
+        assert_eq!(
+            r.align_outward(0x300),
+            Err(MemoryRangeError::InvalidAlignment)
+        );
+        assert_eq!(
+            r.align_inward(0x300),
+            Err(MemoryRangeError::InvalidAlignment)
+        );
+    }
+
+    #[test]
+    fn align_inward_can_be_empty() {
+        let r = range(0x1800, 0x1c00);
+        assert!(r.align_inward(0x1000).unwrap().is_empty());
+        assert!(range(u64::MAX - 1, u64::MAX)
+            .align_inward(0x1000)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn align_outward_rejects_overflow() {
+        assert_eq!(
+            range(0x1000, u64::MAX).align_outward(0x1000),
+            Err(MemoryRangeError::Overflow)
+        );
+    }
+
+    #[test]
+    fn page_count_includes_partial_pages() {
+        assert_eq!(range(0x1000, 0x3000).page_count(0x1000), Ok(2));
+        assert_eq!(range(0x1fff, 0x2001).page_count(0x1000), Ok(2));
+        assert_eq!(range(0x1800, 0x1800).page_count(0x1000), Ok(0));
+        assert_eq!(range(0, 1).page_count(0x200000), Ok(1));
+    }
+
+    #[test]
+    fn split_at() {
+        let r = range(0x1000, 0x3000);
+        assert_eq!(
+            r.split_at(0x2000),
+            Ok((range(0x1000, 0x2000), range(0x2000, 0x3000)))
+        );
+        let (first, second) = r.split_at(0x1000).unwrap();
+        assert!(first.is_empty());
+        assert_eq!(second, r);
+        assert_eq!(r.split_at(0x3001), Err(MemoryRangeError::OutOfRange));
+        assert_eq!(r.split_at(0xfff), Err(MemoryRangeError::OutOfRange));
+    }
+
+    #[test]
+    fn merge() {
+        let r = range(0x1000, 0x2000);
+        assert_eq!(r.merge(&range(0x2000, 0x3000)), Ok(range(0x1000, 0x3000)));
+        assert_eq!(r.merge(&range(0x1800, 0x2800)), Ok(range(0x1000, 0x2800)));
+        assert_eq!(r.merge(&range(0x1200, 0x1400)), Ok(r));
+        assert_eq!(
+            r.merge(&range(0x2001, 0x3000)),
+            Err(MemoryRangeError::Disjoint)
+        );
+        assert_eq!(r.merge(&range(0x9000, 0x9000)), Ok(r));
+    }
+}
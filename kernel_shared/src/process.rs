@@ -0,0 +1,280 @@
+//! The stack a new process starts with, laid out as the System V x86_64 ABI describes, so
+//! runtimes written for it can find their arguments, environment, and auxiliary vector.
+
+pub const AT_NULL: u64 = 0;
+pub const AT_PAGESZ: u64 = 6;
+pub const AT_ENTRY: u64 = 9;
+/// The address of 16 random bytes, for seeding stack protectors and the like.
+pub const AT_RANDOM: u64 = 25;
+
+pub const AT_RANDOM_LENGTH: usize = 16;
+const STACK_ALIGNMENT: u64 = 16;
+
+/// The user and group with every privilege.
+pub const ROOT_ID: u32 = 0;
+/// The user and group with none, which processes the kernel starts run as unless it says
+/// otherwise.
+pub const NOBODY_ID: u32 = 65534;
+
+pub const SET_ID_OK: u64 = 0;
+/// Only root may take on an identity other than its real one.
+pub const SET_ID_NOT_PERMITTED: u64 = 1;
+
+/// The parameter to SyscallNumber::SetUserId and SetGroupId. Root sets the real and effective
+/// ids, anyone else may only set the effective id back to the real one.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct SetIdRequest {
+    pub id: u64,
+    /// One of the SET_ID_ statuses.
+    pub status: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InitialStackError {
+    /// The arguments, environment, and auxiliary vector don't fit in the stack.
+    TooLarge,
+    /// The top of the stack isn't 16 byte aligned.
+    Misaligned,
+}
+
+/// What a process is started with.
+pub struct StartupInfo<'a> {
+    pub arguments: &'a [&'a str],
+    pub environment: &'a [&'a str],
+    pub entry: u64,
+    pub page_size: u64,
+    pub random: [u8; AT_RANDOM_LENGTH],
+}
+
+// Writes downwards from the top of the stack, tracking the address the process will see.
+struct StackWriter<'a> {
+    stack: &'a mut [u8],
+    // The process's address of the end of stack.
+    top: u64,
+    offset: usize,
+}
+
+impl<'a> StackWriter<'a> {
+    fn address(&self) -> u64 {
+        self.top - (self.stack.len() - self.offset) as u64
+    }
+
+    fn push_bytes(&mut self, bytes: &[u8]) -> Result<u64, InitialStackError> {
+        self.offset = self
+            .offset
+            .checked_sub(bytes.len())
+            .ok_or(InitialStackError::TooLarge)?;
+        self.stack[self.offset..self.offset + bytes.len()].copy_from_slice(bytes);
+        Ok(self.address())
+    }
+
+    fn push_string(&mut self, string: &str) -> Result<u64, InitialStackError> {
+        self.push_bytes(&[0])?;
+        self.push_bytes(string.as_bytes())
+    }
+
+    fn push_word(&mut self, word: u64) -> Result<u64, InitialStackError> {
+        self.push_bytes(&word.to_ne_bytes())
+    }
+
+    fn align_down(&mut self, alignment: u64) -> Result<(), InitialStackError> {
+        let padding = (self.address() % alignment) as usize;
+        self.offset = self
+            .offset
+            .checked_sub(padding)
+            .ok_or(InitialStackError::TooLarge)?;
+        Ok(())
+    }
+}
+
+/// Writes the initial stack into stack, the top of a process's stack as mapped in the kernel,
+/// which the process sees ending at top. Returns the stack pointer to start the process with,
+/// which points at argc, followed by argv, envp, and the auxiliary vector.
+pub fn build_initial_stack(
+    stack: &mut [u8],
+    top: u64,
+    info: &StartupInfo,
+) -> Result<u64, InitialStackError> {
+    if top % STACK_ALIGNMENT != 0 {
+        return Err(InitialStackError::Misaligned);
+    }
+    let mut writer = StackWriter {
+        offset: stack.len(),
+        stack,
+        top,
+    };
+    let random = writer.push_bytes(&info.random)?;
+    // The strings, argv's first, so each one's address follows from the lengths of those before.
+    for string in info.arguments.iter().chain(info.environment.iter()).rev() {
+        writer.push_string(string)?;
+    }
+    let auxiliary = [
+        (AT_PAGESZ, info.page_size),
+        (AT_ENTRY, info.entry),
+        (AT_RANDOM, random),
+        (AT_NULL, 0),
+    ];
+    // argc, argv and its terminator, envp and its terminator, then the auxiliary pairs. Padded
+    // so argc lands on a 16 byte boundary.
+    let words = 1 + (info.arguments.len() + 1) + (info.environment.len() + 1) + auxiliary.len() * 2;
+    writer.align_down(STACK_ALIGNMENT)?;
+    if words % 2 != 0 {
+        writer.push_word(0)?;
+    }
+    for (key, value) in auxiliary.iter().rev() {
+        writer.push_word(*value)?;
+        writer.push_word(*key)?;
+    }
+    // The strings end where the random bytes start, so walk them backwards from there.
+    let mut end = random;
+    let mut string_addresses = info
+        .arguments
+        .iter()
+        .chain(info.environment.iter())
+        .rev()
+        .map(|string| {
+            end -= string.len() as u64 + 1;
+            end
+        });
+    writer.push_word(0)?;
+    for _ in info.environment {
+        writer.push_word(string_addresses.next().unwrap())?;
+    }
+    writer.push_word(0)?;
+    for _ in info.arguments {
+        writer.push_word(string_addresses.next().unwrap())?;
+    }
+    writer.push_word(info.arguments.len() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOP: u64 = 0x7fff_0000;
+    const STACK_SIZE: usize = 512;
+
+    // Reads the stack at an address the process would see.
+    struct Reader<'a> {
+        stack: &'a [u8],
+    }
+
+    impl Reader<'_> {
+        fn offset(&self, address: u64) -> usize {
+            self.stack.len() - (TOP - address) as usize
+        }
+
+        fn word(&self, address: u64) -> u64 {
+            let offset = self.offset(address);
+            u64::from_ne_bytes(self.stack[offset..offset + 8].try_into().unwrap())
+        }
+
+        fn string(&self, address: u64) -> &str {
+            let offset = self.offset(address);
+            let length = self.stack[offset..]
+                .iter()
+                .position(|byte| *byte == 0)
+                .unwrap();
+            core::str::from_utf8(&self.stack[offset..offset + length]).unwrap()
+        }
+    }
+
+    fn info<'a>(arguments: &'a [&'a str], environment: &'a [&'a str]) -> StartupInfo<'a> {
+        StartupInfo {
+            arguments,
+            environment,
+            entry: 0x40_1000,
+            page_size: 4096,
+            random: [0xa5; AT_RANDOM_LENGTH],
+        }
+    }
+
+    #[test]
+    fn lays_out_arguments_environment_and_auxiliary_vector() {
+        let mut stack = [0xffu8; STACK_SIZE];
+        let arguments = ["init", "--verbose"];
+        let environment = ["PATH=/bin", "HOME=/", "TERM=vt100"];
+        let sp = build_initial_stack(&mut stack, TOP, &info(&arguments, &environment)).unwrap();
+        let reader = Reader { stack: &stack };
+
+        assert_eq!(reader.word(sp), 2);
+        let mut address = sp + 8;
+        for argument in arguments {
+            assert_eq!(reader.string(reader.word(address)), argument);
+            address += 8;
+        }
+        assert_eq!(reader.word(address), 0);
+        address += 8;
+        for variable in environment {
+            assert_eq!(reader.string(reader.word(address)), variable);
+            address += 8;
+        }
+        assert_eq!(reader.word(address), 0);
+        address += 8;
+
+        let mut auxiliary = [(0, 0); 4];
+        for pair in auxiliary.iter_mut() {
+            *pair = (reader.word(address), reader.word(address + 8));
+            address += 16;
+        }
+        assert_eq!(auxiliary[0], (AT_PAGESZ, 4096));
+        assert_eq!(auxiliary[1], (AT_ENTRY, 0x40_1000));
+        assert_eq!(auxiliary[2].0, AT_RANDOM);
+        assert_eq!(auxiliary[3], (AT_NULL, 0));
+        let random = reader.offset(auxiliary[2].1);
+        assert_eq!(
+            stack[random..random + AT_RANDOM_LENGTH],
+            [0xa5; AT_RANDOM_LENGTH]
+        );
+    }
+
+    #[test]
+    fn terminates_every_string() {
+        let mut stack = [0xffu8; STACK_SIZE];
+        let arguments = ["a", "bc"];
+        let environment = ["d=e"];
+        let sp = build_initial_stack(&mut stack, TOP, &info(&arguments, &environment)).unwrap();
+        let reader = Reader { stack: &stack };
+        for (index, expected) in [(1, "a"), (2, "bc"), (4, "d=e")] {
+            let address = reader.word(sp + index * 8);
+            assert_eq!(stack[reader.offset(address) + expected.len()], 0);
+        }
+    }
+
+    #[test]
+    fn aligns_argc_to_16_bytes() {
+        let arguments = ["one", "two", "three", "four"];
+        for count in 0..=arguments.len() {
+            let mut stack = [0u8; STACK_SIZE];
+            let sp = build_initial_stack(&mut stack, TOP, &info(&arguments[..count], &[])).unwrap();
+            assert_eq!(sp % STACK_ALIGNMENT, 0, "with {} arguments", count);
+            assert_eq!(Reader { stack: &stack }.word(sp), count as u64);
+        }
+    }
+
+    #[test]
+    fn handles_no_arguments_or_environment() {
+        let mut stack = [0xffu8; STACK_SIZE];
+        let sp = build_initial_stack(&mut stack, TOP, &info(&[], &[])).unwrap();
+        let reader = Reader { stack: &stack };
+        assert_eq!(reader.word(sp), 0);
+        assert_eq!(reader.word(sp + 8), 0);
+        assert_eq!(reader.word(sp + 16), 0);
+        assert_eq!(reader.word(sp + 24), AT_PAGESZ);
+    }
+
+    #[test]
+    fn rejects_stacks_it_cannot_use() {
+        let mut stack = [0u8; 64];
+        assert_eq!(
+            build_initial_stack(&mut stack, TOP, &info(&["init"], &[])),
+            Err(InitialStackError::TooLarge)
+        );
+        let mut stack = [0u8; STACK_SIZE];
+        assert_eq!(
+            build_initial_stack(&mut stack, TOP + 8, &info(&["init"], &[])),
+            Err(InitialStackError::Misaligned)
+        );
+    }
+}
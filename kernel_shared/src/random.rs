@@ -0,0 +1,8 @@
+/// The parameter to SyscallNumber::GetRandom, which fills length bytes at buffer. They're good
+/// enough for stack protectors and hash seeds, not for keys.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct RandomRequest {
+    pub buffer: *mut u8,
+    pub length: u64,
+}
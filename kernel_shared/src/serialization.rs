@@ -0,0 +1,397 @@
+//! Tag-length-value encoding for data that crosses the kernel/userspace boundary.
+//!
+//! Every field is written as a little endian u16 tag, a little endian u16 length, and then the
+//! value. Decoders look fields up by tag and skip the ones they don't know, so fields can be
+//! added without breaking older readers, and a missing or mistyped field is an error instead of
+//! garbage read from the wrong offset.
+//!
+//! Integers are stored little endian at their native width, and can be read back into any type
+//! wide enough to hold the value.
+
+const HEADER_LENGTH: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SerializationError {
+    BufferTooSmall,
+    ValueTooLarge,
+    Truncated,
+    MissingField(u16),
+    InvalidValue(u16),
+}
+
+pub type Result<T> = core::result::Result<T, SerializationError>;
+
+/// A type that can be written as a set of tagged fields.
+pub trait Encode {
+    fn encode(&self, encoder: &mut Encoder) -> Result<()>;
+}
+
+/// A type that can be read back from a set of tagged fields.
+pub trait Decode<'a>: Sized {
+    fn decode(decoder: &Decoder<'a>) -> Result<Self>;
+}
+
+/// A single field value.
+pub trait Value<'a>: Sized {
+    fn encoded_length(&self) -> usize;
+    fn write_value(&self, buffer: &mut [u8]);
+    fn read_value(bytes: &'a [u8]) -> Option<Self>;
+}
+
+macro_rules! integer_value {
+    ($($t:ty),*) => {
+        $(
+            impl<'a> Value<'a> for $t {
+                fn encoded_length(&self) -> usize {
+                    core::mem::size_of::<$t>()
+                }
+
+                fn write_value(&self, buffer: &mut [u8]) {
+                    buffer.copy_from_slice(&self.to_le_bytes());
+                }
+
+                fn read_value(bytes: &'a [u8]) -> Option<Self> {
+                    if bytes.len() > 16 {
+                        return None;
+                    }
+                    let mut wide = [0u8; 16];
+                    wide[0..bytes.len()].copy_from_slice(bytes);
+                    <$t>::try_from(u128::from_le_bytes(wide)).ok()
+                }
+            }
+        )*
+    };
+}
+
+integer_value!(u8, u16, u32, u64, u128, usize);
+
+impl<'a> Value<'a> for bool {
+    fn encoded_length(&self) -> usize {
+        1
+    }
+
+    fn write_value(&self, buffer: &mut [u8]) {
+        buffer[0] = *self as u8;
+    }
+
+    fn read_value(bytes: &'a [u8]) -> Option<Self> {
+        match u8::read_value(bytes)? {
+            0 => Some(false),
+            1 => Some(true),
+            _ => None,
+        }
+    }
+}
+
+impl<'a> Value<'a> for &'a [u8] {
+    fn encoded_length(&self) -> usize {
+        self.len()
+    }
+
+    fn write_value(&self, buffer: &mut [u8]) {
+        buffer.copy_from_slice(self);
+    }
+
+    fn read_value(bytes: &'a [u8]) -> Option<Self> {
+        Some(bytes)
+    }
+}
+
+impl<'a> Value<'a> for &'a str {
+    fn encoded_length(&self) -> usize {
+        self.len()
+    }
+
+    fn write_value(&self, buffer: &mut [u8]) {
+        buffer.copy_from_slice(self.as_bytes());
+    }
+
+    fn read_value(bytes: &'a [u8]) -> Option<Self> {
+        core::str::from_utf8(bytes).ok()
+    }
+}
+
+/// Writes tagged fields into a caller supplied buffer.
+pub struct Encoder<'b> {
+    buffer: &'b mut [u8],
+    position: usize,
+}
+
+impl<'b> Encoder<'b> {
+    pub fn new(buffer: &'b mut [u8]) -> Self {
+        Self {
+            buffer,
+            position: 0,
+        }
+    }
+
+    /// Number of bytes written so far.
+    pub fn len(&self) -> usize {
+        self.position
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.position == 0
+    }
+
+    pub fn finish(self) -> &'b [u8] {
+        &self.buffer[0..self.position]
+    }
+
+    fn write_header(&mut self, tag: u16, length: usize) -> Result<usize> {
+        if length > u16::MAX as usize {
+            return Err(SerializationError::ValueTooLarge);
+        }
+        let start = self.position + HEADER_LENGTH;
+        if start + length > self.buffer.len() {
+            return Err(SerializationError::BufferTooSmall);
+        }
+        self.buffer[self.position..self.position + 2].copy_from_slice(&tag.to_le_bytes());
+        self.buffer[self.position + 2..start].copy_from_slice(&(length as u16).to_le_bytes());
+        Ok(start)
+    }
+
+    pub fn field<'v, V: Value<'v>>(&mut self, tag: u16, value: &V) -> Result<()> {
+        let length = value.encoded_length();
+        let start = self.write_header(tag, length)?;
+        value.write_value(&mut self.buffer[start..start + length]);
+        self.position = start + length;
+        Ok(())
+    }
+
+    /// Writes a field holding another encoded type.
+    pub fn nested<T: Encode>(&mut self, tag: u16, value: &T) -> Result<()> {
+        let start = self.position + HEADER_LENGTH;
+        if start > self.buffer.len() {
+            return Err(SerializationError::BufferTooSmall);
+        }
+        let mut inner = Encoder::new(&mut self.buffer[start..]);
+        value.encode(&mut inner)?;
+        let length = inner.len();
+        self.write_header(tag, length)?;
+        self.position = start + length;
+        Ok(())
+    }
+}
+
+/// Looks up tagged fields in an encoded buffer.
+#[derive(Debug, Clone, Copy)]
+pub struct Decoder<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+    /// Checks that every field in the buffer is complete.
+    pub fn new(bytes: &'a [u8]) -> Result<Self> {
+        let decoder = Self { bytes };
+        let mut position = 0;
+        while position < bytes.len() {
+            let (_, value) = decoder.field_at(position)?;
+            position += HEADER_LENGTH + value.len();
+        }
+        Ok(decoder)
+    }
+
+    fn field_at(&self, position: usize) -> Result<(u16, &'a [u8])> {
+        let header = self
+            .bytes
+            .get(position..position + HEADER_LENGTH)
+            .ok_or(SerializationError::Truncated)?;
+        let tag = u16::from_le_bytes([header[0], header[1]]);
+        let length = u16::from_le_bytes([header[2], header[3]]) as usize;
+        let start = position + HEADER_LENGTH;
+        let value = self
+            .bytes
+            .get(start..start + length)
+            .ok_or(SerializationError::Truncated)?;
+        Ok((tag, value))
+    }
+
+    /// Iterates over every (tag, value) pair, in the order they were written.
+    pub fn fields(&self) -> impl Iterator<Item = (u16, &'a [u8])> + 'a {
+        let decoder = *self;
+        let mut position = 0;
+        core::iter::from_fn(move || {
+            let (tag, value) = decoder.field_at(position).ok()?;
+            position += HEADER_LENGTH + value.len();
+            Some((tag, value))
+        })
+    }
+
+    /// Returns the raw value of the first field with this tag.
+    pub fn raw(&self, tag: u16) -> Option<&'a [u8]> {
+        self.fields().find(|(t, _)| *t == tag).map(|(_, v)| v)
+    }
+
+    pub fn optional<V: Value<'a>>(&self, tag: u16) -> Result<Option<V>> {
+        match self.raw(tag) {
+            Some(bytes) => V::read_value(bytes)
+                .map(Some)
+                .ok_or(SerializationError::InvalidValue(tag)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn required<V: Value<'a>>(&self, tag: u16) -> Result<V> {
+        self.optional(tag)?
+            .ok_or(SerializationError::MissingField(tag))
+    }
+
+    pub fn nested<T: Decode<'a>>(&self, tag: u16) -> Result<T> {
+        let bytes = self.raw(tag).ok_or(SerializationError::MissingField(tag))?;
+        T::decode(&Decoder::new(bytes)?)
+    }
+}
+
+/// Encodes a value into buffer, returning the encoded bytes.
+pub fn to_bytes<'b, T: Encode>(value: &T, buffer: &'b mut [u8]) -> Result<&'b [u8]> {
+    let mut encoder = Encoder::new(buffer);
+    value.encode(&mut encoder)?;
+    Ok(encoder.finish())
+}
+
+pub fn from_bytes<'a, T: Decode<'a>>(bytes: &'a [u8]) -> Result<T> {
+    T::decode(&Decoder::new(bytes)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq, Eq)]
+    struct Inner {
+        value: u16,
+    }
+
+    impl Encode for Inner {
+        fn encode(&self, encoder: &mut Encoder) -> Result<()> {
+            encoder.field(1, &self.value)
+        }
+    }
+
+    impl<'a> Decode<'a> for Inner {
+        fn decode(decoder: &Decoder<'a>) -> Result<Self> {
+            Ok(Self {
+                value: decoder.required(1)?,
+            })
+        }
+    }
+
+    #[derive(Debug, PartialEq, Eq)]
+    struct Outer<'a> {
+        number: u64,
+        flag: bool,
+        name: &'a str,
+        bytes: &'a [u8],
+        inner: Inner,
+    }
+
+    impl Encode for Outer<'_> {
+        fn encode(&self, encoder: &mut Encoder) -> Result<()> {
+            encoder.field(1, &self.number)?;
+            encoder.field(2, &self.flag)?;
+            encoder.field(3, &self.name)?;
+            encoder.field(4, &self.bytes)?;
+            encoder.nested(5, &self.inner)
+        }
+    }
+
+    impl<'a> Decode<'a> for Outer<'a> {
+        fn decode(decoder: &Decoder<'a>) -> Result<Self> {
+            Ok(Self {
+                number: decoder.required(1)?,
+                flag: decoder.required(2)?,
+                name: decoder.required(3)?,
+                bytes: decoder.required(4)?,
+                inner: decoder.nested(5)?,
+            })
+        }
+    }
+
+    fn outer() -> Outer<'static> {
+        Outer {
+            number: 0x1234_5678_9abc,
+            flag: true,
+            name: "serial",
+            bytes: &[1, 2, 3],
+            inner: Inner { value: 300 },
+        }
+    }
+
+    #[test]
+    fn round_trips() {
+        let mut buffer = [0u8; 128];
+        let bytes = to_bytes(&outer(), &mut buffer).unwrap();
+        assert_eq!(from_bytes::<Outer>(bytes), Ok(outer()));
+    }
+
+    #[test]
+    fn skips_unknown_fields() {
+        let mut buffer = [0u8; 16];
+        let mut encoder = Encoder::new(&mut buffer);
+        encoder.field(9, &0xFFu8).unwrap();
+        encoder.field(1, &7u16).unwrap();
+        assert_eq!(
+            from_bytes::<Inner>(encoder.finish()),
+            Ok(Inner { value: 7 })
+        );
+    }
+
+    #[test]
+    fn reports_missing_and_invalid_fields() {
+        let mut buffer = [0u8; 16];
+        let mut encoder = Encoder::new(&mut buffer);
+        encoder.field(2, &7u16).unwrap();
+        assert_eq!(
+            from_bytes::<Inner>(encoder.finish()),
+            Err(SerializationError::MissingField(1))
+        );
+        let decoder = Decoder::new(&[1, 0, 2, 0, 0x2c, 0x01]).unwrap();
+        assert_eq!(decoder.required::<u16>(1), Ok(300));
+        assert_eq!(
+            decoder.required::<u8>(1),
+            Err(SerializationError::InvalidValue(1))
+        );
+    }
+
+    #[test]
+    fn rejects_truncated_input() {
+        let mut buffer = [0u8; 128];
+        let bytes = to_bytes(&outer(), &mut buffer).unwrap();
+        let mut field_ends = Decoder::new(bytes)
+            .unwrap()
+            .fields()
+            .scan(0, |end, (_, value)| {
+                *end += HEADER_LENGTH + value.len();
+                Some(*end)
+            });
+        let mut next_end = field_ends.next();
+        for length in 1..bytes.len() {
+            if next_end == Some(length) {
+                next_end = field_ends.next();
+                continue;
+            }
+            assert_eq!(
+                Decoder::new(&bytes[0..length]).err(),
+                Some(SerializationError::Truncated),
+                "cut at {}",
+                length
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_a_buffer_too_small() {
+        let mut buffer = [0u8; 128];
+        let length = to_bytes(&outer(), &mut buffer).unwrap().len();
+        for size in 0..length {
+            let mut buffer = [0u8; 128];
+            assert_eq!(
+                to_bytes(&outer(), &mut buffer[0..size]),
+                Err(SerializationError::BufferTooSmall),
+                "buffer of {}",
+                size
+            );
+        }
+    }
+}
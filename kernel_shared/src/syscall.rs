@@ -2,6 +2,56 @@ use core::arch::asm;
 
 use crate::constants::*;
 
+/// The personality of the kernel's own system calls.
+pub const NATIVE_PERSONALITY: u64 = u64::MAX;
+/// The number of a personality's default handler, which takes every call without its own.
+pub const DEFAULT_SYSCALL: u64 = u64::MAX;
+pub const SYSCALL_NAME_LENGTH: usize = 32;
+
+/// One registered system call, as listed by SyscallNumber::ListSyscalls.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct SyscallDescription {
+    pub personality: u64,
+    pub number: u64,
+    /// How many of the caller's parameters the call uses.
+    pub arguments: u64,
+    /// UTF-8, padded with zeros.
+    pub name: [u8; SYSCALL_NAME_LENGTH],
+}
+
+impl SyscallDescription {
+    pub fn name(&self) -> &str {
+        let length = self
+            .name
+            .iter()
+            .position(|b| *b == 0)
+            .unwrap_or(self.name.len());
+        core::str::from_utf8(&self.name[..length]).unwrap_or("")
+    }
+}
+
+impl Default for SyscallDescription {
+    fn default() -> Self {
+        Self {
+            personality: 0,
+            number: 0,
+            arguments: 0,
+            name: [0; SYSCALL_NAME_LENGTH],
+        }
+    }
+}
+
+/// The parameter to SyscallNumber::ListSyscalls. Up to capacity descriptions are written to
+/// entries, and count is set to how many there are in total, so a caller can size its buffer.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct SyscallListRequest {
+    pub entries: *mut SyscallDescription,
+    pub capacity: u64,
+    pub count: u64,
+}
+
 //#[cfg(any(target_feature = "client", target_feature = "server"))]
 #[cfg(target_arch = "x86_64")]
 pub extern "C" fn syscall(function: SyscallNumber, parameters: *const u8) -> *const u8 {
@@ -0,0 +1,105 @@
+//! Tunables, named kernel settings that can be changed while it runs, like sysctl. Each is an
+//! integer, a bool, or a short string. Anyone may read them, only root may change them.
+
+pub const TUNABLE_NAME_LENGTH: usize = 32;
+pub const TUNABLE_STRING_LENGTH: usize = 32;
+
+pub const TUNABLE_KIND_INTEGER: u64 = 1;
+pub const TUNABLE_KIND_BOOL: u64 = 2;
+pub const TUNABLE_KIND_STRING: u64 = 3;
+
+pub const TUNABLE_OK: u64 = 0;
+pub const TUNABLE_NOT_FOUND: u64 = 1;
+pub const TUNABLE_NOT_PERMITTED: u64 = 2;
+/// The wrong kind, out of range, or refused by the subsystem the tunable belongs to.
+pub const TUNABLE_INVALID: u64 = 3;
+
+// UTF-8 padded with zeros, as names and strings are passed.
+fn padded_str(bytes: &[u8]) -> &str {
+    let length = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+    core::str::from_utf8(&bytes[..length]).unwrap_or("")
+}
+
+/// A tunable's value. Which field holds it depends on kind, bools are the integers 0 and 1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct TunableValue {
+    /// One of the TUNABLE_KIND_ constants.
+    pub kind: u64,
+    pub integer: i64,
+    /// UTF-8, padded with zeros.
+    pub string: [u8; TUNABLE_STRING_LENGTH],
+}
+
+impl TunableValue {
+    pub fn string(&self) -> &str {
+        padded_str(&self.string)
+    }
+}
+
+impl Default for TunableValue {
+    fn default() -> Self {
+        Self {
+            kind: 0,
+            integer: 0,
+            string: [0; TUNABLE_STRING_LENGTH],
+        }
+    }
+}
+
+/// One tunable, as listed by SyscallNumber::ListTunables.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct TunableDescription {
+    /// UTF-8, padded with zeros.
+    pub name: [u8; TUNABLE_NAME_LENGTH],
+    pub value: TunableValue,
+    /// The range integers may be set to, inclusive.
+    pub minimum: i64,
+    pub maximum: i64,
+}
+
+impl TunableDescription {
+    pub fn name(&self) -> &str {
+        padded_str(&self.name)
+    }
+}
+
+impl Default for TunableDescription {
+    fn default() -> Self {
+        Self {
+            name: [0; TUNABLE_NAME_LENGTH],
+            value: TunableValue::default(),
+            minimum: 0,
+            maximum: 0,
+        }
+    }
+}
+
+/// The parameter to SyscallNumber::ListTunables. Up to capacity descriptions are written to
+/// entries, and count is set to how many there are in total, so a caller can size its buffer.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct TunableListRequest {
+    pub entries: *mut TunableDescription,
+    pub capacity: u64,
+    pub count: u64,
+}
+
+/// The parameter to SyscallNumber::GetTunable, which fills value in, and SetTunable, which
+/// changes the tunable to it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct TunableRequest {
+    /// UTF-8, padded with zeros.
+    pub name: [u8; TUNABLE_NAME_LENGTH],
+    pub value: TunableValue,
+    /// One of the TUNABLE_ statuses.
+    pub status: u64,
+}
+
+impl TunableRequest {
+    pub fn name(&self) -> &str {
+        padded_str(&self.name)
+    }
+}
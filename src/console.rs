@@ -0,0 +1,90 @@
+//! Interactive passthrough to the guest's serial port: the host terminal goes into raw mode, so
+//! every keystroke reaches the kernel as typed, and Ctrl-] quits, like telnet. The session can be
+//! logged to a timestamped file beside the runner.
+
+use std::{
+    fs::File,
+    io::{Read, Write},
+    path::Path,
+    process::{Command, ExitStatus, Stdio},
+    sync::mpsc,
+    thread,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::triage;
+
+const ESCAPE: u8 = 0x1d;
+
+/// QEMU's serial arguments for interactive mode. The serial port is on QEMU's stdio, which the
+/// runner pipes, with Ctrl-C passed to the guest rather than killing QEMU.
+pub fn serial_arguments(cmd: &mut Command) {
+    cmd.arg("-chardev")
+        .arg("stdio,id=console,signal=off")
+        .arg("-serial")
+        .arg("chardev:console");
+}
+
+/// Runs QEMU with the terminal passed through to its serial port, until it exits, the kernel
+/// panics, or the escape is pressed, then puts the terminal back.
+pub fn run(mut cmd: Command, log_session: bool, qmp_path: &Path) -> ExitStatus {
+    let log = match log_session {
+        true => Some(create_session_log()),
+        false => None,
+    };
+    println!("Interactive serial console, press Ctrl-] to quit");
+    let saved = stty(&["-g"]).expect("Unable to read the terminal settings, is this a terminal?");
+    stty(&["-icanon", "-echo", "-isig", "-ixon", "min", "1"]);
+
+    let mut child = cmd
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("Unable to spawn qemu process");
+    let mut guest_input = child.stdin.take().unwrap();
+    let (output, panics) = triage::forward_output(child.stdout.take().unwrap(), log);
+
+    // Reading the terminal blocks, so it's on its own thread, which is left behind if QEMU exits
+    // by itself.
+    let (escaped, escape) = mpsc::channel();
+    thread::spawn(move || {
+        let mut stdin = std::io::stdin();
+        let mut byte = [0u8];
+        while let Ok(1) = stdin.read(&mut byte) {
+            if byte[0] == ESCAPE || guest_input.write_all(&byte).is_err() {
+                break;
+            }
+        }
+        let _ = escaped.send(());
+    });
+
+    let status = triage::supervise(&mut child, &panics, Some(&escape), qmp_path);
+    let _ = output.join();
+    stty(&[saved.trim()]);
+    println!();
+    status
+}
+
+fn create_session_log() -> File {
+    let seconds = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    let path = std::env::current_exe()
+        .expect("Unable to find the runner")
+        .with_file_name(format!("session-{}.log", seconds));
+    println!("Logging the session to {}", path.display());
+    File::create(path).expect("Unable to create the session log")
+}
+
+// The terminal is the runner's stdin, which stty reads its settings from.
+fn stty(arguments: &[&str]) -> Option<String> {
+    let output = Command::new("stty")
+        .args(arguments)
+        .stdin(Stdio::inherit())
+        .output()
+        .ok()?;
+    match output.status.success() {
+        true => Some(String::from_utf8_lossy(&output.stdout).into_owned()),
+        false => None,
+    }
+}
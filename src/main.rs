@@ -1,25 +1,133 @@
-use std::process::Command;
+use std::{
+    fs::File,
+    path::PathBuf,
+    process::{Command, Stdio},
+};
+
+mod console;
+mod triage;
+
+/// The architectures the runner knows how to boot. The kernel builds for x86_64 today, the
+/// others are ready for the ports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Arch {
+    X86_64,
+    Aarch64,
+    Riscv64,
+}
+
+impl Arch {
+    fn from_name(name: &str) -> Arch {
+        match name {
+            "x86_64" => Arch::X86_64,
+            "aarch64" => Arch::Aarch64,
+            "riscv64" => Arch::Riscv64,
+            _ => panic!("Unsupported kernel architecture {}", name),
+        }
+    }
+
+    fn qemu(&self) -> &'static str {
+        match self {
+            Arch::X86_64 => "qemu-system-x86_64",
+            Arch::Aarch64 => "qemu-system-aarch64",
+            Arch::Riscv64 => "qemu-system-riscv64",
+        }
+    }
+
+    // KVM only runs guests of the host's own architecture.
+    fn can_accelerate(&self) -> bool {
+        match self {
+            Arch::X86_64 => cfg!(target_arch = "x86_64"),
+            Arch::Aarch64 => cfg!(target_arch = "aarch64"),
+            Arch::Riscv64 => cfg!(target_arch = "riscv64"),
+        }
+    }
+}
+
+// Where to find the aarch64 EDK2 build, which isn't bundled like OVMF is. The default is where
+// Debian and Ubuntu's qemu-efi-aarch64 package puts it.
+const AARCH64_FIRMWARE_VARIABLE: &str = "OXIDIZED_AARCH64_FIRMWARE";
+const AARCH64_FIRMWARE_DEFAULT: &str = "/usr/share/qemu-efi-aarch64/QEMU_EFI.fd";
+// A disk that outlives rebuilds, for the kernel to keep data on. Attached when this is set to
+// its size in MiB, and created beside the runner the first time.
+const DATA_SIZE_VARIABLE: &str = "OXIDIZED_DATA_MB";
+const DATA_IMAGE_NAME: &str = "data.img";
+
+/// Set with arguments after `cargo run --`.
+#[derive(Debug, Default)]
+struct Options {
+    /// --interactive: pass the terminal through to the serial port, see console.rs.
+    interactive: bool,
+    /// --log-session: also log the serial output to a file, implies --interactive.
+    log_session: bool,
+}
+
+impl Options {
+    fn parse() -> Options {
+        let mut options = Options::default();
+        for argument in std::env::args().skip(1) {
+            match argument.as_str() {
+                "--interactive" => options.interactive = true,
+                "--log-session" => {
+                    options.interactive = true;
+                    options.log_session = true;
+                }
+                _ => panic!("Unknown argument {}", argument),
+            }
+        }
+        options
+    }
+}
 
 fn main() {
+    let options = Options::parse();
     // read env variables that were set in build script
+    let arch = Arch::from_name(env!("KERNEL_ARCH"));
+    let kernel_path = env!("KERNEL_PATH");
+    let initramfs_path = env!("INITRAMFS_PATH");
     let uefi_path = env!("UEFI_PATH");
     let bios_path = env!("BIOS_PATH");
 
-    // choose whether to start the UEFI or BIOS image
-    const UEFI: bool = true;
-    let image = match UEFI {
-        true => uefi_path,
-        false => bios_path,
+    let mut cmd = match arch {
+        Arch::X86_64 => {
+            // choose whether to start the UEFI or BIOS image
+            const UEFI: bool = true;
+            let image = match UEFI {
+                true => uefi_path,
+                false => bios_path,
+            };
+            println!("Starting image {} with {}", image, arch.qemu());
+            create_x86_64_command(image, UEFI)
+        }
+        Arch::Aarch64 | Arch::Riscv64 => {
+            println!("Starting kernel {} with {}", kernel_path, arch.qemu());
+            create_virt_command(arch, kernel_path, initramfs_path)
+        }
     };
-
-    let mut cmd = create_command(image, UEFI);
-    println!("Starting image {} with qemu", image);
-    let mut child = cmd.spawn().expect("Unable to spawn qemu process");
-    child.wait().expect("Unable to wait for child exit!");
+    add_common_arguments(&mut cmd, arch);
+    if let Some(data_image) = data_image() {
+        cmd.arg("-drive")
+            .arg(format!("format=raw,file={}", data_image.display()));
+    }
+    let qmp_path = triage::qmp_arguments(&mut cmd);
+    if options.interactive {
+        console::serial_arguments(&mut cmd);
+        console::run(cmd, options.log_session, &qmp_path);
+        return;
+    }
+    cmd.arg("-serial").arg("stdio");
+    let mut child = cmd
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("Unable to spawn qemu process");
+    // Piped rather than inherited, so a panic can be spotted and triaged.
+    let (output, panics) = triage::forward_output(child.stdout.take().unwrap(), None);
+    triage::supervise(&mut child, &panics, None, &qmp_path);
+    let _ = output.join();
 }
 
-fn create_command(image_path: &str, uefi: bool) -> Command {
-    let mut cmd = std::process::Command::new("qemu-system-x86_64");
+fn create_x86_64_command(image_path: &str, uefi: bool) -> Command {
+    let mut cmd = std::process::Command::new(Arch::X86_64.qemu());
 
     if uefi {
         cmd.arg("-bios").arg(ovmf_prebuilt::ovmf_pure_efi());
@@ -27,16 +135,84 @@ fn create_command(image_path: &str, uefi: bool) -> Command {
 
     cmd.arg("-drive")
         .arg(format!("format=raw,file={image_path}"))
-        .arg("-serial")
-        .arg("stdio")
-        .arg("-m")
-        .arg("size=1024")
-        .arg("-smp")
-        .arg("cpus=4")
         .arg("-d")
-        .arg("cpu_reset")
-        .arg("-accel")
-        .arg("kvm");
+        .arg("cpu_reset");
 
     return cmd;
 }
+
+// The virt machines boot the kernel ELF directly: through EDK2 on aarch64, and through QEMU's
+// bundled OpenSBI on riscv64, which then jumps to it in supervisor mode. The initramfs is
+// loaded beside it, x86_64 has it in the disk image.
+fn create_virt_command(arch: Arch, kernel_path: &str, initramfs_path: &str) -> Command {
+    let mut cmd = std::process::Command::new(arch.qemu());
+    cmd.arg("-machine").arg("virt");
+
+    match arch {
+        Arch::Aarch64 => {
+            let firmware = std::env::var(AARCH64_FIRMWARE_VARIABLE)
+                .unwrap_or_else(|_| AARCH64_FIRMWARE_DEFAULT.to_string());
+            cmd.arg("-cpu").arg("cortex-a72").arg("-bios").arg(firmware);
+        }
+        Arch::Riscv64 => {
+            cmd.arg("-bios").arg("default");
+        }
+        Arch::X86_64 => unreachable!("x86_64 boots from a disk image"),
+    }
+
+    cmd.arg("-kernel")
+        .arg(kernel_path)
+        .arg("-initrd")
+        .arg(initramfs_path);
+    return cmd;
+}
+
+fn add_common_arguments(cmd: &mut Command, arch: Arch) {
+    cmd.arg("-m").arg("size=1024").arg("-smp").arg("cpus=4");
+
+    if cfg!(feature = "deterministic") {
+        // Instruction counting needs the emulator, runs every CPU on one host thread, and times
+        // the guest by instructions run, so it sees the same interleaving and clock every run.
+        cmd.arg("-icount")
+            .arg("shift=5,align=off,sleep=off")
+            .arg("-rtc")
+            .arg("base=2000-01-01T00:00:00,clock=vm");
+    } else if arch.can_accelerate() {
+        cmd.arg("-accel").arg("kvm");
+    }
+}
+
+// The data disk, if one was asked for. New ones are formatted ext2 if the host has the tools,
+// and left blank otherwise.
+fn data_image() -> Option<PathBuf> {
+    let megabytes: u64 = std::env::var(DATA_SIZE_VARIABLE)
+        .ok()?
+        .parse()
+        .expect("OXIDIZED_DATA_MB must be a size in MiB");
+    let path = std::env::current_exe()
+        .expect("Unable to find the runner")
+        .with_file_name(DATA_IMAGE_NAME);
+    if path.exists() {
+        return Some(path);
+    }
+
+    let file = File::create(&path).expect("Unable to create the data disk");
+    file.set_len(megabytes * 1024 * 1024)
+        .expect("Unable to size the data disk");
+    let formatted = Command::new("mkfs.ext2")
+        .arg("-q")
+        .arg("-F")
+        .arg("-L")
+        .arg("oxidized")
+        .arg(&path)
+        .status()
+        .map_or(false, |status| status.success());
+    match formatted {
+        true => println!("Created ext2 data disk {}", path.display()),
+        false => println!(
+            "Created blank data disk {}, mkfs.ext2 is unavailable",
+            path.display()
+        ),
+    }
+    Some(path)
+}
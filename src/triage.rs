@@ -0,0 +1,248 @@
+//! Crash triage. The kernel prints a marker line on the serial port when it panics, with where
+//! its log ring is. When the runner sees it, it stops the guest through the QEMU monitor (QMP),
+//! saves every CPU's registers and the log ring to a crash directory beside the runner, and tears
+//! the VM down.
+
+use std::{
+    fs::{self, File},
+    io::{self, BufRead, BufReader, Read, Write},
+    os::unix::net::UnixStream,
+    path::{Path, PathBuf},
+    process::{Child, Command, ExitStatus},
+    sync::mpsc::{self, Receiver},
+    thread::{self, JoinHandle},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// Printed by the kernel's panic handler, see kernel/src/panic.rs.
+const PANIC_MARKER: &str = "OXIDIZED-PANIC";
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// What the kernel said when it panicked.
+#[derive(Debug, Clone)]
+pub struct PanicReport {
+    line: String,
+    log_address: Option<u64>,
+    log_length: Option<u64>,
+}
+
+impl PanicReport {
+    // The marker line is `OXIDIZED-PANIC log=<hex address> length=<bytes>`.
+    fn parse(line: &str) -> Option<PanicReport> {
+        let start = line.find(PANIC_MARKER)?;
+        let mut report = PanicReport {
+            line: line.trim_end().to_string(),
+            log_address: None,
+            log_length: None,
+        };
+        for field in line[start + PANIC_MARKER.len()..].split_whitespace() {
+            match field.split_once('=') {
+                Some(("log", value)) => {
+                    report.log_address =
+                        u64::from_str_radix(value.trim_start_matches("0x"), 16).ok()
+                }
+                Some(("length", value)) => report.log_length = value.parse().ok(),
+                _ => continue,
+            }
+        }
+        Some(report)
+    }
+}
+
+/// Opens the QMP socket, and returns where it is.
+pub fn qmp_arguments(cmd: &mut Command) -> PathBuf {
+    let path = std::env::current_exe()
+        .expect("Unable to find the runner")
+        .with_file_name(format!("qmp-{}.sock", std::process::id()));
+    cmd.arg("-qmp")
+        .arg(format!("unix:{},server=on,wait=off", path.display()));
+    path
+}
+
+/// Copies the guest's serial output to stdout, and the session log if there is one, watching it
+/// for the panic marker.
+pub fn forward_output(
+    mut output: impl Read + Send + 'static,
+    mut log: Option<File>,
+) -> (JoinHandle<()>, Receiver<PanicReport>) {
+    let (panicked, panics) = mpsc::channel();
+    let thread = thread::spawn(move || {
+        let mut stdout = io::stdout();
+        let mut buffer = [0u8; 4096];
+        let mut line = Vec::new();
+        loop {
+            let length = match output.read(&mut buffer) {
+                Ok(0) | Err(_) => break,
+                Ok(length) => length,
+            };
+            let _ = stdout.write_all(&buffer[..length]);
+            let _ = stdout.flush();
+            if let Some(log) = log.as_mut() {
+                let _ = log.write_all(&buffer[..length]);
+            }
+            for byte in &buffer[..length] {
+                if *byte != b'\n' {
+                    line.push(*byte);
+                    continue;
+                }
+                if let Some(report) = PanicReport::parse(&String::from_utf8_lossy(&line)) {
+                    let _ = panicked.send(report);
+                }
+                line.clear();
+            }
+        }
+    });
+    (thread, panics)
+}
+
+/// Waits for QEMU to exit, the kernel to panic, or quit to be signalled. A panic is triaged
+/// before QEMU is killed.
+pub fn supervise(
+    child: &mut Child,
+    panics: &Receiver<PanicReport>,
+    quit: Option<&Receiver<()>>,
+    qmp_path: &Path,
+) -> ExitStatus {
+    let status = loop {
+        if let Some(status) = child.try_wait().expect("Unable to wait for child exit!") {
+            break status;
+        }
+        if let Ok(report) = panics.try_recv() {
+            match capture(qmp_path, &report) {
+                Ok(directory) => eprintln!(
+                    "\r\nKernel panic, triage saved to {}\r",
+                    directory.display()
+                ),
+                Err(err) => eprintln!("\r\nKernel panic, triage failed: {}\r", err),
+            }
+            let _ = child.kill();
+            break child.wait().expect("Unable to wait for child exit!");
+        }
+        if quit.map_or(false, |quit| quit.try_recv().is_ok()) {
+            let _ = child.kill();
+            break child.wait().expect("Unable to wait for child exit!");
+        }
+        thread::sleep(POLL_INTERVAL);
+    };
+    let _ = fs::remove_file(qmp_path);
+    status
+}
+
+fn capture(qmp_path: &Path, report: &PanicReport) -> io::Result<PathBuf> {
+    let seconds = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    let directory = std::env::current_exe()?.with_file_name(format!("crash-{}", seconds));
+    fs::create_dir_all(&directory)?;
+    fs::write(directory.join("panic.txt"), format!("{}\n", report.line))?;
+
+    let mut qmp = Qmp::connect(qmp_path)?;
+    qmp.execute("stop", None)?;
+    let registers = qmp.human("info registers -a")?;
+    fs::write(directory.join("registers.txt"), registers)?;
+    if let (Some(address), Some(length)) = (report.log_address, report.log_length) {
+        // memsave reads guest virtual memory, as the first CPU sees it, which is where the kernel
+        // reported the log ring.
+        let log_path = directory.join("log.bin");
+        qmp.human(&format!(
+            "memsave {:#x} {} {}",
+            address,
+            length,
+            log_path.display()
+        ))?;
+    }
+    Ok(directory)
+}
+
+/// Just enough of a QMP client to run commands: one JSON object per line each way.
+struct Qmp {
+    writer: UnixStream,
+    reader: BufReader<UnixStream>,
+}
+
+impl Qmp {
+    fn connect(path: &Path) -> io::Result<Qmp> {
+        let writer = UnixStream::connect(path)?;
+        let reader = BufReader::new(writer.try_clone()?);
+        let mut qmp = Qmp { writer, reader };
+        // The greeting, then capabilities negotiation, which leaves command mode.
+        qmp.read_reply()?;
+        qmp.execute("qmp_capabilities", None)?;
+        Ok(qmp)
+    }
+
+    fn execute(&mut self, command: &str, arguments: Option<&str>) -> io::Result<String> {
+        let request = match arguments {
+            Some(arguments) => {
+                format!(
+                    "{{\"execute\":\"{}\",\"arguments\":{}}}\n",
+                    command, arguments
+                )
+            }
+            None => format!("{{\"execute\":\"{}\"}}\n", command),
+        };
+        self.writer.write_all(request.as_bytes())?;
+        let reply = self.read_reply()?;
+        match reply.contains("\"error\"") {
+            true => Err(io::Error::new(io::ErrorKind::Other, reply)),
+            false => Ok(reply),
+        }
+    }
+
+    /// Runs a human monitor command, and returns what it printed.
+    fn human(&mut self, command_line: &str) -> io::Result<String> {
+        let arguments = format!("{{\"command-line\":\"{}\"}}", escape_json(command_line));
+        let reply = self.execute("human-monitor-command", Some(&arguments))?;
+        Ok(unescape_json_string(&reply, "\"return\":"))
+    }
+
+    // The next reply, skipping asynchronous events.
+    fn read_reply(&mut self) -> io::Result<String> {
+        loop {
+            let mut line = String::new();
+            if self.reader.read_line(&mut line)? == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "QMP closed the connection",
+                ));
+            }
+            if !line.starts_with("{\"event\"") {
+                return Ok(line);
+            }
+        }
+    }
+}
+
+fn escape_json(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+// The string value after key, unescaped. Enough for monitor output, which is plain text.
+fn unescape_json_string(json: &str, key: &str) -> String {
+    let start = match json.find(key) {
+        Some(index) => index + key.len(),
+        None => return String::new(),
+    };
+    let mut value = String::new();
+    let mut characters = json[start..].trim_start().chars().skip(1);
+    while let Some(character) = characters.next() {
+        match character {
+            '"' => break,
+            '\\' => match characters.next() {
+                Some('n') => value.push('\n'),
+                Some('r') => value.push('\r'),
+                Some('t') => value.push('\t'),
+                Some('u') => {
+                    let code: String = characters.by_ref().take(4).collect();
+                    if let Some(c) = u32::from_str_radix(&code, 16).ok().and_then(char::from_u32) {
+                        value.push(c);
+                    }
+                }
+                Some(other) => value.push(other),
+                None => break,
+            },
+            _ => value.push(character),
+        }
+    }
+    value
+}